@@ -364,7 +364,7 @@ def main() -> None:
     run_parser.add_argument(
         "--script",
         "-c",
-        help="Script to execute in VM (not yet implemented)",
+        help="Command to execute in VM instead of the shell",
     )
     run_parser.add_argument(
         "--debug",
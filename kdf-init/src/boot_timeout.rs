@@ -0,0 +1,146 @@
+//! Reboot instead of hanging forever when setup gets stuck
+
+use anyhow::{Context, Result};
+use rustix::system::{reboot, RebootCommand};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::timings;
+
+/// Setup stage init is currently in, reported when the boot times out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    KernelMounts,
+    Modules,
+    Virtiofs,
+    Symlinks,
+    Env,
+    Exec,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::KernelMounts,
+        Phase::Modules,
+        Phase::Virtiofs,
+        Phase::Symlinks,
+        Phase::Env,
+        Phase::Exec,
+    ];
+
+    /// Name of the phase in the init.timings summary
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::KernelMounts => "kernel mounts",
+            Phase::Modules => "modules",
+            Phase::Virtiofs => "virtiofs",
+            Phase::Symlinks => "symlinks",
+            Phase::Env => "env",
+            Phase::Exec => "exec",
+        }
+    }
+
+    fn from_raw(raw: u8) -> Phase {
+        Phase::ALL
+            .into_iter()
+            .find(|&phase| phase as u8 == raw)
+            .unwrap_or(Phase::KernelMounts)
+    }
+}
+
+static PHASE: AtomicU8 = AtomicU8::new(Phase::KernelMounts as u8);
+
+/// Record that setup moved on to `phase`
+pub fn enter(phase: Phase) {
+    debug!("entering phase {:?}", phase);
+    PHASE.store(phase as u8, Ordering::Relaxed);
+    timings::record(phase);
+}
+
+/// Setup stage last recorded with [`enter`]
+pub fn current_phase() -> Phase {
+    Phase::from_raw(PHASE.load(Ordering::Relaxed))
+}
+
+/// Armed boot timeout, disarmed on drop
+pub struct BootTimer {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Reboot the VM if the timer isn't disarmed within `timeout`
+pub fn arm(timeout: Duration) -> Result<BootTimer> {
+    info!("boot timeout armed: {}s", timeout.as_secs());
+
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = std::thread::Builder::new()
+        .name("boot-timeout".to_string())
+        .spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout) {
+                error!(
+                    "boot timed out after {}s in phase {:?}, rebooting",
+                    timeout.as_secs(),
+                    current_phase()
+                );
+                if let Err(e) = reboot(RebootCommand::Restart) {
+                    error!("failed to reboot: {}", e);
+                }
+            }
+        })
+        .context("Failed to spawn boot timeout thread")?;
+
+    Ok(BootTimer {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+impl BootTimer {
+    /// Stop the timer, must happen right before handing over to the command
+    pub fn disarm(self) {}
+}
+
+impl Drop for BootTimer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        // Join so no helper thread is left behind to catch init's signals
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        debug!("boot timeout disarmed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_transitions() {
+        assert_eq!(current_phase(), Phase::KernelMounts);
+        for phase in Phase::ALL {
+            enter(phase);
+            assert_eq!(current_phase(), phase);
+        }
+        enter(Phase::KernelMounts);
+        assert_eq!(current_phase(), Phase::KernelMounts);
+    }
+
+    #[test]
+    fn test_phase_from_raw() {
+        for phase in Phase::ALL {
+            assert_eq!(Phase::from_raw(phase as u8), phase);
+        }
+        assert_eq!(Phase::from_raw(u8::MAX), Phase::KernelMounts);
+    }
+
+    #[test]
+    fn test_disarm_before_timeout() {
+        let timer = arm(Duration::from_secs(3600)).unwrap();
+        timer.disarm();
+    }
+}
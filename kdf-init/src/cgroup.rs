@@ -0,0 +1,151 @@
+//! cgroup2 mount and optional leaf cgroup for the command
+
+use anyhow::{Context, Result};
+use rustix::mount::MountFlags;
+use std::path::{Component, Path};
+
+use crate::{system, virtiofs};
+
+/// Where the unified cgroup hierarchy is mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Controllers delegated down to the leaf cgroup
+const CONTROLLERS: &str = "+cpu +memory";
+
+/// Check that an init.cgroup_path stays below the cgroup root
+///
+/// The path must be relative, non-empty and free of ".." components.
+pub fn validate_cgroup_path(path: &str) -> Result<()> {
+    if path.is_empty() {
+        anyhow::bail!("cgroup path is empty");
+    }
+
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir => anyhow::bail!("cgroup path must not contain '..': {}", path),
+            Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("cgroup path must be relative: {}", path)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether /proc/self/mounts contents list a cgroup2 mount at `target`
+fn cgroup2_mounted(mounts: &str, target: &str) -> bool {
+    mounts.lines().any(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        fields.next() == Some(target) && fields.next() == Some("cgroup2")
+    })
+}
+
+fn mount_cgroup2(dry_run: bool) -> Result<()> {
+    let mounts =
+        std::fs::read_to_string("/proc/self/mounts").context("Failed to read /proc/self/mounts")?;
+    if cgroup2_mounted(&mounts, CGROUP_ROOT) {
+        info!("cgroup2 already mounted at {}", CGROUP_ROOT);
+        return Ok(());
+    }
+
+    virtiofs::mkdir_p(CGROUP_ROOT, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+    system::mount_fs(
+        &format!("cgroup2 at {}", CGROUP_ROOT),
+        "cgroup2",
+        CGROUP_ROOT,
+        "cgroup2",
+        MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+        "",
+        dry_run,
+    )
+    .with_context(|| format!("Failed to mount cgroup2 at {}", CGROUP_ROOT))
+}
+
+/// Create the leaf cgroup, delegating controllers along the way, and move init into it
+fn enter_cgroup(path: &str, dry_run: bool) -> Result<()> {
+    let leaf = Path::new(CGROUP_ROOT).join(path);
+
+    // Controllers must be enabled in every ancestor's subtree_control. The
+    // leaf itself is left alone, a cgroup delegating controllers can't hold
+    // processes.
+    let mut dir = Path::new(CGROUP_ROOT).to_path_buf();
+    for component in Path::new(path).components() {
+        let subtree_control = dir.join("cgroup.subtree_control");
+        if dry_run {
+            info!(
+                "dry-run: would write '{}' to {}",
+                CONTROLLERS,
+                subtree_control.display()
+            );
+        } else if let Err(e) = std::fs::write(&subtree_control, CONTROLLERS) {
+            warn!(
+                "failed to enable controllers '{}' in {}: {}",
+                CONTROLLERS,
+                subtree_control.display(),
+                e
+            );
+        }
+
+        dir.push(component);
+        virtiofs::mkdir_p(&dir.to_string_lossy(), virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+    }
+
+    let procs = leaf.join("cgroup.procs");
+    let pid = rustix::process::getpid().as_raw_nonzero();
+    if dry_run {
+        info!("dry-run: would move pid {} into {}", pid, leaf.display());
+        return Ok(());
+    }
+
+    std::fs::write(&procs, pid.to_string())
+        .with_context(|| format!("Failed to move init into cgroup {}", leaf.display()))?;
+    info!("moved pid {} into cgroup {}", pid, leaf.display());
+    Ok(())
+}
+
+/// Mount cgroup2 (init.cgroup2) and move init into init.cgroup_path
+///
+/// The command inherits init's cgroup, whether it is exec'd or spawned.
+pub fn setup_cgroups(mount: bool, path: Option<&str>, dry_run: bool) -> Result<()> {
+    if mount {
+        mount_cgroup2(dry_run)?;
+    }
+
+    if let Some(path) = path {
+        enter_cgroup(path, dry_run)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_cgroup_path() {
+        for path in ["kdf", "kdf/job", "./kdf", "kdf/./job"] {
+            assert!(validate_cgroup_path(path).is_ok(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_validate_cgroup_path_invalid() {
+        for path in ["", "..", "kdf/../..", "kdf/..", "/kdf", "/"] {
+            assert!(validate_cgroup_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_cgroup2_mounted() {
+        let mounts = "proc /proc proc rw,nosuid 0 0\n\
+                      cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec 0 0\n";
+        assert!(cgroup2_mounted(mounts, "/sys/fs/cgroup"));
+        assert!(!cgroup2_mounted(mounts, "/proc"));
+
+        let mounts = "tmpfs /sys/fs/cgroup tmpfs rw 0 0\n";
+        assert!(!cgroup2_mounted(mounts, "/sys/fs/cgroup"));
+        assert!(!cgroup2_mounted("", "/sys/fs/cgroup"));
+    }
+}
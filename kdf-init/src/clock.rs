@@ -0,0 +1,253 @@
+//! Setting the wall clock from the cmdline or the RTC (init.settime), and
+//! writing it back to the RTC on shutdown (init.sync_rtc)
+
+use anyhow::{Context, Result};
+use rustix::ioctl::{Getter, ReadOpcode, Setter, WriteOpcode};
+use rustix::time::{ClockId, Timespec};
+use std::fs::File;
+
+use crate::cmdline::SetTime;
+
+/// RTC device read for init.settime=rtc and written for init.sync_rtc
+const RTC_DEVICE: &str = "/dev/rtc0";
+
+/// struct rtc_time from linux/rtc.h, same layout as struct tm
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RtcTime {
+    tm_sec: i32,
+    tm_min: i32,
+    tm_hour: i32,
+    tm_mday: i32,
+    tm_mon: i32,
+    tm_year: i32,
+    tm_wday: i32,
+    tm_yday: i32,
+    tm_isdst: i32,
+}
+
+/// RTC_RD_TIME: _IOR('p', 0x09, struct rtc_time)
+type RtcReadTime = Getter<ReadOpcode<b'p', 0x09, RtcTime>, RtcTime>;
+
+/// RTC_SET_TIME: _IOW('p', 0x0a, struct rtc_time)
+type RtcSetTime = Setter<WriteOpcode<b'p', 0x0a, RtcTime>, RtcTime>;
+
+/// Days since 1970-01-01 for a proleptic Gregorian date
+///
+/// `month` is 1-based. Howard Hinnant's days_from_civil.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Proleptic Gregorian (year, month, day) for days since 1970-01-01
+///
+/// `month` is 1-based. Howard Hinnant's civil_from_days, the inverse of
+/// days_from_civil.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Convert seconds since the epoch to an RTC time, kept in UTC
+fn epoch_to_rtc(secs: u64) -> Result<RtcTime> {
+    let secs = i64::try_from(secs).with_context(|| format!("Time out of range: {}", secs))?;
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let field =
+        |value: i64| i32::try_from(value).with_context(|| format!("Time out of range: {}", secs));
+
+    Ok(RtcTime {
+        tm_sec: field(time_of_day % 60)?,
+        tm_min: field(time_of_day / 60 % 60)?,
+        tm_hour: field(time_of_day / 3600)?,
+        tm_mday: field(day)?,
+        // The RTC counts months from 0 and years from 1900
+        tm_mon: field(month - 1)?,
+        tm_year: field(year - 1900)?,
+        // 1970-01-01 was a Thursday
+        tm_wday: field((days + 4).rem_euclid(7))?,
+        tm_yday: field(days - days_from_civil(year, 1, 1))?,
+        tm_isdst: 0,
+    })
+}
+
+/// Convert an RTC reading (kept in UTC) to seconds since the epoch
+fn rtc_to_epoch(rtc: &RtcTime) -> Result<u64> {
+    let valid = (0..12).contains(&rtc.tm_mon)
+        && (1..=31).contains(&rtc.tm_mday)
+        && (0..24).contains(&rtc.tm_hour)
+        && (0..60).contains(&rtc.tm_min)
+        && (0..=60).contains(&rtc.tm_sec);
+    if !valid {
+        anyhow::bail!("Invalid RTC time: {:?}", rtc);
+    }
+
+    let days = days_from_civil(
+        i64::from(rtc.tm_year) + 1900,
+        i64::from(rtc.tm_mon) + 1,
+        i64::from(rtc.tm_mday),
+    );
+    let secs = days * 86400
+        + i64::from(rtc.tm_hour) * 3600
+        + i64::from(rtc.tm_min) * 60
+        + i64::from(rtc.tm_sec);
+
+    u64::try_from(secs).with_context(|| format!("RTC time before the epoch: {:?}", rtc))
+}
+
+/// Read the RTC, returning seconds since the epoch
+fn read_rtc() -> Result<u64> {
+    let rtc = File::open(RTC_DEVICE).with_context(|| format!("Failed to open {}", RTC_DEVICE))?;
+    // Safety: RTC_RD_TIME fills in a struct rtc_time
+    let time = unsafe { rustix::ioctl::ioctl(&rtc, RtcReadTime::new()) }
+        .with_context(|| format!("Failed to read time from {}", RTC_DEVICE))?;
+    rtc_to_epoch(&time)
+}
+
+/// Write `secs` since the epoch to the RTC
+fn write_rtc(secs: u64) -> Result<()> {
+    let time = epoch_to_rtc(secs)?;
+    let rtc = File::options()
+        .write(true)
+        .open(RTC_DEVICE)
+        .with_context(|| format!("Failed to open {}", RTC_DEVICE))?;
+    // Safety: RTC_SET_TIME only reads the struct rtc_time passed in
+    unsafe { rustix::ioctl::ioctl(&rtc, RtcSetTime::new(time)) }
+        .with_context(|| format!("Failed to set time on {}", RTC_DEVICE))
+}
+
+/// Write the system time back to the RTC on a graceful shutdown (init.sync_rtc)
+///
+/// Keeps the clock of a persistent VM close across reboots, read back with
+/// init.settime=rtc. A failure only leaves the RTC as it was, so it is
+/// only worth a warning.
+pub fn sync_rtc(dry_run: bool) {
+    if dry_run {
+        info!("dry-run: would write the system time to {}", RTC_DEVICE);
+        return;
+    }
+
+    let secs = match rustix::time::clock_gettime(ClockId::Realtime).tv_sec {
+        secs if secs >= 0 => secs as u64,
+        secs => {
+            warn!(
+                "system time {} is before the epoch, not writing the RTC",
+                secs
+            );
+            return;
+        }
+    };
+    match write_rtc(secs) {
+        Ok(()) => info!("wrote the system time {} to {}", secs, RTC_DEVICE),
+        Err(e) => warn!("failed to write the system time to the RTC: {:#}", e),
+    }
+}
+
+/// Set CLOCK_REALTIME as requested with init.settime
+///
+/// Needs devtmpfs mounted for init.settime=rtc.
+pub fn set_time(settime: &SetTime, dry_run: bool) -> Result<()> {
+    if dry_run {
+        match settime {
+            SetTime::Epoch(secs) => info!("dry-run: would set the clock to {}", secs),
+            SetTime::Rtc => info!("dry-run: would set the clock from {}", RTC_DEVICE),
+        }
+        return Ok(());
+    }
+
+    let secs = match settime {
+        SetTime::Epoch(secs) => *secs,
+        SetTime::Rtc => read_rtc()?,
+    };
+
+    let timespec = Timespec {
+        tv_sec: secs as _,
+        tv_nsec: 0,
+    };
+    rustix::time::clock_settime(ClockId::Realtime, timespec)
+        .with_context(|| format!("Failed to set the clock to {}", secs))?;
+
+    info!("set the clock to {} (seconds since the epoch)", secs);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtc(year: i32, mon: i32, mday: i32, hour: i32, min: i32, sec: i32) -> RtcTime {
+        RtcTime {
+            tm_year: year - 1900,
+            tm_mon: mon - 1,
+            tm_mday: mday,
+            tm_hour: hour,
+            tm_min: min,
+            tm_sec: sec,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rtc_to_epoch() {
+        assert_eq!(rtc_to_epoch(&rtc(1970, 1, 1, 0, 0, 0)).unwrap(), 0);
+        assert_eq!(rtc_to_epoch(&rtc(2000, 3, 1, 0, 0, 0)).unwrap(), 951868800);
+        assert_eq!(
+            rtc_to_epoch(&rtc(2024, 2, 29, 12, 34, 56)).unwrap(),
+            1709210096
+        );
+    }
+
+    #[test]
+    fn test_epoch_to_rtc() {
+        let time = epoch_to_rtc(0).unwrap();
+        assert_eq!((time.tm_year, time.tm_mon, time.tm_mday), (70, 0, 1));
+        assert_eq!((time.tm_wday, time.tm_yday), (4, 0));
+
+        // 2024-02-29T12:34:56Z, a Thursday
+        let time = epoch_to_rtc(1709210096).unwrap();
+        assert_eq!((time.tm_year, time.tm_mon, time.tm_mday), (124, 1, 29));
+        assert_eq!((time.tm_hour, time.tm_min, time.tm_sec), (12, 34, 56));
+        assert_eq!((time.tm_wday, time.tm_yday), (4, 59));
+
+        // 2023-12-31T23:59:59Z, the last day of the year
+        let time = epoch_to_rtc(1704067199).unwrap();
+        assert_eq!((time.tm_year, time.tm_mon, time.tm_mday), (123, 11, 31));
+        assert_eq!((time.tm_hour, time.tm_min, time.tm_sec), (23, 59, 59));
+        assert_eq!((time.tm_wday, time.tm_yday), (0, 364));
+    }
+
+    #[test]
+    fn test_epoch_to_rtc_round_trip() {
+        for secs in [0, 951868800, 1709210096, 4102444800] {
+            assert_eq!(rtc_to_epoch(&epoch_to_rtc(secs).unwrap()).unwrap(), secs);
+        }
+    }
+
+    #[test]
+    fn test_rtc_to_epoch_rejects_invalid() {
+        assert!(rtc_to_epoch(&rtc(2024, 13, 1, 0, 0, 0)).is_err());
+        assert!(rtc_to_epoch(&rtc(2024, 1, 0, 0, 0, 0)).is_err());
+        assert!(rtc_to_epoch(&rtc(2024, 1, 1, 24, 0, 0)).is_err());
+        assert!(rtc_to_epoch(&rtc(1969, 12, 31, 23, 59, 59)).is_err());
+    }
+}
@@ -1,10 +1,26 @@
 //! Kernel cmdline parser for kdf-init parameters
 
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use rustix::process::{Resource, Signal};
+use rustix::thread::UnshareFlags;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Component, Path, PathBuf};
+
+use crate::cgroup::validate_cgroup_path;
+use crate::entropy::decode_hex;
+use crate::error::InitError;
+use crate::log::LogLevel;
+use crate::namespace::parse_namespaces;
+use crate::rlimit::{parse_limit, parse_resource};
+use crate::shutdown::parse_signal;
+use crate::sysctl::sysctl_path;
+use crate::system::console_path;
+use crate::virtiofs::{is_flag_option, DEFAULT_OVERLAY_BASE};
 
 /// Virtiofs mount specification
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
 pub struct VirtiofsMount {
     /// Virtiofs tag to mount
     pub tag: String,
@@ -12,10 +28,278 @@ pub struct VirtiofsMount {
     pub path: String,
     /// Whether to create overlayfs with writable layer
     pub with_overlay: bool,
+    /// Mount data options passed through to virtiofs (e.g. "dax")
+    pub options: Vec<String>,
+    /// Mount flag options (nosuid, nodev, noexec, noatime, ...), applied as MountFlags
+    pub flag_options: Vec<String>,
+    /// Additional read-only overlay lower directories, below the virtiofs share
+    pub extra_lowers: Vec<String>,
+    /// Mount the overlay with index=on
+    pub index: bool,
+    /// Mount the overlay with metacopy=on
+    pub metacopy: bool,
+    /// Mount the overlay with redirect_dir=on or off, the kernel's default otherwise
+    pub redirect_dir: Option<bool>,
+    /// Mount the overlay with xino=on or off, the kernel's default otherwise
+    pub xino: Option<bool>,
+    /// Mode for the mount point directory if init creates it, 0755 unless given
+    pub mode: Option<u32>,
+    /// Mount position, lower first, equal orders keep the cmdline order
+    pub order: i32,
+    /// Virtiofs tag holding the overlay upper/work dirs instead of /run
+    ///
+    /// The host must export this share writable and with xattr support
+    /// (e.g. virtiofsd --xattr) since overlayfs stores its metadata there.
+    pub upper_tag: Option<String>,
+    /// Mount source passed to mount(2) instead of the tag
+    pub source: Option<String>,
+}
+
+impl VirtiofsMount {
+    /// Source argument for mount(2), the tag unless src= overrides it
+    pub fn mount_source(&self) -> &str {
+        self.source.as_deref().unwrap_or(&self.tag)
+    }
+}
+
+/// 9p (virtio transport) mount specification
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct Plan9Mount {
+    /// 9p mount tag exported by the host
+    pub tag: String,
+    /// Path to mount at
+    pub path: String,
+    /// Maximum 9p message size in bytes
+    pub msize: u32,
+}
+
+/// squashfs image loop-mounted read-only, from init.squashfs
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct SquashfsMount {
+    /// Image file, usually on a share mounted earlier
+    pub image: String,
+    /// Path to mount at
+    pub path: String,
+}
+
+/// Several virtiofs shares merged into one overlay, from init.merge
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct MergeMount {
+    /// Path to mount the overlay at
+    pub path: String,
+    /// Virtiofs tags of the lower layers, the first one on top
+    pub tags: Vec<String>,
+    /// Whether the overlay gets a writable upper layer
+    pub writable: bool,
+}
+
+/// Path to wait for before handing over, from init.wait_for
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct WaitFor {
+    /// Path that has to exist, e.g. a file the host creates on a share
+    pub path: String,
+    /// Seconds to wait before giving up
+    pub timeout: u64,
+}
+
+/// Additional kernel filesystem mount specification
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct ExtraMount {
+    /// Filesystem type, also used as the mount source
+    pub fstype: String,
+    /// Path to mount at
+    pub target: String,
+    /// Filesystem specific mount data (may be empty)
+    pub data: String,
+}
+
+/// Kind of device node created with init.mknod
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "lowercase"))]
+pub enum DeviceType {
+    /// Character device ('c')
+    Char,
+    /// Block device ('b')
+    Block,
+}
+
+/// Device node specification
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct DeviceNode {
+    /// Path to create the node at
+    pub path: String,
+    /// Character or block device
+    pub kind: DeviceType,
+    /// Device major number
+    pub major: u32,
+    /// Device minor number
+    pub minor: u32,
+    /// Permission bits, 0600 unless given
+    pub mode: u32,
+}
+
+/// Source of the wall clock time set with init.settime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "lowercase"))]
+pub enum SetTime {
+    /// Seconds since the epoch, usually passed in by the host
+    Epoch(u64),
+    /// Read from /dev/rtc0
+    Rtc,
+}
+
+/// What to do with a persistent overlay upper on boot, from init.overlay_reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "snake_case"))]
+pub enum OverlayReset {
+    /// Keep the upper, whatever happened on the last boot
+    Never,
+    /// Wipe the upper if the last boot didn't shut down cleanly
+    OnDirty,
+    /// Wipe the upper on every boot
+    Always,
+}
+
+/// Seed credited to the kernel RNG, from init.seed or init.seedfile
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "lowercase"))]
+pub enum Seed {
+    /// Seed bytes given on the cmdline as hex
+    Bytes(Vec<u8>),
+    /// File on a share holding the seed bytes
+    File(ShareFile),
+}
+
+/// Where init.ready_marker signals that setup finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "lowercase"))]
+pub enum ReadyMarker {
+    /// Print this exact line on the console
+    Console(String),
+    /// Create this file, usually on a share the host watches
+    File(String),
+}
+
+/// Extra tmpfs mount specification
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct TmpfsMount {
+    /// Path to mount at
+    pub path: String,
+    /// Size cap in bytes, the tmpfs default (half of RAM) if None
+    pub size: Option<u64>,
+    /// Permission bits of the tmpfs root, the tmpfs default (1777) if None
+    pub mode: Option<u32>,
+}
+
+/// Already mounted filesystem to move elsewhere, from init.move
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct MoveMount {
+    /// Mount point to move away from
+    pub source: String,
+    /// Mount point to move to
+    pub target: String,
+}
+
+/// Bind mount specification, from init.bind
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct BindMount {
+    /// Existing file or directory to expose
+    pub source: String,
+    /// Path to bind it at
+    pub target: String,
+    /// Remount the bind read-only
+    pub read_only: bool,
+}
+
+/// Static IPv4 configuration for one interface, from init.ip
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct IpConfig {
+    /// Interface name, e.g. "eth0"
+    pub iface: String,
+    /// Address assigned to the interface
+    pub address: Ipv4Addr,
+    /// Prefix length of the subnet, 0 to 32
+    pub prefix_len: u8,
+    /// Default route via this gateway, inside the subnet
+    pub gateway: Option<Ipv4Addr>,
+}
+
+/// One command to run, with its own environment on top of init.env.XXX
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct CmdStage {
+    /// Program and args
+    pub command: (String, Vec<String>),
+    /// Environment variables for this stage only, from init.cmd.N.env.XXX
+    #[cfg_attr(
+        feature = "print-config",
+        serde(serialize_with = "serialize_sorted_map")
+    )]
+    pub env: HashMap<String, String>,
+}
+
+/// User to run the command as, by id or by name
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(untagged))]
+pub enum UserSpec {
+    /// Numeric uid
+    Id(u32),
+    /// User name, looked up in /etc/passwd
+    Name(String),
+}
+
+/// Identity the command drops to before exec
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct RunAs {
+    /// Target user
+    pub user: UserSpec,
+    /// Target gid, defaults to the user's primary group
+    pub gid: Option<u32>,
+}
+
+/// Resource limit the command starts with, from init.rlimit.<name>
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct ResourceLimit {
+    /// Limited resource
+    #[cfg_attr(feature = "print-config", serde(serialize_with = "serialize_resource"))]
+    pub resource: Resource,
+    /// Soft limit, None for unlimited
+    pub soft: Option<u64>,
+    /// Hard limit, None for unlimited
+    pub hard: Option<u64>,
+}
+
+/// Hardware watchdog to keep fed while supervising the command
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct WatchdogConfig {
+    /// Watchdog device path
+    pub device: String,
+    /// Keepalive interval in seconds, defaults to half the device timeout
+    pub interval: Option<u64>,
 }
 
 /// Symlink specification
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
 pub struct Symlink {
     /// Source path for symlink
     pub source: String,
@@ -23,11 +307,21 @@ pub struct Symlink {
     pub target: String,
 }
 
-/// Parse init.shell value by splitting on whitespace
+/// File on a virtiofs share, from init.config, init.seedfile or init.fstab
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+pub struct ShareFile {
+    /// Virtiofs tag of the share holding the file
+    pub tag: String,
+    /// Path of the file relative to the share root
+    pub path: String,
+}
+
+/// Parse init.shell or init.script value by splitting on whitespace
 ///
 /// Example: "sh -i" -> ("sh", vec!["-i"])
 /// Example: "sh" -> ("sh", vec![])
-fn parse_shell_command(value: &str) -> Result<(String, Vec<String>)> {
+pub fn parse_shell_command(value: &str) -> Result<(String, Vec<String>)> {
     let mut parts: Vec<String> = value.split_whitespace().map(|s| s.to_string()).collect();
     if parts.is_empty() {
         anyhow::bail!("Shell command is empty");
@@ -49,55 +343,1022 @@ fn parse_backtick_command(value: &str) -> Result<String> {
     Ok(value[1..value.len() - 1].to_string())
 }
 
+/// Percent-decode a cmdline value
+///
+/// Lets values carry characters that are structural on the cmdline, e.g.
+/// "%20" for space, "%2C" for comma, "%3A" for colon and "%25" for '%'.
+/// Example: "/mnt/my%20dir" -> "/mnt/my dir"
+pub fn decode_value(raw: &str) -> Result<String> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .with_context(|| format!("Truncated percent escape in: {}", raw))?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                anyhow::bail!("Invalid percent escape at offset {} in: {}", i, raw);
+            }
+            // Both bytes are ASCII hex digits, so this cannot fail
+            let hex = std::str::from_utf8(hex)?;
+            decoded.push(u8::from_str_radix(hex, 16)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).with_context(|| format!("Decoded value is not UTF-8: {}", raw))
+}
+
+/// Default 9p message size, large enough for reasonable throughput
+const DEFAULT_P9_MSIZE: u32 = 262144;
+
+/// Default grace period for a supervised command on shutdown, in seconds
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Earliest init.settime accepted, 2000-01-01T00:00:00Z
+const MIN_SETTIME_EPOCH: u64 = 946684800;
+
+/// Latest init.settime accepted, 3000-01-01T00:00:00Z
+const MAX_SETTIME_EPOCH: u64 = 32503680000;
+
+/// Permission bits for an init.mknod node without an explicit mode
+const DEFAULT_DEVICE_MODE: u32 = 0o600;
+
+/// Kernel cmdline, as the kernel exposes it
+const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// Environment variable holding the cmdline when /proc/cmdline is missing
+const CMDLINE_ENV: &str = "KDF_INIT_CMDLINE";
+
+/// What an empty cmdline stands for: a shell on the console, and the
+/// rescue shell should that fail to start
+const EMPTY_CMDLINE_PARAMS: [&str; 3] = [
+    "init.console=console",
+    "init.shell=`/bin/sh`",
+    "init.rescue=Y",
+];
+
+/// Parse a size with an optional k/m/g suffix into bytes
+///
+/// Example: "4096" -> 4096
+/// Example: "512m" -> 536870912
+pub fn parse_size(value: &str) -> Result<u64> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        anyhow::bail!("Invalid size: {}", value);
+    }
+
+    let size: u64 = digits
+        .parse()
+        .with_context(|| format!("Invalid size: {}", value))?;
+    if size == 0 {
+        anyhow::bail!("Size must be greater than zero: {}", value);
+    }
+
+    size.checked_mul(multiplier)
+        .with_context(|| format!("Size is too large: {}", value))
+}
+
 /// Parsed init configuration from kernel cmdline
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
 pub struct Config {
+    /// Virtiofs tag to switch the root filesystem to
+    pub root: Option<String>,
     /// Virtiofs mounts to create
     pub virtiofs_mounts: Vec<VirtiofsMount>,
+    /// Overlays merging several virtiofs shares, after the shares
+    pub merge_mounts: Vec<MergeMount>,
+    /// 9p shares to mount
+    pub p9_mounts: Vec<Plan9Mount>,
+    /// fstab file on a share, its entries mounted after the 9p shares
+    pub fstab: Option<ShareFile>,
+    /// squashfs images to loop-mount once the shares are mounted
+    pub squashfs_mounts: Vec<SquashfsMount>,
     /// Symlinks to create
     pub symlinks: Vec<Symlink>,
     /// Environment variables to set
+    #[cfg_attr(
+        feature = "print-config",
+        serde(serialize_with = "serialize_sorted_map")
+    )]
     pub env_vars: HashMap<String, String>,
+    /// File of KEY=VALUE lines read after mounts, init.env.XXX takes precedence
+    pub envfile: Option<String>,
+    /// Variables taken from the host's /run/host-env, if set there
+    pub env_passthrough: Vec<String>,
+    /// Expand undefined `${VAR}` references to "" instead of failing
+    pub allow_unset_vars: bool,
+    /// Directories placed in front of PATH
+    pub path_prepend: Vec<String>,
+    /// Directories added to the end of PATH
+    pub path_append: Vec<String>,
     /// Shell program and args - required (program, args)
     pub shell: (String, Vec<String>),
-    /// Optional script to execute (not yet implemented)
+    /// Optional command to exec in place of init instead of spawning the shell
     pub script: Option<String>,
+    /// Command argv from init.arg.N, ordered by index, instead of init.script
+    ///
+    /// Filled from init.argv_file or init.cmd_fifo once the shares are mounted.
+    pub args: Vec<String>,
+    /// File of NUL-separated command argv, read once the shares are mounted
+    pub argv_file: Option<String>,
+    /// FIFO created to read a single line command from, with a timeout
+    pub cmd_fifo: Option<WaitFor>,
+    /// Command run first, its exit status picks init.cmd_ok or init.cmd_fail
+    pub probe: Option<String>,
+    /// Command to run when init.probe exits 0
+    pub cmd_ok: Option<String>,
+    /// Command to run when init.probe exits nonzero or is killed
+    pub cmd_fail: Option<String>,
+    /// Interpreter to run a command with when the kernel can't exec it
+    pub interp: Option<String>,
+    /// Setup command argv from init.preexec.N, run to completion before the command
+    pub preexec: Vec<String>,
+    /// Command stages from init.cmd.N, ordered by index, run one after another
+    ///
+    /// Always supervised, a failing stage skips the rest unless
+    /// init.keep_going is set. Excludes init.script and init.arg.N.
+    pub cmds: Vec<CmdStage>,
+    /// Run the remaining init.cmd.N stages after one fails
+    pub keep_going: bool,
+    /// Re-runs of a supervised command or stage that failed
+    pub cmd_retries: u32,
+    /// Run the command as a child of init instead of exec'ing it
+    pub supervise: bool,
+    /// Supervise the command and start the shell once it exits instead of powering off
+    ///
+    /// init.exec_timeout still kills the command, the shell starts afterwards
+    /// like after any other exit. A command that fails to start goes through
+    /// init.rescue as usual.
+    pub keepalive: bool,
+    /// Drop into a rescue shell if the command fails to start or init panics
+    pub rescue: bool,
+    /// Drop the command to this user instead of running it as root
+    ///
+    /// Implies supervision, so init itself keeps running as root.
+    pub run_as: Option<RunAs>,
+    /// Supplementary gids for the command, an empty list clears them
+    pub groups: Option<Vec<u32>>,
+    /// Resource limits for the command, one per resource
+    pub rlimits: Vec<ResourceLimit>,
+    /// Watchdog kept alive while the supervised command runs
+    pub watchdog: Option<WatchdogConfig>,
+    /// Seconds setup may take before init gives up and reboots
+    pub boot_timeout: Option<u64>,
+    /// Seconds to wait for a supervised command after SIGTERM/SIGINT before SIGKILL
+    pub shutdown_timeout: u64,
+    /// Seconds a supervised command may run before it is terminated
+    ///
+    /// The command gets SIGTERM, then SIGKILL once init.shutdown_timeout
+    /// has passed as well.
+    pub exec_timeout: Option<u64>,
+    /// Signal emitted right before the command or shell is started
+    pub ready_marker: Option<ReadyMarker>,
+    /// File the supervised command's exit code is written to, usually on a share
+    pub result_file: Option<String>,
+    /// Signal that, killing the supervised command, reboots instead of powering off
+    #[cfg_attr(feature = "print-config", serde(serialize_with = "serialize_signal"))]
+    pub reboot_on: Signal,
     /// Directory to load kernel modules from (if None, no modules loaded)
     pub moddir: Option<String>,
+    /// Directory holding the per-tag overlay dirs, /run/overlayfs unless given
+    pub overlay_base: String,
+    /// Size cap in bytes for a dedicated tmpfs holding overlay upper/work dirs
+    pub overlay_tmpfs_size: Option<u64>,
+    /// Whether to wipe persistent overlay uppers (upper=) on boot
+    pub overlay_reset: OverlayReset,
+    /// Extra attempts for a virtiofs mount whose device isn't ready yet
+    pub mount_retries: u32,
+    /// Unmount the virtiofs shares already mounted when a later one fails
+    pub atomic_mounts: bool,
+    /// Mount virtiofs, overlay and 9p without checking /proc/filesystems first
+    pub skip_fs_check: bool,
+    /// Check the host exports every virtiofs tag before mounting any
+    pub verify_tags: bool,
+    /// Extra kernel filesystems to mount after the default set
+    pub extra_mounts: Vec<ExtraMount>,
+    /// tmpfs mounts made after the kernel filesystems
+    pub tmpfs_mounts: Vec<TmpfsMount>,
+    /// Mount data for /proc instead of none, applied with a remount
+    pub proc_opts: Option<String>,
+    /// Mount data for /run instead of mode=0755
+    pub run_opts: Option<String>,
+    /// Mount data every init.tmpfs mount starts from, before its size and mode
+    pub tmpfs_default_opts: Option<String>,
+    /// Mounts moved into place once the shares are mounted, in order
+    pub move_mounts: Vec<MoveMount>,
+    /// Bind mounts made after the moves, in order
+    pub bind_mounts: Vec<BindMount>,
+    /// Mount cgroup2 at /sys/fs/cgroup
+    pub cgroup2: bool,
+    /// cgroup (relative to /sys/fs/cgroup) to move init into before the command
+    pub cgroup_path: Option<String>,
+    /// Namespaces the command starts in fresh copies of
+    #[cfg_attr(
+        feature = "print-config",
+        serde(serialize_with = "serialize_namespaces")
+    )]
+    pub unshare: UnshareFlags,
+    /// Kernel tunables written to /proc/sys, keyed in dotted notation
+    #[cfg_attr(
+        feature = "print-config",
+        serde(serialize_with = "serialize_sorted_map")
+    )]
+    pub sysctls: HashMap<String, String>,
+    /// Bring up the loopback interface, init.ip does so as well
+    pub loopback: bool,
+    /// Interface to bring up with a static address once modules are loaded
+    pub ip: Option<IpConfig>,
+    /// Interface to configure over DHCP once modules are loaded
+    pub dhcp: Option<String>,
+    /// DNS servers written to /etc/resolv.conf once the rootfs is ready
+    pub nameservers: Vec<IpAddr>,
+    /// Set the wall clock once devtmpfs is mounted
+    pub settime: Option<SetTime>,
+    /// Write the system time back to /dev/rtc0 on a graceful shutdown
+    pub sync_rtc: bool,
+    /// Seed for the kernel RNG, credited once the modules are loaded
+    pub seed: Option<Seed>,
+    /// Device nodes to create once devtmpfs is mounted
+    pub device_nodes: Vec<DeviceNode>,
+    /// Mount devpts at /dev/pts and point /dev/ptmx at it
+    pub devpts: bool,
+    /// tmpfs for POSIX shared memory at /dev/shm, from init.devshm
+    pub devshm: Option<TmpfsMount>,
+    /// Default kernel filesystem targets to skip (/proc is always mounted)
+    pub nomount: Vec<String>,
+    /// Make every mount private before mounting anything else
+    pub mount_private: bool,
+    /// Mount securityfs at /sys/kernel/security
+    pub securityfs: bool,
+    /// Where to mount selinuxfs, usually /sys/fs/selinux
+    pub selinuxfs: Option<String>,
+    /// Mount points to unmount right before handing over to the command
+    pub umounts: Vec<String>,
     /// Console device to use - required
     pub console: String,
     /// Optional directory to change to before spawning shell
     pub chdir: Option<String>,
+    /// Paths to wait for before handing over, in order
+    pub wait_for: Vec<WaitFor>,
+    /// Log every boot action instead of performing it, then power off
+    pub dry_run: bool,
+    /// Console log verbosity
+    pub log_level: LogLevel,
+    /// Mirror log output to /dev/kmsg
+    pub kmsg: bool,
+    /// Kernel console log level written to /proc/sys/kernel/printk
+    pub printk: Option<u8>,
+    /// Print the parsed configuration as JSON before acting on it
+    pub print_config: bool,
+    /// Log the arguments of every mount, mkdir, symlink and execve
+    pub verbose: bool,
+    /// Log how long each setup phase took before handing over
+    pub timings: bool,
+}
+
+impl Config {
+    /// Whether init.script, init.arg.N, init.argv_file, init.cmd_fifo,
+    /// init.cmd.N or init.probe configured a command
+    pub fn has_command(&self) -> bool {
+        self.script.is_some()
+            || self.probe.is_some()
+            || !self.args.is_empty()
+            || self.argv_file.is_some()
+            || self.cmd_fifo.is_some()
+            || !self.cmds.is_empty()
+    }
+
+    /// Every virtiofs tag mounted by init.root, init.virtiofs, init.merge or
+    /// init.fstab, in mount order
+    pub fn virtiofs_tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = self.root.iter().map(String::as_str).collect();
+        for mount in &self.virtiofs_mounts {
+            tags.extend(mount.upper_tag.as_deref());
+            tags.push(mount.mount_source());
+        }
+        for merge in &self.merge_mounts {
+            tags.extend(merge.tags.iter().map(String::as_str));
+        }
+        tags.extend(self.fstab.iter().map(|file| file.tag.as_str()));
+        tags
+    }
+
+    /// Every command to run in order, the init.cmd.N stages or the single command
+    ///
+    /// The single command has no environment of its own.
+    pub fn commands(&self) -> Result<Vec<CmdStage>> {
+        if !self.cmds.is_empty() {
+            return Ok(self.cmds.clone());
+        }
+        Ok(self
+            .command()?
+            .into_iter()
+            .map(|command| CmdStage {
+                command,
+                env: HashMap::new(),
+            })
+            .collect())
+    }
+
+    /// Program and args of init.arg.N or init.script, if any
+    pub fn command(&self) -> Result<Option<(String, Vec<String>)>> {
+        if let Some((program, args)) = self.args.split_first() {
+            return Ok(Some((program.clone(), args.to_vec())));
+        }
+        self.script.as_deref().map(parse_shell_command).transpose()
+    }
+}
+
+/// Render a program and its args for log output
+pub fn command_display((program, args): &(String, Vec<String>)) -> String {
+    if args.is_empty() {
+        program.to_string()
+    } else {
+        format!("{} {}", program, args.join(" "))
+    }
+}
+
+/// Serialize a map with sorted keys so the JSON output is stable
+#[cfg(feature = "print-config")]
+fn serialize_sorted_map<S: serde::Serializer>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    let sorted: std::collections::BTreeMap<_, _> = map.iter().collect();
+    serde::Serialize::serialize(&sorted, serializer)
+}
+
+/// Serialize namespaces by their init.unshare names
+#[cfg(feature = "print-config")]
+fn serialize_namespaces<S: serde::Serializer>(
+    flags: &UnshareFlags,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serde::Serialize::serialize(&crate::namespace::namespace_names(*flags), serializer)
+}
+
+/// Serialize a signal by name, e.g. "SIGUSR1"
+#[cfg(feature = "print-config")]
+fn serialize_signal<S: serde::Serializer>(
+    signal: &Signal,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&crate::shutdown::signal_name(*signal))
+}
+
+/// Serialize a resource by its init.rlimit name
+#[cfg(feature = "print-config")]
+fn serialize_resource<S: serde::Serializer>(
+    resource: &Resource,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(crate::rlimit::resource_name(*resource))
+}
+
+/// Print the parsed configuration as a single JSON line on stdout
+#[cfg(feature = "print-config")]
+pub fn print_config(config: &Config) -> Result<()> {
+    let json = serde_json::to_string(config).context("Failed to serialize configuration")?;
+    println!("{}", json);
+    Ok(())
+}
+
+/// Print the parsed configuration as a single JSON line on stdout
+#[cfg(not(feature = "print-config"))]
+pub fn print_config(_config: &Config) -> Result<()> {
+    warn!("init.print_config needs kdf-init built with the print-config feature");
+    Ok(())
+}
+
+/// Every init.* parameter with its value syntax and a short description
+const PARAMS: &[(&str, &str)] = &[
+    (
+        "init.console=<device>",
+        "console device under /dev, required",
+    ),
+    (
+        "init.shell=`<cmd> [args]`",
+        "shell to start when no command is set, required",
+    ),
+    (
+        "init.script=`<cmd> [args]`",
+        "command to run in place of the shell",
+    ),
+    (
+        "init.arg.N=<arg>",
+        "command argv by index, instead of init.script",
+    ),
+    (
+        "init.argv_file=<path>",
+        "file of NUL-separated command argv, instead of init.arg.N",
+    ),
+    (
+        "init.cmd_fifo=<path>:<secs>",
+        "FIFO to read a one-line command from, instead of init.arg.N",
+    ),
+    (
+        "init.probe=`<cmd> [args]`",
+        "command whose exit status picks init.cmd_ok or init.cmd_fail",
+    ),
+    (
+        "init.cmd_ok=`<cmd> [args]`",
+        "command run when init.probe exits 0",
+    ),
+    (
+        "init.cmd_fail=`<cmd> [args]`",
+        "command run when init.probe fails",
+    ),
+    (
+        "init.interp=<path>",
+        "interpreter for commands without exec bit or shebang",
+    ),
+    (
+        "init.preexec.N=<arg>",
+        "argv of a setup command run before the command",
+    ),
+    (
+        "init.cmd.N=<cmd> [args]",
+        "command stages run in order by index, supervised",
+    ),
+    (
+        "init.cmd.N.env.<NAME>=<value>",
+        "environment variable for stage N only",
+    ),
+    (
+        "init.keep_going=Y",
+        "run the remaining init.cmd.N stages after a failure",
+    ),
+    (
+        "init.cmd_retries=<n>",
+        "re-runs of a failed supervised command",
+    ),
+    ("init.supervise=Y", "run the command as a child of init"),
+    ("init.keepalive=Y", "start the shell once the command exits"),
+    (
+        "init.rescue=Y",
+        "drop into a shell if the command fails to start",
+    ),
+    (
+        "init.user=<uid>[:<gid>]|<name>",
+        "user to run the command as",
+    ),
+    (
+        "init.groups=<gid>,...",
+        "supplementary groups for the command",
+    ),
+    (
+        "init.rlimit.<name>=<soft>[:<hard>]",
+        "resource limit for the command, e.g. nofile or as",
+    ),
+    (
+        "init.root=<tag>",
+        "virtiofs tag to switch the root filesystem to",
+    ),
+    (
+        "init.virtiofs=<tag>:<path>[:Y|N[:<opts>]],...",
+        "virtiofs shares, Y adds a writable overlay",
+    ),
+    (
+        "init.virtiofs.if=<flag>:<tag>:<path>[...],...",
+        "virtiofs shares added only if <flag> is on the cmdline",
+    ),
+    (
+        "init.merge=<mountpoint>:<tag>,...[:Y]",
+        "virtiofs shares merged into one overlay, writable with Y",
+    ),
+    ("init.p9=<tag>:<path>[:<msize>],...", "9p shares"),
+    (
+        "init.fstab=<tag>:<relpath>",
+        "fstab file on a share, mounted in file order after the shares",
+    ),
+    (
+        "init.squashfs=<imagepath>:<mountpoint>",
+        "squashfs image loop-mounted read-only after the shares",
+    ),
+    (
+        "init.symlinks=<link>:<target>,...",
+        "symlinks created after the mounts",
+    ),
+    (
+        "init.mount=<fstype>:<target>[:<data>]",
+        "extra kernel filesystem, repeatable",
+    ),
+    (
+        "init.tmpfs=<path>[:<size>][:<mode>],...",
+        "extra tmpfs mounts",
+    ),
+    (
+        "init.tmpfs_default_opts=<data>",
+        "mount data for every init.tmpfs mount",
+    ),
+    (
+        "init.proc_opts=<data>",
+        "mount data for /proc, e.g. hidepid=2",
+    ),
+    ("init.run_opts=<data>", "mount data for /run"),
+    (
+        "init.move=<src>:<dst>,...",
+        "move a mount elsewhere after the shares are mounted",
+    ),
+    (
+        "init.bind=<src>:<dst>[:ro],...",
+        "bind mount, made after init.move",
+    ),
+    (
+        "init.nomount=<target>,...",
+        "default kernel filesystems to skip",
+    ),
+    (
+        "init.mount_private=Y",
+        "make every mount private, so none propagate between namespaces",
+    ),
+    (
+        "init.securityfs=Y",
+        "mount securityfs at /sys/kernel/security",
+    ),
+    ("init.selinuxfs=<path>", "mount selinuxfs at <path>"),
+    (
+        "init.umount=<path>,...",
+        "mount points to unmount right before the command starts",
+    ),
+    (
+        "init.overlay_base=<path>",
+        "directory for the overlay upper/work dirs instead of /run/overlayfs",
+    ),
+    (
+        "init.overlay_tmpfs_size=<size>",
+        "dedicated tmpfs for overlay upper dirs",
+    ),
+    (
+        "init.overlay_reset=never|on_dirty|always",
+        "wipe persistent overlay uppers on boot, on_dirty after an unclean shutdown",
+    ),
+    (
+        "init.mount_retries=<n>",
+        "extra attempts for a virtiofs mount",
+    ),
+    (
+        "init.atomic_mounts=Y",
+        "undo the virtiofs mounts if any of them fails",
+    ),
+    (
+        "init.skip_fs_check=Y",
+        "mount shares without checking /proc/filesystems",
+    ),
+    (
+        "init.verify_tags=Y",
+        "fail early if the host doesn't export a virtiofs tag",
+    ),
+    ("init.moddir=<dir>", "directory to load kernel modules from"),
+    (
+        "init.mknod=<path>:c|b:<major>:<minor>[:<mode>]",
+        "device node, repeatable",
+    ),
+    (
+        "init.devpts=Y",
+        "mount devpts at /dev/pts for pseudo-terminals",
+    ),
+    (
+        "init.devshm=Y[:<size>]",
+        "mount a tmpfs at /dev/shm for POSIX shared memory",
+    ),
+    (
+        "init.sysctl=<key>=<value>,...",
+        "kernel tunables written to /proc/sys",
+    ),
+    ("init.settime=<epoch>|rtc", "set the wall clock"),
+    (
+        "init.sync_rtc=Y",
+        "write the system time to the RTC on shutdown",
+    ),
+    ("init.seed=<hexbytes>", "entropy credited to the kernel RNG"),
+    (
+        "init.seedfile=<tag>:<relpath>",
+        "file on a share credited to the kernel RNG",
+    ),
+    ("init.loopback=Y", "bring up the lo interface"),
+    (
+        "init.ip=<iface>:<addr>/<prefix>[:<gateway>]",
+        "bring up an interface with a static IPv4 address",
+    ),
+    (
+        "init.dhcp=<iface>",
+        "configure an interface over DHCP (dhcp feature)",
+    ),
+    (
+        "init.nameserver=<ip>,...",
+        "DNS servers written to /etc/resolv.conf",
+    ),
+    ("init.env.<NAME>=<value>", "environment variable"),
+    (
+        "init.env.passthrough=<NAME>,...",
+        "variables taken from /run/host-env, left by the host",
+    ),
+    (
+        "init.allow_unset_vars=Y",
+        "expand undefined ${VAR} in virtiofs specs and preexec to \"\"",
+    ),
+    (
+        "init.envfile=<path>",
+        "file of KEY=VALUE lines read after the mounts",
+    ),
+    (
+        "init.path_prepend=<dir>,...",
+        "directories placed in front of PATH",
+    ),
+    (
+        "init.path_append=<dir>,...",
+        "directories added to the end of PATH",
+    ),
+    ("init.chdir=<dir>", "working directory for the command"),
+    (
+        "init.wait_for=<path>:<secs>",
+        "wait for a path to appear before the command, repeatable",
+    ),
+    ("init.cgroup2=Y", "mount cgroup2 at /sys/fs/cgroup"),
+    ("init.cgroup_path=<path>", "cgroup to move init into"),
+    (
+        "init.unshare=<mount|pid|net|uts|ipc|user|cgroup|time>,...",
+        "run the command in fresh namespaces",
+    ),
+    (
+        "init.watchdog=<device>[:<secs>]",
+        "watchdog fed while supervising",
+    ),
+    ("init.boot_timeout=<secs>", "reboot if setup takes longer"),
+    (
+        "init.shutdown_timeout=<secs>",
+        "grace period before SIGKILL",
+    ),
+    (
+        "init.exec_timeout=<secs>",
+        "terminate a supervised command after this long",
+    ),
+    (
+        "init.ready_marker=<text>|file:<path>",
+        "signal emitted before the command",
+    ),
+    (
+        "init.result_file=<path>",
+        "file the command's exit code is written to",
+    ),
+    (
+        "init.reboot_on=<signal>",
+        "reboot if the command dies of this signal (default SIGUSR1)",
+    ),
+    ("init.dryrun=Y", "log every action instead of performing it"),
+    ("init.loglevel=quiet|info|debug", "console log verbosity"),
+    ("init.kmsg=Y", "mirror log output to /dev/kmsg"),
+    ("init.printk=<0-7>", "kernel console log level"),
+    (
+        "init.verbose=Y",
+        "log the arguments of every mount, mkdir, symlink and execve",
+    ),
+    ("init.timings=Y", "log how long each setup phase took"),
+    (
+        "init.print_config=Y",
+        "print the parsed configuration as JSON",
+    ),
+    (
+        "init.config=<tag>:<relpath>",
+        "config file on a virtiofs share, one init.* param per line",
+    ),
+    (
+        "init.check=Y",
+        "only validate the cmdline, print OK or the error and power off",
+    ),
+    (
+        "init.<list>.sep=<char>",
+        "delimiter instead of ',' for virtiofs, p9, symlinks, tmpfs, move, bind, nomount or umount",
+    ),
+];
+
+/// Usage text listing every supported init.* parameter
+pub fn describe_params() -> String {
+    let width = PARAMS
+        .iter()
+        .map(|(param, _)| param.len())
+        .max()
+        .unwrap_or(0);
+    let mut usage = format!(
+        "kdf-init {}: minimal init for initramfs, configured on the kernel cmdline\n\n\
+         Not running as PID 1, pass init.force=Y to run anyway.\n\n\
+         Parameters:\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for (param, description) in PARAMS {
+        usage.push_str(&format!("  {:width$}  {}\n", param, description));
+    }
+    usage
 }
 
 /// Parse kernel cmdline into Config
 ///
-/// Supports: init.virtiofs, init.symlinks, init.env.XXX, init.shell, init.script, init.moddir, init.console, init.chdir
+/// Supports: init.root, init.virtiofs, init.virtiofs.if, init.merge, init.p9, init.fstab, init.squashfs, init.symlinks, init.env.XXX, init.env.passthrough, init.allow_unset_vars, init.envfile, init.path_prepend, init.path_append, init.shell, init.script, init.arg.N, init.argv_file, init.cmd_fifo, init.probe, init.cmd_ok, init.cmd_fail, init.interp, init.preexec.N, init.cmd.N, init.cmd.N.env.XXX, init.keep_going, init.cmd_retries, init.supervise, init.keepalive, init.rescue, init.user, init.groups, init.rlimit.<name>, init.watchdog, init.boot_timeout, init.shutdown_timeout, init.exec_timeout, init.ready_marker, init.result_file, init.reboot_on, init.moddir, init.overlay_base, init.overlay_tmpfs_size, init.overlay_reset, init.mount_retries, init.atomic_mounts, init.skip_fs_check, init.verify_tags, init.mount, init.tmpfs, init.tmpfs_default_opts, init.proc_opts, init.run_opts, init.move, init.bind, init.nomount, init.mount_private, init.securityfs, init.selinuxfs, init.umount, init.mknod, init.devpts, init.devshm, init.settime, init.sync_rtc, init.seed, init.seedfile, init.sysctl, init.loopback, init.ip, init.dhcp, init.nameserver, init.cgroup2, init.cgroup_path, init.unshare, init.console, init.chdir, init.wait_for, init.dryrun, init.loglevel, init.kmsg, init.printk, init.verbose, init.timings, init.print_config
 /// init.shell and init.script values must be wrapped in backticks
-/// init.shell is required, init.script is optional
+/// init.shell is required, init.script is optional and replaces init when set
+/// init.arg.N assembles the command argv by index, for args containing spaces
+/// init.preexec.N assembles the argv of a setup command the same way
+/// init.cmd.N adds one whitespace-split command per index, run as stages
 /// init.console is required
+/// init.mount may be repeated, one extra kernel filesystem each
+/// init.mknod may be repeated, one device node each
+/// init.squashfs may be repeated, one image each
+/// init.wait_for may be repeated, the paths are waited for in order
+/// init.tmpfs, init.move and init.bind may be repeated, their lists accumulate
+/// init.<list>.sep changes the ',' delimiter of a list, wherever it appears
+/// init.config and init.check are handled before parsing, see
+/// [`config_source`] and [`check_requested`]
+/// Paths, tags and env values are percent-decoded after splitting
+/// An empty cmdline is valid, see [`EMPTY_CMDLINE_PARAMS`]
 pub fn parse_cmdline(cmdline: &str) -> Result<Config> {
+    // Parse parameters respecting backtick and double-quoted values
+    let params = tokenize_cmdline(cmdline)?;
+    if params.is_empty() {
+        warn!(
+            "empty kernel cmdline, defaulting to {}",
+            EMPTY_CMDLINE_PARAMS.join(" ")
+        );
+        return parse_params(EMPTY_CMDLINE_PARAMS.map(String::from).to_vec());
+    }
+    parse_params(params)
+}
+
+/// Parse a second-stage config file into Config
+///
+/// The file holds one cmdline parameter per line, e.g.
+/// "init.virtiofs=share:/mnt". Blank lines and lines starting with '#' are
+/// skipped. A line is taken whole, so values may contain spaces. Like on
+/// the cmdline, init.console and init.shell are required.
+pub fn parse_config_file(contents: &str) -> Result<Config> {
+    parse_params(config_file_params(contents)?)
+}
+
+/// Config file contents with the init.* cmdline parameters appended
+///
+/// Parsed with [`parse_config_file`], a parameter set in both places takes
+/// the cmdline value, and repeatable ones like init.mount accumulate.
+pub fn merge_config_file(contents: &str, cmdline: &str) -> Result<String> {
+    let mut merged = contents.to_string();
+    for param in tokenize_cmdline(cmdline)? {
+        if param.starts_with("init.")
+            && !param.starts_with("init.config=")
+            && !param.starts_with("init.check=")
+        {
+            merged.push('\n');
+            merged.push_str(&param);
+        }
+    }
+    Ok(merged)
+}
+
+/// Parameters from the lines of a config file
+fn config_file_params(contents: &str) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with("init.") || !line.contains('=') {
+            anyhow::bail!(
+                "Invalid config file line {}, expected init.<param>=<value>: {}",
+                index + 1,
+                line
+            );
+        }
+        if line.starts_with("init.config=") {
+            anyhow::bail!(
+                "init.config is only supported on the kernel cmdline (config file line {})",
+                index + 1
+            );
+        }
+        params.push(line.to_string());
+    }
+
+    Ok(params)
+}
+
+/// Find the init.config parameter on the cmdline
+///
+/// Read before the rest of the cmdline, since the file it names has to be
+/// merged in first. Example: "share:kdf/init.conf"
+pub fn config_source(cmdline: &str) -> Result<Option<ShareFile>> {
+    let mut source = None;
+    for param in tokenize_cmdline(cmdline)? {
+        if let Some(value) = param.strip_prefix("init.config=") {
+            source = Some(
+                parse_share_file(value)
+                    .with_context(|| format!("Invalid init.config: {}", value))?,
+            );
+        }
+    }
+    Ok(source)
+}
+
+/// Whether init.check=Y asks to only validate the cmdline
+///
+/// Read before the rest of the cmdline, so a cmdline that fails to parse
+/// is still reported instead of aborting the boot.
+pub fn check_requested(cmdline: &str) -> Result<bool> {
+    let mut check = false;
+    for param in tokenize_cmdline(cmdline)? {
+        if let Some(value) = param.strip_prefix("init.check=") {
+            check = value == "Y";
+        }
+    }
+    Ok(check)
+}
+
+/// Parse an init.config, init.seedfile or init.fstab value
+///
+/// Example: "share:init.conf" -> init.conf at the root of share "share"
+/// Example: "share:vm/init.conf" -> vm/init.conf inside the share
+fn parse_share_file(value: &str) -> Result<ShareFile> {
+    let (tag, path) = value.split_once(':').context("Expected <tag>:<relpath>")?;
+    let tag = decode_value(tag)?;
+    let path = decode_value(path)?;
+    if tag.is_empty() || path.is_empty() {
+        anyhow::bail!("Tag and path must not be empty");
+    }
+    if path.starts_with('/') || path.split('/').any(|component| component == "..") {
+        anyhow::bail!("Path must be relative to the share root: {}", path);
+    }
+    Ok(ShareFile { tag, path })
+}
+
+/// Parse cmdline parameters into Config, later parameters win
+fn parse_params(params: Vec<String>) -> Result<Config> {
+    let mut root = None;
     let mut virtiofs_mounts = Vec::new();
+    let mut p9_mounts = Vec::new();
+    let mut merge_mounts = Vec::new();
+    let mut fstab = None;
+    let mut squashfs_mounts = Vec::new();
     let mut symlinks = Vec::new();
     let mut env_vars = HashMap::new();
+    let mut envfile = None;
+    let mut env_passthrough = Vec::new();
+    let mut allow_unset_vars = false;
+    let mut path_prepend = Vec::new();
+    let mut path_append = Vec::new();
     let mut shell = None;
     let mut script = None;
+    let mut probe = None;
+    let mut cmd_ok = None;
+    let mut cmd_fail = None;
+    let mut args = BTreeMap::new();
+    let mut argv_file = None;
+    let mut cmd_fifo = None;
+    let mut interp = None;
+    let mut preexec = BTreeMap::new();
+    let mut supervise = false;
+    let mut keepalive = false;
+    let mut cmds = BTreeMap::new();
+    let mut cmd_envs: BTreeMap<usize, HashMap<String, String>> = BTreeMap::new();
+    let mut keep_going = false;
+    let mut cmd_retries = 0;
+    let mut rescue = false;
+    let mut run_as = None;
+    let mut groups = None;
+    let mut rlimits: Vec<ResourceLimit> = Vec::new();
+    let mut watchdog = None;
+    let mut boot_timeout = None;
+    let mut shutdown_timeout = DEFAULT_SHUTDOWN_TIMEOUT;
+    let mut exec_timeout = None;
+    let mut ready_marker = None;
+    let mut result_file = None;
+    let mut reboot_on = Signal::Usr1;
     let mut moddir = None;
+    let mut overlay_base = DEFAULT_OVERLAY_BASE.to_string();
+    let mut overlay_tmpfs_size = None;
+    let mut overlay_reset = OverlayReset::Never;
+    let mut mount_retries = 0;
+    let mut atomic_mounts = false;
+    let mut skip_fs_check = false;
+    let mut verify_tags = false;
+    let mut extra_mounts = Vec::new();
+    let mut tmpfs_mounts = Vec::new();
+    let mut proc_opts = None;
+    let mut run_opts = None;
+    let mut tmpfs_default_opts = None;
+    let mut move_mounts = Vec::new();
+    let mut bind_mounts = Vec::new();
+    let mut nomount = Vec::new();
+    let mut mount_private = false;
+    let mut securityfs = false;
+    let mut selinuxfs = None;
+    let mut umounts = Vec::new();
+    let mut device_nodes = Vec::new();
+    let mut devpts = false;
+    let mut devshm = None;
+    let mut settime = None;
+    let mut sync_rtc = false;
+    let mut seed = None;
+    let mut loopback = false;
+    let mut ip = None;
+    let mut dhcp = None;
+    let mut nameservers = Vec::new();
+    let mut sysctls = HashMap::new();
+    let mut cgroup2 = false;
+    let mut cgroup_path = None;
+    let mut unshare = UnshareFlags::empty();
     let mut console = None;
     let mut chdir = None;
+    let mut wait_for = Vec::new();
+    let mut dry_run = false;
+    let mut log_level = LogLevel::default();
+    let mut kmsg = false;
+    let mut printk = None;
+    let mut print_config = false;
+    let mut verbose = false;
+    let mut timings = false;
 
-    // Parse parameters respecting backtick-enclosed values
-    let params = parse_cmdline_params(cmdline);
+    // First pass, a separator applies wherever it appears on the cmdline
+    let separators = parse_separators(&params)?;
+    let sep = |list: &str| separators.get(list).copied().unwrap_or(',');
+    // Conditions see every flag, wherever it appears on the cmdline
+    let flags = bare_flags(&params);
+    let mut conditional_virtiofs = Vec::new();
 
     for param in params {
-        if let Some(value) = param.strip_prefix("init.virtiofs=") {
-            virtiofs_mounts = parse_virtiofs_mounts(value)?;
+        if let Some(value) = param.strip_prefix("init.root=") {
+            if value.is_empty() {
+                anyhow::bail!("init.root must not be empty");
+            }
+            root = Some(decode_value(value)?);
+        } else if let Some(value) = param.strip_prefix("init.virtiofs=") {
+            virtiofs_mounts = parse_virtiofs_mounts(value, sep("virtiofs"))?;
+        } else if let Some(value) = param.strip_prefix("init.virtiofs.if=") {
+            let (flag, specs) = parse_condition(value)
+                .with_context(|| format!("Invalid init.virtiofs.if: {}", value))?;
+            // Parsed either way, so a typo shows up without the flag as well
+            let mounts = parse_virtiofs_mounts(specs, sep("virtiofs"))?;
+            if flags.contains(flag) {
+                conditional_virtiofs.extend(mounts);
+            }
+        } else if let Some(value) = param.strip_prefix("init.merge=") {
+            merge_mounts.push(
+                parse_merge_mount(value)
+                    .with_context(|| format!("Invalid init.merge: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.p9=") {
+            p9_mounts = parse_p9_mounts(value, sep("p9"))?;
+        } else if let Some(value) = param.strip_prefix("init.fstab=") {
+            fstab = Some(
+                parse_share_file(value)
+                    .with_context(|| format!("Invalid init.fstab: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.squashfs=") {
+            squashfs_mounts.push(
+                parse_squashfs_mount(value)
+                    .with_context(|| format!("Invalid init.squashfs: {}", value))?,
+            );
         } else if let Some(value) = param.strip_prefix("init.symlinks=") {
-            symlinks = parse_symlinks(value)?;
+            symlinks = parse_symlinks(value, sep("symlinks"))?;
+        } else if let Some(value) = param.strip_prefix("init.env.passthrough=") {
+            // Takes the name, so there is no init.env.passthrough variable
+            for key in value.split(',').filter(|key| !key.is_empty()) {
+                if key.contains('=') || key.contains(char::is_whitespace) {
+                    anyhow::bail!("Invalid init.env.passthrough: variable name {:?}", key);
+                }
+                env_passthrough.push(key.to_string());
+            }
         } else if let Some(rest) = param.strip_prefix("init.env.") {
+            // Split on the first '=' only, values may contain '=' themselves
             if let Some((key, value)) = rest.split_once('=') {
-                env_vars.insert(key.to_string(), value.to_string());
+                if key.is_empty() {
+                    anyhow::bail!("Empty environment variable name in: {}", param);
+                }
+                env_vars.insert(key.to_string(), decode_value(value)?);
+            }
+        } else if let Some(value) = param.strip_prefix("init.allow_unset_vars=") {
+            allow_unset_vars = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.envfile=") {
+            if value.is_empty() {
+                anyhow::bail!("init.envfile must not be empty");
             }
+            envfile = Some(decode_value(value)?);
+        } else if let Some(value) = param.strip_prefix("init.path_prepend=") {
+            path_prepend = parse_path_dirs(value)
+                .with_context(|| format!("Invalid init.path_prepend: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.path_append=") {
+            path_append = parse_path_dirs(value)
+                .with_context(|| format!("Invalid init.path_append: {}", value))?;
         } else if let Some(value) = param.strip_prefix("init.shell=") {
             // First unwrap backticks, then split on whitespace
             let shell_cmd = parse_backtick_command(value)?;
@@ -105,399 +1366,4847 @@ pub fn parse_cmdline(cmdline: &str) -> Result<Config> {
         } else if let Some(value) = param.strip_prefix("init.script=") {
             let script_cmd = parse_backtick_command(value)?;
             script = Some(script_cmd);
+        } else if let Some(value) = param.strip_prefix("init.probe=") {
+            probe = Some(parse_backtick_command(value)?);
+        } else if let Some(value) = param.strip_prefix("init.cmd_ok=") {
+            cmd_ok = Some(parse_backtick_command(value)?);
+        } else if let Some(value) = param.strip_prefix("init.cmd_fail=") {
+            cmd_fail = Some(parse_backtick_command(value)?);
+        } else if let Some(rest) = param.strip_prefix("init.arg.") {
+            parse_indexed_arg(rest, &mut args)
+                .with_context(|| format!("Invalid init.arg: {}", param))?;
+        } else if let Some(value) = param.strip_prefix("init.argv_file=") {
+            let path = decode_value(value)?;
+            if !path.starts_with('/') {
+                anyhow::bail!("init.argv_file must be an absolute path: {}", value);
+            }
+            argv_file = Some(path);
+        } else if let Some(value) = param.strip_prefix("init.cmd_fifo=") {
+            cmd_fifo = Some(
+                parse_wait_for(value)
+                    .with_context(|| format!("Invalid init.cmd_fifo: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.interp=") {
+            let path = decode_value(value)?;
+            if !path.starts_with('/') {
+                anyhow::bail!("init.interp must be an absolute path: {}", value);
+            }
+            interp = Some(path);
+        } else if let Some(rest) = param.strip_prefix("init.preexec.") {
+            parse_indexed_arg(rest, &mut preexec)
+                .with_context(|| format!("Invalid init.preexec: {}", param))?;
+        } else if let Some(rest) = param.strip_prefix("init.cmd.") {
+            // Only the key can tell a stage env apart, values may contain ".env."
+            let is_env = rest
+                .split_once('=')
+                .is_some_and(|(key, _)| key.contains(".env."));
+            if is_env {
+                parse_stage_env(rest, &mut cmd_envs)
+            } else {
+                parse_indexed_arg(rest, &mut cmds)
+            }
+            .with_context(|| format!("Invalid init.cmd: {}", param))?;
+        } else if let Some(value) = param.strip_prefix("init.keep_going=") {
+            keep_going = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.cmd_retries=") {
+            cmd_retries = value
+                .parse()
+                .with_context(|| format!("Invalid init.cmd_retries: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.keepalive=") {
+            keepalive = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.supervise=") {
+            supervise = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.rescue=") {
+            rescue = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.user=") {
+            run_as = Some(parse_run_as(value)?);
+        } else if let Some(value) = param.strip_prefix("init.groups=") {
+            groups = Some(parse_groups(value)?);
+        } else if let Some(rest) = param.strip_prefix("init.rlimit.") {
+            let (name, value) = rest
+                .split_once('=')
+                .with_context(|| format!("Invalid init.rlimit: {}", param))?;
+            let limit = parse_rlimit(name, value)
+                .with_context(|| format!("Invalid init.rlimit.{}: {}", name, value))?;
+            // A repeated resource replaces the earlier limit
+            rlimits.retain(|known| known.resource != limit.resource);
+            rlimits.push(limit);
+        } else if let Some(value) = param.strip_prefix("init.watchdog=") {
+            watchdog = Some(parse_watchdog(value)?);
+        } else if let Some(value) = param.strip_prefix("init.boot_timeout=") {
+            boot_timeout = Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .with_context(|| format!("Invalid init.boot_timeout: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.shutdown_timeout=") {
+            shutdown_timeout = value
+                .parse()
+                .with_context(|| format!("Invalid init.shutdown_timeout: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.exec_timeout=") {
+            exec_timeout = Some(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .with_context(|| format!("Invalid init.exec_timeout: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.ready_marker=") {
+            ready_marker = Some(parse_ready_marker(value)?);
+        } else if let Some(value) = param.strip_prefix("init.result_file=") {
+            if value.is_empty() {
+                anyhow::bail!("init.result_file must not be empty");
+            }
+            result_file = Some(decode_value(value)?);
+        } else if let Some(value) = param.strip_prefix("init.reboot_on=") {
+            reboot_on = parse_signal(value)
+                .with_context(|| format!("Invalid init.reboot_on: {}", value))?;
         } else if let Some(value) = param.strip_prefix("init.moddir=") {
             moddir = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.overlay_base=") {
+            overlay_base = decode_value(value)?;
+            if !overlay_base.starts_with('/') {
+                anyhow::bail!(
+                    "Invalid init.overlay_base: path must be absolute: {}",
+                    value
+                );
+            }
+        } else if let Some(value) = param.strip_prefix("init.overlay_tmpfs_size=") {
+            overlay_tmpfs_size = Some(
+                parse_size(value)
+                    .with_context(|| format!("Invalid init.overlay_tmpfs_size: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.overlay_reset=") {
+            overlay_reset = parse_overlay_reset(value)?;
+        } else if let Some(value) = param.strip_prefix("init.mount_retries=") {
+            mount_retries = value
+                .parse()
+                .with_context(|| format!("Invalid init.mount_retries: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.atomic_mounts=") {
+            atomic_mounts = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.skip_fs_check=") {
+            skip_fs_check = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.verify_tags=") {
+            verify_tags = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.mount=") {
+            extra_mounts.push(parse_extra_mount(value)?);
+        } else if let Some(value) = param.strip_prefix("init.tmpfs=") {
+            for spec in value.split(sep("tmpfs")).filter(|spec| !spec.is_empty()) {
+                tmpfs_mounts.push(
+                    parse_tmpfs_mount(spec)
+                        .with_context(|| format!("Invalid init.tmpfs: {}", spec))?,
+                );
+            }
+        } else if let Some(value) = param.strip_prefix("init.tmpfs_default_opts=") {
+            tmpfs_default_opts = Some(
+                parse_mount_data(value)
+                    .with_context(|| format!("Invalid init.tmpfs_default_opts: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.proc_opts=") {
+            proc_opts = Some(
+                parse_mount_data(value)
+                    .with_context(|| format!("Invalid init.proc_opts: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.run_opts=") {
+            run_opts = Some(
+                parse_mount_data(value)
+                    .with_context(|| format!("Invalid init.run_opts: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.move=") {
+            for spec in value.split(sep("move")).filter(|spec| !spec.is_empty()) {
+                move_mounts.push(
+                    parse_move_mount(spec)
+                        .with_context(|| format!("Invalid init.move: {}", spec))?,
+                );
+            }
+        } else if let Some(value) = param.strip_prefix("init.bind=") {
+            for spec in value.split(sep("bind")).filter(|spec| !spec.is_empty()) {
+                bind_mounts.push(
+                    parse_bind_mount(spec)
+                        .with_context(|| format!("Invalid init.bind: {}", spec))?,
+                );
+            }
+        } else if let Some(value) = param.strip_prefix("init.nomount=") {
+            for target in value
+                .split(sep("nomount"))
+                .filter(|target| !target.is_empty())
+            {
+                nomount.push(decode_value(target)?);
+            }
+        } else if let Some(value) = param.strip_prefix("init.mount_private=") {
+            mount_private = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.securityfs=") {
+            securityfs = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.selinuxfs=") {
+            let path = decode_value(value)?;
+            if !path.starts_with('/') {
+                anyhow::bail!("init.selinuxfs must be an absolute path: {}", value);
+            }
+            selinuxfs = Some(path);
+        } else if let Some(value) = param.strip_prefix("init.umount=") {
+            for path in value.split(sep("umount")).filter(|path| !path.is_empty()) {
+                let path = decode_value(path)?;
+                if !path.starts_with('/') {
+                    anyhow::bail!("Invalid init.umount: path must be absolute: {}", path);
+                }
+                umounts.push(path);
+            }
+        } else if let Some(value) = param.strip_prefix("init.mknod=") {
+            device_nodes.push(
+                parse_device_node(value)
+                    .with_context(|| format!("Invalid init.mknod: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.devpts=") {
+            devpts = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.devshm=") {
+            devshm =
+                parse_devshm(value).with_context(|| format!("Invalid init.devshm: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.sysctl=") {
+            sysctls.extend(
+                parse_sysctls(value).with_context(|| format!("Invalid init.sysctl: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.loopback=") {
+            loopback = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.ip=") {
+            ip = Some(
+                parse_ip_config(value).with_context(|| format!("Invalid init.ip: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.dhcp=") {
+            dhcp = Some(
+                parse_iface_name(value).with_context(|| format!("Invalid init.dhcp: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.nameserver=") {
+            nameservers = parse_nameservers(value)
+                .with_context(|| format!("Invalid init.nameserver: {}", value))?;
+        } else if let Some(value) = param.strip_prefix("init.settime=") {
+            settime = Some(parse_settime(value)?);
+        } else if let Some(value) = param.strip_prefix("init.sync_rtc=") {
+            sync_rtc = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.seedfile=") {
+            seed =
+                Some(Seed::File(parse_share_file(value).with_context(|| {
+                    format!("Invalid init.seedfile: {}", value)
+                })?));
+        } else if let Some(value) = param.strip_prefix("init.seed=") {
+            seed = Some(Seed::Bytes(
+                decode_hex(value).with_context(|| format!("Invalid init.seed: {}", value))?,
+            ));
+        } else if let Some(value) = param.strip_prefix("init.cgroup2=") {
+            cgroup2 = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.cgroup_path=") {
+            let path = decode_value(value)?;
+            validate_cgroup_path(&path)
+                .with_context(|| format!("Invalid init.cgroup_path: {}", value))?;
+            cgroup_path = Some(path);
+        } else if let Some(value) = param.strip_prefix("init.unshare=") {
+            unshare = parse_namespaces(value)
+                .with_context(|| format!("Invalid init.unshare: {}", value))?;
         } else if let Some(value) = param.strip_prefix("init.console=") {
+            console_path(value).with_context(|| format!("Invalid init.console: {}", value))?;
             console = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.wait_for=") {
+            wait_for.push(
+                parse_wait_for(value)
+                    .with_context(|| format!("Invalid init.wait_for: {}", value))?,
+            );
         } else if let Some(value) = param.strip_prefix("init.chdir=") {
+            if value.is_empty() {
+                anyhow::bail!("init.chdir must not be empty");
+            }
             chdir = Some(value.to_string());
+        } else if let Some(value) = param.strip_prefix("init.dryrun=") {
+            dry_run = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.loglevel=") {
+            log_level = parse_log_level(value)?;
+        } else if let Some(value) = param.strip_prefix("init.kmsg=") {
+            kmsg = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.printk=") {
+            printk = Some(
+                parse_printk_level(value)
+                    .with_context(|| format!("Invalid init.printk: {}", value))?,
+            );
+        } else if let Some(value) = param.strip_prefix("init.verbose=") {
+            verbose = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.timings=") {
+            timings = value == "Y";
+        } else if let Some(value) = param.strip_prefix("init.print_config=") {
+            print_config = value == "Y";
         }
     }
 
     // Ensure required fields are present
     let shell = shell.context("init.shell is required")?;
+
+    // After init.virtiofs, which replaces the list wherever it appears
+    virtiofs_mounts.extend(conditional_virtiofs);
+
+    if script.is_some() && !args.is_empty() {
+        anyhow::bail!("init.script and init.arg.N are mutually exclusive");
+    }
+    if !cmds.is_empty() && (script.is_some() || !args.is_empty()) {
+        anyhow::bail!("init.cmd.N excludes init.script and init.arg.N");
+    }
+    if argv_file.is_some() && (script.is_some() || !args.is_empty() || !cmds.is_empty()) {
+        anyhow::bail!("init.argv_file excludes init.script, init.arg.N and init.cmd.N");
+    }
+    if ip.is_some() && dhcp.is_some() {
+        anyhow::bail!("init.ip and init.dhcp are mutually exclusive");
+    }
+    if cmd_fifo.is_some()
+        && (script.is_some() || !args.is_empty() || argv_file.is_some() || !cmds.is_empty())
+    {
+        anyhow::bail!(
+            "init.cmd_fifo excludes init.script, init.arg.N, init.argv_file and init.cmd.N"
+        );
+    }
+    if probe.is_some() != cmd_ok.is_some() || probe.is_some() != cmd_fail.is_some() {
+        anyhow::bail!("init.probe, init.cmd_ok and init.cmd_fail must be set together");
+    }
+    if probe.is_some()
+        && (script.is_some()
+            || !args.is_empty()
+            || argv_file.is_some()
+            || cmd_fifo.is_some()
+            || !cmds.is_empty())
+    {
+        anyhow::bail!(
+            "init.probe excludes init.script, init.arg.N, init.argv_file, init.cmd_fifo and init.cmd.N"
+        );
+    }
+    // Gaps in the indices are skipped, only the order matters
+    let args = args.into_values().collect();
+    let preexec = preexec.into_values().collect();
+    if let Some(index) = cmd_envs.keys().find(|index| !cmds.contains_key(index)) {
+        anyhow::bail!(
+            "init.cmd.{}.env.XXX is set without init.cmd.{}",
+            index,
+            index
+        );
+    }
+    let cmds = cmds
+        .into_iter()
+        .map(|(index, stage)| {
+            Ok(CmdStage {
+                command: parse_shell_command(&stage)
+                    .with_context(|| format!("Invalid init.cmd.{}", index))?,
+                env: cmd_envs.remove(&index).unwrap_or_default(),
+            })
+        })
+        .collect::<Result<_>>()?;
     let console = console.context("init.console is required")?;
 
     Ok(Config {
+        root,
         virtiofs_mounts,
+        p9_mounts,
+        merge_mounts,
+        fstab,
+        squashfs_mounts,
         symlinks,
         env_vars,
+        envfile,
+        env_passthrough,
+        allow_unset_vars,
+        path_prepend,
+        path_append,
         shell,
         script,
+        probe,
+        cmd_ok,
+        cmd_fail,
+        args,
+        argv_file,
+        cmd_fifo,
+        interp,
+        preexec,
+        cmds,
+        keep_going,
+        cmd_retries,
+        supervise,
+        keepalive,
+        rescue,
+        run_as,
+        groups,
+        rlimits,
+        watchdog,
+        boot_timeout,
+        shutdown_timeout,
+        exec_timeout,
+        ready_marker,
+        result_file,
+        reboot_on,
         moddir,
+        overlay_base,
+        overlay_tmpfs_size,
+        overlay_reset,
+        mount_retries,
+        atomic_mounts,
+        skip_fs_check,
+        verify_tags,
+        extra_mounts,
+        tmpfs_mounts,
+        proc_opts,
+        run_opts,
+        tmpfs_default_opts,
+        move_mounts,
+        bind_mounts,
+        nomount,
+        mount_private,
+        securityfs,
+        selinuxfs,
+        umounts,
+        device_nodes,
+        devpts,
+        devshm,
+        settime,
+        sync_rtc,
+        seed,
+        loopback,
+        ip,
+        dhcp,
+        nameservers,
+        sysctls,
+        cgroup2,
+        cgroup_path,
+        unshare,
         console,
         chdir,
+        wait_for,
+        dry_run,
+        log_level,
+        kmsg,
+        printk,
+        print_config,
+        verbose,
+        timings,
     })
 }
 
-/// Parse cmdline parameters, handling backtick-enclosed values
-fn parse_cmdline_params(cmdline: &str) -> Vec<String> {
-    let mut params = Vec::new();
-    let mut current_param = String::new();
-    let mut in_backticks = false;
-    let chars = cmdline.chars().peekable();
+/// Parse the "<index>=<value>" part of an init.arg.N or init.preexec.N
+///
+/// A repeated index overrides the earlier value.
+fn parse_indexed_arg(rest: &str, args: &mut BTreeMap<usize, String>) -> Result<()> {
+    let (index, value) = rest.split_once('=').context("Missing '='")?;
+    let index = index
+        .parse()
+        .with_context(|| format!("Invalid index: {}", index))?;
+    args.insert(index, decode_value(value)?);
+    Ok(())
+}
 
-    for ch in chars {
-        match ch {
-            '`' => {
-                in_backticks = !in_backticks;
-                current_param.push(ch);
-            }
-            ' ' | '\t' | '\n' if !in_backticks => {
-                if !current_param.is_empty() {
-                    params.push(current_param.clone());
-                    current_param.clear();
-                }
-            }
-            _ => {
-                current_param.push(ch);
-            }
-        }
+/// Parse the "<index>.env.<key>=<value>" part of an init.cmd.N.env.XXX
+///
+/// A repeated key overrides the earlier value.
+fn parse_stage_env(rest: &str, envs: &mut BTreeMap<usize, HashMap<String, String>>) -> Result<()> {
+    let (name, value) = rest.split_once('=').context("Missing '='")?;
+    let (index, key) = name.split_once(".env.").context("Missing '.env.'")?;
+    let index = index
+        .parse()
+        .with_context(|| format!("Invalid index: {}", index))?;
+    if key.is_empty() {
+        anyhow::bail!("Empty environment variable name");
     }
+    envs.entry(index)
+        .or_default()
+        .insert(key.to_string(), decode_value(value)?);
+    Ok(())
+}
 
-    if !current_param.is_empty() {
-        params.push(current_param);
+/// Parse an init.user value
+///
+/// Example: "1000" -> uid 1000, primary group of that user
+/// Example: "1000:100" -> uid 1000, gid 100
+/// Example: "dev" -> user "dev" from /etc/passwd
+fn parse_run_as(value: &str) -> Result<RunAs> {
+    let (user, gid) = match value.split_once(':') {
+        Some((user, gid)) => {
+            let gid = gid
+                .parse()
+                .with_context(|| format!("Invalid gid in init.user: {}", value))?;
+            (user, Some(gid))
+        }
+        None => (value, None),
+    };
+
+    if user.is_empty() {
+        anyhow::bail!("Missing user in init.user: {}", value);
     }
 
-    params
+    let user = if user.bytes().all(|b| b.is_ascii_digit()) {
+        UserSpec::Id(
+            user.parse()
+                .with_context(|| format!("Invalid uid in init.user: {}", value))?,
+        )
+    } else {
+        UserSpec::Name(user.to_string())
+    };
+
+    Ok(RunAs { user, gid })
 }
 
-fn parse_virtiofs_mounts(value: &str) -> Result<Vec<VirtiofsMount>> {
-    let mut mounts = Vec::new();
+/// Parse an init.path_prepend or init.path_append value into directories
+///
+/// Example: "/opt/bin,/usr/local/bin" -> ["/opt/bin", "/usr/local/bin"]
+fn parse_path_dirs(value: &str) -> Result<Vec<String>> {
+    let mut dirs = Vec::new();
 
-    for mount_spec in value.split(',') {
-        if mount_spec.is_empty() {
-            continue;
+    for dir in value.split(',').filter(|dir| !dir.is_empty()) {
+        let dir = decode_value(dir)?;
+        if dir.contains(':') {
+            anyhow::bail!("PATH directory must not contain ':': {}", dir);
         }
+        dirs.push(dir);
+    }
 
-        let parts: Vec<&str> = mount_spec.split(':').collect();
-
-        let (tag, path, with_overlay) = match parts.as_slice() {
-            [tag, path] => (*tag, *path, false),
-            [tag, path, overlay] => (*tag, *path, *overlay == "Y"),
-            _ => anyhow::bail!("Invalid virtiofs mount spec: {}", mount_spec),
-        };
+    Ok(dirs)
+}
 
-        mounts.push(VirtiofsMount {
-            tag: tag.to_string(),
-            path: path.to_string(),
-            with_overlay,
-        });
+/// Parse an init.groups value into gids
+///
+/// Example: "10,100" -> [10, 100]
+/// Example: "" -> [] (clears the inherited groups)
+fn parse_groups(value: &str) -> Result<Vec<u32>> {
+    if value.is_empty() {
+        return Ok(Vec::new());
     }
 
-    Ok(mounts)
+    value
+        .split(',')
+        .map(|gid| {
+            gid.parse()
+                .with_context(|| format!("Invalid gid in init.groups: {:?}", gid))
+        })
+        .collect()
 }
 
-fn parse_symlinks(value: &str) -> Result<Vec<Symlink>> {
-    let mut symlinks = Vec::new();
+/// Parse an init.printk console log level, 0 (KERN_EMERG) to 7 (KERN_DEBUG)
+fn parse_printk_level(value: &str) -> Result<u8> {
+    let level: u8 = value.parse().context("Expected a number from 0 to 7")?;
+    if level > 7 {
+        anyhow::bail!("Level must be from 0 to 7");
+    }
+    Ok(level)
+}
 
-    for symlink_spec in value.split(',') {
-        if symlink_spec.is_empty() {
-            continue;
+/// Parse an init.rlimit.<name> value, a single value sets both limits
+///
+/// Example: ("nofile", "1024:4096") -> soft 1024, hard 4096
+/// Example: ("as", "2g") -> soft and hard 2 GiB
+/// Example: ("core", "0:unlimited") -> soft 0, hard unlimited
+fn parse_rlimit(name: &str, value: &str) -> Result<ResourceLimit> {
+    let (resource, is_size) = parse_resource(name)?;
+    let (soft, hard) = match value.split_once(':') {
+        Some((soft, hard)) => (parse_limit(soft, is_size)?, parse_limit(hard, is_size)?),
+        None => {
+            let limit = parse_limit(value, is_size)?;
+            (limit, limit)
+        }
+    };
+
+    // Unlimited is the largest value there is
+    let soft_above_hard = match (soft, hard) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(soft), Some(hard)) => soft > hard,
+    };
+    if soft_above_hard {
+        anyhow::bail!("Soft limit is above the hard limit");
+    }
+
+    Ok(ResourceLimit {
+        resource,
+        soft,
+        hard,
+    })
+}
+
+/// Parse an init.watchdog value
+///
+/// Example: "/dev/watchdog" -> interval derived from the device timeout
+/// Example: "/dev/watchdog:10" -> keepalive every 10 seconds
+fn parse_watchdog(value: &str) -> Result<WatchdogConfig> {
+    let (device, interval) = match value.split_once(':') {
+        Some((device, interval)) => {
+            let interval = interval
+                .parse()
+                .ok()
+                .filter(|&secs| secs > 0)
+                .with_context(|| format!("Invalid interval in init.watchdog: {}", value))?;
+            (device, Some(interval))
+        }
+        None => (value, None),
+    };
+
+    if device.is_empty() {
+        anyhow::bail!("Missing device in init.watchdog: {}", value);
+    }
+
+    Ok(WatchdogConfig {
+        device: decode_value(device)?,
+        interval,
+    })
+}
+
+/// Parse an init.loglevel value: quiet, info or debug
+fn parse_log_level(value: &str) -> Result<LogLevel> {
+    match value {
+        "quiet" => Ok(LogLevel::Quiet),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => anyhow::bail!(
+            "Invalid init.loglevel: {} (expected quiet, info or debug)",
+            value
+        ),
+    }
+}
+
+/// Parse an init.overlay_reset value: never, on_dirty or always
+fn parse_overlay_reset(value: &str) -> Result<OverlayReset> {
+    match value {
+        "never" => Ok(OverlayReset::Never),
+        "on_dirty" => Ok(OverlayReset::OnDirty),
+        "always" => Ok(OverlayReset::Always),
+        _ => anyhow::bail!(
+            "Invalid init.overlay_reset: {} (expected never, on_dirty or always)",
+            value
+        ),
+    }
+}
+
+/// Split the cmdline into parameters
+///
+/// Whitespace separates parameters, except inside backticks or double
+/// quotes. Backticks are kept for [`parse_backtick_command`], while double
+/// quotes (as left in place by bootloaders) are removed, e.g.
+/// `init.env.X="a b"` -> `init.env.X=a b`. Outside backticks, `\"` is a
+/// literal quote. An unterminated double quote is an error.
+fn tokenize_cmdline(cmdline: &str) -> Result<Vec<String>> {
+    let mut params = Vec::new();
+    let mut current_param = String::new();
+    let mut in_backticks = false;
+    let mut in_quotes = false;
+    let mut chars = cmdline.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '`' if !in_quotes => {
+                in_backticks = !in_backticks;
+                current_param.push(ch);
+            }
+            '\\' if !in_backticks && chars.peek() == Some(&'"') => {
+                current_param.push('"');
+                chars.next();
+            }
+            '"' if !in_backticks => {
+                in_quotes = !in_quotes;
+            }
+            ' ' | '\t' | '\n' if !in_backticks && !in_quotes => {
+                if !current_param.is_empty() {
+                    params.push(current_param.clone());
+                    current_param.clear();
+                }
+            }
+            _ => {
+                current_param.push(ch);
+            }
+        }
+    }
+
+    if in_quotes {
+        anyhow::bail!("Unterminated double quote in cmdline: {}", current_param);
+    }
+    if !current_param.is_empty() {
+        params.push(current_param);
+    }
+
+    Ok(params)
+}
+
+/// Lists whose delimiter can be changed with init.<list>.sep
+const SEPARATOR_LISTS: [&str; 8] = [
+    "virtiofs", "p9", "symlinks", "tmpfs", "move", "bind", "nomount", "umount",
+];
+
+/// Bare cmdline tokens, the flags init.virtiofs.if conditions test for
+///
+/// Example: ["ci", "quiet", "init.shell=`sh`"] -> {"ci", "quiet"}
+fn bare_flags(params: &[String]) -> HashSet<String> {
+    params
+        .iter()
+        .filter(|param| !param.is_empty() && !param.contains('='))
+        .cloned()
+        .collect()
+}
+
+/// Split a conditional spec into its flag and the specs it guards
+///
+/// Example: "ci:share:/mnt" -> ("ci", "share:/mnt")
+fn parse_condition(value: &str) -> Result<(&str, &str)> {
+    let (flag, specs) = value.split_once(':').context("Expected <flag>:<spec>")?;
+    if flag.is_empty() {
+        anyhow::bail!("Empty flag");
+    }
+    Ok((flag, specs))
+}
+
+/// Collect the init.<list>.sep=<char> directives, keyed by list name
+///
+/// Example: "init.virtiofs.sep=;" -> init.virtiofs specs are split on ';'
+fn parse_separators(params: &[String]) -> Result<HashMap<&'static str, char>> {
+    let mut separators = HashMap::new();
+
+    for param in params {
+        // Only the key counts, values may well contain ".sep="
+        let Some((list, value)) = param.split_once('=').and_then(|(key, value)| {
+            key.strip_prefix("init.")
+                .and_then(|key| key.strip_suffix(".sep"))
+                .map(|list| (list, value))
+        }) else {
+            continue;
+        };
+        let Some(list) = SEPARATOR_LISTS.iter().find(|name| **name == list) else {
+            anyhow::bail!("init.{}.sep is not supported", list);
+        };
+
+        // ':' splits the fields of a spec and '%' starts an escape
+        let mut chars = value.chars();
+        let sep = match (chars.next(), chars.next()) {
+            (Some(c), None) if !c.is_alphanumeric() && !":%=`".contains(c) => c,
+            _ => anyhow::bail!("Invalid init.{}.sep: {}", list, value),
+        };
+        separators.insert(*list, sep);
+    }
+
+    Ok(separators)
+}
+
+/// Split an init.virtiofs value into individual mount specs
+///
+/// Specs are comma-separated by default, but the options field of a spec is
+/// itself a comma-joined list. A segment without a ':' can't start a new spec
+/// (which needs at least tag:path), so it continues the options of the
+/// previous spec if that one has an options field. With any other `sep`
+/// the options are unambiguous and specs are split on it directly.
+fn split_virtiofs_specs(value: &str, sep: char) -> Vec<String> {
+    if sep != ',' {
+        return value
+            .split(sep)
+            .filter(|spec| !spec.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    let mut specs: Vec<String> = Vec::new();
+
+    for segment in value.split(',') {
+        match specs.last_mut() {
+            // Empty ones included, so parsing rejects the empty option
+            Some(spec) if !segment.contains(':') && spec.split(':').count() > 3 => {
+                spec.push(',');
+                spec.push_str(segment);
+            }
+            _ if segment.is_empty() => {}
+            _ => specs.push(segment.to_string()),
+        }
+    }
+
+    specs
+}
+
+/// Parse init.virtiofs value
+///
+/// Example: "share:/mnt" -> direct mount of tag "share" at /mnt
+/// Example: "share:/mnt:Y" -> overlay over a read-only virtiofs mount
+/// Example: "share:/mnt:N:dax,cache=always" -> direct mount with data options
+/// Example: "share:/mnt:Y:nosuid,nodev" -> overlay with nosuid/nodev mounts
+/// Example: "share:/mnt:Y:noatime" -> overlay and its read-only lower without atime
+/// Example: "share:/mnt:Y:lower=/base" -> overlay stacking /base below the share
+/// Example: "share:/mnt:Y:upper=persist" -> overlay writes land on the "persist" share
+/// Example: "share:/mnt:N:order=-1" -> mounted before shares without an order
+/// Example: "share:/mnt:N:src=other" -> mount(2) gets "other" as its source
+/// Example: "share:/mnt:Y:redirect_dir=on,xino=off" -> overlay with those options
+fn parse_virtiofs_mounts(value: &str, sep: char) -> Result<Vec<VirtiofsMount>> {
+    let mut mounts = Vec::new();
+
+    for mount_spec in split_virtiofs_specs(value, sep) {
+        let parts: Vec<&str> = mount_spec.split(':').collect();
+
+        let (tag, path, with_overlay, option_fields) = match parts.as_slice() {
+            [tag, path] => (*tag, *path, false, &[][..]),
+            [tag, path, overlay] => (*tag, *path, *overlay == "Y", &[][..]),
+            [tag, path, overlay, option_fields @ ..] => {
+                (*tag, *path, *overlay == "Y", option_fields)
+            }
+            _ => return Err(InitError::invalid_spec("virtiofs mount", &mount_spec).into()),
+        };
+
+        // Every field after the overlay flag holds comma-joined options
+        let mut options = Vec::new();
+        let mut flag_options = Vec::new();
+        let mut extra_lowers = Vec::new();
+        let mut upper_tag = None;
+        let mut source = None;
+        let mut mode = None;
+        let mut order = None;
+        let mut index = false;
+        let mut metacopy = false;
+        let mut redirect_dir = None;
+        let mut xino = None;
+        for option in option_fields.iter().flat_map(|field| field.split(',')) {
+            if option.is_empty() {
+                anyhow::bail!("Empty option in virtiofs mount spec: {}", mount_spec);
+            }
+
+            if let Some(upper) = option.strip_prefix("upper=") {
+                if !with_overlay {
+                    anyhow::bail!("upper= requires an overlay mount: {}", mount_spec);
+                }
+                if upper_tag.is_some() {
+                    anyhow::bail!("upper= given more than once: {}", mount_spec);
+                }
+                upper_tag = Some(decode_value(upper)?);
+            } else if let Some(value) = option.strip_prefix("src=") {
+                if source.is_some() {
+                    anyhow::bail!("src= given more than once: {}", mount_spec);
+                }
+                if value.is_empty() {
+                    anyhow::bail!("Empty src= in virtiofs mount spec: {}", mount_spec);
+                }
+                source = Some(decode_value(value)?);
+            } else if option == "index" || option == "metacopy" {
+                if !with_overlay {
+                    anyhow::bail!("{} requires an overlay mount: {}", option, mount_spec);
+                }
+                index |= option == "index";
+                metacopy |= option == "metacopy";
+            } else if let Some((name, value)) = option
+                .split_once('=')
+                .filter(|(name, _)| *name == "redirect_dir" || *name == "xino")
+            {
+                if !with_overlay {
+                    anyhow::bail!("{}= requires an overlay mount: {}", name, mount_spec);
+                }
+                let setting = if name == "redirect_dir" {
+                    &mut redirect_dir
+                } else {
+                    &mut xino
+                };
+                if setting.is_some() {
+                    anyhow::bail!("{}= given more than once: {}", name, mount_spec);
+                }
+                *setting = Some(match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => anyhow::bail!(
+                        "Invalid {} value (expected on or off): {}",
+                        name,
+                        mount_spec
+                    ),
+                });
+            } else if let Some(value) = option.strip_prefix("mode=") {
+                mode = Some(
+                    parse_mode(value)
+                        .with_context(|| format!("Invalid mode in: {}", mount_spec))?,
+                );
+            } else if let Some(value) = option.strip_prefix("order=") {
+                if order.is_some() {
+                    anyhow::bail!("order= given more than once: {}", mount_spec);
+                }
+                order = Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("Invalid order in: {}", mount_spec))?,
+                );
+            } else if let Some(lower) = option.strip_prefix("lower=") {
+                if !with_overlay {
+                    anyhow::bail!("lower= requires an overlay mount: {}", mount_spec);
+                }
+                extra_lowers.push(decode_value(lower)?);
+            } else if is_flag_option(option) {
+                // A read-only overlay would defeat the point of the overlay
+                if option == "ro" && with_overlay {
+                    anyhow::bail!("ro requires a mount without overlay: {}", mount_spec);
+                }
+                flag_options.push(option.to_string());
+            } else {
+                options.push(decode_value(option)?);
+            }
+        }
+
+        let tag = decode_value(tag)?;
+        if upper_tag.as_ref() == Some(&tag) {
+            anyhow::bail!(
+                "Overlay upper tag must differ from the lower tag: {}",
+                mount_spec
+            );
+        }
+
+        mounts.push(VirtiofsMount {
+            tag,
+            path: decode_value(path)?,
+            with_overlay,
+            options,
+            flag_options,
+            extra_lowers,
+            upper_tag,
+            source,
+            index,
+            metacopy,
+            redirect_dir,
+            xino,
+            mode,
+            order: order.unwrap_or_default(),
+        });
+    }
+
+    Ok(mounts)
+}
+
+/// Parse init.p9 value
+///
+/// Example: "share:/mnt" -> tag "share" at /mnt with the default msize
+/// Example: "share:/mnt:524288" -> same, with a 512KiB msize
+fn parse_p9_mounts(value: &str, sep: char) -> Result<Vec<Plan9Mount>> {
+    let mut mounts = Vec::new();
+
+    for mount_spec in value.split(sep) {
+        if mount_spec.is_empty() {
+            continue;
+        }
+
+        let parts: Vec<&str> = mount_spec.split(':').collect();
+        let (tag, path, msize) = match parts.as_slice() {
+            [tag, path] => (*tag, *path, DEFAULT_P9_MSIZE),
+            [tag, path, msize] => {
+                let msize = msize
+                    .parse()
+                    .ok()
+                    .filter(|&msize| msize > 0)
+                    .with_context(|| format!("Invalid 9p msize in: {}", mount_spec))?;
+                (*tag, *path, msize)
+            }
+            _ => return Err(InitError::invalid_spec("9p mount", mount_spec).into()),
+        };
+
+        mounts.push(Plan9Mount {
+            tag: decode_value(tag)?,
+            path: decode_value(path)?,
+            msize,
+        });
+    }
+
+    Ok(mounts)
+}
+
+/// Parse a single init.squashfs value, both paths must be absolute
+///
+/// Example: "/mnt/images/tools.sqfs:/opt/tools"
+fn parse_squashfs_mount(value: &str) -> Result<SquashfsMount> {
+    let (image, path) = value
+        .split_once(':')
+        .context("Expected <imagepath>:<mountpoint>")?;
+    let image = decode_value(image)?;
+    let path = decode_value(path)?;
+    if !image.starts_with('/') || !path.starts_with('/') {
+        anyhow::bail!("Image and mount point must be absolute paths");
+    }
+    Ok(SquashfsMount { image, path })
+}
+
+/// Parse a single init.merge value
+///
+/// Example: "/data:a,b" -> read-only overlay of a over b at /data
+/// Example: "/data:a,b,c:Y" -> same with c at the bottom, writable
+fn parse_merge_mount(value: &str) -> Result<MergeMount> {
+    let (path, rest) = value
+        .split_once(':')
+        .context("Expected <mountpoint>:<tag>,...[:Y]")?;
+    let (tags, writable) = match rest.rsplit_once(':') {
+        Some((tags, "Y")) => (tags, true),
+        Some((tags, "N")) => (tags, false),
+        Some((_, flag)) => anyhow::bail!("Expected Y or N, got {}", flag),
+        None => (rest, false),
+    };
+
+    let path = decode_value(path)?;
+    if !path.starts_with('/') {
+        anyhow::bail!("Mount point must be an absolute path: {}", path);
+    }
+    let tags = tags
+        .split(',')
+        .map(decode_value)
+        .collect::<Result<Vec<_>>>()?;
+    if tags.iter().any(String::is_empty) {
+        anyhow::bail!("Tags must not be empty");
+    }
+    if let Some((index, tag)) = tags
+        .iter()
+        .enumerate()
+        .find(|(index, tag)| tags[..*index].contains(tag))
+    {
+        anyhow::bail!("Tag {} is listed twice (position {})", tag, index + 1);
+    }
+    // overlayfs refuses a lone lower without an upper
+    if tags.len() < 2 && !writable {
+        anyhow::bail!("A read-only merge needs at least two tags");
+    }
+    Ok(MergeMount {
+        path,
+        tags,
+        writable,
+    })
+}
+
+/// Parse a single init.wait_for value, the path must be absolute
+///
+/// Example: "/mnt/share/ready:30" -> wait up to 30s for /mnt/share/ready
+fn parse_wait_for(value: &str) -> Result<WaitFor> {
+    // The timeout comes last, so the path may contain ':'
+    let (path, timeout) = value.rsplit_once(':').context("Expected <path>:<secs>")?;
+    let path = decode_value(path)?;
+    if !path.starts_with('/') {
+        anyhow::bail!("Path must be absolute: {}", path);
+    }
+    let timeout = timeout
+        .parse()
+        .with_context(|| format!("Invalid timeout: {}", timeout))?;
+    Ok(WaitFor { path, timeout })
+}
+
+/// Parse a single init.mount value
+///
+/// Example: "cgroup2:/sys/fs/cgroup" -> fstype "cgroup2", target "/sys/fs/cgroup", no data
+/// Example: "tmpfs:/tmp:size=64m,mode=1777" -> data "size=64m,mode=1777"
+fn parse_extra_mount(value: &str) -> Result<ExtraMount> {
+    let mut parts = value.splitn(3, ':');
+
+    let (fstype, target) = match (parts.next(), parts.next()) {
+        (Some(fstype), Some(target)) if !fstype.is_empty() && !target.is_empty() => {
+            (fstype, target)
+        }
+        _ => return Err(InitError::invalid_spec("mount", value).into()),
+    };
+
+    Ok(ExtraMount {
+        fstype: decode_value(fstype)?,
+        target: decode_value(target)?,
+        data: decode_value(parts.next().unwrap_or_default())?,
+    })
+}
+
+/// Parse an init.ready_marker value
+///
+/// Example: "KDF_READY" -> print "KDF_READY" on the console
+/// Example: "file:/mnt/share/ready" -> create /mnt/share/ready
+fn parse_ready_marker(value: &str) -> Result<ReadyMarker> {
+    let marker = match value.strip_prefix("file:") {
+        Some(path) => ReadyMarker::File(decode_value(path)?),
+        None => ReadyMarker::Console(decode_value(value)?),
+    };
+
+    match &marker {
+        ReadyMarker::Console(s) | ReadyMarker::File(s) if s.is_empty() => {
+            anyhow::bail!("init.ready_marker must not be empty")
+        }
+        _ => Ok(marker),
+    }
+}
+
+/// Parse an init.sysctl value into key/value pairs
+///
+/// Example: "net.ipv4.ip_forward=1,vm.swappiness=10"
+fn parse_sysctls(value: &str) -> Result<HashMap<String, String>> {
+    let mut sysctls = HashMap::new();
+
+    for setting in value.split(',').filter(|setting| !setting.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .with_context(|| format!("Missing '=' in sysctl: {}", setting))?;
+        let key = decode_value(key)?;
+        sysctl_path(&key)?;
+        sysctls.insert(key, decode_value(value)?);
+    }
+
+    Ok(sysctls)
+}
+
+/// Longest interface name the kernel accepts, IFNAMSIZ without the NUL
+const MAX_IFACE_NAME: usize = 15;
+
+/// Parse a network interface name, for init.ip and init.dhcp
+fn parse_iface_name(iface: &str) -> Result<String> {
+    if iface.is_empty() || iface.len() > MAX_IFACE_NAME || iface.contains('/') {
+        anyhow::bail!("Invalid interface name: {:?}", iface);
+    }
+    Ok(iface.to_string())
+}
+
+/// Parse an init.ip value
+///
+/// Example: "eth0:10.0.2.15/24" -> 10.0.2.15/24 on eth0, no default route
+/// Example: "eth0:10.0.2.15/24:10.0.2.2" -> same, default route via 10.0.2.2
+fn parse_ip_config(value: &str) -> Result<IpConfig> {
+    let parts: Vec<&str> = value.split(':').collect();
+    let (iface, cidr, gateway) = match parts.as_slice() {
+        [iface, cidr] => (*iface, *cidr, None),
+        [iface, cidr, gateway] => (*iface, *cidr, Some(*gateway)),
+        _ => anyhow::bail!("Expected <iface>:<addr>/<prefix>[:<gateway>]"),
+    };
+
+    let iface = parse_iface_name(iface)?;
+
+    let (address, prefix_len) = cidr
+        .split_once('/')
+        .context("Address needs a /<prefix> length")?;
+    let address: Ipv4Addr = address
+        .parse()
+        .with_context(|| format!("Invalid address: {}", address))?;
+    let prefix_len: u8 = prefix_len
+        .parse()
+        .ok()
+        .filter(|&len| len <= 32)
+        .with_context(|| format!("Invalid prefix length, expected 0 to 32: {}", prefix_len))?;
+    if address.is_unspecified() || address.is_broadcast() || address.is_multicast() {
+        anyhow::bail!("Not a usable host address: {}", address);
+    }
+
+    let gateway = gateway
+        .map(|gateway| {
+            let gateway: Ipv4Addr = gateway
+                .parse()
+                .with_context(|| format!("Invalid gateway: {}", gateway))?;
+            // The kernel only accepts a gateway it can reach on-link
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            if u32::from(gateway) & mask != u32::from(address) & mask {
+                anyhow::bail!("Gateway {} is outside {}/{}", gateway, address, prefix_len);
+            }
+            if gateway == address {
+                anyhow::bail!("Gateway must differ from the address: {}", gateway);
+            }
+            Ok(gateway)
+        })
+        .transpose()?;
+
+    Ok(IpConfig {
+        iface,
+        address,
+        prefix_len,
+        gateway,
+    })
+}
+
+/// Parse an init.nameserver value into IPv4 and IPv6 addresses
+///
+/// Example: "10.0.2.3" -> [10.0.2.3]
+/// Example: "1.1.1.1,2606:4700:4700::1111" -> one IPv4 and one IPv6 server
+fn parse_nameservers(value: &str) -> Result<Vec<IpAddr>> {
+    let nameservers: Vec<IpAddr> = value
+        .split(',')
+        .map(|server| {
+            server
+                .parse()
+                .with_context(|| format!("Invalid address: {:?}", server))
+        })
+        .collect::<Result<_>>()?;
+    if let Some(server) = nameservers.iter().find(|server| server.is_unspecified()) {
+        anyhow::bail!("Not a usable nameserver: {}", server);
+    }
+    Ok(nameservers)
+}
+
+/// Parse an init.settime value
+///
+/// Example: "1700000000" -> set the clock to that many seconds since the epoch
+/// Example: "rtc" -> set the clock from /dev/rtc0
+fn parse_settime(value: &str) -> Result<SetTime> {
+    if value == "rtc" {
+        return Ok(SetTime::Rtc);
+    }
+
+    value
+        .parse()
+        .ok()
+        .filter(|secs| (MIN_SETTIME_EPOCH..MAX_SETTIME_EPOCH).contains(secs))
+        .map(SetTime::Epoch)
+        .with_context(|| format!("Invalid init.settime: {}", value))
+}
+
+/// Parse an octal permission mode
+///
+/// Example: "0700" -> 0o700
+/// Example: "644" -> 0o644
+pub fn parse_mode(value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o7777 && !value.starts_with('+'))
+        .with_context(|| format!("Invalid octal mode: {}", value))
+}
+
+/// Parse an init.mknod value
+///
+/// Example: "/dev/fuse:c:10:229" -> char device 10:229, mode 0600
+/// Example: "/dev/vdb:b:254:16:0660" -> block device 254:16, mode 0660
+fn parse_device_node(value: &str) -> Result<DeviceNode> {
+    let parts: Vec<&str> = value.split(':').collect();
+    let (path, kind, major, minor, mode) = match parts.as_slice() {
+        [path, kind, major, minor] => (path, kind, major, minor, None),
+        [path, kind, major, minor, mode] => (path, kind, major, minor, Some(mode)),
+        _ => anyhow::bail!("Expected <path>:<type>:<major>:<minor>[:<mode>]"),
+    };
+
+    if path.is_empty() {
+        anyhow::bail!("Empty device node path");
+    }
+
+    let kind = match *kind {
+        "c" => DeviceType::Char,
+        "b" => DeviceType::Block,
+        other => anyhow::bail!("Invalid device type {:?}, expected 'c' or 'b'", other),
+    };
+
+    let mode = match mode {
+        Some(mode) => parse_mode(mode)?,
+        None => DEFAULT_DEVICE_MODE,
+    };
+
+    Ok(DeviceNode {
+        path: decode_value(path)?,
+        kind,
+        major: major
+            .parse()
+            .with_context(|| format!("Invalid device major: {}", major))?,
+        minor: minor
+            .parse()
+            .with_context(|| format!("Invalid device minor: {}", minor))?,
+        mode,
+    })
+}
+
+/// Parse a single init.tmpfs spec
+///
+/// Example: "/tmp" -> tmpfs with the default size and mode
+/// Example: "/tmp:64m" -> capped at 64 MiB
+/// Example: "/var/tmp:1g:1777" -> capped at 1 GiB, mode 1777
+/// Example: "/scratch::0700" -> default size, mode 0700
+fn parse_tmpfs_mount(spec: &str) -> Result<TmpfsMount> {
+    let mut parts = spec.split(':');
+    let path = decode_value(parts.next().unwrap_or_default())?;
+    if path.is_empty() {
+        anyhow::bail!("Empty tmpfs path");
+    }
+
+    let size = match parts.next() {
+        Some(size) if !size.is_empty() => Some(parse_size(size)?),
+        _ => None,
+    };
+    let mode = parts.next().map(parse_mode).transpose()?;
+
+    if parts.next().is_some() {
+        anyhow::bail!("Expected <path>[:<size>][:<mode>]");
+    }
+
+    Ok(TmpfsMount { path, size, mode })
+}
+
+/// Mount point of init.devshm
+const DEVSHM_PATH: &str = "/dev/shm";
+
+/// Parse an init.devshm value into the /dev/shm tmpfs, if enabled
+///
+/// Example: "Y" -> tmpfs at /dev/shm, mode 1777, default size
+/// Example: "Y:64m" -> same, capped at 64 MiB
+/// Example: "N" -> None
+fn parse_devshm(value: &str) -> Result<Option<TmpfsMount>> {
+    let (flag, size) = match value.split_once(':') {
+        Some((flag, size)) => (flag, Some(size)),
+        None => (value, None),
+    };
+    if flag != "Y" {
+        if size.is_some() {
+            anyhow::bail!("A size needs Y, expected Y[:<size>]");
+        }
+        return Ok(None);
+    }
+
+    Ok(Some(TmpfsMount {
+        path: DEVSHM_PATH.to_string(),
+        size: size.map(parse_size).transpose()?,
+        // Any user may create shared memory objects, only their owner removes them
+        mode: Some(0o1777),
+    }))
+}
+
+/// Parse mount data overriding a built-in default, e.g. init.proc_opts
+///
+/// Example: "hidepid=2,gid=10" -> passed to mount(2) as is
+fn parse_mount_data(value: &str) -> Result<String> {
+    let data = decode_value(value)?;
+    if data.chars().any(char::is_whitespace) {
+        anyhow::bail!("Mount data must not contain whitespace: {:?}", data);
+    }
+    Ok(data)
+}
+
+/// Parse a single init.move spec
+///
+/// Example: "/run/staging:/mnt" -> move the mount at /run/staging to /mnt
+fn parse_move_mount(spec: &str) -> Result<MoveMount> {
+    let (source, target) = spec.split_once(':').context("Expected <src>:<dst>")?;
+    let source = decode_value(source)?;
+    let target = decode_value(target)?;
+
+    for path in [&source, &target] {
+        if !path.starts_with('/') {
+            anyhow::bail!("Path must be absolute: {:?}", path);
+        }
+    }
+    if Path::new(&source) == Path::new(&target) {
+        anyhow::bail!("Source and destination are the same: {}", source);
+    }
+
+    Ok(MoveMount { source, target })
+}
+
+/// Parse a single init.bind spec
+///
+/// Example: "/mnt/share/sub:/sub" -> writable bind of /mnt/share/sub at /sub
+/// Example: "/mnt/share/sub:/sub:ro" -> same, remounted read-only
+fn parse_bind_mount(spec: &str) -> Result<BindMount> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let (source, target, read_only) = match parts.as_slice() {
+        [source, target] => (*source, *target, false),
+        [source, target, "ro"] => (*source, *target, true),
+        _ => anyhow::bail!("Expected <src>:<dst>[:ro]"),
+    };
+    let source = decode_value(source)?;
+    let target = decode_value(target)?;
+
+    for path in [&source, &target] {
+        if !path.starts_with('/') {
+            anyhow::bail!("Path must be absolute: {:?}", path);
+        }
+    }
+
+    Ok(BindMount {
+        source,
+        target,
+        read_only,
+    })
+}
+
+fn parse_symlinks(value: &str, sep: char) -> Result<Vec<Symlink>> {
+    let mut symlinks = Vec::new();
+
+    for symlink_spec in value.split(sep) {
+        if symlink_spec.is_empty() {
+            continue;
         }
 
         let (source, target) = symlink_spec
             .split_once(':')
-            .context(format!("Invalid symlink spec: {}", symlink_spec))?;
+            .ok_or_else(|| InitError::invalid_spec("symlink", symlink_spec))?;
 
         symlinks.push(Symlink {
-            source: source.to_string(),
-            target: target.to_string(),
+            source: decode_value(source)?,
+            target: decode_value(target)?,
         });
     }
 
     Ok(symlinks)
 }
 
-/// Read kernel cmdline from /proc/cmdline
-pub fn read_cmdline() -> Result<String> {
-    std::fs::read_to_string("/proc/cmdline")
-        .context("Failed to read /proc/cmdline")
-        .map(|s| s.trim().to_string())
-}
+/// Check the parsed config for combinations that only show up at boot
+///
+/// Shares are always mounted before symlinks are created, so a symlink
+/// whose source lies under a virtiofs mount path is created inside the
+/// mounted share, not on the initramfs underneath it.
+pub fn validate_config(config: &Config) -> Result<()> {
+    if let Some((first, second)) = duplicate_virtiofs_path(&config.virtiofs_mounts) {
+        anyhow::bail!(
+            "Virtiofs mounts {}:{} and {}:{} use the same path",
+            first.tag,
+            first.path,
+            second.tag,
+            second.path
+        );
+    }
+    // Legitimate for mounting one share in two places, but may be a typo
+    for tag in duplicate_virtiofs_tags(&config.virtiofs_mounts) {
+        warn!("virtiofs tag {} is mounted more than once", tag);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    let mut paths: Vec<(&str, &str)> = Vec::new();
+    for (kind, path) in mount_points(config) {
+        if let Some((other, _)) = paths
+            .iter()
+            .find(|(_, seen)| Path::new(seen) == Path::new(path))
+        {
+            anyhow::bail!("Mount path {} is used by both {} and {}", path, other, kind);
+        }
+        paths.push((kind, path));
+    }
 
-    #[test]
-    fn test_parse_empty_cmdline() {
-        let result = parse_cmdline("");
+    if let Some(cycle) = symlink_cycle(&config.symlinks) {
+        anyhow::bail!("Symlinks form a cycle: {}", cycle.join(" -> "));
+    }
+
+    for (symlink, mount) in symlink_mount_overlaps(&config.symlinks, &config.virtiofs_mounts) {
+        if Path::new(&symlink.source) == Path::new(&mount.path) {
+            anyhow::bail!(
+                "Symlink {} would replace virtiofs mount point {}",
+                symlink.source,
+                mount.path
+            );
+        }
+
+        if mount.flag_options.iter().any(|option| option == "ro") {
+            anyhow::bail!(
+                "Symlink {} is inside read-only virtiofs share {}",
+                symlink.source,
+                mount.tag
+            );
+        }
+
+        if mount.with_overlay {
+            debug!(
+                "symlink {} is created on the overlay of virtiofs share {}",
+                symlink.source, mount.tag
+            );
+        } else {
+            warn!(
+                "symlink {} is created inside virtiofs share {} and is visible on the host",
+                symlink.source, mount.tag
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// First pair of virtiofs mounts at the same path, one would shadow the other
+fn duplicate_virtiofs_path(mounts: &[VirtiofsMount]) -> Option<(&VirtiofsMount, &VirtiofsMount)> {
+    mounts.iter().enumerate().find_map(|(index, first)| {
+        mounts[index + 1..]
+            .iter()
+            .find(|second| Path::new(&first.path) == Path::new(&second.path))
+            .map(|second| (first, second))
+    })
+}
+
+/// Virtiofs tags mounted more than once, each listed once
+fn duplicate_virtiofs_tags(mounts: &[VirtiofsMount]) -> Vec<&str> {
+    let mut duplicates = Vec::new();
+    for (index, mount) in mounts.iter().enumerate() {
+        let repeated = mounts[..index].iter().any(|other| other.tag == mount.tag);
+        if repeated && !duplicates.contains(&mount.tag.as_str()) {
+            duplicates.push(mount.tag.as_str());
+        }
+    }
+    duplicates
+}
+
+/// Every path init mounts something at, with the param that asks for it
+fn mount_points(config: &Config) -> Vec<(&'static str, &str)> {
+    let virtiofs = config
+        .virtiofs_mounts
+        .iter()
+        .map(|mount| ("init.virtiofs", mount.path.as_str()));
+    let merges = config
+        .merge_mounts
+        .iter()
+        .map(|mount| ("init.merge", mount.path.as_str()));
+    let p9 = config
+        .p9_mounts
+        .iter()
+        .map(|mount| ("init.p9", mount.path.as_str()));
+    let squashfs = config
+        .squashfs_mounts
+        .iter()
+        .map(|mount| ("init.squashfs", mount.path.as_str()));
+    let extra = config
+        .extra_mounts
+        .iter()
+        .map(|mount| ("init.mount", mount.target.as_str()));
+    let tmpfs = config
+        .tmpfs_mounts
+        .iter()
+        .map(|mount| ("init.tmpfs", mount.path.as_str()));
+    let moves = config
+        .move_mounts
+        .iter()
+        .map(|mount| ("init.move", mount.target.as_str()));
+    let binds = config
+        .bind_mounts
+        .iter()
+        .map(|mount| ("init.bind", mount.target.as_str()));
+    virtiofs
+        .chain(merges)
+        .chain(p9)
+        .chain(squashfs)
+        .chain(extra)
+        .chain(tmpfs)
+        .chain(moves)
+        .chain(binds)
+        .collect()
+}
+
+/// Links of the first cycle among the symlinks, if they form one
+///
+/// Relative targets are resolved against the link's directory. Only links
+/// pointing straight at another link are followed, not paths below one.
+fn symlink_cycle(symlinks: &[Symlink]) -> Option<Vec<String>> {
+    let links: HashMap<PathBuf, PathBuf> = symlinks
+        .iter()
+        .map(|symlink| {
+            let source = Path::new(&symlink.source);
+            let target = source
+                .parent()
+                .unwrap_or(Path::new("/"))
+                .join(&symlink.target);
+            (normalize_path(source), normalize_path(&target))
+        })
+        .collect();
+
+    for symlink in symlinks {
+        let mut chain = vec![normalize_path(Path::new(&symlink.source))];
+        while let Some(next) = links.get(chain.last()?) {
+            if let Some(start) = chain.iter().position(|link| link == next) {
+                let mut cycle: Vec<String> = chain[start..]
+                    .iter()
+                    .map(|link| link.display().to_string())
+                    .collect();
+                cycle.push(next.display().to_string());
+                return Some(cycle);
+            }
+            chain.push(next.clone());
+        }
+    }
+    None
+}
+
+/// Resolve "." and ".." in an absolute path without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+/// Pair each symlink with the virtiofs mount its source lives under
+fn symlink_mount_overlaps<'a>(
+    symlinks: &'a [Symlink],
+    mounts: &'a [VirtiofsMount],
+) -> Vec<(&'a Symlink, &'a VirtiofsMount)> {
+    symlinks
+        .iter()
+        .filter_map(|symlink| {
+            mounts
+                .iter()
+                .filter(|mount| Path::new(&symlink.source).starts_with(&mount.path))
+                // Nested mounts: the innermost one holds the symlink
+                .max_by_key(|mount| Path::new(&mount.path).components().count())
+                .map(|mount| (symlink, mount))
+        })
+        .collect()
+}
+
+/// Read kernel cmdline from /proc/cmdline, or KDF_INIT_CMDLINE without it
+pub fn read_cmdline() -> Result<String> {
+    read_cmdline_with(
+        || std::fs::read_to_string(PROC_CMDLINE),
+        |name| std::env::var(name).ok(),
+    )
+}
+
+/// [`read_cmdline`] with the file read and the environment lookup injected
+///
+/// Only a missing file falls back to the environment, any other read error
+/// is returned as is.
+fn read_cmdline_with(
+    read: impl FnOnce() -> std::io::Result<String>,
+    var: impl FnOnce(&str) -> Option<String>,
+) -> Result<String> {
+    let cmdline = match read() {
+        Ok(cmdline) => cmdline,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let cmdline = var(CMDLINE_ENV).with_context(|| {
+                format!("{} is missing and {} is unset", PROC_CMDLINE, CMDLINE_ENV)
+            })?;
+            info!("{} is missing, using {}", PROC_CMDLINE, CMDLINE_ENV);
+            cmdline
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", PROC_CMDLINE)),
+    };
+    Ok(cmdline.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_virtiofs_order() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` \
+             init.virtiofs=base:/base:N:order=1,over:/mnt:Y:lower=/base,order=2,plain:/p,neg:/n:N:order=-3",
+        )
+        .unwrap();
+        let orders: Vec<i32> = config.virtiofs_mounts.iter().map(|m| m.order).collect();
+        assert_eq!(orders, vec![1, 2, 0, -3]);
+        assert_eq!(config.virtiofs_mounts[1].extra_lowers, vec!["/base"]);
+        // Not passed through as a data option
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_invalid_order() {
+        for spec in [
+            "share:/mnt:N:order=",
+            "share:/mnt:N:order=x",
+            "share:/mnt:N:order=1.5",
+            "share:/mnt:N:order=1,order=2",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                spec
+            ));
+            assert!(result.is_err(), "order should be rejected: {}", spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_if_flag_present() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=base:/base \
+             init.virtiofs.if=ci:share:/mnt:Y,cache:/cache ci",
+        )
+        .unwrap();
+        let mounts: Vec<(&str, &str)> = config
+            .virtiofs_mounts
+            .iter()
+            .map(|m| (m.tag.as_str(), m.path.as_str()))
+            .collect();
+        assert_eq!(
+            mounts,
+            vec![("base", "/base"), ("share", "/mnt"), ("cache", "/cache")]
+        );
+        assert!(config.virtiofs_mounts[1].with_overlay);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_if_flag_absent() {
+        for flags in ["", "dev", "ci=1", "init.ci"] {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs=base:/base \
+                 init.virtiofs.if=ci:share:/mnt {}",
+                flags
+            ))
+            .unwrap();
+            assert_eq!(config.virtiofs_mounts.len(), 1, "{}", flags);
+            assert_eq!(config.virtiofs_mounts[0].tag, "base");
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_if_order_independent() {
+        // The flag may come first, and init.virtiofs after the condition
+        let config = parse_cmdline(
+            "ci init.console=console init.virtiofs.if=ci:share:/mnt \
+             init.virtiofs.if=dev:dev:/dev-src init.shell=`sh` init.virtiofs=base:/base",
+        )
+        .unwrap();
+        let tags: Vec<&str> = config
+            .virtiofs_mounts
+            .iter()
+            .map(|m| m.tag.as_str())
+            .collect();
+        assert_eq!(tags, vec!["base", "share"]);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_if_rejects_invalid() {
+        // Rejected whether the flag is set or not
+        for params in [
+            "init.virtiofs.if=ci",
+            "init.virtiofs.if=:share:/mnt",
+            "init.virtiofs.if=ci:share",
+            "init.virtiofs.if=ci:share ci",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_custom_separator() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs.sep=; init.virtiofs=a:/x;b:/y",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 2);
+        assert_eq!(config.virtiofs_mounts[0].tag, "a");
+        assert_eq!(config.virtiofs_mounts[0].path, "/x");
+        assert_eq!(config.virtiofs_mounts[1].tag, "b");
+        assert_eq!(config.virtiofs_mounts[1].path, "/y");
+    }
+
+    #[test]
+    fn test_parse_virtiofs_separator_keeps_commas() {
+        // The directive applies even after the list, commas stay in the options
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` \
+             init.virtiofs=a,b:/x:N:dax,cache=always;c:/y init.virtiofs.sep=;",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 2);
+        assert_eq!(config.virtiofs_mounts[0].tag, "a,b");
+        assert_eq!(
+            config.virtiofs_mounts[0].options,
+            vec!["dax".to_string(), "cache=always".to_string()]
+        );
+        assert_eq!(config.virtiofs_mounts[1].tag, "c");
+    }
+
+    #[test]
+    fn test_parse_list_separators() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.symlinks.sep=| init.tmpfs.sep=; \
+             init.symlinks=/a:/b|/c:/d,e init.tmpfs=/tmp:64m;/run/x init.nomount=/sys,/dev",
+        )
+        .unwrap();
+        assert_eq!(config.symlinks.len(), 2);
+        assert_eq!(config.symlinks[1].target, "/d,e");
+        assert_eq!(config.tmpfs_mounts.len(), 2);
+        // Lists without a directive keep ','
+        assert_eq!(config.nomount, vec!["/sys", "/dev"]);
+    }
+
+    #[test]
+    fn test_parse_separator_text_in_values() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.env.X=a.sep=b init.arg.0=echo init.arg.1=init.virtiofs.sep=x",
+        )
+        .unwrap();
+        assert_eq!(config.env_vars.get("X"), Some(&"a.sep=b".to_string()));
+        assert_eq!(config.args, vec!["echo", "init.virtiofs.sep=x"]);
+    }
+
+    #[test]
+    fn test_parse_invalid_separators() {
+        for sep in [
+            "init.virtiofs.sep=",
+            "init.virtiofs.sep=;;",
+            "init.virtiofs.sep=:",
+            "init.virtiofs.sep=%",
+            "init.virtiofs.sep=x",
+            "init.env.sep=;",
+            "init.shell.sep=;",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", sep));
+            assert!(result.is_err(), "separator should be rejected: {}", sep);
+        }
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let config = parse_config_file(
+            "# second stage\n\
+             init.console=ttyS0\n\
+             \n\
+             init.shell=`sh -i`\n\
+             \tinit.virtiofs=share:/mnt:Y  \n\
+             init.env.GREETING=hello world\n",
+        )
+        .unwrap();
+        assert_eq!(config.console, "ttyS0");
+        assert_eq!(config.shell, ("sh".to_string(), vec!["-i".to_string()]));
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert!(config.virtiofs_mounts[0].with_overlay);
+        assert_eq!(config.env_vars.get("GREETING").unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_parse_config_file_rejects_invalid_lines() {
+        for contents in [
+            "console=ttyS0",
+            "init.console",
+            "init.console=ttyS0\ninit.config=share:other.conf",
+            "init.console=ttyS0\ninit.shell=`sh`\ninit.boot_timeout=0",
+        ] {
+            let result = parse_config_file(contents);
+            assert!(
+                result.is_err(),
+                "config file should be rejected: {}",
+                contents
+            );
+        }
+
+        let err = parse_config_file("init.console=ttyS0\n\nbogus").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_merge_config_file_cmdline_wins() {
+        let contents = "init.console=ttyS0\n\
+                        init.shell=`sh`\n\
+                        init.loglevel=debug\n\
+                        init.virtiofs=share:/mnt\n\
+                        init.mount=tmpfs:/tmp\n\
+                        init.env.A=file\n\
+                        init.env.B=file\n";
+        let cmdline = "console=ttyS0 init.config=cfg:init.conf init.loglevel=quiet \
+                       init.mount=cgroup2:/sys/fs/cgroup init.env.B=cmdline";
+        let config = parse_config_file(&merge_config_file(contents, cmdline).unwrap()).unwrap();
+
+        assert_eq!(config.log_level, LogLevel::Quiet);
+        // Only set in the file
+        assert_eq!(config.console, "ttyS0");
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        // Repeatable params accumulate, env vars merge per key
+        assert_eq!(config.extra_mounts.len(), 2);
+        assert_eq!(config.env_vars.get("A").unwrap(), "file");
+        assert_eq!(config.env_vars.get("B").unwrap(), "cmdline");
+    }
+
+    #[test]
+    fn test_config_source() {
+        assert_eq!(
+            config_source("init.console=console init.config=share:vm/init.conf").unwrap(),
+            Some(ShareFile {
+                tag: "share".to_string(),
+                path: "vm/init.conf".to_string(),
+            })
+        );
+        assert_eq!(config_source("init.console=console").unwrap(), None);
+
+        for value in [
+            "share",
+            ":init.conf",
+            "share:",
+            "share:/init.conf",
+            "share:../init.conf",
+        ] {
+            let result = config_source(&format!("init.config={}", value));
+            assert!(result.is_err(), "init.config should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_describe_params_mentions_every_param() {
+        let usage = describe_params();
+        // Every parameter the parser accepts, numbered and named ones by prefix
+        let params = [
+            "init.allow_unset_vars",
+            "init.arg.",
+            "init.argv_file",
+            "init.atomic_mounts",
+            "init.bind",
+            "init.boot_timeout",
+            "init.cgroup2",
+            "init.cgroup_path",
+            "init.chdir",
+            "init.check",
+            "init.cmd.",
+            "init.cmd.N.env.",
+            "init.cmd_fail",
+            "init.cmd_fifo",
+            "init.cmd_ok",
+            "init.cmd_retries",
+            "init.config",
+            "init.console",
+            "init.devpts",
+            "init.devshm",
+            "init.dhcp",
+            "init.dryrun",
+            "init.env.",
+            "init.env.passthrough",
+            "init.envfile",
+            "init.exec_timeout",
+            "init.fstab",
+            "init.groups",
+            "init.interp",
+            "init.ip",
+            "init.keep_going",
+            "init.keepalive",
+            "init.kmsg",
+            "init.loglevel",
+            "init.loopback",
+            "init.merge",
+            "init.mknod",
+            "init.moddir",
+            "init.mount",
+            "init.mount_private",
+            "init.mount_retries",
+            "init.move",
+            "init.nameserver",
+            "init.nomount",
+            "init.overlay_base",
+            "init.overlay_reset",
+            "init.overlay_tmpfs_size",
+            "init.p9",
+            "init.path_append",
+            "init.path_prepend",
+            "init.preexec.",
+            "init.print_config",
+            "init.printk",
+            "init.probe",
+            "init.proc_opts",
+            "init.ready_marker",
+            "init.reboot_on",
+            "init.rescue",
+            "init.result_file",
+            "init.rlimit.",
+            "init.root",
+            "init.run_opts",
+            "init.script",
+            "init.securityfs",
+            "init.seed",
+            "init.seedfile",
+            "init.selinuxfs",
+            "init.settime",
+            "init.shell",
+            "init.shutdown_timeout",
+            "init.skip_fs_check",
+            "init.squashfs",
+            "init.supervise",
+            "init.symlinks",
+            "init.sync_rtc",
+            "init.sysctl",
+            "init.timings",
+            "init.tmpfs",
+            "init.tmpfs_default_opts",
+            "init.umount",
+            "init.unshare",
+            "init.user",
+            "init.verbose",
+            "init.verify_tags",
+            "init.virtiofs",
+            "init.virtiofs.if",
+            "init.wait_for",
+            "init.watchdog",
+        ];
+        for param in params {
+            assert!(usage.contains(param), "usage doesn't mention {}", param);
+        }
+    }
+
+    #[test]
+    fn test_parse_empty_cmdline() {
+        for cmdline in ["", "  \t\n"] {
+            let config = parse_cmdline(cmdline).unwrap();
+            assert_eq!(config.console, "console");
+            assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
+            assert!(config.rescue);
+            assert!(config.virtiofs_mounts.is_empty());
+        }
+
+        // Only a cmdline without any parameter gets the defaults
+        let result = parse_cmdline("console=ttyS0");
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("init.shell is required"));
+    }
+
+    #[test]
+    fn test_read_cmdline_with() {
+        let missing = || Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+
+        let cmdline = read_cmdline_with(
+            || Ok("init.console=ttyS0 init.shell=`sh`\n".to_string()),
+            |_| panic!("the environment is only a fallback"),
+        )
+        .unwrap();
+        assert_eq!(cmdline, "init.console=ttyS0 init.shell=`sh`");
+        assert_eq!(
+            read_cmdline_with(|| Ok(String::new()), |_| None).unwrap(),
+            ""
+        );
+
+        let cmdline = read_cmdline_with(missing, |name| {
+            assert_eq!(name, "KDF_INIT_CMDLINE");
+            Some(" init.console=console init.shell=`sh` ".to_string())
+        })
+        .unwrap();
+        assert_eq!(cmdline, "init.console=console init.shell=`sh`");
+
+        let err = read_cmdline_with(missing, |_| None).unwrap_err();
+        assert!(
+            err.to_string().contains("KDF_INIT_CMDLINE is unset"),
+            "{}",
+            err
+        );
+
+        // Anything but a missing file is an error, not a reason to fall back
+        let denied = || Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        let result = read_cmdline_with(denied, |_| Some("init.shell=`sh`".to_string()));
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Failed to read /proc/cmdline"));
+    }
+
+    #[test]
+    fn test_parse_virtiofs_basic() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share")
+                .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/share");
+        assert!(!config.virtiofs_mounts[0].with_overlay);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_with_overlay() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share:Y")
+                .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert!(config.virtiofs_mounts[0].with_overlay);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_multiple() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share1:/mnt/a,share2:/mnt/b:Y",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 2);
+        assert_eq!(config.virtiofs_mounts[0].tag, "share1");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/a");
+        assert!(!config.virtiofs_mounts[0].with_overlay);
+        assert_eq!(config.virtiofs_mounts[1].tag, "share2");
+        assert_eq!(config.virtiofs_mounts[1].path, "/mnt/b");
+        assert!(config.virtiofs_mounts[1].with_overlay);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_with_options() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share:N:dax,cache=always,other:/mnt/other:Y",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts.len(), 2);
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/share");
+        assert!(!config.virtiofs_mounts[0].with_overlay);
+        assert_eq!(
+            config.virtiofs_mounts[0].options,
+            vec!["dax".to_string(), "cache=always".to_string()]
+        );
+        assert_eq!(config.virtiofs_mounts[1].tag, "other");
+        assert!(config.virtiofs_mounts[1].with_overlay);
+        assert!(config.virtiofs_mounts[1].options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_flag_options() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:N:dax,nosuid,noexec,other:/mnt/other:Y:nodev",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].options, vec!["dax".to_string()]);
+        assert_eq!(
+            config.virtiofs_mounts[0].flag_options,
+            vec!["nosuid".to_string(), "noexec".to_string()]
+        );
+        assert_eq!(
+            config.virtiofs_mounts[1].flag_options,
+            vec!["nodev".to_string()]
+        );
+        assert!(config.virtiofs_mounts[1].options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_index_metacopy() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:metacopy,index,other:/mnt/other:Y:index",
+        )
+        .unwrap();
+        assert!(config.virtiofs_mounts[0].index);
+        assert!(config.virtiofs_mounts[0].metacopy);
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+        assert!(config.virtiofs_mounts[1].index);
+        assert!(!config.virtiofs_mounts[1].metacopy);
+
+        for spec in ["share:/mnt:N:index", "share:/mnt:N:metacopy"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                spec
+            ));
+            assert!(result.is_err(), "{} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_redirect_dir_xino() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` \
+             init.virtiofs=share:/mnt:Y:redirect_dir=on,xino=off,index",
+        )
+        .unwrap();
+        let mount = &config.virtiofs_mounts[0];
+        assert_eq!(mount.redirect_dir, Some(true));
+        assert_eq!(mount.xino, Some(false));
+        assert!(mount.index);
+        assert!(mount.options.is_empty());
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y")
+                .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].redirect_dir, None);
+        assert_eq!(config.virtiofs_mounts[0].xino, None);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_redirect_dir_xino_invalid() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:redirect_dir=maybe",
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid redirect_dir value"));
+
+        for spec in [
+            "share:/mnt:Y:xino=auto",
+            "share:/mnt:Y:xino=",
+            "share:/mnt:N:redirect_dir=on",
+            "share:/mnt:Y:xino=on,xino=off",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                spec
+            ));
+            assert!(result.is_err(), "{} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_ro() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=data:/mnt/data:N:ro")
+                .unwrap();
+        assert_eq!(
+            config.virtiofs_mounts[0].flag_options,
+            vec!["ro".to_string()]
+        );
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=data:/mnt/data:Y:ro");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_without_options() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=a:/mnt/a,b:/mnt/b:Y")
+                .unwrap();
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+        assert!(config.virtiofs_mounts[1].options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_empty_option() {
+        for value in [
+            "share:/mnt:N:",
+            "share:/mnt:Y:dax:",
+            "share:/mnt:N::dax",
+            "share:/mnt:N:dax,,x",
+            "share:/mnt:N:dax,",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                value
+            ));
+            assert!(result.is_err());
+            assert!(result.unwrap_err().to_string().contains("Empty option"));
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_extra_lowers() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:lower=/base1:lower=/base2",
+        )
+        .unwrap();
+        let mount = &config.virtiofs_mounts[0];
+        assert!(mount.with_overlay);
+        assert_eq!(
+            mount.extra_lowers,
+            vec!["/base1".to_string(), "/base2".to_string()]
+        );
+        assert!(mount.options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_extra_lowers_without_overlay() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:N:lower=/base1",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_upper_tag() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:upper=persist",
+        )
+        .unwrap();
+        assert_eq!(
+            config.virtiofs_mounts[0].upper_tag,
+            Some("persist".to_string())
+        );
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y")
+                .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].upper_tag, None);
+    }
+
+    #[test]
+    fn test_parse_virtiofs_upper_tag_same_as_lower() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:upper=share",
+        );
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("must differ"));
+    }
+
+    #[test]
+    fn test_parse_virtiofs_upper_tag_invalid() {
+        for spec in ["share:/mnt:N:upper=persist", "share:/mnt:Y:upper=a:upper=b"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                spec
+            ));
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_source() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:N:src=other,dax",
+        )
+        .unwrap();
+        let mount = &config.virtiofs_mounts[0];
+        assert_eq!(mount.tag, "share");
+        assert_eq!(mount.source, Some("other".to_string()));
+        assert_eq!(mount.mount_source(), "other");
+        assert_eq!(mount.options, vec!["dax"]);
+
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:src=my%20src",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].mount_source(), "my src");
+    }
+
+    #[test]
+    fn test_parse_virtiofs_source_defaults_to_tag() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt:N:dax")
+                .unwrap();
+        let mount = &config.virtiofs_mounts[0];
+        assert_eq!(mount.source, None);
+        assert_eq!(mount.mount_source(), "share");
+    }
+
+    #[test]
+    fn test_parse_virtiofs_source_invalid() {
+        for spec in ["share:/mnt:N:src=", "share:/mnt:N:src=a,src=b"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.virtiofs={}",
+                spec
+            ));
+            assert!(result.is_err(), "{} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_stray_segment() {
+        // A bare segment only continues a spec that already has options
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt,dax");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_virtiofs_percent_encoded_path() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/my%20dir")
+                .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/my dir");
+    }
+
+    #[test]
+    fn test_parse_p9() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.p9=share:/mnt/share,big:/mnt/big:524288",
+        )
+        .unwrap();
+        assert_eq!(
+            config.p9_mounts,
+            vec![
+                Plan9Mount {
+                    tag: "share".to_string(),
+                    path: "/mnt/share".to_string(),
+                    msize: DEFAULT_P9_MSIZE,
+                },
+                Plan9Mount {
+                    tag: "big".to_string(),
+                    path: "/mnt/big".to_string(),
+                    msize: 524288,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_p9_invalid() {
+        for spec in [
+            "share",
+            "share:/mnt:0",
+            "share:/mnt:-1",
+            "share:/mnt:big",
+            "a:/b:1:2",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.p9={}",
+                spec
+            ));
+            assert!(result.is_err(), "spec should be rejected: {}", spec);
+        }
+
+        // Wrong field counts, as opposed to a bad msize
+        for spec in ["share", "a:/b:1:2"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.p9={}",
+                spec
+            ));
+            assert_eq!(
+                init_error(result),
+                Some(InitError::invalid_spec("9p mount", spec)),
+                "{}",
+                spec
+            );
+        }
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.p9=share:/mnt:big");
+        assert_eq!(init_error(result), None);
+    }
+
+    #[test]
+    fn test_parse_symlinks_percent_encoded() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.symlinks=/a%2Cb:/c%3Ad%25")
+                .unwrap();
+        assert_eq!(config.symlinks[0].source, "/a,b");
+        assert_eq!(config.symlinks[0].target, "/c:d%");
+    }
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(decode_value("plain").unwrap(), "plain");
+        assert_eq!(decode_value("a%20b%2cc").unwrap(), "a b,c");
+        assert_eq!(decode_value("%25%3A").unwrap(), "%:");
+    }
+
+    #[test]
+    fn test_decode_value_malformed() {
+        assert!(decode_value("/mnt/dir%2").is_err());
+        assert!(decode_value("/mnt/dir%").is_err());
+        assert!(decode_value("/mnt/%zzdir").is_err());
+        assert!(decode_value("/mnt/%+1dir").is_err());
+        assert!(
+            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt%2")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_symlinks() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.symlinks=/bin/sh:/bin/bash,/usr/bin/vi:/usr/bin/vim").unwrap();
+        assert_eq!(config.symlinks.len(), 2);
+        assert_eq!(config.symlinks[0].source, "/bin/sh");
+        assert_eq!(config.symlinks[0].target, "/bin/bash");
+        assert_eq!(config.symlinks[1].source, "/usr/bin/vi");
+        assert_eq!(config.symlinks[1].target, "/usr/bin/vim");
+    }
+
+    #[test]
+    fn test_parse_env_vars() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.env.PATH=/usr/bin init.env.HOME=/root",
+        )
+        .unwrap();
+        assert_eq!(config.env_vars.len(), 2);
+        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+        assert_eq!(config.env_vars.get("HOME"), Some(&"/root".to_string()));
+    }
+
+    #[test]
+    fn test_parse_env_var_with_equals_in_value() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.env.OPTS=a=b,c=d").unwrap();
+        assert_eq!(config.env_vars.get("OPTS"), Some(&"a=b,c=d".to_string()));
+    }
+
+    #[test]
+    fn test_parse_allow_unset_vars() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.allow_unset_vars=Y").unwrap();
+        assert!(config.allow_unset_vars);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.allow_unset_vars);
+    }
+
+    #[test]
+    fn test_parse_env_passthrough() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.env.passthrough=TERM,LANG, init.env.passthrough=SSH_AUTH_SOCK init.env.HOME=/root",
+        )
+        .unwrap();
+        assert_eq!(
+            config.env_passthrough,
+            vec!["TERM", "LANG", "SSH_AUTH_SOCK"]
+        );
+        // Not an environment variable of its own
+        assert_eq!(
+            config.env_vars,
+            HashMap::from([("HOME".to_string(), "/root".to_string())])
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.env_passthrough.is_empty());
+
+        let result = parse_cmdline(
+            r#"init.console=console init.shell=`sh` init.env.passthrough="TERM,MY VAR""#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_env_var_empty_key() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.env.=foo");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Empty environment variable name"));
+    }
+
+    #[test]
+    fn test_parse_envfile() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.envfile=/mnt/share/env")
+                .unwrap();
+        assert_eq!(config.envfile, Some("/mnt/share/env".to_string()));
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.envfile=");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_path_prepend_append() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.path_prepend=/opt/bin,/usr/local/bin init.path_append=/sbin",
+        )
+        .unwrap();
+        assert_eq!(config.path_prepend, vec!["/opt/bin", "/usr/local/bin"]);
+        assert_eq!(config.path_append, vec!["/sbin"]);
+        assert!(config.env_vars.is_empty());
+    }
+
+    #[test]
+    fn test_parse_path_dirs_reject_colon() {
+        for param in ["init.path_prepend=/a:/b", "init.path_append=/opt,/c:/d"] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", param));
+            assert!(result.is_err(), "{} should be rejected", param);
+        }
+    }
+
+    #[test]
+    fn test_parse_args_out_of_order() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.arg.2=world init.arg.0=/bin/echo init.arg.1=hello",
+        )
+        .unwrap();
+        assert_eq!(config.args, vec!["/bin/echo", "hello", "world"]);
+        assert_eq!(
+            config.command().unwrap(),
+            Some((
+                "/bin/echo".to_string(),
+                vec!["hello".to_string(), "world".to_string()]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_args_with_gaps() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.arg.10=b init.arg.0=/bin/echo init.arg.5=hello%20world",
+        )
+        .unwrap();
+        assert_eq!(config.args, vec!["/bin/echo", "hello world", "b"]);
+        assert!(config.has_command());
+    }
+
+    #[test]
+    fn test_parse_args_rejects_invalid() {
+        for param in ["init.arg.x=foo", "init.arg.-1=foo", "init.arg.0"] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", param));
+            assert!(result.is_err(), "{} should be rejected", param);
+        }
+    }
+
+    #[test]
+    fn test_parse_preexec() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.preexec.1=/var/lib/db init.preexec.0=initdb init.arg.0=postgres",
+        )
+        .unwrap();
+        assert_eq!(config.preexec, vec!["initdb", "/var/lib/db"]);
+        assert_eq!(config.args, vec!["postgres"]);
+
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.preexec.first=initdb");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_cmd_stages_ordered() {
+        let config = parse_cmdline(
+            r#"init.console=console init.shell=`sh` init.cmd.10=true init.cmd.2="make -j4" init.cmd.0="./configure --prefix=/usr""#,
+        )
+        .unwrap();
+        let commands: Vec<_> = config.cmds.iter().map(|stage| &stage.command).collect();
+        assert_eq!(
+            commands,
+            vec![
+                &("./configure".to_string(), vec!["--prefix=/usr".to_string()]),
+                &("make".to_string(), vec!["-j4".to_string()]),
+                &("true".to_string(), vec![]),
+            ]
+        );
+        assert!(config.cmds.iter().all(|stage| stage.env.is_empty()));
+        assert!(config.has_command());
+        assert!(!config.keep_going);
+        assert_eq!(config.commands().unwrap(), config.cmds);
+        assert_eq!(config.command().unwrap(), None);
+
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.cmd.0=false init.cmd.1=true init.keep_going=Y",
+        )
+        .unwrap();
+        assert!(config.keep_going);
+    }
+
+    #[test]
+    fn test_parse_cmd_retries() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`run-tests` init.supervise=Y init.cmd_retries=3",
+        )
+        .unwrap();
+        assert_eq!(config.cmd_retries, 3);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.cmd_retries, 0);
+
+        for value in ["-1", "twice", ""] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.cmd_retries={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.cmd_retries should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_argv_file() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.argv_file=/mnt/share/my%20argv",
+        )
+        .unwrap();
+        assert_eq!(config.argv_file.as_deref(), Some("/mnt/share/my argv"));
+        assert!(config.has_command());
+        // Only read once the shares are mounted
+        assert_eq!(config.command().unwrap(), None);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.argv_file, None);
+        assert!(!config.has_command());
+    }
+
+    #[test]
+    fn test_parse_argv_file_rejects_invalid() {
+        for params in [
+            "init.argv_file=argv",
+            "init.argv_file=",
+            "init.argv_file=/argv init.script=`true`",
+            "init.argv_file=/argv init.arg.0=true",
+            "init.argv_file=/argv init.cmd.0=true",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_cmd_fifo() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.cmd_fifo=/mnt/share/cmd:60")
+                .unwrap();
+        assert_eq!(
+            config.cmd_fifo,
+            Some(WaitFor {
+                path: "/mnt/share/cmd".to_string(),
+                timeout: 60,
+            })
+        );
+        assert!(config.has_command());
+        // Only read once the shares are mounted
+        assert_eq!(config.command().unwrap(), None);
+
+        for params in [
+            "init.cmd_fifo=/cmd",
+            "init.cmd_fifo=cmd:60",
+            "init.cmd_fifo=/cmd:soon",
+            "init.cmd_fifo=/cmd:60 init.script=`true`",
+            "init.cmd_fifo=/cmd:60 init.arg.0=true",
+            "init.cmd_fifo=/cmd:60 init.argv_file=/argv",
+            "init.cmd_fifo=/cmd:60 init.cmd.0=true",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_interp() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.interp=/bin/sh init.arg.0=/setup",
+        )
+        .unwrap();
+        assert_eq!(config.interp.as_deref(), Some("/bin/sh"));
+        // The command itself is left alone, the interpreter is only a fallback
+        assert_eq!(
+            config.command().unwrap(),
+            Some(("/setup".to_string(), vec![]))
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.interp, None);
+
+        for params in ["init.interp=sh", "init.interp="] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_cmd_stages_rejects_invalid() {
+        for params in [
+            "init.cmd.x=true",
+            "init.cmd.0",
+            "init.cmd.0=",
+            "init.cmd.0=true init.script=`true`",
+            "init.cmd.0=true init.arg.0=true",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_probe() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.probe=`test -e /dev/vdb` init.cmd_ok=`/bin/fsck /dev/vdb` init.cmd_fail=`/bin/echo no disk`",
+        )
+        .unwrap();
+        assert_eq!(config.probe, Some("test -e /dev/vdb".to_string()));
+        assert_eq!(config.cmd_ok, Some("/bin/fsck /dev/vdb".to_string()));
+        assert_eq!(config.cmd_fail, Some("/bin/echo no disk".to_string()));
+        assert!(config.has_command());
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.probe, None);
+        assert_eq!(config.cmd_ok, None);
+        assert_eq!(config.cmd_fail, None);
+    }
+
+    #[test]
+    fn test_parse_probe_rejects_invalid() {
+        for params in [
+            "init.probe=true init.cmd_ok=`true` init.cmd_fail=`false`",
+            "init.probe=`true` init.cmd_ok=true init.cmd_fail=`false`",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=false",
+            "init.probe=`true`",
+            "init.probe=`true` init.cmd_ok=`true`",
+            "init.probe=`true` init.cmd_fail=`false`",
+            "init.cmd_ok=`true`",
+            "init.cmd_fail=`false`",
+            "init.cmd_ok=`true` init.cmd_fail=`false`",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=`false` init.script=`true`",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=`false` init.arg.0=true",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=`false` init.argv_file=/argv",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=`false` init.cmd_fifo=/cmd:60",
+            "init.probe=`true` init.cmd_ok=`true` init.cmd_fail=`false` init.cmd.0=true",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_cmd_stage_env() {
+        let config = parse_cmdline(
+            r#"init.console=console init.shell=`sh` init.env.CC=gcc init.cmd.0=./configure init.cmd.0.env.CC=clang init.cmd.0.env.CFLAGS="-O2 -g" init.cmd.1=make init.cmd.1.env.V=1"#,
+        )
+        .unwrap();
+        assert_eq!(config.cmds.len(), 2);
+        assert_eq!(
+            config.cmds[0].env,
+            HashMap::from([
+                ("CC".to_string(), "clang".to_string()),
+                ("CFLAGS".to_string(), "-O2 -g".to_string()),
+            ])
+        );
+        // Stage 0's env stays with stage 0
+        assert_eq!(
+            config.cmds[1].env,
+            HashMap::from([("V".to_string(), "1".to_string())])
+        );
+        // The global env is untouched
+        assert_eq!(
+            config.env_vars,
+            HashMap::from([("CC".to_string(), "gcc".to_string())])
+        );
+
+        // Only the key marks a stage env, not the value
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.cmd.0=\"cat /etc/app.env.d\"")
+                .unwrap();
+        assert_eq!(
+            config.cmds[0].command,
+            ("cat".to_string(), vec!["/etc/app.env.d".to_string()])
+        );
+        assert!(config.cmds[0].env.is_empty());
+    }
+
+    #[test]
+    fn test_parse_cmd_stage_env_rejects_invalid() {
+        for params in [
+            "init.cmd.0=true init.cmd.0.env.=x",
+            "init.cmd.0=true init.cmd.x.env.A=x",
+            "init.cmd.0=true init.cmd.1.env.A=x",
+            "init.cmd.0.env.A=x",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_commands_without_stages() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`ls -l`").unwrap();
+        assert_eq!(
+            config.commands().unwrap(),
+            vec![CmdStage {
+                command: ("ls".to_string(), vec!["-l".to_string()]),
+                env: HashMap::new(),
+            }]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.commands().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_args_with_script_rejected() {
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/true` init.arg.0=/bin/echo",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_console_rejects_path() {
+        let result = parse_cmdline("init.console=/dev/ttyS0 init.shell=`sh`");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_shell() {
+        let config = parse_cmdline("init.console=console init.shell=`/bin/sh`").unwrap();
+        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
+        assert_eq!(config.console, "console");
+        assert_eq!(config.chdir, None);
+    }
+
+    #[test]
+    fn test_parse_chdir() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.chdir=/mnt/workdir").unwrap();
+        assert_eq!(config.chdir, Some("/mnt/workdir".to_string()));
+    }
+
+    #[test]
+    fn test_parse_chdir_empty() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.chdir=");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("init.chdir must not be empty"));
+    }
+
+    #[test]
+    fn test_parse_shell_with_args() {
+        let config = parse_cmdline("init.console=console init.shell=`sh -i`").unwrap();
+        assert_eq!(config.shell, ("sh".to_string(), vec!["-i".to_string()]));
+        assert_eq!(config.console, "console");
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.script=`/bin/echo hello`")
+                .unwrap();
+        assert_eq!(config.script, Some("/bin/echo hello".to_string()));
+        assert_eq!(config.console, "console");
+    }
+
+    #[test]
+    fn test_parse_full_cmdline() {
+        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.shell=`/bin/sh` quiet";
+        let config = parse_cmdline(cmdline).unwrap();
+
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
+        assert!(config.virtiofs_mounts[0].with_overlay);
+
+        assert_eq!(config.symlinks.len(), 1);
+        assert_eq!(config.symlinks[0].source, "/bin/sh");
+        assert_eq!(config.symlinks[0].target, "/bin/bash");
+
+        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
+        assert_eq!(config.console, "ttyS0");
+    }
+
+    /// The InitError parsing failed with, if it failed with one
+    fn init_error(result: Result<Config>) -> Option<InitError> {
+        result.err()?.downcast_ref::<InitError>().cloned()
+    }
+
+    #[test]
+    fn test_parse_invalid_virtiofs() {
+        let result = parse_cmdline("init.virtiofs=invalid");
+        assert_eq!(
+            init_error(result),
+            Some(InitError::invalid_spec("virtiofs mount", "invalid"))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_symlink() {
+        let result = parse_cmdline("init.symlinks=invalid");
+        assert_eq!(
+            init_error(result),
+            Some(InitError::invalid_spec("symlink", "invalid"))
+        );
+
+        // The error names the bad spec, not the whole list
+        let result = parse_cmdline("init.symlinks=/ok:/fine,invalid");
+        assert_eq!(
+            init_error(result),
+            Some(InitError::invalid_spec("symlink", "invalid"))
+        );
+    }
+
+    #[test]
+    fn test_parse_command_with_backticks() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/echo hello world`",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_with_backticks_and_args() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/usr/bin/ls -la /tmp`",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some("/usr/bin/ls -la /tmp".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_without_backticks() {
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.script=/bin/sh");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("must be wrapped in backticks"));
+    }
+
+    #[test]
+    fn test_parse_command_with_backticks_in_full_cmdline() {
+        let cmdline =
+            "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.script=`/bin/echo hello world` quiet";
+        let config = parse_cmdline(cmdline).unwrap();
+        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
+        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_with_multiple_spaces() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/echo   multiple   spaces`",
+        )
+        .unwrap();
+        assert_eq!(
+            config.script,
+            Some("/bin/echo   multiple   spaces".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_backticked_command_with_special_chars() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/sh -c \"echo test\"`",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some("/bin/sh -c \"echo test\"".to_string()));
+    }
+
+    #[test]
+    fn test_parse_empty_backticked_command() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.script=``").unwrap();
+        assert_eq!(config.script, Some("".to_string()));
+    }
+
+    #[test]
+    fn test_parse_cmdline_with_all_features_and_backticks() {
+        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.env.HOME=/root init.shell=`sh` init.script=`/bin/echo test 1 2 3` init.moddir=/lib/modules quiet";
+        let config = parse_cmdline(cmdline).unwrap();
+
+        assert_eq!(config.virtiofs_mounts.len(), 1);
+        assert_eq!(config.virtiofs_mounts[0].tag, "share");
+        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
+        assert!(config.virtiofs_mounts[0].with_overlay);
+
+        assert_eq!(config.symlinks.len(), 1);
+        assert_eq!(config.symlinks[0].source, "/bin/sh");
+        assert_eq!(config.symlinks[0].target, "/bin/bash");
+
+        assert_eq!(config.env_vars.len(), 2);
+        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+        assert_eq!(config.env_vars.get("HOME"), Some(&"/root".to_string()));
+
+        assert_eq!(config.shell, ("sh".to_string(), vec![]));
+        assert_eq!(config.script, Some("/bin/echo test 1 2 3".to_string()));
+
+        assert_eq!(config.moddir, Some("/lib/modules".to_string()));
+        assert_eq!(config.console, "ttyS0");
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_basic() {
+        let params = tokenize_cmdline("foo bar baz").unwrap();
+        assert_eq!(params, vec!["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_with_backticks() {
+        let params = tokenize_cmdline("foo init.script=`hello world` bar").unwrap();
+        assert_eq!(params, vec!["foo", "init.script=`hello world`", "bar"]);
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_multiple_backticks() {
+        let params =
+            tokenize_cmdline("init.script=`echo test` init.env.X=`value with spaces`").unwrap();
+        assert_eq!(
+            params,
+            vec!["init.script=`echo test`", "init.env.X=`value with spaces`"]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_tabs_and_newlines() {
+        let params = tokenize_cmdline("foo\tbar\nbaz").unwrap();
+        assert_eq!(params, vec!["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_double_quotes() {
+        let params = tokenize_cmdline(
+            r#"console=ttyS0 init.env.X="value with spaces" "init.env.Y=a b" quiet"#,
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            vec![
+                "console=ttyS0",
+                "init.env.X=value with spaces",
+                "init.env.Y=a b",
+                "quiet"
+            ]
+        );
+
+        // An empty quoted value still yields its parameter
+        assert_eq!(
+            tokenize_cmdline(r#"init.env.X="""#).unwrap(),
+            vec!["init.env.X="]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_escaped_quotes() {
+        let params = tokenize_cmdline(r#"init.env.X="say \"hi there\"" init.env.Y=a\"b"#).unwrap();
+        assert_eq!(
+            params,
+            vec![r#"init.env.X=say "hi there""#, r#"init.env.Y=a"b"#]
+        );
+
+        // Other backslashes are left alone
+        assert_eq!(tokenize_cmdline(r"a\b").unwrap(), vec![r"a\b"]);
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_quotes_and_backticks() {
+        // Quotes inside backticks belong to the command, and the other way round
+        let params =
+            tokenize_cmdline(r#"init.script=`sh -c "echo hi"` init.env.X="`a b`""#).unwrap();
+        assert_eq!(
+            params,
+            vec![r#"init.script=`sh -c "echo hi"`"#, "init.env.X=`a b`"]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_cmdline_unterminated_quote() {
+        for cmdline in [r#"init.env.X="a b"#, r#"""#, r#"init.env.X="a \""#] {
+            let result = tokenize_cmdline(cmdline);
+            assert!(result.is_err(), "cmdline should be rejected: {}", cmdline);
+        }
+        assert!(parse_cmdline(r#"init.console=console init.shell=`sh` init.env.X="oops"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_quoted_env_value() {
+        let config = parse_cmdline(
+            r#"init.console=console init.shell=`sh` init.env.GREETING="hello world""#,
+        )
+        .unwrap();
+        assert_eq!(config.env_vars.get("GREETING").unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_parse_command_with_equals_in_backticks() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/env KEY=VALUE ls`",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some("/bin/env KEY=VALUE ls".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_with_multiple_equals_in_backticks() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`FOO=bar BAZ=qux /bin/test`",
+        )
+        .unwrap();
+        assert_eq!(config.script, Some("FOO=bar BAZ=qux /bin/test".to_string()));
+    }
+
+    #[test]
+    fn test_parse_full_cmdline_with_equals_in_command() {
+        let cmdline =
+            "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.shell=`sh` init.script=`/bin/env TEST=123 ls -la` quiet";
+        let config = parse_cmdline(cmdline).unwrap();
+        assert_eq!(config.script, Some("/bin/env TEST=123 ls -la".to_string()));
+        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+    }
+
+    #[test]
+    fn test_parse_supervise() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`/bin/ls` init.supervise=Y",
+        )
+        .unwrap();
+        assert!(config.supervise);
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.supervise=N").unwrap();
+        assert!(!config.supervise);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.supervise);
+    }
+
+    #[test]
+    fn test_parse_dryrun() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.dryrun=Y").unwrap();
+        assert!(config.dry_run);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.dryrun=N").unwrap();
+        assert!(!config.dry_run);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.dry_run);
+    }
+
+    #[test]
+    fn test_parse_loglevel() {
+        for (value, expected) in [
+            ("quiet", LogLevel::Quiet),
+            ("info", LogLevel::Info),
+            ("debug", LogLevel::Debug),
+        ] {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.loglevel={}",
+                value
+            ))
+            .unwrap();
+            assert_eq!(config.log_level, expected);
+        }
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.log_level, LogLevel::Info);
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.loglevel=verbose");
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid init.loglevel"));
+    }
+
+    #[test]
+    fn test_parse_kmsg() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.kmsg=Y").unwrap();
+        assert!(config.kmsg);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.kmsg);
+    }
+
+    #[test]
+    fn test_parse_printk() {
+        for level in 0..=7 {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.printk={}",
+                level
+            ))
+            .unwrap();
+            assert_eq!(config.printk, Some(level));
+        }
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.printk, None);
+
+        for value in ["8", "255", "256", "-1", "", "debug", "7,4"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.printk={}",
+                value
+            ));
+            assert!(result.is_err(), "init.printk should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_verbose() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.verbose=Y").unwrap();
+        assert!(config.verbose);
+
+        for params in ["", "init.verbose=N", "init.verbose=1"] {
+            let config =
+                parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params)).unwrap();
+            assert!(!config.verbose, "{}", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_timings() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.timings=Y").unwrap();
+        assert!(config.timings);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.timings);
+    }
+
+    #[test]
+    fn test_parse_user() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.user=1000").unwrap();
+        assert_eq!(
+            config.run_as,
+            Some(RunAs {
+                user: UserSpec::Id(1000),
+                gid: None,
+            })
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.user=1000:1000").unwrap();
+        assert_eq!(
+            config.run_as,
+            Some(RunAs {
+                user: UserSpec::Id(1000),
+                gid: Some(1000),
+            })
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.user=dev:100").unwrap();
+        assert_eq!(
+            config.run_as,
+            Some(RunAs {
+                user: UserSpec::Name("dev".to_string()),
+                gid: Some(100),
+            })
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.run_as, None);
+    }
+
+    #[test]
+    fn test_parse_user_invalid() {
+        for value in [":500", "", "1000:", "1000:staff", "99999999999"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.user={}",
+                value
+            ));
+            assert!(result.is_err(), "init.user should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.groups=10,100").unwrap();
+        assert_eq!(config.groups, Some(vec![10, 100]));
+
+        // An empty list is kept so the inherited groups get cleared
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.groups=").unwrap();
+        assert_eq!(config.groups, Some(vec![]));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.groups, None);
+
+        for value in ["wheel", "10,,20", "10,", "-1"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.groups={}",
+                value
+            ));
+            assert!(result.is_err(), "init.groups should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_rlimit_numeric() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.rlimit.nofile=1024:4096")
+                .unwrap();
+        assert_eq!(
+            config.rlimits,
+            vec![ResourceLimit {
+                resource: Resource::Nofile,
+                soft: Some(1024),
+                hard: Some(4096),
+            }]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.rlimits.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rlimit_size() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.rlimit.as=2g init.rlimit.stack=8m:64m",
+        )
+        .unwrap();
+        assert_eq!(
+            config.rlimits,
+            vec![
+                ResourceLimit {
+                    resource: Resource::As,
+                    soft: Some(2 << 30),
+                    hard: Some(2 << 30),
+                },
+                ResourceLimit {
+                    resource: Resource::Stack,
+                    soft: Some(8 << 20),
+                    hard: Some(64 << 20),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rlimit_unlimited() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.rlimit.core=0:unlimited \
+             init.rlimit.nproc=unlimited init.rlimit.nproc=unlimited:unlimited",
+        )
+        .unwrap();
+        assert_eq!(
+            config.rlimits,
+            vec![
+                ResourceLimit {
+                    resource: Resource::Core,
+                    soft: Some(0),
+                    hard: None,
+                },
+                ResourceLimit {
+                    resource: Resource::Nproc,
+                    soft: None,
+                    hard: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rlimit_rejects_invalid() {
+        for params in [
+            "init.rlimit.files=1024",
+            "init.rlimit.nofile",
+            "init.rlimit.nofile=",
+            "init.rlimit.nofile=1k",
+            "init.rlimit.nofile=4096:1024",
+            "init.rlimit.nofile=unlimited:1024",
+            "init.rlimit.nofile=1:2:3",
+            "init.rlimit.as=2x",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_watchdog() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.watchdog=/dev/watchdog")
+                .unwrap();
+        assert_eq!(
+            config.watchdog,
+            Some(WatchdogConfig {
+                device: "/dev/watchdog".to_string(),
+                interval: None,
+            })
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.watchdog=/dev/watchdog0:15")
+                .unwrap();
+        assert_eq!(
+            config.watchdog,
+            Some(WatchdogConfig {
+                device: "/dev/watchdog0".to_string(),
+                interval: Some(15),
+            })
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.watchdog, None);
+    }
+
+    #[test]
+    fn test_parse_watchdog_invalid() {
+        for value in [
+            "",
+            ":10",
+            "/dev/watchdog:0",
+            "/dev/watchdog:",
+            "/dev/watchdog:soon",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.watchdog={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.watchdog should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_boot_timeout() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.boot_timeout=120").unwrap();
+        assert_eq!(config.boot_timeout, Some(120));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.boot_timeout, None);
+
+        for value in ["0", "-5", "soon", ""] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.boot_timeout={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.boot_timeout should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rescue() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.rescue=Y").unwrap();
+        assert!(config.rescue);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.rescue);
+    }
+
+    #[test]
+    fn test_parse_cgroup2() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.cgroup2=Y").unwrap();
+        assert!(config.cgroup2);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.cgroup2);
+        assert_eq!(config.cgroup_path, None);
+    }
+
+    #[test]
+    fn test_parse_cgroup_path() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.cgroup_path=kdf/job").unwrap();
+        assert_eq!(config.cgroup_path, Some("kdf/job".to_string()));
+
+        for value in ["", "../escape", "/kdf", "kdf/%2E%2E"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.cgroup_path={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.cgroup_path should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_unshare() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.unshare.is_empty());
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.unshare=mount,pid").unwrap();
+        assert_eq!(config.unshare, UnshareFlags::NEWNS | UnshareFlags::NEWPID);
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.unshare=mount,disk");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_root() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.root=rootfs").unwrap();
+        assert_eq!(config.root, Some("rootfs".to_string()));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.root, None);
+
+        assert!(parse_cmdline("init.console=console init.shell=`sh` init.root=").is_err());
+    }
+
+    #[test]
+    fn test_parse_print_config() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.print_config=Y").unwrap();
+        assert!(config.print_config);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.print_config);
+    }
+
+    #[cfg(feature = "print-config")]
+    #[test]
+    fn test_config_json() {
+        let config = parse_cmdline(
+            "init.console=ttyS0 init.shell=`sh -i` init.virtiofs=share:/mnt:Y:nosuid \
+             init.symlinks=/a:/b init.env.ZED=last init.env.ALPHA=first init.user=1000:100 \
+             init.loglevel=quiet",
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::to_value(&config).unwrap();
+
+        assert_eq!(json["console"], "ttyS0");
+        assert_eq!(json["shell"], serde_json::json!(["sh", ["-i"]]));
+        assert_eq!(
+            json["virtiofs_mounts"],
+            serde_json::json!([{
+                "tag": "share",
+                "path": "/mnt",
+                "with_overlay": true,
+                "options": [],
+                "flag_options": ["nosuid"],
+                "extra_lowers": [],
+                "upper_tag": null,
+                "source": null,
+                "index": false,
+                "metacopy": false,
+                "redirect_dir": null,
+                "xino": null,
+                "mode": null,
+                "order": 0,
+            }])
+        );
+        assert_eq!(
+            json["symlinks"],
+            serde_json::json!([{"source": "/a", "target": "/b"}])
+        );
+        assert_eq!(
+            json["run_as"],
+            serde_json::json!({"user": 1000, "gid": 100})
+        );
+        assert_eq!(json["log_level"], "quiet");
+        assert_eq!(json["script"], serde_json::Value::Null);
+
+        // Keys come out sorted no matter the HashMap order
+        let text = serde_json::to_string(&config).unwrap();
+        assert!(text.contains(r#""env_vars":{"ALPHA":"first","ZED":"last"}"#));
+    }
+
+    #[test]
+    fn test_parse_exec_timeout() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.script=`run-tests` init.supervise=Y init.exec_timeout=300",
+        )
+        .unwrap();
+        assert_eq!(config.exec_timeout, Some(300));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.exec_timeout, None);
+
+        for value in ["0", "-5", "soon", ""] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.exec_timeout={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.exec_timeout should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_ready_marker() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.ready_marker=KDF%20READY")
+                .unwrap();
+        assert_eq!(
+            config.ready_marker,
+            Some(ReadyMarker::Console("KDF READY".to_string()))
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.ready_marker=file:/mnt/ready")
+                .unwrap();
+        assert_eq!(
+            config.ready_marker,
+            Some(ReadyMarker::File("/mnt/ready".to_string()))
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.ready_marker, None);
+
+        for value in ["", "file:"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.ready_marker={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.ready_marker should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_result_file() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.result_file=/mnt/share/result",
+        )
+        .unwrap();
+        assert_eq!(config.result_file, Some("/mnt/share/result".to_string()));
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.result_file=");
         assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_reboot_on() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.reboot_on, Signal::Usr1);
+
+        for value in ["SIGHUP", "HUP", "1"] {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.reboot_on={}",
+                value
+            ))
+            .unwrap();
+            assert_eq!(config.reboot_on, Signal::Hup, "{}", value);
+        }
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.reboot_on=SIGFOO");
         assert!(result
             .unwrap_err()
             .to_string()
-            .contains("init.shell is required"));
+            .contains("Invalid init.reboot_on"));
+    }
+
+    #[test]
+    fn test_parse_shutdown_timeout() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.shutdown_timeout=30").unwrap();
+        assert_eq!(config.shutdown_timeout, 30);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.shutdown_timeout, DEFAULT_SHUTDOWN_TIMEOUT);
+    }
+
+    #[test]
+    fn test_parse_invalid_shutdown_timeout() {
+        for value in ["ten", "-1", "", "5s"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.shutdown_timeout={}",
+                value
+            ));
+            assert!(result.is_err());
+            assert!(result
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid init.shutdown_timeout"));
+        }
+    }
+
+    #[test]
+    fn test_parse_sysctls() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.sysctl=net.ipv4.ip_forward=1,vm.swappiness=10 init.sysctl=kernel.domainname=a%2Cb",
+        )
+        .unwrap();
+        assert_eq!(config.sysctls.len(), 3);
+        assert_eq!(config.sysctls["net.ipv4.ip_forward"], "1");
+        assert_eq!(config.sysctls["vm.swappiness"], "10");
+        assert_eq!(config.sysctls["kernel.domainname"], "a,b");
+
+        for value in ["net.ipv4.ip_forward", "../../etc/passwd=x", "/etc/shadow=x"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.sysctl={}",
+                value
+            ));
+            assert!(result.is_err(), "init.sysctl should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_settime_epoch() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.settime=1700000000").unwrap();
+        assert_eq!(config.settime, Some(SetTime::Epoch(1700000000)));
+
+        for value in ["0", "-1700000000", "123", "99999999999", "now", "", "1.5e9"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.settime={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.settime should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_settime_rtc() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.settime=rtc").unwrap();
+        assert_eq!(config.settime, Some(SetTime::Rtc));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.settime, None);
+    }
+
+    #[test]
+    fn test_parse_seed() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.seed=00ff7a").unwrap();
+        assert_eq!(config.seed, Some(Seed::Bytes(vec![0x00, 0xff, 0x7a])));
+
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.seed=00 init.seedfile=share:vm/seed",
+        )
+        .unwrap();
+        assert_eq!(
+            config.seed,
+            Some(Seed::File(ShareFile {
+                tag: "share".to_string(),
+                path: "vm/seed".to_string(),
+            }))
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.seed, None);
+    }
+
+    #[test]
+    fn test_parse_seed_invalid() {
+        for param in [
+            "init.seed=abc",
+            "init.seed=xyz0",
+            "init.seed=",
+            "init.seedfile=share",
+            "init.seedfile=share:/seed",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", param));
+            assert!(result.is_err(), "{} should be rejected", param);
+        }
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0700").unwrap(), 0o700);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("1777").unwrap(), 0o1777);
+
+        for value in ["", "0o700", "0x1ff", "800", "rwx", "17777", "+755", "-1"] {
+            assert!(parse_mode(value).is_err(), "{:?} should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_virtiofs_mode() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=secrets:/run/secrets:N:mode=0700,ro,cache:/mnt/cache",
+        )
+        .unwrap();
+        assert_eq!(config.virtiofs_mounts[0].mode, Some(0o700));
+        assert!(config.virtiofs_mounts[0].options.is_empty());
+        assert_eq!(config.virtiofs_mounts[1].mode, None);
+
+        let result = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=secrets:/run/secrets:N:mode=rwx",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_mknod_char_device_with_mode() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.mknod=/dev/fuse:c:10:229:0666 init.mknod=/dev/vdb:b:254:16",
+        )
+        .unwrap();
+        assert_eq!(
+            config.device_nodes,
+            vec![
+                DeviceNode {
+                    path: "/dev/fuse".to_string(),
+                    kind: DeviceType::Char,
+                    major: 10,
+                    minor: 229,
+                    mode: 0o666,
+                },
+                DeviceNode {
+                    path: "/dev/vdb".to_string(),
+                    kind: DeviceType::Block,
+                    major: 254,
+                    minor: 16,
+                    mode: 0o600,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mknod_rejects_invalid() {
+        for value in [
+            "/dev/fuse:x:10:229",
+            "/dev/fuse:p:10:229",
+            "/dev/fuse:c:ten:229",
+            "/dev/fuse:c:10:-1",
+            "/dev/fuse:c:10:229:0999",
+            "/dev/fuse:c:10:229:17777",
+            "/dev/fuse:c:10",
+            ":c:10:229",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.mknod={}",
+                value
+            ));
+            assert!(result.is_err(), "init.mknod should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_data_overrides() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.proc_opts=hidepid=2,gid=10 \
+             init.run_opts=mode=0700,size=16m init.tmpfs_default_opts=noswap",
+        )
+        .unwrap();
+        assert_eq!(config.proc_opts.as_deref(), Some("hidepid=2,gid=10"));
+        assert_eq!(config.run_opts.as_deref(), Some("mode=0700,size=16m"));
+        assert_eq!(config.tmpfs_default_opts.as_deref(), Some("noswap"));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.proc_opts, None);
+        assert_eq!(config.run_opts, None);
+        assert_eq!(config.tmpfs_default_opts, None);
+    }
+
+    #[test]
+    fn test_parse_mount_data_rejects_whitespace() {
+        for param in [
+            "init.proc_opts=hidepid=2%20gid=10",
+            "init.run_opts=mode=0700%09",
+            "init.tmpfs_default_opts=%0Anoswap",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", param));
+            assert!(result.is_err(), "{} should be rejected", param);
+        }
+    }
+
+    #[test]
+    fn test_parse_tmpfs_mounts() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.tmpfs=/tmp,/var/tmp:64m init.tmpfs=/scratch:1g:0700",
+        )
+        .unwrap();
+        assert_eq!(
+            config.tmpfs_mounts,
+            vec![
+                TmpfsMount {
+                    path: "/tmp".to_string(),
+                    size: None,
+                    mode: None,
+                },
+                TmpfsMount {
+                    path: "/var/tmp".to_string(),
+                    size: Some(64 * 1024 * 1024),
+                    mode: None,
+                },
+                TmpfsMount {
+                    path: "/scratch".to_string(),
+                    size: Some(1024 * 1024 * 1024),
+                    mode: Some(0o700),
+                },
+            ]
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.tmpfs=/scratch::1777")
+                .unwrap();
+        assert_eq!(config.tmpfs_mounts[0].size, None);
+        assert_eq!(config.tmpfs_mounts[0].mode, Some(0o1777));
+    }
+
+    #[test]
+    fn test_parse_tmpfs_rejects_invalid() {
+        for value in ["/tmp:64x", "/tmp:64m:rwx", "/tmp:64m:0700:extra", ":64m"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.tmpfs={}",
+                value
+            ));
+            assert!(result.is_err(), "init.tmpfs should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_move_mounts() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.move=/run/a:/mnt/a,/run/b:/mnt/my%20b init.move=/run/c:/c",
+        )
+        .unwrap();
+        assert_eq!(
+            config.move_mounts,
+            vec![
+                MoveMount {
+                    source: "/run/a".to_string(),
+                    target: "/mnt/a".to_string(),
+                },
+                MoveMount {
+                    source: "/run/b".to_string(),
+                    target: "/mnt/my b".to_string(),
+                },
+                MoveMount {
+                    source: "/run/c".to_string(),
+                    target: "/c".to_string(),
+                },
+            ]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.move_mounts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_move_rejects_invalid() {
+        for value in [
+            "/run/a",
+            "/run/a:",
+            ":/mnt",
+            "run/a:/mnt",
+            "/run/a:mnt",
+            "/run/a:/run/a/",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.move={}",
+                value
+            ));
+            assert!(result.is_err(), "init.move should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_bind_mounts() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.bind=/mnt/share/a:/a,/mnt/share/b:/b:ro init.bind=/etc/my%3Aconf:/conf",
+        )
+        .unwrap();
+        assert_eq!(
+            config.bind_mounts,
+            vec![
+                BindMount {
+                    source: "/mnt/share/a".to_string(),
+                    target: "/a".to_string(),
+                    read_only: false,
+                },
+                BindMount {
+                    source: "/mnt/share/b".to_string(),
+                    target: "/b".to_string(),
+                    read_only: true,
+                },
+                BindMount {
+                    source: "/etc/my:conf".to_string(),
+                    target: "/conf".to_string(),
+                    read_only: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bind_rejects_invalid() {
+        for value in [
+            "/a",
+            "/a:",
+            ":/b",
+            "a:/b",
+            "/a:b",
+            "/a:/b:rw",
+            "/a:/b:ro:extra",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.bind={}",
+                value
+            ));
+            assert!(result.is_err(), "init.bind should be rejected: {}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.ip=eth0:10.0.2.15/24:10.0.2.2",
+        )
+        .unwrap();
+        assert_eq!(
+            config.ip,
+            Some(IpConfig {
+                iface: "eth0".to_string(),
+                address: Ipv4Addr::new(10, 0, 2, 15),
+                prefix_len: 24,
+                gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+            })
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.ip=enp0s1:192.168.1.5/16")
+                .unwrap();
+        let ip = config.ip.unwrap();
+        assert_eq!(ip.iface, "enp0s1");
+        assert_eq!(ip.prefix_len, 16);
+        assert_eq!(ip.gateway, None);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.ip, None);
+    }
+
+    #[test]
+    fn test_parse_sync_rtc() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.sync_rtc);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.sync_rtc=Y").unwrap();
+        assert!(config.sync_rtc);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.sync_rtc=N").unwrap();
+        assert!(!config.sync_rtc);
+    }
+
+    #[test]
+    fn test_parse_devpts() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.devpts);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.devpts=Y").unwrap();
+        assert!(config.devpts);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.devpts=N").unwrap();
+        assert!(!config.devpts);
+    }
+
+    #[test]
+    fn test_parse_devshm() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.devshm, None);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.devshm=Y").unwrap();
+        assert_eq!(
+            config.devshm,
+            Some(TmpfsMount {
+                path: "/dev/shm".to_string(),
+                size: None,
+                mode: Some(0o1777),
+            })
+        );
+
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.devshm=Y:64m").unwrap();
+        let devshm = config.devshm.unwrap();
+        assert_eq!(devshm.size, Some(64 * 1024 * 1024));
+        assert_eq!(devshm.mode, Some(0o1777));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.devshm=N").unwrap();
+        assert_eq!(config.devshm, None);
+    }
+
+    #[test]
+    fn test_parse_devshm_rejects_invalid() {
+        for params in [
+            "init.devshm=Y:",
+            "init.devshm=Y:0",
+            "init.devshm=Y:lots",
+            "init.devshm=Y:64m:1777",
+            "init.devshm=N:64m",
+        ] {
+            let result = parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params));
+            assert!(result.is_err(), "{} should be rejected", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_loopback() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.loopback);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.loopback=Y").unwrap();
+        assert!(config.loopback);
+    }
+
+    #[test]
+    fn test_parse_nameservers() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.nameserver=10.0.2.3,2001:4860:4860::8888",
+        )
+        .unwrap();
+        assert_eq!(
+            config.nameservers,
+            vec![
+                "10.0.2.3".parse::<IpAddr>().unwrap(),
+                "2001:4860:4860::8888".parse::<IpAddr>().unwrap(),
+            ]
+        );
+
+        for value in [
+            "",
+            "10.0.2",
+            "10.0.2.3,",
+            "10.0.2.256",
+            "dns.example.com",
+            "2001:db8:::1",
+            "0.0.0.0",
+            "::",
+            " 10.0.2.3",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.nameserver={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.nameserver should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_prefix_bounds() {
+        for prefix in [0, 1, 31, 32] {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.ip=eth0:10.0.2.15/{}",
+                prefix
+            ))
+            .unwrap();
+            assert_eq!(config.ip.unwrap().prefix_len, prefix);
+        }
+
+        for prefix in ["33", "-1", "255", "256", "", "24a"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.ip=eth0:10.0.2.15/{}",
+                prefix
+            ));
+            assert!(result.is_err(), "prefix should be rejected: {}", prefix);
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_rejects_invalid() {
+        for value in [
+            "eth0",
+            "eth0:10.0.2.15",
+            ":10.0.2.15/24",
+            "averyveryverylongname:10.0.2.15/24",
+            "eth0:10.0.2/24",
+            "eth0:10.0.2.256/24",
+            "eth0:0.0.0.0/24",
+            "eth0:224.0.0.1/24",
+            "eth0:10.0.2.15/24:10.0.3.1",
+            "eth0:10.0.2.15/24:10.0.2.15",
+            "eth0:10.0.2.15/24:gateway",
+            "eth0:10.0.2.15/24:10.0.2.2:extra",
+            "eth0:fe80::1/64",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.ip={}",
+                value
+            ));
+            assert!(result.is_err(), "init.ip should be rejected: {}", value);
+        }
+
+        // /0 puts every gateway on-link
+        assert!(parse_cmdline(
+            "init.console=console init.shell=`sh` init.ip=eth0:10.0.2.15/0:192.168.0.1"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_dhcp() {
+        let config = parse_cmdline("init.console=console init.shell=`sh` init.dhcp=eth0").unwrap();
+        assert_eq!(config.dhcp.as_deref(), Some("eth0"));
+        assert_eq!(config.ip, None);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.dhcp, None);
+
+        for value in ["", "averyveryverylongname", "eth/0"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.dhcp={}",
+                value
+            ));
+            assert!(result.is_err(), "init.dhcp should be rejected: {}", value);
+        }
+
+        let err = parse_cmdline(
+            "init.console=console init.shell=`sh` init.dhcp=eth0 init.ip=eth0:10.0.2.15/24",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"));
+    }
+
+    #[test]
+    fn test_parse_extra_mounts() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.mount=cgroup2:/sys/fs/cgroup init.mount=tmpfs:/tmp:size=64m,mode=1777",
+        )
+        .unwrap();
+        assert_eq!(
+            config.extra_mounts,
+            vec![
+                ExtraMount {
+                    fstype: "cgroup2".to_string(),
+                    target: "/sys/fs/cgroup".to_string(),
+                    data: "".to_string(),
+                },
+                ExtraMount {
+                    fstype: "tmpfs".to_string(),
+                    target: "/tmp".to_string(),
+                    data: "size=64m,mode=1777".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_extra_mount() {
+        for value in ["debugfs", "debugfs:", ":/sys/kernel/debug"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.mount={}",
+                value
+            ));
+            assert_eq!(
+                init_error(result),
+                Some(InitError::invalid_spec("mount", value)),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_securityfs() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.securityfs=Y").unwrap();
+        assert!(config.securityfs);
+
+        for params in ["", "init.securityfs=N"] {
+            let config =
+                parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params)).unwrap();
+            assert!(!config.securityfs, "{}", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_selinuxfs() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.selinuxfs=/sys/fs/selinux")
+                .unwrap();
+        assert_eq!(config.selinuxfs.as_deref(), Some("/sys/fs/selinux"));
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.selinuxfs, None);
+
+        for value in ["", "selinux", "Y"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.selinuxfs={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.selinuxfs should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_private() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.mount_private=Y").unwrap();
+        assert!(config.mount_private);
+
+        for params in ["", "init.mount_private=N", "init.mount_private=yes"] {
+            let config =
+                parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params)).unwrap();
+            assert!(!config.mount_private, "{}", params);
+        }
+    }
+
+    #[test]
+    fn test_parse_nomount() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.nomount=/dev,/run").unwrap();
+        assert_eq!(config.nomount, vec!["/dev".to_string(), "/run".to_string()]);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.nomount.is_empty());
+        assert!(config.extra_mounts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_squashfs() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.squashfs=/mnt/img/tools.sqfs:/opt/tools init.squashfs=/mnt/my%20img.sqfs:/opt/b",
+        )
+        .unwrap();
+        assert_eq!(
+            config.squashfs_mounts,
+            vec![
+                SquashfsMount {
+                    image: "/mnt/img/tools.sqfs".to_string(),
+                    path: "/opt/tools".to_string(),
+                },
+                SquashfsMount {
+                    image: "/mnt/my img.sqfs".to_string(),
+                    path: "/opt/b".to_string(),
+                },
+            ]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.squashfs_mounts.is_empty());
     }
 
     #[test]
-    fn test_parse_virtiofs_basic() {
+    fn test_parse_fstab() {
         let config =
-            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share")
+            parse_cmdline("init.console=console init.shell=`sh` init.fstab=share:vm/fstab")
                 .unwrap();
-        assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
-        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/share");
-        assert!(!config.virtiofs_mounts[0].with_overlay);
+        assert_eq!(
+            config.fstab,
+            Some(ShareFile {
+                tag: "share".to_string(),
+                path: "vm/fstab".to_string(),
+            })
+        );
+
+        for value in ["", "share", "share:/fstab", ":fstab", "share:../fstab"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.fstab={}",
+                value
+            ));
+            assert!(result.is_err(), "init.fstab should be rejected: {}", value);
+        }
     }
 
     #[test]
-    fn test_parse_virtiofs_with_overlay() {
+    fn test_parse_merge() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.merge=/data:a,b,c init.merge=/srv:web:Y",
+        )
+        .unwrap();
+        assert_eq!(
+            config.merge_mounts,
+            vec![
+                MergeMount {
+                    path: "/data".to_string(),
+                    tags: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                    writable: false,
+                },
+                MergeMount {
+                    path: "/srv".to_string(),
+                    tags: vec!["web".to_string()],
+                    writable: true,
+                },
+            ]
+        );
+
         let config =
-            parse_cmdline("init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share:Y")
+            parse_cmdline("init.console=console init.shell=`sh` init.merge=/my%20data:x,y:N")
                 .unwrap();
-        assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert!(config.virtiofs_mounts[0].with_overlay);
+        assert_eq!(config.merge_mounts[0].path, "/my data");
+        assert!(!config.merge_mounts[0].writable);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.merge_mounts.is_empty());
     }
 
     #[test]
-    fn test_parse_virtiofs_multiple() {
+    fn test_parse_merge_rejects_invalid() {
+        for value in [
+            "",
+            "/data",
+            "data:a,b",
+            "/data:",
+            "/data:a,,b",
+            "/data:a,b:X",
+            "/data:a",
+            "/data:a,b,a",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.merge={}",
+                value
+            ));
+            assert!(result.is_err(), "init.merge should be rejected: {}", value);
+        }
+
         let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.virtiofs=share1:/mnt/a,share2:/mnt/b:Y",
+            "init.console=console init.shell=`sh` init.virtiofs=a:/data init.merge=/data:b,c",
         )
         .unwrap();
-        assert_eq!(config.virtiofs_mounts.len(), 2);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share1");
-        assert_eq!(config.virtiofs_mounts[0].path, "/mnt/a");
-        assert!(!config.virtiofs_mounts[0].with_overlay);
-        assert_eq!(config.virtiofs_mounts[1].tag, "share2");
-        assert_eq!(config.virtiofs_mounts[1].path, "/mnt/b");
-        assert!(config.virtiofs_mounts[1].with_overlay);
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("init.merge"), "{}", err);
     }
 
     #[test]
-    fn test_parse_symlinks() {
-        let config =
-            parse_cmdline("init.console=console init.shell=`sh` init.symlinks=/bin/sh:/bin/bash,/usr/bin/vi:/usr/bin/vim").unwrap();
-        assert_eq!(config.symlinks.len(), 2);
-        assert_eq!(config.symlinks[0].source, "/bin/sh");
-        assert_eq!(config.symlinks[0].target, "/bin/bash");
-        assert_eq!(config.symlinks[1].source, "/usr/bin/vi");
-        assert_eq!(config.symlinks[1].target, "/usr/bin/vim");
+    fn test_parse_squashfs_rejects_invalid() {
+        for value in [
+            "",
+            "/img.sqfs",
+            "img.sqfs:/opt",
+            "/img.sqfs:opt",
+            ":/opt",
+            "/img.sqfs:",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.squashfs={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.squashfs should be rejected: {}",
+                value
+            );
+        }
     }
 
     #[test]
-    fn test_parse_env_vars() {
+    fn test_parse_wait_for() {
         let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.env.PATH=/usr/bin init.env.HOME=/root",
+            "init.console=console init.shell=`sh` init.wait_for=/mnt/share/ready:30 init.wait_for=/mnt/a:b%20c:0",
         )
         .unwrap();
-        assert_eq!(config.env_vars.len(), 2);
-        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
-        assert_eq!(config.env_vars.get("HOME"), Some(&"/root".to_string()));
+        assert_eq!(
+            config.wait_for,
+            vec![
+                WaitFor {
+                    path: "/mnt/share/ready".to_string(),
+                    timeout: 30,
+                },
+                WaitFor {
+                    path: "/mnt/a:b c".to_string(),
+                    timeout: 0,
+                },
+            ]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.wait_for.is_empty());
     }
 
     #[test]
-    fn test_parse_shell() {
-        let config = parse_cmdline("init.console=console init.shell=`/bin/sh`").unwrap();
-        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
-        assert_eq!(config.console, "console");
-        assert_eq!(config.chdir, None);
+    fn test_parse_wait_for_rejects_invalid() {
+        for value in [
+            "",
+            "/ready",
+            "ready:30",
+            "/ready:",
+            "/ready:-1",
+            "/ready:1s",
+        ] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.wait_for={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.wait_for should be rejected: {}",
+                value
+            );
+        }
     }
 
     #[test]
-    fn test_parse_chdir() {
-        let config =
-            parse_cmdline("init.console=console init.shell=`sh` init.chdir=/mnt/workdir").unwrap();
-        assert_eq!(config.chdir, Some("/mnt/workdir".to_string()));
+    fn test_parse_umount() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.umount=/kdf-config,/mnt/my%20share, init.umount=/srv",
+        )
+        .unwrap();
+        assert_eq!(config.umounts, vec!["/kdf-config", "/mnt/my share", "/srv"]);
+
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.umount.sep=; init.umount=/a;/b",
+        )
+        .unwrap();
+        assert_eq!(config.umounts, vec!["/a", "/b"]);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.umounts.is_empty());
+
+        let result = parse_cmdline("init.console=console init.shell=`sh` init.umount=mnt");
+        assert!(result.is_err());
     }
 
     #[test]
-    fn test_parse_shell_with_args() {
-        let config = parse_cmdline("init.console=console init.shell=`sh -i`").unwrap();
-        assert_eq!(config.shell, ("sh".to_string(), vec!["-i".to_string()]));
-        assert_eq!(config.console, "console");
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("512m").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
     }
 
     #[test]
-    fn test_parse_script() {
+    fn test_parse_invalid_size() {
+        for value in [
+            "",
+            "m",
+            "0",
+            "0m",
+            "12t",
+            "1.5g",
+            "-1m",
+            "+1m",
+            "99999999999g",
+        ] {
+            assert!(parse_size(value).is_err(), "{} should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_overlay_reset() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.overlay_reset, OverlayReset::Never);
+
+        for (value, expected) in [
+            ("never", OverlayReset::Never),
+            ("on_dirty", OverlayReset::OnDirty),
+            ("always", OverlayReset::Always),
+        ] {
+            let config = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.overlay_reset={}",
+                value
+            ))
+            .unwrap();
+            assert_eq!(config.overlay_reset, expected);
+        }
+
+        for value in ["", "dirty", "on-dirty", "ALWAYS", "Y"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.overlay_reset={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.overlay_reset should be rejected: {}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_overlay_base() {
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.overlay_base, "/run/overlayfs");
+
         let config =
-            parse_cmdline("init.console=console init.shell=`sh` init.script=`/bin/echo hello`")
+            parse_cmdline("init.console=console init.shell=`sh` init.overlay_base=/scratch/ovl")
                 .unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello".to_string()));
-        assert_eq!(config.console, "console");
+        assert_eq!(config.overlay_base, "/scratch/ovl");
+
+        for value in ["", "scratch/ovl"] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.overlay_base={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.overlay_base should be rejected: {}",
+                value
+            );
+        }
     }
 
     #[test]
-    fn test_parse_full_cmdline() {
-        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.shell=`/bin/sh` quiet";
-        let config = parse_cmdline(cmdline).unwrap();
+    fn test_parse_overlay_tmpfs_size() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.overlay_tmpfs_size=512m")
+                .unwrap();
+        assert_eq!(config.overlay_tmpfs_size, Some(512 * 1024 * 1024));
 
-        assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
-        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
-        assert!(config.virtiofs_mounts[0].with_overlay);
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.overlay_tmpfs_size, None);
 
-        assert_eq!(config.symlinks.len(), 1);
-        assert_eq!(config.symlinks[0].source, "/bin/sh");
-        assert_eq!(config.symlinks[0].target, "/bin/bash");
+        let result =
+            parse_cmdline("init.console=console init.shell=`sh` init.overlay_tmpfs_size=lots");
+        assert!(result.is_err());
+    }
 
-        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
-        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
-        assert_eq!(config.console, "ttyS0");
+    #[test]
+    fn test_parse_atomic_mounts() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.atomic_mounts=Y").unwrap();
+        assert!(config.atomic_mounts);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.atomic_mounts);
     }
 
     #[test]
-    fn test_parse_invalid_virtiofs() {
-        let result = parse_cmdline("init.virtiofs=invalid");
-        assert!(result.is_err());
+    fn test_parse_skip_fs_check() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.skip_fs_check=Y").unwrap();
+        assert!(config.skip_fs_check);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.skip_fs_check);
     }
 
     #[test]
-    fn test_parse_invalid_symlink() {
-        let result = parse_cmdline("init.symlinks=invalid");
-        assert!(result.is_err());
+    fn test_parse_verify_tags() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.verify_tags=Y").unwrap();
+        assert!(config.verify_tags);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(!config.verify_tags);
     }
 
     #[test]
-    fn test_parse_command_with_backticks() {
+    fn test_virtiofs_tags() {
         let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`/bin/echo hello world`",
+            "init.console=console init.shell=`sh` init.root=rootfs init.virtiofs=share:/mnt:N,work:/work:Y:upper=persist init.merge=/opt:a,b init.fstab=conf:etc/fstab",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
+        assert_eq!(
+            config.virtiofs_tags(),
+            vec!["rootfs", "share", "persist", "work", "a", "b", "conf"]
+        );
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert!(config.virtiofs_tags().is_empty());
     }
 
     #[test]
-    fn test_parse_command_with_backticks_and_args() {
-        let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`/usr/bin/ls -la /tmp`",
-        )
-        .unwrap();
-        assert_eq!(config.script, Some("/usr/bin/ls -la /tmp".to_string()));
+    fn test_parse_mount_retries() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`sh` init.mount_retries=5").unwrap();
+        assert_eq!(config.mount_retries, 5);
+
+        let config = parse_cmdline("init.console=console init.shell=`sh`").unwrap();
+        assert_eq!(config.mount_retries, 0);
+
+        for value in ["-1", "many", ""] {
+            let result = parse_cmdline(&format!(
+                "init.console=console init.shell=`sh` init.mount_retries={}",
+                value
+            ));
+            assert!(
+                result.is_err(),
+                "init.mount_retries should be rejected: {}",
+                value
+            );
+        }
     }
 
     #[test]
-    fn test_parse_command_without_backticks() {
-        let result = parse_cmdline("init.console=console init.shell=`sh` init.script=/bin/sh");
-        assert!(result.is_err());
-        assert!(result
-            .unwrap_err()
-            .to_string()
-            .contains("must be wrapped in backticks"));
+    fn test_parse_shell_and_script_together() {
+        let config =
+            parse_cmdline("init.console=console init.shell=`/bin/sh` init.script=`/bin/ls`")
+                .unwrap();
+        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
+        assert_eq!(config.script, Some("/bin/ls".to_string()));
+    }
+
+    fn overlap_mount(tag: &str, path: &str) -> VirtiofsMount {
+        VirtiofsMount {
+            tag: tag.to_string(),
+            path: path.to_string(),
+            with_overlay: false,
+            options: vec![],
+            flag_options: vec![],
+            extra_lowers: vec![],
+            upper_tag: None,
+            source: None,
+            index: false,
+            metacopy: false,
+            redirect_dir: None,
+            xino: None,
+            mode: None,
+            order: 0,
+        }
+    }
+
+    fn overlap_symlink(source: &str) -> Symlink {
+        Symlink {
+            source: source.to_string(),
+            target: "/target".to_string(),
+        }
     }
 
     #[test]
-    fn test_parse_command_with_backticks_in_full_cmdline() {
-        let cmdline =
-            "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.script=`/bin/echo hello world` quiet";
-        let config = parse_cmdline(cmdline).unwrap();
-        assert_eq!(config.script, Some("/bin/echo hello world".to_string()));
-        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+    fn test_symlink_mount_overlaps() {
+        let mounts = vec![overlap_mount("share", "/mnt/share")];
+        let symlinks = vec![
+            overlap_symlink("/mnt/share/link"),
+            overlap_symlink("/mnt/shared/link"),
+            overlap_symlink("/usr/bin/tool"),
+        ];
+
+        let overlaps = symlink_mount_overlaps(&symlinks, &mounts);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(overlaps[0].0.source, "/mnt/share/link");
+        assert_eq!(overlaps[0].1.tag, "share");
     }
 
     #[test]
-    fn test_parse_command_with_multiple_spaces() {
+    fn test_symlink_mount_overlaps_nested() {
+        let mounts = vec![
+            overlap_mount("outer", "/mnt"),
+            overlap_mount("inner", "/mnt/share/"),
+        ];
+        let symlinks = vec![overlap_symlink("/mnt/share/link")];
+
+        let overlaps = symlink_mount_overlaps(&symlinks, &mounts);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(overlaps[0].1.tag, "inner");
+    }
+
+    #[test]
+    fn test_validate_config_rejects_symlink_on_mount_point() {
         let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`/bin/echo   multiple   spaces`",
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share init.symlinks=/mnt/share:/tmp",
         )
         .unwrap();
-        assert_eq!(
-            config.script,
-            Some("/bin/echo   multiple   spaces".to_string())
-        );
+        let result = validate_config(&config);
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("would replace virtiofs mount point"));
     }
 
     #[test]
-    fn test_parse_backticked_command_with_special_chars() {
+    fn test_validate_config_rejects_symlink_in_read_only_share() {
         let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`/bin/sh -c \"echo test\"`",
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share:N:ro init.symlinks=/mnt/share/link:/tmp",
         )
         .unwrap();
-        assert_eq!(config.script, Some("/bin/sh -c \"echo test\"".to_string()));
+        assert!(validate_config(&config).is_err());
     }
 
-    #[test]
-    fn test_parse_empty_backticked_command() {
-        let config = parse_cmdline("init.console=console init.shell=`sh` init.script=``").unwrap();
-        assert_eq!(config.script, Some("".to_string()));
+    fn validate(params: &str) -> Result<()> {
+        let config =
+            parse_cmdline(&format!("init.console=console init.shell=`sh` {}", params)).unwrap();
+        validate_config(&config)
     }
 
-    #[test]
-    fn test_parse_cmdline_with_all_features_and_backticks() {
-        let cmdline = "console=ttyS0 init.console=ttyS0 init.virtiofs=share:/mnt:Y init.symlinks=/bin/sh:/bin/bash init.env.PATH=/usr/bin init.env.HOME=/root init.shell=`sh` init.script=`/bin/echo test 1 2 3` init.moddir=/lib/modules quiet";
-        let config = parse_cmdline(cmdline).unwrap();
-
-        assert_eq!(config.virtiofs_mounts.len(), 1);
-        assert_eq!(config.virtiofs_mounts[0].tag, "share");
-        assert_eq!(config.virtiofs_mounts[0].path, "/mnt");
-        assert!(config.virtiofs_mounts[0].with_overlay);
-
-        assert_eq!(config.symlinks.len(), 1);
-        assert_eq!(config.symlinks[0].source, "/bin/sh");
-        assert_eq!(config.symlinks[0].target, "/bin/bash");
-
-        assert_eq!(config.env_vars.len(), 2);
-        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
-        assert_eq!(config.env_vars.get("HOME"), Some(&"/root".to_string()));
+    fn virtiofs_mounts(value: &str) -> Vec<VirtiofsMount> {
+        parse_virtiofs_mounts(value, ',').unwrap()
+    }
 
-        assert_eq!(config.shell, ("sh".to_string(), vec![]));
-        assert_eq!(config.script, Some("/bin/echo test 1 2 3".to_string()));
+    #[test]
+    fn test_validate_config_rejects_duplicate_virtiofs_path() {
+        let result = validate("init.virtiofs=a:/mnt/a,b:/mnt/shared,c:/mnt/shared/");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Virtiofs mounts b:/mnt/shared and c:/mnt/shared/ use the same path"
+        );
 
-        assert_eq!(config.moddir, Some("/lib/modules".to_string()));
-        assert_eq!(config.console, "ttyS0");
+        let mounts = virtiofs_mounts("a:/mnt,b:/mnt");
+        let (first, second) = duplicate_virtiofs_path(&mounts).unwrap();
+        assert_eq!((first.tag.as_str(), second.tag.as_str()), ("a", "b"));
     }
 
     #[test]
-    fn test_parse_cmdline_params_basic() {
-        let params = parse_cmdline_params("foo bar baz");
-        assert_eq!(params, vec!["foo", "bar", "baz"]);
+    fn test_validate_config_warns_on_duplicate_virtiofs_tag() {
+        // One share mounted in two places is allowed, only warned about
+        assert!(validate("init.virtiofs=share:/mnt/a,share:/mnt/b").is_ok());
+        assert_eq!(
+            duplicate_virtiofs_tags(&virtiofs_mounts("share:/a,other:/b,share:/c,share:/d")),
+            vec!["share"]
+        );
     }
 
     #[test]
-    fn test_parse_cmdline_params_with_backticks() {
-        let params = parse_cmdline_params("foo init.script=`hello world` bar");
-        assert_eq!(params, vec!["foo", "init.script=`hello world`", "bar"]);
+    fn test_validate_config_allows_distinct_virtiofs_mounts() {
+        let mounts = virtiofs_mounts("a:/mnt/a,b:/mnt/b,c:/mnt/a/c");
+        assert_eq!(duplicate_virtiofs_path(&mounts), None);
+        assert!(duplicate_virtiofs_tags(&mounts).is_empty());
+        assert!(validate("init.virtiofs=a:/mnt/a,b:/mnt/b,c:/mnt/a/c").is_ok());
     }
 
     #[test]
-    fn test_parse_cmdline_params_multiple_backticks() {
-        let params = parse_cmdline_params("init.script=`echo test` init.env.X=`value with spaces`");
+    fn test_validate_config_rejects_overlapping_mount_paths() {
+        for params in [
+            "init.virtiofs=a:/mnt,b:/mnt",
+            "init.virtiofs=a:/mnt init.p9=b:/mnt/",
+            "init.virtiofs=a:/mnt init.tmpfs=/mnt",
+            "init.p9=a:/srv init.mount=tmpfs:/srv",
+            "init.tmpfs=/scratch init.bind=/data:/scratch",
+            "init.virtiofs=a:/mnt init.move=/run/x:/mnt",
+        ] {
+            assert!(validate(params).is_err(), "{} should be rejected", params);
+        }
+
+        let result = validate("init.virtiofs=a:/mnt init.tmpfs=/mnt");
         assert_eq!(
-            params,
-            vec!["init.script=`echo test`", "init.env.X=`value with spaces`"]
+            result.unwrap_err().to_string(),
+            "Mount path /mnt is used by both init.virtiofs and init.tmpfs"
         );
     }
 
     #[test]
-    fn test_parse_cmdline_params_tabs_and_newlines() {
-        let params = parse_cmdline_params("foo\tbar\nbaz");
-        assert_eq!(params, vec!["foo", "bar", "baz"]);
+    fn test_validate_config_allows_nested_mount_paths() {
+        assert!(
+            validate("init.virtiofs=a:/mnt init.tmpfs=/mnt/cache init.bind=/data:/srv").is_ok()
+        );
     }
 
     #[test]
-    fn test_parse_command_with_equals_in_backticks() {
-        let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`/bin/env KEY=VALUE ls`",
-        )
-        .unwrap();
-        assert_eq!(config.script, Some("/bin/env KEY=VALUE ls".to_string()));
+    fn test_validate_config_rejects_symlink_cycles() {
+        for params in [
+            "init.symlinks=/a:/a",
+            "init.symlinks=/a:/b,/b:/a",
+            "init.symlinks=/x:/a,/a:/b,/b:/c,/c:/a",
+            // Relative targets resolve against the link's directory
+            "init.symlinks=/etc/a:b,/etc/b:../etc/a",
+        ] {
+            assert!(validate(params).is_err(), "{} should be rejected", params);
+        }
+
+        let result = validate("init.symlinks=/a:/b,/b:/a");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Symlinks form a cycle: /a -> /b -> /a"
+        );
     }
 
     #[test]
-    fn test_parse_command_with_multiple_equals_in_backticks() {
-        let config = parse_cmdline(
-            "init.console=console init.shell=`sh` init.script=`FOO=bar BAZ=qux /bin/test`",
-        )
-        .unwrap();
-        assert_eq!(config.script, Some("FOO=bar BAZ=qux /bin/test".to_string()));
+    fn test_validate_config_allows_symlink_chains() {
+        assert!(validate("init.symlinks=/a:/b,/b:/c,/d:/c").is_ok());
+        assert!(validate("init.symlinks=/etc/a:b,/etc/b:/etc/c").is_ok());
     }
 
     #[test]
-    fn test_parse_full_cmdline_with_equals_in_command() {
-        let cmdline =
-            "console=ttyS0 init.console=ttyS0 init.shell=`sh` init.env.PATH=/usr/bin init.shell=`sh` init.script=`/bin/env TEST=123 ls -la` quiet";
-        let config = parse_cmdline(cmdline).unwrap();
-        assert_eq!(config.script, Some("/bin/env TEST=123 ls -la".to_string()));
-        assert_eq!(config.env_vars.get("PATH"), Some(&"/usr/bin".to_string()));
+    fn test_check_requested() {
+        assert!(check_requested("console=ttyS0 init.check=Y").unwrap());
+        assert!(!check_requested("console=ttyS0 init.check=N").unwrap());
+        assert!(!check_requested("console=ttyS0").unwrap());
+        // Found even if the rest of the cmdline would not parse
+        assert!(check_requested("init.check=Y init.mount_retries=x").unwrap());
     }
 
     #[test]
-    fn test_parse_shell_and_script_together() {
-        let config =
-            parse_cmdline("init.console=console init.shell=`/bin/sh` init.script=`/bin/ls`")
-                .unwrap();
-        assert_eq!(config.shell, ("/bin/sh".to_string(), vec![]));
-        assert_eq!(config.script, Some("/bin/ls".to_string()));
+    fn test_validate_config_allows_symlink_inside_share() {
+        let config = parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt/share init.symlinks=/mnt/share/link:/tmp",
+        )
+        .unwrap();
+        assert!(validate_config(&config).is_ok());
     }
 }
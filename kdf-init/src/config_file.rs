@@ -0,0 +1,36 @@
+//! Second-stage config file on a virtiofs share (init.config)
+
+use anyhow::{Context, Result};
+
+use crate::cmdline::ShareFile;
+use crate::virtiofs;
+
+/// Mount point for the config share inside the initramfs
+const CONFIG_MOUNT: &str = "/kdf-config";
+
+/// Mount the config share read-only and read the config file from it
+///
+/// Runs before the kernel modules are loaded, so virtiofs has to be built
+/// into the kernel. The file is needed to know what a dry-run would do, so
+/// it is read in dry-run as well.
+pub fn read_config_file(source: &ShareFile) -> Result<String> {
+    let contents = virtiofs::read_share_file(source, CONFIG_MOUNT, "init.config")?;
+    let contents = String::from_utf8(contents)
+        .with_context(|| format!("Config file {} is not UTF-8", source.path));
+    if contents.is_err() {
+        release_config_share(false);
+    }
+    contents
+}
+
+/// Unmount the config share once the config is parsed
+///
+/// A share that is also listed in init.virtiofs is left mounted, it gets
+/// mounted at its own path later on anyway.
+pub fn release_config_share(requested: bool) {
+    if requested {
+        debug!("keeping config share mounted at {}", CONFIG_MOUNT);
+        return;
+    }
+    virtiofs::unmount_share(CONFIG_MOUNT);
+}
@@ -0,0 +1,73 @@
+//! Device node creation for initramfs images without udev
+
+use anyhow::{Context, Result};
+use rustix::fs::{FileType, Mode, CWD};
+use std::path::Path;
+
+use crate::cmdline::{DeviceNode, DeviceType};
+use crate::virtiofs;
+
+fn file_type(kind: DeviceType) -> FileType {
+    match kind {
+        DeviceType::Char => FileType::CharacterDevice,
+        DeviceType::Block => FileType::BlockDevice,
+    }
+}
+
+/// Create the init.mknod device nodes, after devtmpfs is mounted
+///
+/// A node that already exists (e.g. created by devtmpfs) is left alone.
+pub fn create_device_nodes(nodes: &[DeviceNode], dry_run: bool) -> Result<()> {
+    for node in nodes {
+        let kind = match node.kind {
+            DeviceType::Char => 'c',
+            DeviceType::Block => 'b',
+        };
+
+        if dry_run {
+            info!(
+                "dry-run: would create device node {} ({} {}:{}, mode {:o})",
+                node.path, kind, node.major, node.minor, node.mode
+            );
+            continue;
+        }
+
+        if let Some(parent) = Path::new(&node.path).parent() {
+            virtiofs::mkdir_p(
+                &parent.to_string_lossy(),
+                virtiofs::DEFAULT_DIR_MODE,
+                dry_run,
+            )?;
+        }
+
+        let mode = Mode::from_raw_mode(node.mode);
+        match rustix::fs::mknodat(
+            CWD,
+            node.path.as_str(),
+            file_type(node.kind),
+            mode,
+            rustix::fs::makedev(node.major, node.minor),
+        ) {
+            Ok(()) => {}
+            Err(rustix::io::Errno::EXIST) => {
+                info!("device node {} already exists, skipping", node.path);
+                continue;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to create device node {}", node.path))
+            }
+        }
+
+        // mknod applies the umask, set the requested mode explicitly
+        rustix::fs::chmod(node.path.as_str(), mode)
+            .with_context(|| format!("Failed to set mode of device node {}", node.path))?;
+
+        info!(
+            "created device node {} ({} {}:{}, mode {:o})",
+            node.path, kind, node.major, node.minor, node.mode
+        );
+    }
+
+    Ok(())
+}
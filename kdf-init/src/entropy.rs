@@ -0,0 +1,172 @@
+//! Seeding the kernel RNG from init.seed and init.seedfile
+
+use anyhow::{Context, Result};
+use rustix::ioctl::{Ioctl, IoctlOutput, Opcode};
+use std::fs::OpenOptions;
+
+use crate::cmdline::{Seed, ShareFile};
+use crate::virtiofs;
+
+/// Device the seed is credited through
+const URANDOM: &str = "/dev/urandom";
+
+/// Mount point for the init.seedfile share inside the initramfs
+const SEED_MOUNT: &str = "/kdf-seed";
+
+/// Decode a string of hex digit pairs into bytes
+///
+/// Example: "00ff7a" -> [0x00, 0xff, 0x7a]
+pub fn decode_hex(value: &str) -> Result<Vec<u8>> {
+    if value.is_empty() {
+        anyhow::bail!("Hex string is empty");
+    }
+    if !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid hex digits in: {}", value);
+    }
+    if !value.len().is_multiple_of(2) {
+        anyhow::bail!("Hex string has an odd number of digits: {}", value);
+    }
+
+    // All ASCII, so every pair is a valid str
+    Ok((0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&value[index..index + 2], 16).unwrap())
+        .collect())
+}
+
+/// RNDADDENTROPY: mix a buffer into the pool and credit it as entropy
+///
+/// The argument is a `struct rand_pool_info`: the entropy count in bits
+/// and the buffer size in bytes, followed by the buffer itself.
+struct AddEntropy {
+    pool_info: Vec<u32>,
+}
+
+impl AddEntropy {
+    fn new(bytes: &[u8]) -> Result<Self> {
+        let size = u32::try_from(bytes.len()).context("Seed is too large")?;
+        let bits = size.checked_mul(8).context("Seed is too large")?;
+        // u32 words keep the header aligned, the buffer follows it unpadded
+        let mut pool_info = vec![bits, size];
+        pool_info.extend(bytes.chunks(4).map(|chunk| {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_ne_bytes(word)
+        }));
+        Ok(Self { pool_info })
+    }
+}
+
+// Safety: RNDADDENTROPY only reads the rand_pool_info, which is sized by
+// its own buf_size field
+unsafe impl Ioctl for AddEntropy {
+    type Output = ();
+
+    const OPCODE: Opcode = Opcode::old(0x4008_5203);
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut std::ffi::c_void {
+        self.pool_info.as_mut_ptr().cast()
+    }
+
+    unsafe fn output_from_ptr(
+        _out: IoctlOutput,
+        _extract_output: *mut std::ffi::c_void,
+    ) -> rustix::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Mix `bytes` into the kernel RNG and credit them as entropy
+///
+/// Unlike a plain write to /dev/urandom, this counts towards the RNG being
+/// initialized, so getrandom() stops blocking once enough is credited.
+pub fn credit_entropy(bytes: &[u8]) -> Result<()> {
+    let urandom = OpenOptions::new()
+        .write(true)
+        .open(URANDOM)
+        .with_context(|| format!("Failed to open {}", URANDOM))?;
+    // Safety: AddEntropy matches RNDADDENTROPY
+    unsafe { rustix::ioctl::ioctl(&urandom, AddEntropy::new(bytes)?) }
+        .context("Failed to credit entropy")?;
+    Ok(())
+}
+
+/// Read an init.seedfile from its share, mounted read-only just for this
+fn read_seed_file(file: &ShareFile) -> Result<Vec<u8>> {
+    let seed = virtiofs::read_share_file(file, SEED_MOUNT, "init.seedfile")?;
+    virtiofs::unmount_share(SEED_MOUNT);
+    if seed.is_empty() {
+        anyhow::bail!("Seed file {} is empty", file.path);
+    }
+    Ok(seed)
+}
+
+/// Credit the init.seed or init.seedfile seed to the kernel RNG
+pub fn seed_rng(seed: &Seed, dry_run: bool) -> Result<()> {
+    match seed {
+        Seed::Bytes(bytes) if dry_run => {
+            info!("dry-run: would credit {} bytes of entropy", bytes.len());
+            Ok(())
+        }
+        Seed::File(file) if dry_run => {
+            info!(
+                "dry-run: would credit entropy from {} on virtiofs {}",
+                file.path, file.tag
+            );
+            Ok(())
+        }
+        Seed::Bytes(bytes) => {
+            credit_entropy(bytes)?;
+            info!("credited {} bytes of entropy from init.seed", bytes.len());
+            Ok(())
+        }
+        Seed::File(file) => {
+            let bytes = read_seed_file(file)?;
+            credit_entropy(&bytes)?;
+            info!(
+                "credited {} bytes of entropy from {} on virtiofs {}",
+                bytes.len(),
+                file.path,
+                file.tag
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff7a").unwrap(), vec![0x00, 0xff, 0x7a]);
+        assert_eq!(
+            decode_hex("DEADbeef").unwrap(),
+            vec![0xde, 0xad, 0xbe, 0xef]
+        );
+    }
+
+    #[test]
+    fn test_decode_hex_odd_length() {
+        let err = decode_hex("abc").unwrap_err();
+        assert!(err.to_string().contains("odd number of digits"));
+    }
+
+    #[test]
+    fn test_decode_hex_invalid() {
+        for value in ["", "zz", "0x12", "+1", "a b ", "é1"] {
+            assert!(decode_hex(value).is_err(), "{:?} should be rejected", value);
+        }
+    }
+
+    #[test]
+    fn test_add_entropy_layout() {
+        let request = AddEntropy::new(&[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(request.pool_info[0], 40);
+        assert_eq!(request.pool_info[1], 5);
+        assert_eq!(request.pool_info[2], u32::from_ne_bytes([1, 2, 3, 4]));
+        assert_eq!(request.pool_info[3], u32::from_ne_bytes([5, 0, 0, 0]));
+    }
+}
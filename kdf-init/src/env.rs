@@ -0,0 +1,440 @@
+//! Environment variable setup inherited by the shell or exec'd command
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use crate::cmdline::Config;
+
+/// Expand every `${VAR}` in `s`, see [`expand_vars_allowing_unset`]
+///
+/// An undefined variable is an error.
+pub fn expand_vars(s: &str, env: &HashMap<String, String>) -> Result<String> {
+    expand(s, env, false)
+}
+
+/// Expand every `${VAR}` in `s`, undefined variables expand to ""
+///
+/// Variables come from `env` (init.env.XXX) first, then from init's own
+/// environment. A `$` not followed by `{` is kept as is.
+pub fn expand_vars_allowing_unset(s: &str, env: &HashMap<String, String>) -> Result<String> {
+    expand(s, env, true)
+}
+
+fn expand(s: &str, env: &HashMap<String, String>, allow_unset: bool) -> Result<String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .with_context(|| format!("Unterminated ${{ in: {}", s))?;
+        let name = &after[..end];
+        if name.contains(['$', '{']) {
+            anyhow::bail!("Nested braces are not supported in: {}", s);
+        }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            anyhow::bail!("Invalid variable name {:?} in: {}", name, s);
+        }
+
+        match env.get(name).cloned().or_else(|| std::env::var(name).ok()) {
+            Some(value) => expanded.push_str(&value),
+            None if allow_unset => {}
+            None => anyhow::bail!(
+                "Undefined variable {} in: {} (init.allow_unset_vars=Y expands it to \"\")",
+                name,
+                s
+            ),
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expand `${VAR}` in the virtiofs tags and paths and the preexec argv
+///
+/// Runs right after the cmdline is parsed, so only init.env.XXX and the
+/// kernel-provided environment are there to expand, init.envfile and
+/// init.env.passthrough are read later.
+pub fn expand_config_vars(config: &mut Config) -> Result<()> {
+    let env = &config.env_vars;
+    let expand_vars = if config.allow_unset_vars {
+        expand_vars_allowing_unset
+    } else {
+        expand_vars
+    };
+    let expand_in_place = |value: &mut String| -> Result<()> {
+        *value = expand_vars(value, env)?;
+        Ok(())
+    };
+    for mount in &mut config.virtiofs_mounts {
+        expand_in_place(&mut mount.tag)?;
+        expand_in_place(&mut mount.path)?;
+    }
+    for arg in &mut config.preexec {
+        expand_in_place(arg)?;
+    }
+    Ok(())
+}
+
+pub fn apply_env_vars(env: &HashMap<String, String>, dry_run: bool) {
+    // Sort keys so the log output is stable across boots
+    let mut keys: Vec<&String> = env.keys().collect();
+    keys.sort();
+
+    for key in keys {
+        let value = &env[key];
+        if dry_run {
+            info!("dry-run: would set env var: {}={}", key, value);
+            continue;
+        }
+        info!("setting env var: {}={}", key, value);
+        std::env::set_var(key, value);
+    }
+}
+
+/// Merge the variables from init.envfile under the cmdline ones
+///
+/// Called once the shares are mounted, since the file usually lives on one.
+/// Variables set with init.env.XXX win over the file.
+pub fn with_env_file(
+    env: &HashMap<String, String>,
+    envfile: Option<&str>,
+    dry_run: bool,
+) -> Result<HashMap<String, String>> {
+    let Some(path) = envfile else {
+        return Ok(env.clone());
+    };
+
+    if dry_run {
+        info!("dry-run: would read env file: {}", path);
+        return Ok(env.clone());
+    }
+
+    info!("reading env file: {}", path);
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path))?;
+    let mut merged =
+        parse_env_file(&contents).with_context(|| format!("Invalid env file {}", path))?;
+    merged.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    Ok(merged)
+}
+
+/// KEY=VALUE lines the host leaves for init.env.passthrough, e.g. through
+/// fw_cfg or a share bound here
+pub const HOST_ENV_FILE: &str = "/run/host-env";
+
+/// Merge the init.env.passthrough variables from [`HOST_ENV_FILE`] under `env`
+///
+/// Only the listed keys are taken from the file, and a key already set by
+/// init.env.XXX or init.envfile keeps its value. A missing file is only a
+/// warning, the host may have nothing to pass through.
+pub fn with_host_env(
+    env: &HashMap<String, String>,
+    passthrough: &[String],
+    dry_run: bool,
+) -> Result<HashMap<String, String>> {
+    if passthrough.is_empty() {
+        return Ok(env.clone());
+    }
+
+    if dry_run {
+        info!(
+            "dry-run: would pass through {} from {}",
+            passthrough.join(","),
+            HOST_ENV_FILE
+        );
+        return Ok(env.clone());
+    }
+
+    let contents = match std::fs::read_to_string(HOST_ENV_FILE) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "{} not found, not passing through {}",
+                HOST_ENV_FILE,
+                passthrough.join(",")
+            );
+            return Ok(env.clone());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", HOST_ENV_FILE));
+        }
+    };
+    let host = parse_env_file(&contents)
+        .with_context(|| format!("Invalid host env file {}", HOST_ENV_FILE))?;
+
+    for key in passthrough.iter().filter(|key| !host.contains_key(*key)) {
+        warn!("{} is not set in {}", key, HOST_ENV_FILE);
+    }
+    let mut merged = select_keys(&host, passthrough);
+    merged.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    Ok(merged)
+}
+
+/// The variables of `env` named in `keys`, the rest is left behind
+fn select_keys(env: &HashMap<String, String>, keys: &[String]) -> HashMap<String, String> {
+    keys.iter()
+        .filter_map(|key| env.get_key_value(key))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// Parse KEY=VALUE lines from an env file
+///
+/// Blank lines and lines starting with '#' are skipped. Lines are split on
+/// the first '=', so values may contain '='. Whitespace around the key and
+/// the value is trimmed, no quoting or escaping is interpreted. A later
+/// line overrides an earlier one with the same key.
+pub fn parse_env_file(contents: &str) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: missing '=': {}", index + 1, line))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            anyhow::bail!("line {}: invalid variable name: {:?}", index + 1, key);
+        }
+
+        env.insert(key.to_string(), value.trim().to_string());
+    }
+
+    Ok(env)
+}
+
+/// Merge init.path_prepend and init.path_append into PATH
+///
+/// The base is init.env.PATH if given, otherwise the PATH init inherited
+/// from the kernel. Runs after [`apply_env_vars`].
+pub fn apply_path(
+    env: &HashMap<String, String>,
+    prepend: &[String],
+    append: &[String],
+    dry_run: bool,
+) {
+    if prepend.is_empty() && append.is_empty() {
+        return;
+    }
+
+    let base = env
+        .get("PATH")
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok());
+    let path = merge_path(base.as_deref(), prepend, append);
+
+    if dry_run {
+        info!("dry-run: would set env var: PATH={}", path);
+        return;
+    }
+    info!("setting env var: PATH={}", path);
+    std::env::set_var("PATH", path);
+}
+
+/// Build a PATH from prepended dirs, the base PATH and appended dirs
+///
+/// A directory listed more than once keeps its first position, so
+/// prepended entries win over the base, which wins over appended ones.
+/// Empty entries are dropped rather than meaning the current directory.
+fn merge_path(base: Option<&str>, prepend: &[String], append: &[String]) -> String {
+    let base = base.unwrap_or_default().split(':');
+    let mut dirs: Vec<&str> = Vec::new();
+
+    for dir in prepend
+        .iter()
+        .map(String::as_str)
+        .chain(base)
+        .chain(append.iter().map(String::as_str))
+    {
+        if !dir.is_empty() && !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    dirs.join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs(dirs: &[&str]) -> Vec<String> {
+        dirs.iter().map(|dir| dir.to_string()).collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let env = vars(&[("TAG", "share"), ("DIR", "/mnt/data"), ("EMPTY", "")]);
+        assert_eq!(expand_vars("${TAG}", &env).unwrap(), "share");
+        assert_eq!(
+            expand_vars("${DIR}/${TAG}-x${EMPTY}", &env).unwrap(),
+            "/mnt/data/share-x"
+        );
+        // Only ${ starts a variable
+        assert_eq!(
+            expand_vars("$TAG a$b {TAG} $", &env).unwrap(),
+            "$TAG a$b {TAG} $"
+        );
+        assert_eq!(expand_vars("", &env).unwrap(), "");
+    }
+
+    #[test]
+    fn test_expand_vars_process_env() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand_vars("${PATH}", &HashMap::new()).unwrap(), path);
+        // init.env.XXX wins over init's own environment
+        let env = vars(&[("PATH", "/custom")]);
+        assert_eq!(expand_vars("${PATH}", &env).unwrap(), "/custom");
+    }
+
+    #[test]
+    fn test_expand_vars_rejects_nested_braces() {
+        let env = vars(&[("A", "x"), ("B", "A")]);
+        for value in ["${${B}}", "${A${B}}", "${{A}}"] {
+            let err = expand_vars(value, &env).unwrap_err();
+            assert!(
+                err.to_string().contains("Nested braces"),
+                "{}: {}",
+                value,
+                err
+            );
+        }
+        for value in ["${A", "${}", "${A B}", "${A-x}"] {
+            assert!(expand_vars(value, &env).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_expand_vars_undefined() {
+        let err = expand_vars("/mnt/${KDF_INIT_UNSET_VAR}", &HashMap::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Undefined variable KDF_INIT_UNSET_VAR"));
+
+        assert_eq!(
+            expand_vars_allowing_unset("/mnt/${KDF_INIT_UNSET_VAR}x", &HashMap::new()).unwrap(),
+            "/mnt/x"
+        );
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let env = parse_env_file("# comment\n\nFOO=bar\n  BAZ = qux  \n").unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["FOO"], "bar");
+        assert_eq!(env["BAZ"], "qux");
+    }
+
+    #[test]
+    fn test_parse_env_file_value_with_equals() {
+        let env = parse_env_file("OPTS=a=b,c=d\nEMPTY=\n").unwrap();
+        assert_eq!(env["OPTS"], "a=b,c=d");
+        assert_eq!(env["EMPTY"], "");
+    }
+
+    #[test]
+    fn test_parse_env_file_later_line_wins() {
+        let env = parse_env_file("FOO=one\nFOO=two\n").unwrap();
+        assert_eq!(env["FOO"], "two");
+    }
+
+    #[test]
+    fn test_parse_env_file_rejects_invalid_lines() {
+        for contents in ["FOO", "FOO=bar\nnot a var\n", "=value", "MY VAR=x"] {
+            let result = parse_env_file(contents);
+            assert!(
+                result.is_err(),
+                "env file should be rejected: {:?}",
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_env_file_reports_line_number() {
+        let err = parse_env_file("# header\nFOO=bar\noops\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_select_keys_subset() {
+        let host =
+            parse_env_file("HOME=/home/dev\nTERM=xterm\nSSH_AUTH_SOCK=/tmp/agent\n").unwrap();
+        let selected = select_keys(&host, &keys(&["TERM", "SSH_AUTH_SOCK"]));
+        assert_eq!(
+            selected,
+            HashMap::from([
+                ("TERM".to_string(), "xterm".to_string()),
+                ("SSH_AUTH_SOCK".to_string(), "/tmp/agent".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_select_keys_skips_missing() {
+        let host = parse_env_file("TERM=xterm\n").unwrap();
+        let selected = select_keys(&host, &keys(&["TERM", "LANG"]));
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected["TERM"], "xterm");
+
+        assert!(select_keys(&host, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_with_host_env_without_passthrough() {
+        let env = HashMap::from([("A".to_string(), "1".to_string())]);
+        assert_eq!(with_host_env(&env, &[], false).unwrap(), env);
+    }
+
+    #[test]
+    fn test_merge_path_prepend_and_append() {
+        let path = merge_path(
+            Some("/usr/bin:/bin"),
+            &dirs(&["/opt/bin"]),
+            &dirs(&["/sbin"]),
+        );
+        assert_eq!(path, "/opt/bin:/usr/bin:/bin:/sbin");
+    }
+
+    #[test]
+    fn test_merge_path_dedup_keeps_first_position() {
+        // /usr/bin is prepended, so it moves ahead of /bin
+        let path = merge_path(
+            Some("/bin:/usr/bin"),
+            &dirs(&["/usr/bin"]),
+            &dirs(&["/bin", "/extra", "/extra"]),
+        );
+        assert_eq!(path, "/usr/bin:/bin:/extra");
+    }
+
+    #[test]
+    fn test_merge_path_without_base() {
+        let path = merge_path(None, &dirs(&["/opt/bin"]), &dirs(&["/sbin"]));
+        assert_eq!(path, "/opt/bin:/sbin");
+    }
+
+    #[test]
+    fn test_merge_path_drops_empty_entries() {
+        let path = merge_path(Some(":/usr/bin::"), &[], &dirs(&["/sbin"]));
+        assert_eq!(path, "/usr/bin:/sbin");
+    }
+}
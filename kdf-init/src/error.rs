@@ -0,0 +1,97 @@
+//! Typed errors for failures callers and tests want to tell apart
+//!
+//! Converted into anyhow::Error like any other error, and recovered with
+//! `downcast_ref::<InitError>()`, context added on the way up included.
+
+use rustix::io::Errno;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InitError {
+    /// The kernel has no virtiofs support, built-in or loaded
+    VirtiofsUnsupported,
+    /// The host exports no virtiofs share tagged `tag`
+    VirtiofsTagNotFound { tag: String },
+    /// Mounting at `target` failed
+    MountFailed { target: String, errno: Errno },
+    /// A cmdline spec of `kind` (e.g. "virtiofs mount") couldn't be parsed
+    InvalidSpec { kind: &'static str, input: String },
+}
+
+impl InitError {
+    /// [`InitError::InvalidSpec`] for a borrowed input
+    pub fn invalid_spec(kind: &'static str, input: &str) -> Self {
+        InitError::InvalidSpec {
+            kind,
+            input: input.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::VirtiofsUnsupported => write!(
+                f,
+                "virtiofs filesystem not supported by kernel. \
+                 Make sure CONFIG_VIRTIO_FS is enabled (either built-in or as a module) \
+                 and that the module is loaded before mounting virtiofs shares."
+            ),
+            InitError::VirtiofsTagNotFound { tag } => write!(
+                f,
+                "virtiofs tag {} not found. \
+                 Check the host exports it (e.g. virtiofsd and -device vhost-user-fs-pci,tag={}).",
+                tag, tag
+            ),
+            InitError::MountFailed { target, errno } => {
+                write!(f, "Failed to mount {}: {}", target, errno)
+            }
+            InitError::InvalidSpec { kind, input } => write!(f, "Invalid {} spec: {}", kind, input),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            InitError::invalid_spec("9p mount", "share").to_string(),
+            "Invalid 9p mount spec: share"
+        );
+        assert_eq!(
+            InitError::MountFailed {
+                target: "/proc".to_string(),
+                errno: Errno::PERM,
+            }
+            .to_string(),
+            format!("Failed to mount /proc: {}", Errno::PERM)
+        );
+        assert!(InitError::VirtiofsUnsupported
+            .to_string()
+            .contains("CONFIG_VIRTIO_FS"));
+        assert!(InitError::VirtiofsTagNotFound {
+            tag: "share".to_string(),
+        }
+        .to_string()
+        .starts_with("virtiofs tag share not found"));
+    }
+
+    #[test]
+    fn test_downcast_through_context() {
+        use anyhow::Context;
+
+        let err = Err::<(), _>(InitError::VirtiofsUnsupported)
+            .context("Failed to mount shares")
+            .context("Boot failed")
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InitError>(),
+            Some(&InitError::VirtiofsUnsupported)
+        );
+    }
+}
@@ -0,0 +1,903 @@
+//! Command execution - replace init with the configured command
+
+use anyhow::{Context, Result};
+use rustix::fd::OwnedFd;
+use rustix::io::Errno;
+use rustix::process::{Pid, Signal};
+use rustix::thread::UnshareFlags;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use crate::cmdline::{command_display, parse_shell_command, Config, WaitFor};
+use crate::namespace::{self, PidNamespaceGuard};
+use crate::reaper::ChildExit;
+use crate::{exit_code, reaper, rlimit, shutdown, syscall, system, user, watchdog};
+
+/// How init hands over to the configured command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    /// Replace init with the command
+    Exec,
+    /// Run the command as a child of init
+    Supervise,
+    /// No command configured, start the interactive shell
+    Shell,
+}
+
+/// Pick the run mode from the command, init.supervise and init.keepalive
+///
+/// init.keepalive and init.cmd.N imply supervision, init has to outlive the
+/// command to start the shell or the next stage afterwards. So do init.user
+/// and init.unshare=user: exec'ing runs the command's setup in init itself,
+/// and a failed exec would leave PID 1 unable to rescue or power off.
+/// init.rescue never changes the mode, it only takes over when the command
+/// fails to start.
+pub fn run_mode(config: &Config) -> RunMode {
+    if !config.has_command() {
+        RunMode::Shell
+    } else if config.supervise
+        || config.keepalive
+        || !config.cmds.is_empty()
+        || config.run_as.is_some()
+        || config.unshare.contains(UnshareFlags::NEWUSER)
+    {
+        RunMode::Supervise
+    } else {
+        RunMode::Exec
+    }
+}
+
+/// Resolve a program name to an executable path
+///
+/// Programs containing a '/' are used as-is, anything else is searched for
+/// in each directory of `path_env` (a colon-separated PATH value).
+pub fn resolve_program(program: &str, path_env: Option<&str>) -> Result<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        if !path.exists() {
+            anyhow::bail!("Command not found: {}", program);
+        }
+        return Ok(path);
+    }
+
+    let path_env = path_env.unwrap_or_default();
+    for dir in path_env.split(':').filter(|dir| !dir.is_empty()) {
+        let candidate = Path::new(dir).join(program);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+    }
+
+    anyhow::bail!("Command not found in PATH ({}): {}", path_env, program)
+}
+
+/// Program and args running `program` through the init.interp interpreter
+///
+/// Example: ("/bin/sh", "/setup", ["a"]) -> ("/bin/sh", ["/setup", "a"])
+pub fn interp_command(interp: &str, program: &Path, args: &[String]) -> (String, Vec<String>) {
+    let mut interp_args = vec![program.display().to_string()];
+    interp_args.extend_from_slice(args);
+    (interp.to_string(), interp_args)
+}
+
+/// Whether an exec failed because the kernel can't run the file itself
+///
+/// ENOEXEC for a file without shebang or known binary format, EACCES for a
+/// file without exec bit.
+pub fn needs_interp(err: &std::io::Error) -> bool {
+    err.raw_os_error().is_some_and(|errno| {
+        errno == Errno::NOEXEC.raw_os_error() || errno == Errno::ACCESS.raw_os_error()
+    })
+}
+
+/// init.interp command to retry `cmd` with, if it failed to exec with `err`
+fn interp_retry(
+    config: &Config,
+    cmd: &Command,
+    args: &[String],
+    err: &std::io::Error,
+) -> Option<(String, Vec<String>)> {
+    let interp = config.interp.as_deref().filter(|_| needs_interp(err))?;
+    let program = Path::new(cmd.get_program());
+    let command = interp_command(interp, program, args);
+    warn!(
+        "failed to exec {}: {}, retrying with init.interp: {}",
+        program.display(),
+        err,
+        command_display(&command)
+    );
+    Some(command)
+}
+
+/// Build the configured command with the console as its controlling terminal
+///
+/// `env` is set on top of init's own environment, for this command only,
+/// and the command unshares `namespaces` before it execs.
+/// The returned console fd must stay open until the command is spawned or
+/// exec'd, since the pre_exec hook borrows it.
+fn build_command(
+    config: &Config,
+    (program, args): &(String, Vec<String>),
+    env: &HashMap<String, String>,
+    namespaces: UnshareFlags,
+) -> Result<(Command, OwnedFd)> {
+    // A PATH of the command's own wins over init's, which is already merged
+    // from init.env.PATH, init.envfile and init.path_prepend/init.path_append
+    let path_env = env
+        .get("PATH")
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok());
+    let resolved = resolve_program(program, path_env.as_deref())?;
+
+    let credentials = config
+        .run_as
+        .as_ref()
+        .map(user::resolve_credentials)
+        .transpose()?;
+    // Converted up front, nothing should allocate after fork
+    let groups = config.groups.as_deref().map(user::to_gids);
+    let rlimits = config.rlimits.clone();
+
+    let console = system::open_console(&config.console)?;
+    let console_fd = console.as_raw_fd();
+
+    let mut cmd = Command::new(&resolved);
+    cmd.arg0(program).args(args).envs(env);
+
+    // Set up the controlling terminal and drop privileges right before exec
+    // Safety: the console fd is kept open by the caller and closed on exec (CLOEXEC).
+    unsafe {
+        cmd.pre_exec(move || {
+            system::detach(rustix::fd::BorrowedFd::borrow_raw(console_fd))?;
+            // Before the privileges to create namespaces are dropped
+            if !namespaces.is_empty() {
+                rustix::thread::unshare(namespaces)?;
+            }
+            // Raising a hard limit takes privileges the uid switch drops
+            rlimit::set_limits(&rlimits)?;
+            // Groups first, setgroups needs the privileges the uid switch drops
+            if let Some(groups) = &groups {
+                rustix::thread::set_thread_groups(groups)?;
+            }
+            match &credentials {
+                Some(credentials) => user::drop_privileges(credentials),
+                None => Ok(()),
+            }
+        });
+    }
+
+    Ok((cmd, console))
+}
+
+/// Whether init no longer runs as root, after a failed exec's setup
+///
+/// Neither the rescue shell nor a power off can work then.
+pub fn privileges_dropped() -> bool {
+    !rustix::process::geteuid().is_root()
+}
+
+/// Namespaces an exec attempt unshares, the init.interp retry or not
+///
+/// The pre_exec hook of an exec runs in init itself, so init already is in
+/// the fresh namespaces when a retry follows. Unsharing again would nest
+/// them, or fail outright for pid.
+fn exec_namespaces(namespaces: UnshareFlags, retry: bool) -> UnshareFlags {
+    if retry {
+        UnshareFlags::empty()
+    } else {
+        namespaces
+    }
+}
+
+/// Replace the init process image with the configured command
+///
+/// Only returns on failure: a successful exec never comes back to init.
+pub fn exec_command(config: &Config) -> Result<Infallible> {
+    let command = configured_command(config)?;
+    if config.unshare.contains(UnshareFlags::NEWPID) {
+        warn!("init.unshare=pid without init.supervise only applies to the command's children");
+    }
+    let (mut cmd, mut _console) = build_command(
+        config,
+        &command,
+        &HashMap::new(),
+        exec_namespaces(config.unshare, false),
+    )?;
+
+    info!(
+        "executing command: {} on console: {}",
+        command_display(&command),
+        config.console
+    );
+
+    syscall::log_execve(&cmd);
+    let mut err = cmd.exec();
+    if let Some(retry) = interp_retry(config, &cmd, &command.1, &err) {
+        (cmd, _console) = build_command(
+            config,
+            &retry,
+            &HashMap::new(),
+            exec_namespaces(config.unshare, true),
+        )?;
+        syscall::log_execve(&cmd);
+        err = cmd.exec();
+    }
+    Err(err).with_context(|| {
+        format!(
+            "Failed to exec command: {}",
+            Path::new(cmd.get_program()).display()
+        )
+    })
+}
+
+/// Run the configured command as a child of init and reap until it exits
+///
+/// Unlike [`exec_command`], init stays PID 1 so orphaned processes get reaped.
+pub fn run_supervised(config: &Config) -> Result<ChildExit> {
+    // Block signals before spawning so no exit notification is missed
+    reaper::block_signals(&reaper::SUPERVISOR_SIGNALS)?;
+
+    // Started after the signals are blocked so the thread never takes them
+    let watchdog = config.watchdog.as_ref().map(watchdog::start).transpose()?;
+
+    let status = run_stages(config);
+
+    // Disarm the watchdog before init powers off
+    drop(watchdog);
+    status
+}
+
+/// Run each configured command in turn, see [`Config::commands`]
+///
+/// init.exec_timeout applies to each stage on its own, a timed out stage
+/// always ends the run, as does a host shutdown. Returns how the last stage
+/// that ran exited.
+fn run_stages(config: &Config) -> Result<ChildExit> {
+    let commands = config.commands()?;
+    let mut last_exit = None;
+
+    for (index, stage) in commands.iter().enumerate() {
+        if commands.len() > 1 {
+            info!(
+                "supervising stage {}/{}: {} on console: {}",
+                index + 1,
+                commands.len(),
+                command_display(&stage.command),
+                config.console
+            );
+        } else {
+            info!(
+                "supervising command: {} on console: {}",
+                command_display(&stage.command),
+                config.console
+            );
+        }
+
+        let exit = run_with_retries(config.cmd_retries, config.reboot_on, || {
+            spawn_and_wait(
+                config,
+                &stage.command,
+                &stage.env,
+                config.unshare,
+                config.exec_timeout.map(Duration::from_secs),
+            )
+        })?;
+        let ChildExit::Exited(status) = exit else {
+            return Ok(exit);
+        };
+        let remaining = commands.len() - index - 1;
+        if remaining > 0 && !status.success() {
+            if !continue_after(status, config.keep_going) {
+                error!(
+                    "stage {} failed with exit code {}, skipping {} remaining stage(s)",
+                    index + 1,
+                    exit_code::encode_exit(status),
+                    remaining
+                );
+                return Ok(exit);
+            }
+            warn!(
+                "stage {} failed with exit code {}, continuing (init.keep_going)",
+                index + 1,
+                exit_code::encode_exit(status)
+            );
+        }
+        last_exit = Some(exit);
+    }
+
+    last_exit.context("Neither init.script, init.arg.N nor init.cmd.N is set")
+}
+
+/// Run `attempt` again after each failure, up to `retries` more times
+///
+/// For init.cmd_retries. A timed out attempt or one the host shut down ends
+/// the run like it does for the stages, and one killed by init.reboot_on
+/// asked for the reboot, so none of them is retried. Returns how the last
+/// attempt ended.
+pub fn run_with_retries(
+    retries: u32,
+    reboot_on: Signal,
+    mut attempt: impl FnMut() -> Result<ChildExit>,
+) -> Result<ChildExit> {
+    let mut exit = attempt()?;
+    for retry in 1..=retries {
+        let ChildExit::Exited(status) = exit else {
+            break;
+        };
+        let code = exit_code::encode_exit(status);
+        if code == 0 || shutdown::exit_action(status, reboot_on) == shutdown::ExitAction::Reboot {
+            break;
+        }
+        warn!(
+            "command failed with exit code {}, retrying ({}/{}, init.cmd_retries)",
+            code, retry, retries
+        );
+        exit = attempt()?;
+    }
+    Ok(exit)
+}
+
+/// Whether the next init.cmd.N stage runs after one exited with `status`
+pub fn continue_after(status: ExitStatus, keep_going: bool) -> bool {
+    keep_going || exit_code::encode_exit(status) == 0
+}
+
+/// Run the init.preexec command to completion before the main command
+///
+/// The preexec command runs on the console as the same user as the main
+/// command. init.shutdown_timeout applies to it, init.exec_timeout doesn't.
+pub fn run_preexec(config: &Config) -> Result<ExitStatus> {
+    let (program, args) = config
+        .preexec
+        .split_first()
+        .context("init.preexec is not set")?;
+    let command = (program.clone(), args.to_vec());
+
+    reaper::block_signals(&reaper::SUPERVISOR_SIGNALS)?;
+    info!("running preexec command: {}", command_display(&command));
+    let exit = spawn_and_wait(
+        config,
+        &command,
+        &HashMap::new(),
+        UnshareFlags::empty(),
+        None,
+    );
+    // The main command may be exec'd next, and would inherit the mask
+    reaper::unblock_signals(&reaper::SUPERVISOR_SIGNALS)?;
+
+    match exit? {
+        ChildExit::Exited(status) | ChildExit::Shutdown(status) | ChildExit::TimedOut(status) => {
+            Ok(status)
+        }
+    }
+}
+
+/// The init.cmd_ok or init.cmd_fail command, by the init.probe exit status
+///
+/// A probe killed by a signal counts as failed.
+pub fn select_command<'a>(status: ExitStatus, cmd_ok: &'a str, cmd_fail: &'a str) -> &'a str {
+    if exit_code::encode_exit(status) == 0 {
+        cmd_ok
+    } else {
+        cmd_fail
+    }
+}
+
+/// Run the init.probe command to completion and pick the command to run
+///
+/// Runs like init.preexec, the command it picks then takes the place of
+/// init.script.
+pub fn run_probe(config: &Config) -> Result<String> {
+    let probe = config.probe.as_deref().context("init.probe is not set")?;
+    let cmd_ok = config.cmd_ok.as_deref().context("init.cmd_ok is not set")?;
+    let cmd_fail = config
+        .cmd_fail
+        .as_deref()
+        .context("init.cmd_fail is not set")?;
+    let command = parse_shell_command(probe)?;
+
+    reaper::block_signals(&reaper::SUPERVISOR_SIGNALS)?;
+    info!("running probe command: {}", command_display(&command));
+    let exit = spawn_and_wait(
+        config,
+        &command,
+        &HashMap::new(),
+        UnshareFlags::empty(),
+        None,
+    );
+    reaper::unblock_signals(&reaper::SUPERVISOR_SIGNALS)?;
+
+    let status = match exit? {
+        ChildExit::Exited(status) | ChildExit::Shutdown(status) | ChildExit::TimedOut(status) => {
+            status
+        }
+    };
+    let selected = select_command(status, cmd_ok, cmd_fail);
+    info!(
+        "probe exited with {}, running: {}",
+        exit_code::encode_exit(status),
+        selected
+    );
+    Ok(selected.to_string())
+}
+
+/// Exit code to abort the boot with, if the preexec command failed
+pub fn preexec_failure(status: ExitStatus) -> Option<i32> {
+    let code = exit_code::encode_exit(status);
+    (code != 0).then_some(code)
+}
+
+/// Split NUL-separated argv, as in /proc/<pid>/cmdline
+///
+/// A trailing NUL terminates the last argument rather than starting an
+/// empty one. Invalid UTF-8 is replaced.
+pub fn parse_argv_nul(bytes: &[u8]) -> Vec<String> {
+    let bytes = bytes.strip_suffix(b"\0").unwrap_or(bytes);
+    if bytes.is_empty() {
+        return Vec::new();
+    }
+    bytes
+        .split(|&byte| byte == 0)
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+/// Read the command argv from init.argv_file
+pub fn read_argv_file(path: &str) -> Result<Vec<String>> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read init.argv_file {}", path))?;
+    let argv = parse_argv_nul(&bytes);
+    if argv.first().is_none_or(|program| program.is_empty()) {
+        anyhow::bail!("init.argv_file {} holds no program", path);
+    }
+    info!("read {} argv entries from {}", argv.len(), path);
+    Ok(argv)
+}
+
+/// Split a command line read from init.cmd_fifo into argv
+///
+/// Example: "/bin/test --quick\n" -> ["/bin/test", "--quick"]
+pub fn parse_command_line(line: &str) -> Result<Vec<String>> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let argv: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+    if argv.is_empty() {
+        anyhow::bail!("Command is empty");
+    }
+    Ok(argv)
+}
+
+/// Create the init.cmd_fifo FIFO, keeping one left by an earlier run
+fn create_fifo(path: &str) -> Result<()> {
+    use rustix::fs::{FileType, Mode, CWD};
+
+    match rustix::fs::mknodat(CWD, path, FileType::Fifo, Mode::from_raw_mode(0o600), 0) {
+        Ok(()) => Ok(()),
+        Err(rustix::io::Errno::EXIST)
+            if std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+                std::os::unix::fs::FileTypeExt::is_fifo(&metadata.file_type())
+            }) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to create FIFO {}", path)),
+    }
+}
+
+/// Read the first line written to `fifo`, giving up after `timeout`
+///
+/// Reads never block, so the timeout holds even if a writer stalls. A
+/// writer closing the FIFO ends the line as well, unless nothing came yet:
+/// without any writer, reads return end-of-file too.
+fn read_fifo_line(fifo: &OwnedFd, path: &str, timeout: Duration) -> Result<String> {
+    let deadline = Instant::now() + timeout;
+    let mut line = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match rustix::io::read(fifo, &mut buf) {
+            Ok(0) if !line.is_empty() => break,
+            Ok(0) | Err(rustix::io::Errno::AGAIN) => {}
+            Ok(n) => {
+                line.extend_from_slice(&buf[..n]);
+                if let Some(end) = line.iter().position(|&byte| byte == b'\n') {
+                    line.truncate(end + 1);
+                    break;
+                }
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read FIFO {}", path)),
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            anyhow::bail!(
+                "Timed out after {}s waiting for a command on {}",
+                timeout.as_secs(),
+                path
+            );
+        }
+        std::thread::sleep(system::WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+    String::from_utf8(line).with_context(|| format!("Command from {} is not UTF-8", path))
+}
+
+/// Create the init.cmd_fifo FIFO and read the command argv from it
+pub fn read_fifo_command(fifo: &WaitFor) -> Result<Vec<String>> {
+    use rustix::fs::{Mode, OFlags};
+
+    create_fifo(&fifo.path)?;
+    let fd = rustix::fs::open(
+        fifo.path.as_str(),
+        OFlags::RDONLY | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+    .with_context(|| format!("Failed to open FIFO {}", fifo.path))?;
+
+    info!(
+        "waiting up to {}s for a command on {}",
+        fifo.timeout, fifo.path
+    );
+    let line = read_fifo_line(&fd, &fifo.path, Duration::from_secs(fifo.timeout))?;
+    let argv =
+        parse_command_line(&line).with_context(|| format!("Invalid command from {}", fifo.path))?;
+    info!("read command from {}: {}", fifo.path, argv.join(" "));
+    Ok(argv)
+}
+
+/// Program and args from init.script or init.arg.N
+fn configured_command(config: &Config) -> Result<(String, Vec<String>)> {
+    config
+        .command()?
+        .context("Neither init.script nor init.arg.N is set")
+}
+
+/// Spawn `command` with `env` in `namespaces` as a child of init and reap
+/// until it exits
+///
+/// [`reaper::SUPERVISOR_SIGNALS`] must already be blocked.
+fn spawn_and_wait(
+    config: &Config,
+    command: &(String, Vec<String>),
+    env: &HashMap<String, String>,
+    namespaces: UnshareFlags,
+    exec_timeout: Option<Duration>,
+) -> Result<ChildExit> {
+    let exec_namespaces = namespace::exec_flags(namespaces);
+    let (mut cmd, mut _console) = build_command(config, command, env, exec_namespaces)?;
+
+    // Only for the spawn, init itself never leaves its pid namespace
+    let pid_namespace = namespaces
+        .contains(UnshareFlags::NEWPID)
+        .then(PidNamespaceGuard::unshare)
+        .transpose()?;
+    syscall::log_execve(&cmd);
+    let mut child = cmd.spawn();
+    if let Some(retry) = child
+        .as_ref()
+        .err()
+        .and_then(|err| interp_retry(config, &cmd, &command.1, err))
+    {
+        (cmd, _console) = build_command(config, &retry, env, exec_namespaces)?;
+        syscall::log_execve(&cmd);
+        child = cmd.spawn();
+    }
+    let child = child.with_context(|| {
+        format!(
+            "Failed to spawn command: {}",
+            Path::new(cmd.get_program()).display()
+        )
+    });
+    drop(pid_namespace);
+    let child = child?;
+
+    reaper::wait_for_child(
+        Pid::from_child(&child),
+        Duration::from_secs(config.shutdown_timeout),
+        exec_timeout,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode_for(params: &str) -> RunMode {
+        let config = crate::cmdline::parse_cmdline(&format!(
+            "init.console=console init.shell=`sh` {}",
+            params
+        ))
+        .unwrap();
+        run_mode(&config)
+    }
+
+    #[test]
+    fn test_exec_retry_keeps_namespaces() {
+        let namespaces = UnshareFlags::NEWNS | UnshareFlags::NEWPID;
+        assert_eq!(exec_namespaces(namespaces, false), namespaces);
+        assert_eq!(exec_namespaces(namespaces, true), UnshareFlags::empty());
+    }
+
+    #[test]
+    fn test_run_mode_precedence() {
+        let cases = [
+            ("", RunMode::Shell),
+            ("init.keepalive=Y", RunMode::Shell),
+            ("init.supervise=Y init.keepalive=Y", RunMode::Shell),
+            ("init.script=`true`", RunMode::Exec),
+            ("init.script=`true` init.rescue=Y", RunMode::Exec),
+            ("init.script=`true` init.supervise=Y", RunMode::Supervise),
+            ("init.script=`true` init.keepalive=Y", RunMode::Supervise),
+            (
+                "init.script=`true` init.keepalive=Y init.supervise=N init.rescue=Y",
+                RunMode::Supervise,
+            ),
+            (
+                "init.arg.0=true init.keepalive=Y init.exec_timeout=5",
+                RunMode::Supervise,
+            ),
+            ("init.cmd.0=true init.cmd.1=false", RunMode::Supervise),
+            ("init.cmd.0=true init.supervise=N", RunMode::Supervise),
+            ("init.script=`true` init.user=1000", RunMode::Supervise),
+            ("init.script=`true` init.unshare=user", RunMode::Supervise),
+            ("init.script=`true` init.unshare=mount,net", RunMode::Exec),
+        ];
+
+        for (params, expected) in cases {
+            assert_eq!(mode_for(params), expected, "{}", params);
+        }
+    }
+
+    /// Exits of the attempts [`run_with_retries`] made, given the statuses
+    /// successive attempts exit with
+    fn attempts(retries: u32, statuses: &[ChildExit]) -> Vec<ChildExit> {
+        let mut made = Vec::new();
+        let last = run_with_retries(retries, Signal::Usr1, || {
+            let exit = statuses[made.len()];
+            made.push(exit);
+            Ok(exit)
+        })
+        .unwrap();
+        assert_eq!(made.last(), Some(&last));
+        made
+    }
+
+    #[test]
+    fn test_run_with_retries() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let success = ChildExit::Exited(ExitStatus::from_raw(0));
+        let failure = ChildExit::Exited(ExitStatus::from_raw(1 << 8));
+        // Killed by SIGKILL
+        let killed = ChildExit::Exited(ExitStatus::from_raw(9));
+
+        // Retried until it succeeds
+        assert_eq!(
+            attempts(3, &[failure, killed, success, failure]),
+            [failure, killed, success]
+        );
+        // The last failure stands once the retries run out
+        assert_eq!(
+            attempts(2, &[failure, failure, failure, success]),
+            [failure, failure, failure]
+        );
+        assert_eq!(attempts(0, &[failure, success]), [failure]);
+        assert_eq!(attempts(3, &[success, failure]), [success]);
+    }
+
+    #[test]
+    fn test_run_with_retries_final_exits() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let failure = ChildExit::Exited(ExitStatus::from_raw(1 << 8));
+        let timed_out = ChildExit::TimedOut(ExitStatus::from_raw(15));
+        // Killed by SIGUSR1, the init.reboot_on signal
+        let reboot = ChildExit::Exited(ExitStatus::from_raw(10));
+        // Killed by the SIGTERM init forwarded from the host
+        let shutdown = ChildExit::Shutdown(ExitStatus::from_raw(15));
+
+        assert_eq!(attempts(3, &[timed_out, failure]), [timed_out]);
+        assert_eq!(attempts(3, &[failure, reboot, failure]), [failure, reboot]);
+        assert_eq!(
+            attempts(3, &[failure, shutdown, failure]),
+            [failure, shutdown]
+        );
+    }
+
+    #[test]
+    fn test_continue_after() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let success = ExitStatus::from_raw(0);
+        let failure = ExitStatus::from_raw(1 << 8);
+        // Killed by SIGTERM
+        let killed = ExitStatus::from_raw(15);
+
+        assert!(continue_after(success, false));
+        assert!(!continue_after(failure, false));
+        assert!(!continue_after(killed, false));
+
+        assert!(continue_after(success, true));
+        assert!(continue_after(failure, true));
+        assert!(continue_after(killed, true));
+    }
+
+    #[test]
+    fn test_preexec_failure() {
+        use std::os::unix::process::ExitStatusExt;
+
+        assert_eq!(preexec_failure(ExitStatus::from_raw(0)), None);
+        assert_eq!(preexec_failure(ExitStatus::from_raw(3 << 8)), Some(3));
+        // Killed by SIGKILL
+        assert_eq!(preexec_failure(ExitStatus::from_raw(9)), Some(137));
+    }
+
+    #[test]
+    fn test_select_command() {
+        use std::os::unix::process::ExitStatusExt;
+
+        assert_eq!(select_command(ExitStatus::from_raw(0), "ok", "fail"), "ok");
+        assert_eq!(
+            select_command(ExitStatus::from_raw(1 << 8), "ok", "fail"),
+            "fail"
+        );
+        // Killed by SIGKILL
+        assert_eq!(
+            select_command(ExitStatus::from_raw(9), "ok", "fail"),
+            "fail"
+        );
+    }
+
+    #[test]
+    fn test_parse_argv_nul() {
+        assert_eq!(
+            parse_argv_nul(b"/bin/sh\0-c\0echo hello world\0"),
+            vec!["/bin/sh", "-c", "echo hello world"]
+        );
+        // Without the trailing NUL, and with newlines and empty args kept
+        assert_eq!(
+            parse_argv_nul(b"printf\0a\nb\0\0c d"),
+            vec!["printf", "a\nb", "", "c d"]
+        );
+    }
+
+    #[test]
+    fn test_parse_argv_nul_empty() {
+        assert!(parse_argv_nul(b"").is_empty());
+        assert!(parse_argv_nul(b"\0").is_empty());
+        assert_eq!(parse_argv_nul(b"\0\0"), vec!["", ""]);
+    }
+
+    #[test]
+    fn test_interp_command() {
+        let args = vec!["-v".to_string(), "two words".to_string()];
+        assert_eq!(
+            interp_command("/bin/sh", Path::new("/setup"), &args),
+            (
+                "/bin/sh".to_string(),
+                vec![
+                    "/setup".to_string(),
+                    "-v".to_string(),
+                    "two words".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            interp_command("/bin/sh", Path::new("/usr/bin/setup"), &[]),
+            ("/bin/sh".to_string(), vec!["/usr/bin/setup".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_needs_interp() {
+        let errno = |errno: Errno| std::io::Error::from_raw_os_error(errno.raw_os_error());
+        assert!(needs_interp(&errno(Errno::NOEXEC)));
+        assert!(needs_interp(&errno(Errno::ACCESS)));
+        assert!(!needs_interp(&errno(Errno::NOENT)));
+        assert!(!needs_interp(&std::io::Error::other("not an errno")));
+    }
+
+    #[test]
+    fn test_interp_runs_unexecutable_script() {
+        // A script without shebang or exec bit, as init.cmd would point at
+        let script = std::env::temp_dir().join(format!("kdf-init-interp-{}", std::process::id()));
+        std::fs::write(&script, "exit 3\n").unwrap();
+
+        let err = Command::new(&script).status().unwrap_err();
+        assert!(needs_interp(&err), "{}", err);
+
+        let (interp, args) = interp_command("/bin/sh", &script, &[]);
+        let status = Command::new(interp).args(args).status().unwrap();
+        std::fs::remove_file(&script).unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn test_parse_command_line() {
+        assert_eq!(
+            parse_command_line("/bin/test --quick\n").unwrap(),
+            vec!["/bin/test", "--quick"]
+        );
+        assert_eq!(
+            parse_command_line("  run   a\tb\r\n").unwrap(),
+            vec!["run", "a", "b"]
+        );
+        assert_eq!(parse_command_line("true").unwrap(), vec!["true"]);
+    }
+
+    #[test]
+    fn test_parse_command_line_rejects_empty() {
+        for line in ["", "\n", "\r\n", "   \n"] {
+            let err = parse_command_line(line).unwrap_err();
+            assert!(err.to_string().contains("Command is empty"), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_read_fifo_command() {
+        let path = std::env::temp_dir().join(format!("kdf-init-fifo-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let fifo = WaitFor {
+            path: path.clone(),
+            timeout: 10,
+        };
+
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                // Opening for writing blocks until the reader is there
+                while !Path::new(&path).exists() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                std::fs::write(&path, "/bin/echo hello\nignored\n").unwrap();
+            })
+        };
+        let argv = read_fifo_command(&fifo).unwrap();
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(argv, vec!["/bin/echo", "hello"]);
+    }
+
+    #[test]
+    fn test_read_fifo_command_times_out() {
+        let path = std::env::temp_dir().join(format!("kdf-init-fifo-idle-{}", std::process::id()));
+        let fifo = WaitFor {
+            path: path.to_str().unwrap().to_string(),
+            timeout: 0,
+        };
+        let err = read_fifo_command(&fifo).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("Timed out after 0s"), "{}", err);
+    }
+
+    #[test]
+    fn test_resolve_absolute_program() {
+        let resolved = resolve_program("/bin/sh", None).unwrap();
+        assert_eq!(resolved, PathBuf::from("/bin/sh"));
+    }
+
+    #[test]
+    fn test_resolve_missing_absolute_program() {
+        let result = resolve_program("/nonexistent/kdf-init-test", Some("/bin"));
+        assert!(result.is_err());
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("Command not found"));
+    }
+
+    #[test]
+    fn test_resolve_program_via_path() {
+        let resolved = resolve_program("sh", Some("/nonexistent::/bin")).unwrap();
+        assert_eq!(resolved, PathBuf::from("/bin/sh"));
+    }
+
+    #[test]
+    fn test_resolve_program_without_path() {
+        assert!(resolve_program("sh", None).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Reporting the supervised command's exit code to the host
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// Encode an exit status as a shell would, 128+signum for a signal death
+pub fn encode_exit(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        // Stopped/continued statuses are never reported for an exited child
+        (None, None) => 1,
+    }
+}
+
+/// Record the exit code in the kernel log and in init.result_file
+///
+/// Failures are only logged, init powers off either way.
+pub fn report_exit(status: ExitStatus, result_file: Option<&str>) {
+    let code = encode_exit(status);
+    info!("command exit code: {}", code);
+    report_code(code, result_file);
+}
+
+/// Record an exit code init picked itself, see [`report_exit`]
+pub fn report_code(code: i32, result_file: Option<&str>) {
+    // Written directly so it shows up even without init.kmsg
+    if let Err(e) = OpenOptions::new()
+        .write(true)
+        .open("/dev/kmsg")
+        .and_then(|mut kmsg| kmsg.write_all(format!("<6>kdf-init: exit code: {}", code).as_bytes()))
+    {
+        warn!("failed to write exit code to /dev/kmsg: {}", e);
+    }
+
+    if let Some(path) = result_file {
+        match std::fs::write(path, format!("{}\n", code)) {
+            Ok(()) => info!("wrote exit code to {}", path),
+            Err(e) => warn!("failed to write exit code to {}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_exit_success() {
+        assert_eq!(encode_exit(ExitStatus::from_raw(0)), 0);
+    }
+
+    #[test]
+    fn test_encode_exit_nonzero() {
+        // Wait statuses keep the exit code in the second byte
+        assert_eq!(encode_exit(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(encode_exit(ExitStatus::from_raw(255 << 8)), 255);
+    }
+
+    #[test]
+    fn test_encode_exit_signal() {
+        // A signal death keeps the signal number in the low bits
+        assert_eq!(encode_exit(ExitStatus::from_raw(9)), 137);
+        assert_eq!(encode_exit(ExitStatus::from_raw(15)), 143);
+        // Core dump flag set
+        assert_eq!(encode_exit(ExitStatus::from_raw(0x80 | 11)), 139);
+    }
+}
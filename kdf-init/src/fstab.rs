@@ -0,0 +1,277 @@
+//! Declarative mounts from an fstab file on a virtiofs share (init.fstab)
+
+use anyhow::{Context, Result};
+use rustix::mount::MountFlags;
+
+use crate::cmdline::{BindMount, ShareFile};
+use crate::{system, virtiofs};
+
+/// Mount point for the fstab share inside the initramfs
+const FSTAB_MOUNT: &str = "/kdf-fstab";
+
+/// fstab options that map to mount flags rather than mount data
+///
+/// "defaults" and "rw" only make the default explicit.
+const FLAG_OPTIONS: [(&str, MountFlags); 12] = [
+    ("defaults", MountFlags::empty()),
+    ("rw", MountFlags::empty()),
+    ("ro", MountFlags::RDONLY),
+    ("nosuid", MountFlags::NOSUID),
+    ("nodev", MountFlags::NODEV),
+    ("noexec", MountFlags::NOEXEC),
+    ("sync", MountFlags::SYNCHRONOUS),
+    ("dirsync", MountFlags::DIRSYNC),
+    ("noatime", MountFlags::NOATIME),
+    ("nodiratime", MountFlags::NODIRATIME),
+    ("relatime", MountFlags::RELATIME),
+    ("bind", MountFlags::BIND),
+];
+
+/// One fstab entry, mounted as is
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountSpec {
+    /// Mount source: a tag, device, or the directory to bind
+    pub source: String,
+    /// Path to mount at
+    pub target: String,
+    /// Filesystem type, "bind" or "none" for bind mounts
+    pub fstype: String,
+    /// Flags from the option column
+    pub flags: MountFlags,
+    /// Remaining options, passed to the filesystem as mount data
+    pub data: String,
+}
+
+impl MountSpec {
+    fn is_bind(&self) -> bool {
+        self.flags.contains(MountFlags::BIND)
+    }
+}
+
+/// Undo fstab's octal escapes, e.g. "\040" for a space
+fn unescape(field: &str) -> Result<String> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut bytes = field.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        let digits: Vec<u8> = bytes.by_ref().take(3).collect();
+        let escaped = std::str::from_utf8(&digits)
+            .ok()
+            .filter(|digits| digits.len() == 3)
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+            .with_context(|| format!("Invalid escape in {:?}", field))?;
+        out.push(escaped);
+    }
+    String::from_utf8(out).with_context(|| format!("Invalid UTF-8 in {:?}", field))
+}
+
+/// Parse a single fstab line: `<src> <target> <fstype> [<opts> [<dump> [<pass>]]]`
+fn parse_line(line: &str) -> Result<MountSpec> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (source, target, fstype, options) = match fields.as_slice() {
+        [source, target, fstype] => (*source, *target, *fstype, "defaults"),
+        [source, target, fstype, options, rest @ ..] if rest.len() <= 2 => {
+            // dump and pass mean nothing here, but must still look right
+            if let Some(field) = rest.iter().find(|field| field.parse::<u32>().is_err()) {
+                anyhow::bail!("Invalid dump or pass field: {}", field);
+            }
+            (*source, *target, *fstype, *options)
+        }
+        _ => anyhow::bail!("Expected <src> <target> <fstype> [<opts>]"),
+    };
+
+    let target = unescape(target)?;
+    if !target.starts_with('/') {
+        anyhow::bail!("Target must be an absolute path: {}", target);
+    }
+
+    let mut flags = if fstype == "bind" {
+        MountFlags::BIND
+    } else {
+        MountFlags::empty()
+    };
+    let mut data = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        match FLAG_OPTIONS.iter().find(|(name, _)| *name == option) {
+            Some((_, flag)) => flags |= *flag,
+            None => data.push(option),
+        }
+    }
+
+    let spec = MountSpec {
+        source: unescape(source)?,
+        target,
+        fstype: fstype.to_string(),
+        flags,
+        data: data.join(","),
+    };
+    // A bind can only be made read-only, see system::bind_mounts
+    if spec.is_bind()
+        && (!data.is_empty()
+            || flags - MountFlags::BIND - MountFlags::RDONLY != MountFlags::empty())
+    {
+        anyhow::bail!("Bind mounts only take the ro option: {}", options);
+    }
+    Ok(spec)
+}
+
+/// Parse the contents of an init.fstab file, in file order
+///
+/// Blank lines and lines starting with '#' are skipped.
+pub fn parse_fstab(contents: &str) -> Result<Vec<MountSpec>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim_start();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(index, line)| {
+            parse_line(line).with_context(|| format!("Invalid fstab line {}: {}", index + 1, line))
+        })
+        .collect()
+}
+
+/// Read an init.fstab from its share, mounted read-only just for this
+fn read_fstab_file(file: &ShareFile) -> Result<String> {
+    let contents = virtiofs::read_share_file(file, FSTAB_MOUNT, "init.fstab")?;
+    virtiofs::unmount_share(FSTAB_MOUNT);
+    String::from_utf8(contents).with_context(|| format!("init.fstab {} is not UTF-8", file.path))
+}
+
+/// Mount a single fstab entry, creating its target first
+fn mount_entry(spec: &MountSpec, skip_mounted: bool, dry_run: bool) -> Result<()> {
+    if spec.is_bind() {
+        let bind = BindMount {
+            source: spec.source.clone(),
+            target: spec.target.clone(),
+            read_only: spec.flags.contains(MountFlags::RDONLY),
+        };
+        return system::bind_mounts(&[bind], skip_mounted, dry_run);
+    }
+
+    if system::already_mounted(&spec.target, skip_mounted)? {
+        return Ok(());
+    }
+    virtiofs::mkdir_p(&spec.target, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+    system::mount_fs(
+        &format!("{} {} at {}", spec.fstype, spec.source, spec.target),
+        &spec.source,
+        &spec.target,
+        &spec.fstype,
+        spec.flags,
+        &spec.data,
+        dry_run,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to mount {} {} at {}",
+            spec.fstype, spec.source, spec.target
+        )
+    })
+}
+
+/// Mount every entry of an init.fstab file, in file order
+pub fn mount_fstab(file: &ShareFile, skip_mounted: bool, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!(
+            "dry-run: would mount the entries of {} on virtiofs {}",
+            file.path, file.tag
+        );
+        return Ok(());
+    }
+
+    let specs = parse_fstab(&read_fstab_file(file)?)
+        .with_context(|| format!("Invalid init.fstab {} on {}", file.path, file.tag))?;
+    for spec in &specs {
+        mount_entry(spec, skip_mounted, dry_run)?;
+    }
+    info!("mounted {} init.fstab entries", specs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(source: &str, target: &str, fstype: &str, flags: MountFlags, data: &str) -> MountSpec {
+        MountSpec {
+            source: source.to_string(),
+            target: target.to_string(),
+            fstype: fstype.to_string(),
+            flags,
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_fstab() {
+        let contents = "\
+# Shares for the test VM
+share   /mnt/share  virtiofs  defaults
+
+  # indented comment
+hostshare /mnt/9p 9p trans=virtio,version=9p2000.L,ro 0 0
+tmpfs /scratch tmpfs size=64m,mode=1777,nosuid,nodev
+/mnt/share/etc /etc bind ro
+/mnt/share/my\\040dir /srv none bind
+";
+        assert_eq!(
+            parse_fstab(contents).unwrap(),
+            vec![
+                spec("share", "/mnt/share", "virtiofs", MountFlags::empty(), ""),
+                spec(
+                    "hostshare",
+                    "/mnt/9p",
+                    "9p",
+                    MountFlags::RDONLY,
+                    "trans=virtio,version=9p2000.L"
+                ),
+                spec(
+                    "tmpfs",
+                    "/scratch",
+                    "tmpfs",
+                    MountFlags::NOSUID | MountFlags::NODEV,
+                    "size=64m,mode=1777"
+                ),
+                spec(
+                    "/mnt/share/etc",
+                    "/etc",
+                    "bind",
+                    MountFlags::BIND | MountFlags::RDONLY,
+                    ""
+                ),
+                spec("/mnt/share/my dir", "/srv", "none", MountFlags::BIND, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_fstab_empty() {
+        assert!(parse_fstab("").unwrap().is_empty());
+        assert!(parse_fstab("# nothing\n\n   \n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_fstab_rejects_malformed() {
+        for contents in [
+            "share /mnt",
+            "share mnt virtiofs",
+            "share /mnt virtiofs defaults 0 0 extra",
+            "share /mnt virtiofs defaults x",
+            "share /mnt\\04 virtiofs",
+            "share /mnt\\999 virtiofs",
+            "/a /b bind nosuid",
+            "/a /b none bind,mode=755",
+        ] {
+            assert!(parse_fstab(contents).is_err(), "{:?}", contents);
+        }
+
+        // The line number points at the culprit
+        let err = parse_fstab("# ok\nshare /mnt virtiofs\nbroken\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{}", err);
+    }
+}
@@ -0,0 +1,248 @@
+//! Leveled console logging, filtered by init.loglevel
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+/// Longest message body written to /dev/kmsg in a single record
+const KMSG_MAX_RECORD: usize = 1000;
+
+/// Severity of a single log line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// Kernel log priority (KERN_ERR, KERN_WARNING, KERN_INFO, KERN_DEBUG)
+    fn kmsg_priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+}
+
+/// Verbosity selected with init.loglevel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "print-config", derive(serde::Serialize))]
+#[cfg_attr(feature = "print-config", serde(rename_all = "lowercase"))]
+pub enum LogLevel {
+    /// Only warnings and errors
+    Quiet,
+    /// Progress messages, warnings and errors
+    #[default]
+    Info,
+    /// Everything, including the parsed configuration dump
+    Debug,
+}
+
+impl LogLevel {
+    /// Most verbose severity printed at this log level
+    fn max_level(self) -> Level {
+        match self {
+            LogLevel::Quiet => Level::Warn,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+        }
+    }
+}
+
+/// Active log level, stored as a `LogLevel` discriminant
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Apply the log level parsed from the kernel cmdline
+pub fn set_log_level(log_level: LogLevel) {
+    LOG_LEVEL.store(log_level as u8, Ordering::Relaxed);
+}
+
+fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        x if x == LogLevel::Quiet as u8 => LogLevel::Quiet,
+        x if x == LogLevel::Debug as u8 => LogLevel::Debug,
+        _ => LogLevel::Info,
+    }
+}
+
+/// Whether the syscall wrappers log their arguments, see [`set_verbose`]
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Log the exact arguments of each traced syscall (init.verbose)
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Whether init.verbose is on, see [`crate::syscall`]
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Mirror of the console log in the kernel ring buffer, see [`enable_kmsg`]
+static KMSG: OnceLock<File> = OnceLock::new();
+
+/// Mirror every subsequent log line to /dev/kmsg
+///
+/// Needs devtmpfs mounted. If /dev/kmsg can't be opened, logging stays on
+/// the console only.
+pub fn enable_kmsg() {
+    match OpenOptions::new().write(true).open("/dev/kmsg") {
+        Ok(file) => {
+            let _ = KMSG.set(file);
+            log(
+                Level::Info,
+                format_args!("mirroring log output to /dev/kmsg"),
+            );
+        }
+        Err(e) => log(
+            Level::Warn,
+            format_args!("failed to open /dev/kmsg: {}, logging to console only", e),
+        ),
+    }
+}
+
+/// Split a log line into /dev/kmsg records with a `<N>` priority prefix
+///
+/// Each record must be written with a single write, and the kernel
+/// truncates long ones, so the line is chunked on char boundaries.
+fn kmsg_records(level: Level, line: &str) -> Vec<String> {
+    let mut records = Vec::new();
+    let mut rest = line;
+
+    loop {
+        let mut end = rest.len().min(KMSG_MAX_RECORD);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        records.push(format!("<{}>{}", level.kmsg_priority(), chunk));
+
+        if tail.is_empty() {
+            return records;
+        }
+        rest = tail;
+    }
+}
+
+/// Whether a line of severity `level` is printed at `log_level`
+pub fn is_enabled(level: Level, log_level: LogLevel) -> bool {
+    level <= log_level.max_level()
+}
+
+/// Print a log line, warnings and errors go to stderr
+///
+/// Use the [`info!`], [`warn!`], [`error!`] and [`debug!`] macros instead of
+/// calling this directly.
+pub fn log(level: Level, args: fmt::Arguments) {
+    if !is_enabled(level, log_level()) {
+        return;
+    }
+
+    let line = format!("kdf-init: {}: {}", level.label(), args);
+
+    // Write errors are ignored, there is nowhere else to report them
+    let _ = match level {
+        Level::Error | Level::Warn => writeln!(std::io::stderr(), "{}", line),
+        Level::Info | Level::Debug => writeln!(std::io::stdout(), "{}", line),
+    };
+
+    if let Some(mut kmsg) = KMSG.get() {
+        for record in kmsg_records(level, &line) {
+            let _ = kmsg.write_all(record.as_bytes());
+        }
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_filters_info_and_debug() {
+        assert!(is_enabled(Level::Error, LogLevel::Quiet));
+        assert!(is_enabled(Level::Warn, LogLevel::Quiet));
+        assert!(!is_enabled(Level::Info, LogLevel::Quiet));
+        assert!(!is_enabled(Level::Debug, LogLevel::Quiet));
+    }
+
+    #[test]
+    fn test_info_filters_debug() {
+        assert!(is_enabled(Level::Warn, LogLevel::Info));
+        assert!(is_enabled(Level::Info, LogLevel::Info));
+        assert!(!is_enabled(Level::Debug, LogLevel::Info));
+    }
+
+    #[test]
+    fn test_debug_shows_everything() {
+        for level in [Level::Error, Level::Warn, Level::Info, Level::Debug] {
+            assert!(is_enabled(level, LogLevel::Debug));
+        }
+    }
+
+    #[test]
+    fn test_kmsg_priority_prefix() {
+        assert_eq!(kmsg_records(Level::Error, "boom"), vec!["<3>boom"]);
+        assert_eq!(kmsg_records(Level::Warn, "hmm"), vec!["<4>hmm"]);
+        assert_eq!(kmsg_records(Level::Info, "ok"), vec!["<6>ok"]);
+        assert_eq!(kmsg_records(Level::Debug, "x"), vec!["<7>x"]);
+    }
+
+    #[test]
+    fn test_kmsg_splits_long_lines() {
+        let line = "a".repeat(KMSG_MAX_RECORD * 2 + 5);
+        let records = kmsg_records(Level::Info, &line);
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.starts_with("<6>")));
+        assert_eq!(records[0].len(), 3 + KMSG_MAX_RECORD);
+        assert_eq!(records[2], "<6>aaaaa");
+    }
+
+    #[test]
+    fn test_kmsg_splits_on_char_boundary() {
+        // 'é' is two bytes, so byte 1000 falls in the middle of a char
+        let line = format!("a{}", "é".repeat(KMSG_MAX_RECORD));
+        let records = kmsg_records(Level::Info, &line);
+        assert_eq!(records[0].len(), 3 + KMSG_MAX_RECORD - 1);
+        let joined: String = records.iter().map(|r| &r[3..]).collect();
+        assert_eq!(joined, line);
+    }
+}
@@ -1,89 +1,670 @@
 //! kdf-init: minimal Rust init for initramfs with virtiofs and overlayfs support
 
+#[macro_use]
+mod log;
+
+mod boot_timeout;
+mod cgroup;
+mod clock;
 mod cmdline;
+mod config_file;
+mod devnodes;
+mod entropy;
+mod env;
+mod error;
+mod exec;
+mod exit_code;
+mod fstab;
+mod mount_guard;
+mod namespace;
+mod net;
+mod overlay_reset;
+mod p9;
+mod reaper;
+mod rescue;
+mod rlimit;
+mod root;
+mod shutdown;
+mod squashfs;
+mod syscall;
+mod sysctl;
 mod system;
+mod timings;
+mod user;
 mod virtiofs;
+mod watchdog;
+
+use anyhow::{Context, Result};
 
-use anyhow::Result;
+use boot_timeout::Phase;
 
 fn main() -> Result<()> {
+    // Run from a shell, e.g. to test the binary, only describe the params
+    if !rustix::process::getpid().is_init() && !std::env::args().any(|arg| arg == "init.force=Y") {
+        print!("{}", cmdline::describe_params());
+        return Ok(());
+    }
+
     // Run main logic and always shutdown, even on error
     if let Err(e) = run() {
-        eprintln!("kdf-init: fatal error: {:?}", e);
-        let _ = system::shutdown();
+        error!("fatal error: {:?}", e);
+        if !exec::privileges_dropped() {
+            let _ = system::shutdown();
+        }
         return Err(e);
     }
     Ok(())
 }
 
 fn run() -> Result<()> {
-    println!("kdf-init: starting minimal Rust init");
+    info!("starting minimal Rust init");
+
+    // The kernel may start init with a umask of 0, set the usual one so
+    // created directories and files get the modes they ask for
+    rustix::process::umask(rustix::fs::Mode::from_raw_mode(0o022));
+
+    // Set by an earlier run, init was re-exec'd and mounts may already exist
+    let rerun = system::setup_done();
+    if rerun {
+        info!(
+            "{} exists, skipping targets that are already mounted",
+            system::DONE_MARKER
+        );
+    }
 
-    // Mount kernel filesystems
-    system::mount_kernel_filesystems()?;
+    // Mount /proc, needed to read the kernel cmdline
+    system::mount_proc(rerun)?;
 
     // Parse kernel cmdline
     let cmdline_str = cmdline::read_cmdline()?;
-    println!("kdf-init: kernel cmdline: {}", cmdline_str);
+    info!("kernel cmdline: {}", cmdline_str);
+
+    // init.check only lints the cmdline, nothing but /proc gets mounted
+    if cmdline::check_requested(&cmdline_str)? {
+        return check_cmdline(&cmdline_str);
+    }
+
+    // init.config merges a file from a share in, the cmdline still wins
+    let mut config = match cmdline::config_source(&cmdline_str)? {
+        Some(source) => {
+            info!(
+                "loading config file {} from virtiofs {}",
+                source.path, source.tag
+            );
+            let contents = config_file::read_config_file(&source)?;
+            let config =
+                cmdline::parse_config_file(&cmdline::merge_config_file(&contents, &cmdline_str)?);
+            config_file::release_config_share(
+                config
+                    .as_ref()
+                    .is_ok_and(|config| config.virtiofs_mounts.iter().any(|m| m.tag == source.tag)),
+            );
+            config.with_context(|| format!("Invalid config file {}", source.path))?
+        }
+        None => cmdline::parse_cmdline(&cmdline_str)?,
+    };
+    log::set_log_level(config.log_level);
+    log::set_verbose(config.verbose);
+    if config.timings {
+        timings::enable();
+    }
+    // Before validation, which has to see the expanded paths
+    env::expand_config_vars(&mut config)?;
+    cmdline::validate_config(&config)?;
+
+    if config.print_config {
+        cmdline::print_config(&config)?;
+    }
+
+    if config.rescue {
+        rescue::install_panic_hook(config.console.clone());
+    }
+
+    // Reboot rather than hang if setup gets stuck, e.g. on a virtiofs mount
+    let boot_timer = config
+        .boot_timeout
+        .map(|secs| boot_timeout::arm(std::time::Duration::from_secs(secs)))
+        .transpose()?;
 
-    let config = cmdline::parse_cmdline(&cmdline_str)?;
+    // Mount the remaining kernel filesystems
+    boot_timeout::enter(Phase::KernelMounts);
+    if config.mount_private {
+        system::make_mounts_private(config.dry_run)?;
+    }
+    system::mount_kernel_filesystems(
+        &config.nomount,
+        &config.extra_mounts,
+        system::KernelMountData {
+            proc: config.proc_opts.as_deref(),
+            run: config.run_opts.as_deref(),
+        },
+        rerun,
+        config.dry_run,
+    )?;
+    if let Some(level) = config.printk {
+        sysctl::set_printk_level(level, config.dry_run)?;
+    }
+    // Their mount points live in sysfs, which is mounted now
+    system::mount_lsm_filesystems(
+        config.securityfs,
+        config.selinuxfs.as_deref(),
+        config.dry_run,
+    )?;
+
+    system::mount_tmpfs_mounts(
+        &config.tmpfs_mounts,
+        config.tmpfs_default_opts.as_deref(),
+        config.dry_run,
+    )?;
 
-    println!("kdf-init: parsed configuration:");
-    println!("  virtiofs mounts: {}", config.virtiofs_mounts.len());
-    println!("  symlinks: {}", config.symlinks.len());
-    println!("  env vars: {}", config.env_vars.len());
-    println!("  shell: {:?}", config.shell);
-    println!("  script: {:?}", config.script);
+    // The console device only exists once devtmpfs is mounted
+    if config.dry_run {
+        info!("dry-run: would redirect stdio to /dev/{}", config.console);
+    } else {
+        system::redirect_console(&config.console)?;
+    }
+
+    // Fix the clock before anything timestamps files or checks certificates
+    if let Some(settime) = &config.settime {
+        clock::set_time(settime, config.dry_run)?;
+    }
+
+    // Device nodes go into the devtmpfs just mounted on /dev
+    devnodes::create_device_nodes(&config.device_nodes, config.dry_run)?;
+    if config.devpts {
+        system::mount_devpts(config.dry_run)?;
+    }
+    if let Some(devshm) = &config.devshm {
+        system::mount_devshm(devshm, config.dry_run)?;
+    }
+
+    // /dev/kmsg is only there once devtmpfs is mounted
+    if config.kmsg {
+        log::enable_kmsg();
+    }
+
+    debug!("parsed configuration:");
+    debug!("  root: {:?}", config.root);
+    debug!("  virtiofs mounts: {}", config.virtiofs_mounts.len());
+    debug!("  merge mounts: {}", config.merge_mounts.len());
+    debug!("  9p mounts: {}", config.p9_mounts.len());
+    debug!("  fstab: {:?}", config.fstab);
+    debug!("  squashfs mounts: {}", config.squashfs_mounts.len());
+    debug!("  symlinks: {}", config.symlinks.len());
+    debug!("  env vars: {}", config.env_vars.len());
+    debug!("  env file: {:?}", config.envfile);
+    debug!("  env passthrough: {:?}", config.env_passthrough);
+    debug!("  allow unset vars: {}", config.allow_unset_vars);
+    debug!("  path prepend: {:?}", config.path_prepend);
+    debug!("  path append: {:?}", config.path_append);
+    debug!("  shell: {:?}", config.shell);
+    debug!("  script: {:?}", config.script);
+    debug!("  args: {:?}", config.args);
+    debug!("  argv file: {:?}", config.argv_file);
+    debug!("  cmd fifo: {:?}", config.cmd_fifo);
+    debug!("  probe: {:?}", config.probe);
+    debug!("  cmd ok: {:?}", config.cmd_ok);
+    debug!("  cmd fail: {:?}", config.cmd_fail);
+    debug!("  interp: {:?}", config.interp);
+    debug!("  preexec: {:?}", config.preexec);
+    debug!("  cmd stages: {:?}", config.cmds);
+    debug!("  keep going: {}", config.keep_going);
+    debug!("  cmd retries: {}", config.cmd_retries);
+    debug!("  supervise: {}", config.supervise);
+    debug!("  keepalive: {}", config.keepalive);
+    debug!("  rescue: {}", config.rescue);
+    debug!("  user: {:?}", config.run_as);
+    debug!("  groups: {:?}", config.groups);
+    debug!(
+        "  rlimits: {:?}",
+        config
+            .rlimits
+            .iter()
+            .map(rlimit::format_limit)
+            .collect::<Vec<_>>()
+    );
+    debug!("  watchdog: {:?}", config.watchdog);
+    debug!("  boot timeout: {:?}", config.boot_timeout);
+    debug!("  exec timeout: {:?}", config.exec_timeout);
+    debug!("  ready marker: {:?}", config.ready_marker);
+    debug!("  wait for: {:?}", config.wait_for);
+    debug!("  result file: {:?}", config.result_file);
+    debug!("  reboot on: {}", shutdown::signal_name(config.reboot_on));
+    debug!("  overlay base: {}", config.overlay_base);
+    debug!("  overlay reset: {:?}", config.overlay_reset);
+    debug!("  atomic mounts: {}", config.atomic_mounts);
+    debug!("  skip fs check: {}", config.skip_fs_check);
+    debug!("  verify tags: {}", config.verify_tags);
+    debug!("  tmpfs mounts: {}", config.tmpfs_mounts.len());
+    debug!("  tmpfs default opts: {:?}", config.tmpfs_default_opts);
+    debug!("  proc opts: {:?}", config.proc_opts);
+    debug!("  run opts: {:?}", config.run_opts);
+    debug!("  move mounts: {}", config.move_mounts.len());
+    debug!("  bind mounts: {}", config.bind_mounts.len());
+    debug!("  mount private: {}", config.mount_private);
+    debug!("  securityfs: {}", config.securityfs);
+    debug!("  selinuxfs: {:?}", config.selinuxfs);
+    debug!("  umounts: {:?}", config.umounts);
+    debug!("  device nodes: {}", config.device_nodes.len());
+    debug!("  devpts: {}", config.devpts);
+    debug!("  devshm: {:?}", config.devshm);
+    debug!("  settime: {:?}", config.settime);
+    debug!("  sync rtc: {}", config.sync_rtc);
+    debug!(
+        "  seed: {:?}",
+        config.seed.as_ref().map(|seed| match seed {
+            cmdline::Seed::Bytes(bytes) => format!("{} bytes", bytes.len()),
+            cmdline::Seed::File(file) => format!("{}:{}", file.tag, file.path),
+        })
+    );
+    debug!("  sysctls: {:?}", config.sysctls);
+    debug!("  loopback: {}", config.loopback);
+    debug!("  ip: {:?}", config.ip);
+    debug!("  dhcp: {:?}", config.dhcp);
+    debug!("  nameservers: {:?}", config.nameservers);
+    debug!("  cgroup2: {}", config.cgroup2);
+    debug!("  cgroup path: {:?}", config.cgroup_path);
+    debug!(
+        "  unshare: {:?}",
+        namespace::namespace_names(config.unshare)
+    );
+    debug!("  dry-run: {}", config.dry_run);
+    debug!("  log level: {:?}", config.log_level);
+    debug!("  kmsg: {}", config.kmsg);
+    debug!("  printk: {:?}", config.printk);
+    debug!("  verbose: {}", config.verbose);
+    debug!("  timings: {}", config.timings);
+    debug!("  print config: {}", config.print_config);
 
     // Load kernel modules from configured directory
-    system::load_kernel_modules(config.moddir.as_deref())?;
+    boot_timeout::enter(Phase::Modules);
+    system::load_kernel_modules(config.moddir.as_deref(), config.dry_run)?;
+
+    // After the modules, which may register sysctls of their own
+    sysctl::apply_sysctls(&config.sysctls, config.dry_run)?;
+
+    // As early as possible, but init.seedfile may need virtiofs loaded
+    if let Some(seed) = &config.seed {
+        entropy::seed_rng(seed, config.dry_run)?;
+    }
+
+    // Also after the modules, virtio_net may be one of them
+    if config.loopback || config.ip.is_some() || config.dhcp.is_some() {
+        net::bring_up_loopback(config.dry_run)?;
+    }
+    if let Some(ip) = &config.ip {
+        net::configure_network(ip, config.dry_run)?;
+    }
+    // init.nameserver first, then whatever the lease names
+    let mut nameservers = config.nameservers.clone();
+    if let Some(iface) = &config.dhcp {
+        nameservers.extend(net::configure_dhcp(iface, config.dry_run)?);
+    }
 
     // Mount virtiofs shares with optional overlayfs
-    virtiofs::mount_virtiofs_shares(&config.virtiofs_mounts)?;
+    boot_timeout::enter(Phase::Virtiofs);
 
-    // TODO: Create symlinks
+    // A missing tag fails here, not as a generic mount(2) error
+    if config.verify_tags {
+        virtiofs::verify_tags(config.virtiofs_tags(), config.dry_run)?;
+    }
 
-    // Set environment variables
-    for (key, value) in &config.env_vars {
-        println!("kdf-init: setting env var: {}={}", key, value);
-        std::env::set_var(key, value);
+    // Switch to the virtiofs root first, so everything below lands inside it
+    if let Some(tag) = &config.root {
+        root::switch_root(tag, config.dry_run)?;
     }
+    virtiofs::mount_virtiofs_shares(
+        &config.virtiofs_mounts,
+        &virtiofs::OverlaySettings {
+            base: &config.overlay_base,
+            tmpfs_size: config.overlay_tmpfs_size,
+            reset: config.overlay_reset,
+        },
+        config.mount_retries,
+        config.atomic_mounts,
+        rerun,
+        config.skip_fs_check,
+        config.dry_run,
+    )?;
+
+    // Merged overlays, their shares may also be mounted above on their own
+    virtiofs::mount_merged_overlays(
+        &config.merge_mounts,
+        &config.overlay_base,
+        config.mount_retries,
+        config.atomic_mounts,
+        rerun,
+        config.skip_fs_check,
+        config.dry_run,
+    )?;
+
+    // Mount 9p shares
+    p9::mount_p9_shares(&config.p9_mounts, config.skip_fs_check, config.dry_run)?;
+
+    // Entries may mount more shares, or build on the ones above
+    if let Some(file) = &config.fstab {
+        fstab::mount_fstab(file, rerun, config.dry_run)?;
+    }
+
+    // Images usually come from one of the shares above
+    squashfs::mount_squashfs_images(&config.squashfs_mounts, config.dry_run)?;
+
+    // Relocate mounts staged above, e.g. under /run
+    system::move_mounts(&config.move_mounts, rerun, config.dry_run)?;
+    system::bind_mounts(&config.bind_mounts, rerun, config.dry_run)?;
+
+    // Create symlinks, only once every share is mounted so links under a
+    // mount path land inside it (see cmdline::validate_config)
+    boot_timeout::enter(Phase::Symlinks);
+    system::create_symlinks(&config.symlinks, config.dry_run)?;
+
+    // /etc may live on a share or the new root, so only now
+    net::write_resolv_conf(&nameservers, config.dry_run)?;
 
-    // Change directory if specified
+    // Set environment variables, init.envfile may live on a share
+    boot_timeout::enter(Phase::Env);
+    let env_vars = env::with_env_file(&config.env_vars, config.envfile.as_deref(), config.dry_run)?;
+    let env_vars = env::with_host_env(&env_vars, &config.env_passthrough, config.dry_run)?;
+    env::apply_env_vars(&env_vars, config.dry_run);
+    env::apply_path(
+        &env_vars,
+        &config.path_prepend,
+        &config.path_append,
+        config.dry_run,
+    );
+
+    // Change directory if specified, now that every share is mounted
     if let Some(chdir) = &config.chdir {
-        println!("kdf-init: changing directory to: {}", chdir);
-        std::env::set_current_dir(chdir)?;
+        if config.dry_run {
+            info!("dry-run: would change directory to: {}", chdir);
+        } else {
+            info!("changing directory to: {}", chdir);
+            if !std::path::Path::new(chdir).is_dir() {
+                anyhow::bail!("init.chdir directory does not exist: {}", chdir);
+            }
+            rustix::process::chdir(chdir.as_str())
+                .with_context(|| format!("Failed to change directory to {}", chdir))?;
+        }
+    }
+
+    // Move init into its cgroup so the command inherits it
+    cgroup::setup_cgroups(
+        config.cgroup2,
+        config.cgroup_path.as_deref(),
+        config.dry_run,
+    )?;
+
+    // Host-side setup may still be creating files the command needs
+    system::wait_for_paths(&config.wait_for, config.dry_run)?;
+
+    // The argv file usually lives on a share, which is mounted by now
+    if let Some(path) = &config.argv_file {
+        if config.dry_run {
+            info!("dry-run: would read the command argv from {}", path);
+        } else {
+            config.args = exec::read_argv_file(path)?;
+        }
+    }
+    // Blocks until the host pushes the command, or init.cmd_fifo times out
+    if let Some(fifo) = &config.cmd_fifo {
+        if config.dry_run {
+            info!(
+                "dry-run: would wait up to {}s for a command on {}",
+                fifo.timeout, fifo.path
+            );
+        } else {
+            config.args = exec::read_fifo_command(fifo)?;
+        }
+    }
+
+    // Stop short of handing over the console in dry-run
+    if config.dry_run {
+        if let Some((program, args)) = config.preexec.split_first() {
+            info!(
+                "dry-run: would run preexec command: {}",
+                cmdline::command_display(&(program.clone(), args.to_vec()))
+            );
+        }
+        if let (Some(probe), Some(cmd_ok), Some(cmd_fail)) =
+            (&config.probe, &config.cmd_ok, &config.cmd_fail)
+        {
+            info!(
+                "dry-run: would run probe command {}, then {} if it succeeds or {} otherwise",
+                probe, cmd_ok, cmd_fail
+            );
+        }
+        system::unmount_paths(&config.umounts, config.dry_run)?;
+        let commands = config.commands()?;
+        let supervised = exec::run_mode(&config) == exec::RunMode::Supervise;
+        for stage in &commands {
+            info!(
+                "dry-run: would {} command: {}",
+                if supervised { "supervise" } else { "exec" },
+                cmdline::command_display(&stage.command)
+            );
+        }
+        let late_source = config
+            .argv_file
+            .as_ref()
+            .or(config.cmd_fifo.as_ref().map(|fifo| &fifo.path));
+        if let Some(path) = late_source.filter(|_| commands.is_empty()) {
+            info!(
+                "dry-run: would {} the command read from {}",
+                if supervised { "supervise" } else { "exec" },
+                path
+            );
+        } else if commands.is_empty() && config.probe.is_none() {
+            info!(
+                "dry-run: would start interactive shell: {}",
+                cmdline::command_display(&config.shell)
+            );
+        }
+        system::mark_setup_done(config.dry_run);
+        if let Some(marker) = &config.ready_marker {
+            info!("dry-run: would emit ready marker: {:?}", marker);
+        }
+        timings::log_summary();
+        info!("dry-run complete");
+        system::shutdown()?;
+        return Ok(());
+    }
+
+    // Setup is done, the command may legitimately run for longer
+    boot_timeout::enter(Phase::Exec);
+    if let Some(boot_timer) = boot_timer {
+        boot_timer.disarm();
+    }
+
+    let mode = exec::run_mode(&config);
+    let supervised = mode == exec::RunMode::Supervise;
+    if config.watchdog.is_some() && !supervised {
+        warn!("init.watchdog only takes effect with a supervised command (init.supervise=Y)");
+    }
+    if config.exec_timeout.is_some() && !supervised {
+        warn!("init.exec_timeout only takes effect with a supervised command (init.supervise=Y)");
+    }
+    if config.result_file.is_some() && !supervised {
+        warn!("init.result_file only takes effect with a supervised command (init.supervise=Y)");
+    }
+    if config.keepalive && !supervised {
+        warn!("init.keepalive only takes effect with init.script, init.arg.N or init.cmd.N");
+    }
+    if config.keep_going && config.cmds.is_empty() {
+        warn!("init.keep_going only takes effect with init.cmd.N");
+    }
+    if config.cmd_retries > 0 && !supervised {
+        warn!("init.cmd_retries only takes effect with a supervised command (init.supervise=Y)");
+    }
+
+    // One-shot setup step, the boot is aborted if it fails
+    if !config.preexec.is_empty() {
+        let status = match exec::run_preexec(&config) {
+            Ok(status) => status,
+            Err(e) => return rescue_and_shutdown(&config, e),
+        };
+        if let Some(code) = exec::preexec_failure(status) {
+            error!(
+                "preexec command failed with exit code {}, aborting boot",
+                code
+            );
+            exit_code::report_exit(status, config.result_file.as_deref());
+            system::shutdown()?;
+            return Ok(());
+        }
+    }
+
+    // Its exit status picks the command, which then runs like init.script
+    if config.probe.is_some() {
+        match exec::run_probe(&config) {
+            Ok(command) => config.script = Some(command),
+            Err(e) => return rescue_and_shutdown(&config, e),
+        }
+    }
+
+    // After preexec and the probe, which may still need what goes away here
+    system::unmount_paths(&config.umounts, config.dry_run)?;
+
+    // A re-exec'd init skips whatever this run already mounted
+    system::mark_setup_done(config.dry_run);
+
+    timings::log_summary();
+
+    // Last thing before handing over, so the harness knows setup succeeded
+    if let Some(marker) = &config.ready_marker {
+        system::emit_ready_marker(marker);
     }
 
-    // Execute shell
-    let (program, args) = &config.shell;
-    let display_cmd = if args.is_empty() {
-        program.to_string()
+    // Execute command if specified, replacing init unless supervised
+    if mode == exec::RunMode::Exec {
+        match exec::exec_command(&config) {
+            Ok(never) => match never {},
+            Err(e) if exec::privileges_dropped() => {
+                error!("init dropped root setting up the command, can't rescue or power off");
+                return Err(e);
+            }
+            Err(e) => return rescue_and_shutdown(&config, e),
+        }
+    }
+
+    let exit_status = if supervised {
+        let exit = match exec::run_supervised(&config) {
+            Ok(exit) => exit,
+            Err(e) => return rescue_and_shutdown(&config, e),
+        };
+
+        let (reaper::ChildExit::Exited(status)
+        | reaper::ChildExit::Shutdown(status)
+        | reaper::ChildExit::TimedOut(status)) = exit;
+        exit_code::report_exit(status, config.result_file.as_deref());
+
+        // init.keepalive still kills a timed out command, but keeps the VM up
+        if let reaper::ChildExit::TimedOut(_) = exit {
+            error!(
+                "command timed out after {}s (init.exec_timeout), {}",
+                config.exec_timeout.unwrap_or_default(),
+                if config.keepalive {
+                    "keeping init alive"
+                } else {
+                    "powering off"
+                }
+            );
+            if !config.keepalive {
+                system::shutdown()?;
+                return Ok(());
+            }
+        }
+
+        if config.keepalive {
+            info!(
+                "command exited with status {:?}, starting shell (init.keepalive)",
+                status.code()
+            );
+            let (program, args) = &config.shell;
+            system::execute_shell(program, args, &config.console)?;
+        }
+        status
     } else {
-        format!("{} {}", program, args.join(" "))
-    };
-    println!("kdf-init: starting interactive shell: {}", display_cmd);
+        // Execute shell
+        let (program, args) = &config.shell;
+        info!(
+            "starting interactive shell: {}",
+            cmdline::command_display(&config.shell)
+        );
 
-    let exit_status = system::execute_shell(program, args, &config.console)?;
+        system::execute_shell(program, args, &config.console)?
+    };
 
     if exit_status.success() {
-        println!("kdf-init: shell exited successfully");
+        info!("command exited successfully");
     } else {
-        eprintln!(
-            "kdf-init: shell exited with status: {:?}",
-            exit_status.code()
-        );
+        warn!("command exited with status: {:?}", exit_status.code());
     }
 
-    // TODO: Handle optional script execution
-    if config.script.is_some() {
-        eprintln!("kdf-init: init.script is not yet implemented");
+    info!("initialization complete");
+
+    // Graceful from here on, the next boot may keep the persistent uppers
+    overlay_reset::mark_clean(
+        config.overlay_reset,
+        &virtiofs::persistent_upper_dirs(&config.virtiofs_mounts, &config.overlay_base),
+        config.dry_run,
+    );
+    if config.sync_rtc {
+        clock::sync_rtc(config.dry_run);
     }
 
-    println!("kdf-init: initialization complete");
+    // The command asks for a warm reboot by dying of init.reboot_on
+    if supervised
+        && !config.keepalive
+        && shutdown::exit_action(exit_status, config.reboot_on) == shutdown::ExitAction::Reboot
+    {
+        info!(
+            "command was killed by {} (init.reboot_on)",
+            shutdown::signal_name(config.reboot_on)
+        );
+        system::reboot()?;
+        return Ok(());
+    }
 
     // Shutdown the system
     system::shutdown()?;
 
     Ok(())
 }
+
+/// Validate the cmdline for init.check=Y, report the result and power off
+///
+/// Prints "OK" or the first error on the console and reports exit code 0
+/// or 1 like a supervised command. init.config is not read, that would need
+/// a mount.
+fn check_cmdline(cmdline_str: &str) -> Result<()> {
+    if cmdline::config_source(cmdline_str).is_ok_and(|source| source.is_some()) {
+        warn!("init.check validates the cmdline only, init.config is not read");
+    }
+
+    // Expanded like a real boot does, an unset ${VAR} fails there too
+    let result = cmdline::parse_cmdline(cmdline_str).and_then(|mut config| {
+        env::expand_config_vars(&mut config)?;
+        cmdline::validate_config(&config)
+    });
+    let code = match result {
+        Ok(()) => {
+            println!("OK");
+            0
+        }
+        Err(e) => {
+            println!("{:#}", e);
+            1
+        }
+    };
+    exit_code::report_code(code, None);
+
+    system::shutdown()
+}
+
+/// Enter the rescue shell for a command that failed to start, then power off
+///
+/// Without init.rescue (or for unrecoverable errors) the error is returned.
+fn rescue_and_shutdown(config: &cmdline::Config, err: anyhow::Error) -> Result<()> {
+    rescue::handle_exec_failure(config, err)?;
+    system::shutdown()
+}
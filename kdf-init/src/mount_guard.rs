@@ -0,0 +1,110 @@
+//! All-or-nothing mounting (init.atomic_mounts)
+
+use rustix::mount::UnmountFlags;
+
+/// Unmounts everything recorded so far when dropped, unless committed
+///
+/// Targets are unmounted in reverse, so an overlay goes before its lower
+/// and a mount before the one it was made on.
+pub struct MountGuard<F: FnMut(&str) -> rustix::io::Result<()>> {
+    mounted: Vec<String>,
+    unmount: F,
+}
+
+/// Unmount a single rolled back target
+fn unmount_target(target: &str) -> rustix::io::Result<()> {
+    rustix::mount::unmount(target, UnmountFlags::empty())
+}
+
+impl MountGuard<fn(&str) -> rustix::io::Result<()>> {
+    pub fn new() -> Self {
+        Self::with_unmount(unmount_target)
+    }
+}
+
+impl<F: FnMut(&str) -> rustix::io::Result<()>> MountGuard<F> {
+    /// Guard unmounting with `unmount` instead of umount(2)
+    pub fn with_unmount(unmount: F) -> Self {
+        Self {
+            mounted: Vec::new(),
+            unmount,
+        }
+    }
+
+    /// Record a successful mount at `target`, to undo on failure
+    pub fn record(&mut self, target: &str) {
+        self.mounted.push(target.to_string());
+    }
+
+    /// Keep everything mounted, the guard unmounts nothing when dropped
+    pub fn commit(mut self) {
+        self.mounted.clear();
+    }
+}
+
+impl<F: FnMut(&str) -> rustix::io::Result<()>> Drop for MountGuard<F> {
+    fn drop(&mut self) {
+        while let Some(target) = self.mounted.pop() {
+            match (self.unmount)(&target) {
+                Ok(()) => info!("rolled back mount at {}", target),
+                // Nothing to do about it, the original error is what counts
+                Err(e) => warn!("failed to roll back mount at {}: {}", target, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_drop_unmounts_in_reverse() {
+        let unmounted = RefCell::new(Vec::new());
+        {
+            let mut guard = MountGuard::with_unmount(|target: &str| {
+                unmounted.borrow_mut().push(target.to_string());
+                Ok(())
+            });
+            for target in ["/mnt/a", "/run/overlayfs/b/lower", "/mnt/b"] {
+                guard.record(target);
+            }
+        }
+        assert_eq!(
+            unmounted.into_inner(),
+            vec!["/mnt/b", "/run/overlayfs/b/lower", "/mnt/a"]
+        );
+    }
+
+    #[test]
+    fn test_drop_continues_after_unmount_failure() {
+        let unmounted = RefCell::new(Vec::new());
+        {
+            let mut guard = MountGuard::with_unmount(|target: &str| {
+                unmounted.borrow_mut().push(target.to_string());
+                if target == "/mnt/b" {
+                    Err(rustix::io::Errno::BUSY)
+                } else {
+                    Ok(())
+                }
+            });
+            for target in ["/mnt/a", "/mnt/b", "/mnt/c"] {
+                guard.record(target);
+            }
+        }
+        assert_eq!(unmounted.into_inner(), vec!["/mnt/c", "/mnt/b", "/mnt/a"]);
+    }
+
+    #[test]
+    fn test_commit_keeps_mounts() {
+        let unmounted = RefCell::new(Vec::new());
+        let mut guard = MountGuard::with_unmount(|target: &str| {
+            unmounted.borrow_mut().push(target.to_string());
+            Ok(())
+        });
+        guard.record("/mnt/a");
+        guard.commit();
+        assert!(unmounted.into_inner().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! Fresh namespaces for the payload (init.unshare)
+//!
+//! Every namespace but pid is unshared by the command itself, right before
+//! it execs. A new pid namespace only applies to children of the caller, so
+//! init unshares it for the supervised command and switches back afterwards,
+//! making the command PID 1 of its own namespace.
+
+use anyhow::{Context, Result};
+use rustix::fd::{AsFd, OwnedFd};
+use rustix::fs::{Mode, OFlags};
+use rustix::thread::{LinkNameSpaceType, UnshareFlags};
+
+/// init.unshare names and the namespaces they stand for
+const NAMESPACES: [(&str, UnshareFlags); 8] = [
+    ("mount", UnshareFlags::NEWNS),
+    ("pid", UnshareFlags::NEWPID),
+    ("net", UnshareFlags::NEWNET),
+    ("uts", UnshareFlags::NEWUTS),
+    ("ipc", UnshareFlags::NEWIPC),
+    ("user", UnshareFlags::NEWUSER),
+    ("cgroup", UnshareFlags::NEWCGROUP),
+    ("time", UnshareFlags::NEWTIME),
+];
+
+/// Parse an init.unshare value into the namespaces to unshare
+///
+/// Example: "mount,net" -> CLONE_NEWNS | CLONE_NEWNET
+pub fn parse_namespaces(value: &str) -> Result<UnshareFlags> {
+    let mut flags = UnshareFlags::empty();
+    for name in value.split(',') {
+        let (_, flag) = NAMESPACES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .with_context(|| {
+                format!(
+                    "Unknown namespace: {:?} (expected {})",
+                    name,
+                    namespace_names(UnshareFlags::all()).join(", ")
+                )
+            })?;
+        flags |= *flag;
+    }
+    Ok(flags)
+}
+
+/// init.unshare names of the namespaces in `flags`, in table order
+pub fn namespace_names(flags: UnshareFlags) -> Vec<&'static str> {
+    NAMESPACES
+        .iter()
+        .filter(|(_, flag)| flags.contains(*flag))
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// The namespaces the command unshares itself, before it execs
+pub fn exec_flags(flags: UnshareFlags) -> UnshareFlags {
+    flags - UnshareFlags::NEWPID
+}
+
+/// Init's own pid namespace, entered again once the command is spawned
+pub struct PidNamespaceGuard {
+    own: OwnedFd,
+}
+
+impl PidNamespaceGuard {
+    /// Have init's next child start a new pid namespace, with itself as PID 1
+    ///
+    /// Unsharing only changes the namespace for children, so init stays in
+    /// its own. Dropping the guard switches back, otherwise the next child
+    /// would be forked into the namespace of this one.
+    pub fn unshare() -> Result<Self> {
+        let own = rustix::fs::open("/proc/self/ns/pid", OFlags::RDONLY, Mode::empty())
+            .context("Failed to open /proc/self/ns/pid")?;
+        rustix::thread::unshare(UnshareFlags::NEWPID)
+            .context("Failed to unshare the pid namespace")?;
+        Ok(Self { own })
+    }
+}
+
+impl Drop for PidNamespaceGuard {
+    fn drop(&mut self) {
+        if let Err(e) = rustix::thread::move_into_link_name_space(
+            self.own.as_fd(),
+            Some(LinkNameSpaceType::ProcessID),
+        ) {
+            error!("failed to return to init's pid namespace: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_namespaces() {
+        assert_eq!(parse_namespaces("mount").unwrap(), UnshareFlags::NEWNS);
+        assert_eq!(
+            parse_namespaces("mount,pid,net").unwrap(),
+            UnshareFlags::NEWNS | UnshareFlags::NEWPID | UnshareFlags::NEWNET
+        );
+        assert_eq!(
+            parse_namespaces("uts,ipc,user,cgroup,time").unwrap(),
+            UnshareFlags::NEWUTS
+                | UnshareFlags::NEWIPC
+                | UnshareFlags::NEWUSER
+                | UnshareFlags::NEWCGROUP
+                | UnshareFlags::NEWTIME
+        );
+        // Repeating a namespace is harmless
+        assert_eq!(parse_namespaces("net,net").unwrap(), UnshareFlags::NEWNET);
+    }
+
+    #[test]
+    fn test_parse_namespaces_unknown() {
+        let err = parse_namespaces("mount,bogus").unwrap_err();
+        assert!(err.to_string().contains("Unknown namespace: \"bogus\""));
+        assert!(err.to_string().contains("mount, pid, net"));
+
+        for value in ["", "mount,", "Mount", "newns"] {
+            assert!(parse_namespaces(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_namespace_names() {
+        assert_eq!(
+            namespace_names(parse_namespaces("net,mount").unwrap()),
+            vec!["mount", "net"]
+        );
+        assert!(namespace_names(UnshareFlags::empty()).is_empty());
+    }
+
+    #[test]
+    fn test_exec_flags_leave_pid_to_init() {
+        let flags = parse_namespaces("mount,pid").unwrap();
+        assert_eq!(exec_flags(flags), UnshareFlags::NEWNS);
+    }
+}
@@ -0,0 +1,355 @@
+//! Network interface bring-up from init.ip, over rtnetlink
+
+use anyhow::{Context, Result};
+use rustix::fd::OwnedFd;
+use rustix::net::{AddressFamily, RecvFlags, SendFlags, SocketType};
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::cmdline::IpConfig;
+use crate::virtiofs;
+
+#[cfg(feature = "dhcp")]
+mod dhcp;
+#[cfg(feature = "dhcp")]
+pub use dhcp::configure_dhcp;
+
+/// Resolver configuration written for init.nameserver
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// Message types, flags and attributes from <linux/netlink.h> and
+// <linux/rtnetlink.h>
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+
+const AF_INET: u8 = 2;
+const IFF_UP: u32 = 0x1;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+/// Length of struct nlmsghdr
+const NLMSG_HDRLEN: usize = 16;
+
+/// Round a netlink length up to the 4 byte alignment
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Append a route attribute (struct rtattr) holding `data`
+fn push_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// A complete netlink request: header, then the `body` as is
+fn request(kind: u16, flags: u16, seq: u32, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(NLMSG_HDRLEN + body.len());
+    msg.extend_from_slice(&((NLMSG_HDRLEN + body.len()) as u32).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+    msg.extend_from_slice(&seq.to_ne_bytes());
+    // Port id 0, the kernel fills in ours
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+/// RTM_NEWLINK setting IFF_UP on interface `index`
+fn link_up_request(index: u32, seq: u32) -> Vec<u8> {
+    // struct ifinfomsg
+    let mut body = vec![0, 0];
+    body.extend_from_slice(&0u16.to_ne_bytes());
+    body.extend_from_slice(&(index as i32).to_ne_bytes());
+    body.extend_from_slice(&IFF_UP.to_ne_bytes());
+    body.extend_from_slice(&IFF_UP.to_ne_bytes());
+    request(RTM_NEWLINK, 0, seq, &body)
+}
+
+/// RTM_NEWADDR assigning `address/prefix_len` to interface `index`
+///
+/// Replaces an existing identical address, so a re-run doesn't fail.
+fn add_address_request(index: u32, address: Ipv4Addr, prefix_len: u8, seq: u32) -> Vec<u8> {
+    // struct ifaddrmsg
+    let mut body = vec![AF_INET, prefix_len, 0, RT_SCOPE_UNIVERSE];
+    body.extend_from_slice(&index.to_ne_bytes());
+    push_attr(&mut body, IFA_LOCAL, &address.octets());
+    push_attr(&mut body, IFA_ADDRESS, &address.octets());
+    request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE, seq, &body)
+}
+
+/// RTM_NEWROUTE adding a default route via `gateway` out of interface `index`
+fn default_route_request(index: u32, gateway: Ipv4Addr, seq: u32) -> Vec<u8> {
+    // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+    let mut body = vec![
+        AF_INET,
+        0,
+        0,
+        0,
+        RT_TABLE_MAIN,
+        RTPROT_BOOT,
+        RT_SCOPE_UNIVERSE,
+        RTN_UNICAST,
+    ];
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    push_attr(&mut body, RTA_GATEWAY, &gateway.octets());
+    push_attr(&mut body, RTA_OIF, &index.to_ne_bytes());
+    request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, seq, &body)
+}
+
+/// Error code of the NLMSG_ERROR acknowledging request `seq`
+///
+/// 0 means success, anything else is a negated errno.
+fn ack_error(reply: &[u8], seq: u32) -> Result<i32> {
+    let field = |offset: usize, len: usize| {
+        reply
+            .get(offset..offset + len)
+            .context("Truncated netlink reply")
+    };
+    let kind = u16::from_ne_bytes(field(4, 2)?.try_into()?);
+    let reply_seq = u32::from_ne_bytes(field(8, 4)?.try_into()?);
+    if kind != NLMSG_ERROR {
+        anyhow::bail!("Unexpected netlink reply type {}", kind);
+    }
+    if reply_seq != seq {
+        anyhow::bail!("Netlink reply for request {}, expected {}", reply_seq, seq);
+    }
+    Ok(i32::from_ne_bytes(field(NLMSG_HDRLEN, 4)?.try_into()?))
+}
+
+/// Send a request and wait for the kernel to acknowledge it
+fn transact(socket: &OwnedFd, msg: &[u8], seq: u32) -> Result<()> {
+    rustix::net::send(socket, msg, SendFlags::empty()).context("Failed to send netlink request")?;
+
+    let mut reply = [0u8; 1024];
+    let len = rustix::net::recv(socket, &mut reply, RecvFlags::empty())
+        .context("Failed to receive netlink reply")?;
+    match ack_error(&reply[..len], seq)? {
+        0 => Ok(()),
+        err => Err(std::io::Error::from_raw_os_error(-err).into()),
+    }
+}
+
+/// Index of network interface `name`
+fn interface_index(name: &str) -> Result<u32> {
+    // Any socket will do for the SIOCGIFINDEX ioctl
+    let inet = rustix::net::socket(AddressFamily::INET, SocketType::DGRAM, None)
+        .context("Failed to create socket")?;
+    rustix::net::netdevice::name_to_index(&inet, name)
+        .with_context(|| format!("Network interface {} not found", name))
+}
+
+/// A NETLINK_ROUTE socket talking to the kernel
+fn netlink_socket() -> Result<OwnedFd> {
+    // NETLINK_ROUTE is protocol 0, the default
+    rustix::net::socket(AddressFamily::NETLINK, SocketType::RAW, None)
+        .context("Failed to create netlink socket")
+}
+
+/// Bring up the loopback interface, for init.loopback and init.ip
+///
+/// Setting IFF_UP on an interface that is already up changes nothing.
+pub fn bring_up_loopback(dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("dry-run: would bring up lo");
+        return Ok(());
+    }
+
+    let index = interface_index("lo")?;
+    transact(&netlink_socket()?, &link_up_request(index, 1), 1).context("Failed to bring up lo")?;
+    info!("brought up lo");
+    Ok(())
+}
+
+/// Bring up the init.ip interface and configure it
+///
+/// The address replaces an identical one and the default route replaces
+/// any existing one, so running this twice is harmless.
+pub fn configure_network(ip: &IpConfig, dry_run: bool) -> Result<()> {
+    let what = format!("{}/{} on {}", ip.address, ip.prefix_len, ip.iface);
+    if dry_run {
+        info!("dry-run: would bring up {}, assigning {}", ip.iface, what);
+        if let Some(gateway) = ip.gateway {
+            info!("dry-run: would add default route via {}", gateway);
+        }
+        return Ok(());
+    }
+
+    let index = interface_index(&ip.iface)?;
+    let socket = netlink_socket()?;
+
+    transact(&socket, &link_up_request(index, 1), 1)
+        .with_context(|| format!("Failed to bring up {}", ip.iface))?;
+    transact(
+        &socket,
+        &add_address_request(index, ip.address, ip.prefix_len, 2),
+        2,
+    )
+    .with_context(|| format!("Failed to assign {}", what))?;
+    info!("configured {}", what);
+
+    if let Some(gateway) = ip.gateway {
+        transact(&socket, &default_route_request(index, gateway, 3), 3)
+            .with_context(|| format!("Failed to add default route via {}", gateway))?;
+        info!("added default route via {}", gateway);
+    }
+
+    Ok(())
+}
+
+/// Stand-in for init.dhcp in builds without the dhcp feature
+#[cfg(not(feature = "dhcp"))]
+pub fn configure_dhcp(iface: &str, _dry_run: bool) -> Result<Vec<IpAddr>> {
+    anyhow::bail!("init.dhcp={} needs init built with the dhcp feature", iface)
+}
+
+/// /etc/resolv.conf contents, one nameserver line per server, in order
+fn resolv_conf(nameservers: &[IpAddr]) -> String {
+    nameservers
+        .iter()
+        .map(|server| format!("nameserver {}\n", server))
+        .collect()
+}
+
+/// Write /etc/resolv.conf for init.nameserver, replacing any existing one
+pub fn write_resolv_conf(nameservers: &[IpAddr], dry_run: bool) -> Result<()> {
+    if nameservers.is_empty() {
+        return Ok(());
+    }
+
+    virtiofs::mkdir_p("/etc", virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+    if dry_run {
+        info!(
+            "dry-run: would write {} with {:?}",
+            RESOLV_CONF, nameservers
+        );
+        return Ok(());
+    }
+
+    std::fs::write(RESOLV_CONF, resolv_conf(nameservers))
+        .with_context(|| format!("Failed to write {}", RESOLV_CONF))?;
+    info!(
+        "wrote {} with {} nameserver(s)",
+        RESOLV_CONF,
+        nameservers.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(msg: &[u8]) -> (u32, u16, u16, u32) {
+        (
+            u32::from_ne_bytes(msg[0..4].try_into().unwrap()),
+            u16::from_ne_bytes(msg[4..6].try_into().unwrap()),
+            u16::from_ne_bytes(msg[6..8].try_into().unwrap()),
+            u32::from_ne_bytes(msg[8..12].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_link_up_request() {
+        let msg = link_up_request(3, 7);
+        // Header plus the 16 byte ifinfomsg
+        assert_eq!(
+            header(&msg),
+            (32, RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, 7)
+        );
+        assert_eq!(&msg[20..24], &3i32.to_ne_bytes());
+        assert_eq!(&msg[24..28], &IFF_UP.to_ne_bytes());
+        assert_eq!(&msg[28..32], &IFF_UP.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_loopback_up_request() {
+        // lo is registered first, so it normally gets index 1
+        let msg = link_up_request(1, 1);
+        assert_eq!(msg.len(), 32);
+        assert_eq!(
+            header(&msg),
+            (32, RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, 1)
+        );
+        // ifinfomsg: unspecified family and type, then index, flags and change mask
+        assert_eq!(&msg[16..20], &[0, 0, 0, 0]);
+        assert_eq!(&msg[20..24], &1i32.to_ne_bytes());
+        assert_eq!(&msg[24..28], &IFF_UP.to_ne_bytes());
+        // Only IFF_UP is changed, the other link flags are left alone
+        assert_eq!(&msg[28..32], &IFF_UP.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_add_address_request() {
+        let msg = add_address_request(2, Ipv4Addr::new(10, 0, 2, 15), 24, 1);
+        let (len, kind, flags, _) = header(&msg);
+        // Header, 8 byte ifaddrmsg and two 8 byte attributes
+        assert_eq!(len as usize, msg.len());
+        assert_eq!(msg.len(), 40);
+        assert_eq!(kind, RTM_NEWADDR);
+        assert_eq!(
+            flags,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE
+        );
+        assert_eq!(&msg[16..20], &[AF_INET, 24, 0, RT_SCOPE_UNIVERSE]);
+        assert_eq!(&msg[24..26], &8u16.to_ne_bytes());
+        assert_eq!(&msg[26..28], &IFA_LOCAL.to_ne_bytes());
+        assert_eq!(&msg[28..32], &[10, 0, 2, 15]);
+        assert_eq!(&msg[36..40], &[10, 0, 2, 15]);
+    }
+
+    #[test]
+    fn test_default_route_request() {
+        let msg = default_route_request(2, Ipv4Addr::new(10, 0, 2, 2), 1);
+        let (len, kind, _, _) = header(&msg);
+        // Header, 12 byte rtmsg and two 8 byte attributes
+        assert_eq!(len, 44);
+        assert_eq!(kind, RTM_NEWROUTE);
+        // Default route: no destination prefix
+        assert_eq!(msg[17], 0);
+        assert_eq!(msg[20], RT_TABLE_MAIN);
+        assert_eq!(&msg[32..36], &[10, 0, 2, 2]);
+        assert_eq!(&msg[40..44], &2u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let nameservers: Vec<IpAddr> = vec![
+            "10.0.2.3".parse().unwrap(),
+            "2606:4700:4700::1111".parse().unwrap(),
+        ];
+        assert_eq!(
+            resolv_conf(&nameservers),
+            "nameserver 10.0.2.3\nnameserver 2606:4700:4700::1111\n"
+        );
+        assert_eq!(resolv_conf(&[]), "");
+    }
+
+    #[test]
+    fn test_ack_error() {
+        let mut reply = request(NLMSG_ERROR, 0, 5, &(-17i32).to_ne_bytes());
+        assert_eq!(ack_error(&reply, 5).unwrap(), -17);
+        assert!(ack_error(&reply, 6).is_err());
+
+        reply[16..20].copy_from_slice(&0i32.to_ne_bytes());
+        assert_eq!(ack_error(&reply, 5).unwrap(), 0);
+
+        assert!(ack_error(&reply[..10], 5).is_err());
+        assert!(ack_error(&request(RTM_NEWLINK, 0, 5, &[0; 4]), 5).is_err());
+    }
+}
@@ -0,0 +1,577 @@
+//! Minimal DHCP client for init.dhcp (RFC 2131)
+//!
+//! Runs a single DISCOVER/OFFER/REQUEST/ACK exchange and applies the lease
+//! like a static init.ip would; the lease is never renewed. rustix has no
+//! packet socket addresses, so instead of a raw socket this uses a UDP
+//! socket on port 68 with a temporary on-link default route, which lets the
+//! kernel broadcast from 0.0.0.0 before the interface has an address.
+
+use anyhow::{Context, Result};
+use rustix::fd::OwnedFd;
+use rustix::io::Errno;
+use rustix::net::sockopt::{self, Timeout};
+use rustix::net::{AddressFamily, RecvFlags, SendFlags, SocketAddrV4, SocketType};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use super::{
+    interface_index, link_up_request, netlink_socket, push_attr, request, transact, AF_INET,
+    NLM_F_CREATE, NLM_F_REPLACE, RTA_OIF, RTM_NEWROUTE, RTN_UNICAST, RTPROT_BOOT, RT_TABLE_MAIN,
+};
+use crate::cmdline::IpConfig;
+
+// Only the temporary route needs these, see <linux/rtnetlink.h>
+const RTM_DELROUTE: u16 = 25;
+const RT_SCOPE_LINK: u8 = 253;
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+// BOOTP header fields, see RFC 951
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHER: u8 = 1;
+/// Ask for broadcast replies, we can't receive unicast without an address
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Offsets into the BOOTP header
+const XID_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 10;
+const YIADDR_OFFSET: usize = 16;
+const CHADDR_OFFSET: usize = 28;
+const COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+
+/// Some servers drop anything shorter than a BOOTP packet
+const MIN_PACKET_LEN: usize = 300;
+
+// Options, see RFC 2132
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_END: u8 = 255;
+
+// DHCP message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Each message is sent this many times before giving up
+const ATTEMPTS: u32 = 4;
+/// How long to wait for a reply to each attempt
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a server handed out in an OFFER or ACK
+#[derive(Debug, Clone, PartialEq)]
+struct Lease {
+    address: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv4Addr>,
+    dns: Vec<Ipv4Addr>,
+    server_id: Ipv4Addr,
+}
+
+/// A DHCP reply: its message type and the lease it carries
+#[derive(Debug, Clone, PartialEq)]
+struct Reply {
+    message_type: u8,
+    lease: Lease,
+}
+
+/// A BOOTREQUEST for `mac` carrying the given options, END and padding
+/// included
+fn request_packet(xid: u32, mac: [u8; 6], options: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut packet = vec![0u8; OPTIONS_OFFSET];
+    packet[0] = BOOTREQUEST;
+    packet[1] = HTYPE_ETHER;
+    packet[2] = mac.len() as u8;
+    packet[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&xid.to_be_bytes());
+    packet[FLAGS_OFFSET..FLAGS_OFFSET + 2].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    packet[CHADDR_OFFSET..CHADDR_OFFSET + 6].copy_from_slice(&mac);
+    packet[COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+    for (code, data) in options {
+        packet.push(*code);
+        packet.push(data.len() as u8);
+        packet.extend_from_slice(data);
+    }
+    packet.push(OPT_END);
+    packet.resize(packet.len().max(MIN_PACKET_LEN), OPT_PAD);
+    packet
+}
+
+/// Options every request asks the server for
+const PARAMETERS: [u8; 3] = [OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS];
+
+/// DHCPDISCOVER broadcast to find a server
+fn build_discover(xid: u32, mac: [u8; 6]) -> Vec<u8> {
+    request_packet(
+        xid,
+        mac,
+        &[
+            (OPT_MESSAGE_TYPE, &[DHCPDISCOVER]),
+            (OPT_PARAMETER_LIST, &PARAMETERS),
+        ],
+    )
+}
+
+/// DHCPREQUEST accepting the `offered` address from `server_id`
+fn build_request(xid: u32, mac: [u8; 6], offered: Ipv4Addr, server_id: Ipv4Addr) -> Vec<u8> {
+    request_packet(
+        xid,
+        mac,
+        &[
+            (OPT_MESSAGE_TYPE, &[DHCPREQUEST]),
+            (OPT_REQUESTED_IP, &offered.octets()),
+            (OPT_SERVER_ID, &server_id.octets()),
+            (OPT_PARAMETER_LIST, &PARAMETERS),
+        ],
+    )
+}
+
+/// Prefix length of a contiguous netmask
+///
+/// Example: 255.255.255.0 -> 24
+fn netmask_prefix_len(mask: Ipv4Addr) -> Result<u8> {
+    let mask = u32::from(mask);
+    let prefix_len = mask.leading_ones();
+    if mask.checked_shl(prefix_len).unwrap_or(0) != 0 {
+        anyhow::bail!("Non-contiguous netmask: {}", Ipv4Addr::from(mask));
+    }
+    Ok(prefix_len as u8)
+}
+
+/// IPv4 addresses in an option, which must be a multiple of 4 bytes
+fn addresses(code: u8, data: &[u8]) -> Result<Vec<Ipv4Addr>> {
+    if data.is_empty() || !data.len().is_multiple_of(4) {
+        anyhow::bail!("Option {} has bad length {}", code, data.len());
+    }
+    Ok(data
+        .chunks_exact(4)
+        .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        .collect())
+}
+
+/// Parse a BOOTREPLY answering transaction `xid`
+fn parse_reply(packet: &[u8], xid: u32) -> Result<Reply> {
+    if packet.len() < OPTIONS_OFFSET {
+        anyhow::bail!("Truncated DHCP reply: {} bytes", packet.len());
+    }
+    if packet[0] != BOOTREPLY {
+        anyhow::bail!("Not a BOOTREPLY: op {}", packet[0]);
+    }
+    let reply_xid = u32::from_be_bytes(packet[XID_OFFSET..XID_OFFSET + 4].try_into()?);
+    if reply_xid != xid {
+        anyhow::bail!(
+            "Reply for transaction {:#x}, expected {:#x}",
+            reply_xid,
+            xid
+        );
+    }
+    if packet[COOKIE_OFFSET..OPTIONS_OFFSET] != MAGIC_COOKIE {
+        anyhow::bail!("Missing DHCP magic cookie");
+    }
+    let yiaddr: [u8; 4] = packet[YIADDR_OFFSET..YIADDR_OFFSET + 4].try_into()?;
+
+    let mut message_type = None;
+    let mut mask = None;
+    let mut gateway = None;
+    let mut dns = Vec::new();
+    let mut server_id = None;
+
+    let mut options = &packet[OPTIONS_OFFSET..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest
+            .split_first()
+            .with_context(|| format!("Option {} is missing its length", code))?;
+        let data = rest
+            .get(..len as usize)
+            .with_context(|| format!("Option {} overruns the packet", code))?;
+        options = &rest[len as usize..];
+
+        match code {
+            OPT_MESSAGE_TYPE => message_type = data.first().copied(),
+            OPT_SUBNET_MASK => mask = addresses(code, data)?.first().copied(),
+            OPT_ROUTER => gateway = addresses(code, data)?.first().copied(),
+            OPT_DNS => dns = addresses(code, data)?,
+            OPT_SERVER_ID => server_id = addresses(code, data)?.first().copied(),
+            _ => {}
+        }
+    }
+
+    let message_type = message_type.context("Reply without a DHCP message type")?;
+    let address = Ipv4Addr::from(yiaddr);
+    let prefix_len = match mask {
+        Some(mask) => netmask_prefix_len(mask)?,
+        // A NAK carries no lease, only its type matters
+        None if message_type == DHCPNAK => 0,
+        None => anyhow::bail!("Reply without a subnet mask"),
+    };
+
+    Ok(Reply {
+        message_type,
+        lease: Lease {
+            address,
+            prefix_len,
+            gateway: gateway.filter(|gateway| !gateway.is_unspecified()),
+            dns,
+            server_id: server_id.context("Reply without a server identifier")?,
+        },
+    })
+}
+
+/// RTM_NEWROUTE or RTM_DELROUTE for an on-link default route out of
+/// interface `index`, with no gateway
+fn link_route_request(kind: u16, index: u32, seq: u32) -> Vec<u8> {
+    let flags = if kind == RTM_NEWROUTE {
+        NLM_F_CREATE | NLM_F_REPLACE
+    } else {
+        0
+    };
+    // struct rtmsg, as in net::default_route_request
+    let mut body = vec![
+        AF_INET,
+        0,
+        0,
+        0,
+        RT_TABLE_MAIN,
+        RTPROT_BOOT,
+        RT_SCOPE_LINK,
+        RTN_UNICAST,
+    ];
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    push_attr(&mut body, RTA_OIF, &index.to_ne_bytes());
+    request(kind, flags, seq, &body)
+}
+
+/// MAC address of `iface`, from sysfs
+fn interface_mac(iface: &str) -> Result<[u8; 6]> {
+    let path = format!("/sys/class/net/{}/address", iface);
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+    parse_mac(text.trim())
+}
+
+/// Parse a colon separated Ethernet address
+///
+/// Example: "52:54:00:12:34:56" -> [0x52, 0x54, 0, 0x12, 0x34, 0x56]
+fn parse_mac(text: &str) -> Result<[u8; 6]> {
+    let octets = text
+        .split(':')
+        .map(|octet| {
+            u8::from_str_radix(octet, 16)
+                .ok()
+                .filter(|_| octet.len() == 2)
+        })
+        .collect::<Option<Vec<u8>>>()
+        .with_context(|| format!("Invalid MAC address: {:?}", text))?;
+    octets
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Not an Ethernet address: {:?}", text))
+}
+
+/// Transaction id, unique enough to tell our replies from other clients'
+fn transaction_id(mac: [u8; 6]) -> u32 {
+    let now = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    (now.tv_nsec as u32) ^ u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]])
+}
+
+/// Broadcast `packet` and wait for a reply of type `expected`
+///
+/// Replies for other transactions or of other types are skipped, a NAK
+/// fails the exchange.
+fn exchange(socket: &OwnedFd, packet: &[u8], xid: u32, expected: u8) -> Result<Lease> {
+    let server = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    let mut buf = [0u8; 1500];
+
+    for attempt in 1..=ATTEMPTS {
+        rustix::net::sendto_v4(socket, packet, SendFlags::empty(), &server)
+            .context("Failed to send DHCP request")?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            sockopt::set_socket_timeout(socket, Timeout::Recv, Some(remaining))?;
+            let len = match rustix::net::recv(socket, &mut buf, RecvFlags::empty()) {
+                Ok(len) => len,
+                Err(Errno::AGAIN) | Err(Errno::INTR) => continue,
+                Err(e) => return Err(e).context("Failed to receive DHCP reply"),
+            };
+            match parse_reply(&buf[..len], xid) {
+                Ok(reply) if reply.message_type == expected => return Ok(reply.lease),
+                Ok(reply) if reply.message_type == DHCPNAK => {
+                    anyhow::bail!("Server {} refused the request", reply.lease.server_id)
+                }
+                Ok(reply) => debug!("ignoring DHCP message type {}", reply.message_type),
+                Err(e) => debug!("ignoring DHCP packet: {:#}", e),
+            }
+        }
+        debug!("no DHCP reply, attempt {}/{}", attempt, ATTEMPTS);
+    }
+    anyhow::bail!("No DHCP reply after {} attempts", ATTEMPTS)
+}
+
+/// UDP socket on the DHCP client port, allowed to broadcast
+fn client_socket() -> Result<OwnedFd> {
+    let socket = rustix::net::socket(AddressFamily::INET, SocketType::DGRAM, None)
+        .context("Failed to create DHCP socket")?;
+    sockopt::set_socket_broadcast(&socket, true)?;
+    sockopt::set_socket_reuseaddr(&socket, true)?;
+    rustix::net::bind_v4(
+        &socket,
+        &SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT),
+    )
+    .context("Failed to bind the DHCP client port")?;
+    Ok(socket)
+}
+
+/// DISCOVER, then REQUEST whatever was offered
+fn obtain_lease(mac: [u8; 6]) -> Result<Lease> {
+    let socket = client_socket()?;
+    let xid = transaction_id(mac);
+
+    let offer = exchange(&socket, &build_discover(xid, mac), xid, DHCPOFFER)?;
+    info!("offered {} by {}", offer.address, offer.server_id);
+    let request = build_request(xid, mac, offer.address, offer.server_id);
+    exchange(&socket, &request, xid, DHCPACK)
+}
+
+/// Configure `iface` over DHCP, for init.dhcp
+///
+/// Returns the DNS servers from the lease, for /etc/resolv.conf.
+pub fn configure_dhcp(iface: &str, dry_run: bool) -> Result<Vec<IpAddr>> {
+    if dry_run {
+        info!("dry-run: would configure {} over DHCP", iface);
+        return Ok(Vec::new());
+    }
+
+    let mac = interface_mac(iface)?;
+    let index = interface_index(iface)?;
+    let netlink = netlink_socket()?;
+    transact(&netlink, &link_up_request(index, 1), 1)
+        .with_context(|| format!("Failed to bring up {}", iface))?;
+    // Only there for the exchange, configure_network installs the real one
+    transact(&netlink, &link_route_request(RTM_NEWROUTE, index, 2), 2)
+        .with_context(|| format!("Failed to add a route out of {}", iface))?;
+
+    let lease = obtain_lease(mac);
+    if let Err(e) = transact(&netlink, &link_route_request(RTM_DELROUTE, index, 3), 3) {
+        warn!("failed to remove the DHCP route out of {}: {:#}", iface, e);
+    }
+    let lease = lease.with_context(|| format!("DHCP failed on {}", iface))?;
+
+    super::configure_network(
+        &IpConfig {
+            iface: iface.to_string(),
+            address: lease.address,
+            prefix_len: lease.prefix_len,
+            gateway: lease.gateway,
+        },
+        false,
+    )?;
+    info!("leased {} from {}", lease.address, lease.server_id);
+    Ok(lease.dns.into_iter().map(IpAddr::V4).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{NLM_F_ACK, NLM_F_REQUEST};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// An OFFER as QEMU's user networking sends it
+    fn sample_offer(xid: u32) -> Vec<u8> {
+        let mut packet = vec![0u8; OPTIONS_OFFSET];
+        packet[0] = BOOTREPLY;
+        packet[1] = HTYPE_ETHER;
+        packet[2] = 6;
+        packet[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&xid.to_be_bytes());
+        packet[YIADDR_OFFSET..YIADDR_OFFSET + 4].copy_from_slice(&[10, 0, 2, 15]);
+        packet[CHADDR_OFFSET..CHADDR_OFFSET + 6].copy_from_slice(&MAC);
+        packet[COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+        for option in [
+            &[OPT_MESSAGE_TYPE, 1, DHCPOFFER][..],
+            &[OPT_SERVER_ID, 4, 10, 0, 2, 2],
+            // Lease time, ignored
+            &[51, 4, 0, 1, 0x51, 0x80],
+            &[OPT_PAD],
+            &[OPT_SUBNET_MASK, 4, 255, 255, 255, 0],
+            &[OPT_ROUTER, 4, 10, 0, 2, 2],
+            &[OPT_DNS, 8, 10, 0, 2, 3, 1, 1, 1, 1],
+            &[OPT_END],
+        ] {
+            packet.extend_from_slice(option);
+        }
+        packet
+    }
+
+    fn netlink_header(msg: &[u8]) -> (u32, u16, u16, u32) {
+        (
+            u32::from_ne_bytes(msg[0..4].try_into().unwrap()),
+            u16::from_ne_bytes(msg[4..6].try_into().unwrap()),
+            u16::from_ne_bytes(msg[6..8].try_into().unwrap()),
+            u32::from_ne_bytes(msg[8..12].try_into().unwrap()),
+        )
+    }
+
+    /// Options of a request, up to END, as (code, data)
+    fn options(packet: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut options = Vec::new();
+        let mut rest = &packet[OPTIONS_OFFSET..];
+        while rest[0] != OPT_END {
+            let len = rest[1] as usize;
+            options.push((rest[0], rest[2..2 + len].to_vec()));
+            rest = &rest[2 + len..];
+        }
+        options
+    }
+
+    #[test]
+    fn test_build_discover() {
+        let packet = build_discover(0x1234_5678, MAC);
+        assert_eq!(packet.len(), MIN_PACKET_LEN);
+        assert_eq!(&packet[..4], &[BOOTREQUEST, HTYPE_ETHER, 6, 0]);
+        assert_eq!(&packet[4..8], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&packet[10..12], &[0x80, 0]);
+        // No address of our own yet
+        assert_eq!(&packet[12..28], &[0; 16]);
+        assert_eq!(&packet[28..34], &MAC);
+        assert_eq!(&packet[236..240], &[0x63, 0x82, 0x53, 0x63]);
+        assert_eq!(
+            options(&packet),
+            vec![
+                (OPT_MESSAGE_TYPE, vec![DHCPDISCOVER]),
+                (OPT_PARAMETER_LIST, vec![1, 3, 6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_request() {
+        let packet = build_request(
+            7,
+            MAC,
+            Ipv4Addr::new(10, 0, 2, 15),
+            Ipv4Addr::new(10, 0, 2, 2),
+        );
+        assert_eq!(packet.len(), MIN_PACKET_LEN);
+        assert_eq!(&packet[4..8], &7u32.to_be_bytes());
+        assert_eq!(
+            options(&packet),
+            vec![
+                (OPT_MESSAGE_TYPE, vec![DHCPREQUEST]),
+                (OPT_REQUESTED_IP, vec![10, 0, 2, 15]),
+                (OPT_SERVER_ID, vec![10, 0, 2, 2]),
+                (OPT_PARAMETER_LIST, vec![1, 3, 6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_offer() {
+        let reply = parse_reply(&sample_offer(42), 42).unwrap();
+        assert_eq!(
+            reply,
+            Reply {
+                message_type: DHCPOFFER,
+                lease: Lease {
+                    address: Ipv4Addr::new(10, 0, 2, 15),
+                    prefix_len: 24,
+                    gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+                    dns: vec![Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)],
+                    server_id: Ipv4Addr::new(10, 0, 2, 2),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_reply_rejects_invalid() {
+        let offer = sample_offer(42);
+        // Someone else's transaction
+        assert!(parse_reply(&offer, 43).is_err());
+        assert!(parse_reply(&offer[..OPTIONS_OFFSET - 1], 42).is_err());
+
+        let mut request = offer.clone();
+        request[0] = BOOTREQUEST;
+        assert!(parse_reply(&request, 42).is_err());
+
+        let mut no_cookie = offer.clone();
+        no_cookie[COOKIE_OFFSET] = 0;
+        assert!(parse_reply(&no_cookie, 42).is_err());
+
+        // Cut off inside the DNS option
+        let truncated = &offer[..offer.len() - 4];
+        assert!(parse_reply(truncated, 42).is_err());
+
+        // Without options there is no message type
+        assert!(parse_reply(&offer[..OPTIONS_OFFSET], 42).is_err());
+    }
+
+    #[test]
+    fn test_link_route_request() {
+        let msg = link_route_request(RTM_NEWROUTE, 2, 1);
+        let (len, kind, flags, _) = netlink_header(&msg);
+        // Header, 12 byte rtmsg and the 8 byte RTA_OIF
+        assert_eq!(len, 36);
+        assert_eq!(kind, RTM_NEWROUTE);
+        assert_eq!(
+            flags,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE
+        );
+        assert_eq!(msg[22], RT_SCOPE_LINK);
+        assert_eq!(&msg[30..32], &RTA_OIF.to_ne_bytes());
+        assert_eq!(&msg[32..36], &2u32.to_ne_bytes());
+
+        let msg = link_route_request(RTM_DELROUTE, 2, 2);
+        assert_eq!(
+            netlink_header(&msg),
+            (36, RTM_DELROUTE, NLM_F_REQUEST | NLM_F_ACK, 2)
+        );
+    }
+
+    #[test]
+    fn test_netmask_prefix_len() {
+        assert_eq!(
+            netmask_prefix_len(Ipv4Addr::new(255, 255, 255, 0)).unwrap(),
+            24
+        );
+        assert_eq!(
+            netmask_prefix_len(Ipv4Addr::new(255, 255, 240, 0)).unwrap(),
+            20
+        );
+        assert_eq!(netmask_prefix_len(Ipv4Addr::BROADCAST).unwrap(), 32);
+        assert_eq!(netmask_prefix_len(Ipv4Addr::UNSPECIFIED).unwrap(), 0);
+        assert!(netmask_prefix_len(Ipv4Addr::new(255, 0, 255, 0)).is_err());
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("52:54:00:12:34:56").unwrap(), MAC);
+        for text in [
+            "",
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "52:54:0:12:34:56",
+            "zz:54:00:12:34:56",
+        ] {
+            assert!(parse_mac(text).is_err(), "{:?}", text);
+        }
+    }
+}
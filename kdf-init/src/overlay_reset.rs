@@ -0,0 +1,227 @@
+//! Clean shutdown marker for persistent overlay uppers (init.overlay_reset)
+//!
+//! The marker lives next to upper/ and work/ on the upper= share rather
+//! than inside upper/, where it would show up in the merged view.
+
+use anyhow::{Context, Result};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::cmdline::OverlayReset;
+
+/// Marker left on a graceful shutdown, removed again on boot
+const CLEAN_MARKER: &str = ".clean";
+
+/// Marker path for the upper/work directories under `persist_dir`
+fn marker_path(persist_dir: &str) -> PathBuf {
+    Path::new(persist_dir).join(CLEAN_MARKER)
+}
+
+/// Whether the last boot shut down cleanly
+///
+/// An upper that doesn't exist yet has nothing to lose, so it counts as
+/// clean even without a marker.
+fn was_clean(persist_dir: &str, upper_dir: &str) -> bool {
+    marker_path(persist_dir).exists() || !Path::new(upper_dir).exists()
+}
+
+/// Whether to wipe the upper under `policy`, given how the last boot ended
+pub fn should_reset(policy: OverlayReset, clean: bool) -> bool {
+    match policy {
+        OverlayReset::Never => false,
+        OverlayReset::OnDirty => !clean,
+        OverlayReset::Always => true,
+    }
+}
+
+/// Remove a directory tree, one that doesn't exist is fine
+fn remove_tree(dir: &str) -> Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Take the marker and wipe the upper/work directories if `policy` says so
+///
+/// Runs once the upper= share is mounted at `persist_dir`, before the
+/// directories are created. The marker is removed either way, so a crash
+/// during this boot shows up on the next one.
+pub fn prepare_upper(
+    policy: OverlayReset,
+    persist_dir: &str,
+    upper_dir: &str,
+    work_dir: &str,
+    dry_run: bool,
+) -> Result<()> {
+    if policy == OverlayReset::Never {
+        return Ok(());
+    }
+
+    let clean = was_clean(persist_dir, upper_dir);
+    let marker = marker_path(persist_dir);
+    let reset = should_reset(policy, clean);
+    if dry_run {
+        info!(
+            "dry-run: would remove {}{}",
+            marker.display(),
+            if reset {
+                format!(" and wipe {} and {}", upper_dir, work_dir)
+            } else {
+                String::new()
+            }
+        );
+        return Ok(());
+    }
+
+    match std::fs::remove_file(&marker) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("Failed to remove {}", marker.display()));
+        }
+        _ => {}
+    }
+
+    if reset {
+        if clean {
+            info!("wiping overlay upper {} (init.overlay_reset)", upper_dir);
+        } else {
+            warn!(
+                "last shutdown was unclean, wiping overlay upper {} (init.overlay_reset)",
+                upper_dir
+            );
+        }
+        remove_tree(upper_dir)?;
+        remove_tree(work_dir)?;
+    }
+    Ok(())
+}
+
+/// Leave the clean marker for each persistent upper on a graceful shutdown
+///
+/// Everything is synced first, so the marker never outlives lost writes.
+/// A failure only means the next boot sees a dirty upper, so it is only
+/// worth a warning.
+pub fn mark_clean(policy: OverlayReset, persist_dirs: &[String], dry_run: bool) {
+    if policy == OverlayReset::Never || persist_dirs.is_empty() {
+        return;
+    }
+    if dry_run {
+        info!(
+            "dry-run: would mark {} overlay upper(s) clean",
+            persist_dirs.len()
+        );
+        return;
+    }
+
+    rustix::fs::sync();
+    for dir in persist_dirs {
+        let marker = marker_path(dir);
+        match std::fs::write(&marker, "") {
+            Ok(()) => debug!("wrote {}", marker.display()),
+            Err(e) => warn!("failed to write {}: {}", marker.display(), e),
+        }
+    }
+    rustix::fs::sync();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kdf-init-overlay-reset-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// persist/, upper/ and work/ paths below `base`
+    fn dirs(base: &Path) -> (String, String, String) {
+        let path = |name: &str| base.join(name).to_str().unwrap().to_string();
+        (path(""), path("upper"), path("work"))
+    }
+
+    #[test]
+    fn test_should_reset() {
+        assert!(!should_reset(OverlayReset::Never, true));
+        assert!(!should_reset(OverlayReset::Never, false));
+        assert!(!should_reset(OverlayReset::OnDirty, true));
+        assert!(should_reset(OverlayReset::OnDirty, false));
+        assert!(should_reset(OverlayReset::Always, true));
+        assert!(should_reset(OverlayReset::Always, false));
+    }
+
+    #[test]
+    fn test_marker_round_trip() {
+        let base = temp_dir("marker");
+        let (persist, upper, _) = dirs(&base);
+        let persist_dirs = [persist.clone()];
+        std::fs::create_dir(&upper).unwrap();
+
+        assert!(!was_clean(&persist, &upper));
+        mark_clean(OverlayReset::OnDirty, &persist_dirs, false);
+        assert!(base.join(".clean").is_file());
+        assert!(was_clean(&persist, &upper));
+
+        // Never leaves no marker behind
+        std::fs::remove_file(base.join(".clean")).unwrap();
+        mark_clean(OverlayReset::Never, &persist_dirs, false);
+        assert!(!base.join(".clean").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_missing_upper_is_clean() {
+        let base = temp_dir("fresh");
+        let (persist, upper, _) = dirs(&base);
+        assert!(was_clean(&persist, &upper));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    /// Run prepare_upper over an upper holding a file, the marker present
+    /// if `clean`, and report whether the file survived
+    fn upper_survives(policy: OverlayReset, clean: bool) -> bool {
+        let base = temp_dir(&format!("{:?}-{}", policy, clean));
+        let (persist, upper, work) = dirs(&base);
+        std::fs::create_dir_all(&upper).unwrap();
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::write(base.join("upper/file"), "data").unwrap();
+        if clean {
+            std::fs::write(base.join(".clean"), "").unwrap();
+        }
+
+        prepare_upper(policy, &persist, &upper, &work, false).unwrap();
+        let survived = base.join("upper/file").exists();
+        if policy != OverlayReset::Never {
+            // Taken on boot, only a clean shutdown puts it back
+            assert!(!base.join(".clean").exists());
+            assert_eq!(base.join("work").exists(), survived);
+        }
+        std::fs::remove_dir_all(&base).unwrap();
+        survived
+    }
+
+    #[test]
+    fn test_prepare_upper_never() {
+        assert!(upper_survives(OverlayReset::Never, true));
+        assert!(upper_survives(OverlayReset::Never, false));
+    }
+
+    #[test]
+    fn test_prepare_upper_on_dirty() {
+        assert!(upper_survives(OverlayReset::OnDirty, true));
+        assert!(!upper_survives(OverlayReset::OnDirty, false));
+    }
+
+    #[test]
+    fn test_prepare_upper_always() {
+        assert!(!upper_survives(OverlayReset::Always, true));
+        assert!(!upper_survives(OverlayReset::Always, false));
+    }
+}
@@ -0,0 +1,68 @@
+//! 9p (virtio transport) share mounting for hosts without virtiofs
+
+use anyhow::{Context, Result};
+use rustix::mount::MountFlags;
+
+use crate::cmdline::Plan9Mount;
+use crate::{system, virtiofs};
+
+fn check_9p_support() -> Result<()> {
+    if system::filesystem_supported("9p")? {
+        info!("9p support detected");
+        Ok(())
+    } else {
+        anyhow::bail!(
+            "9p filesystem not supported by kernel. \
+             Make sure CONFIG_9P_FS and CONFIG_NET_9P_VIRTIO are enabled \
+             (either built-in or as modules) and loaded before mounting 9p shares."
+        )
+    }
+}
+
+/// Mount data for a 9p share over virtio
+fn p9_options(msize: u32) -> String {
+    format!("trans=virtio,version=9p2000.L,msize={}", msize)
+}
+
+pub fn mount_p9_shares(mounts: &[Plan9Mount], skip_fs_check: bool, dry_run: bool) -> Result<()> {
+    if mounts.is_empty() {
+        return Ok(());
+    }
+
+    if system::check_fs_support(skip_fs_check, dry_run) {
+        check_9p_support()?;
+    }
+
+    for p9_mount in mounts {
+        virtiofs::mkdir_p(&p9_mount.path, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+
+        system::mount_fs(
+            &format!(
+                "9p {} at {} (msize={})",
+                p9_mount.tag, p9_mount.path, p9_mount.msize
+            ),
+            &p9_mount.tag,
+            &p9_mount.path,
+            "9p",
+            MountFlags::empty(),
+            &p9_options(p9_mount.msize),
+            dry_run,
+        )
+        .with_context(|| format!("Failed to mount 9p {} at {}", p9_mount.tag, p9_mount.path))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_p9_options() {
+        assert_eq!(
+            p9_options(262144),
+            "trans=virtio,version=9p2000.L,msize=262144"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Zombie reaping while init stays alive as PID 1
+
+use anyhow::{Context, Result};
+use rustix::process::{wait, Pid, Signal, WaitOptions};
+use rustix::runtime::{How, Sigset, Timespec};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use crate::shutdown;
+
+/// Signals init handles synchronously while supervising a command
+pub const SUPERVISOR_SIGNALS: [Signal; 3] = [Signal::Child, Signal::Term, Signal::Int];
+
+/// Upper bound on a single signal wait, in case a signal was delivered to
+/// another thread and never shows up in ours
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Build a kernel signal set from a list of signals
+fn signal_set(signals: &[Signal]) -> Sigset {
+    // Safety: Sigset is a plain bitmask, all zeroes is the empty set
+    let mut set: Sigset = unsafe { std::mem::zeroed() };
+    let bits = 8 * std::mem::size_of_val(&set.sig[0]);
+    for signal in signals {
+        let index = *signal as usize - 1;
+        set.sig[index / bits] |= 1 << (index % bits);
+    }
+    set
+}
+
+/// Block signals so they are only consumed synchronously by init
+///
+/// Children spawned through std::process::Command start with an empty mask
+/// again, so this doesn't leak into the supervised command.
+pub fn block_signals(signals: &[Signal]) -> Result<()> {
+    let set = signal_set(signals);
+    // Safety: blocked signals are only ever consumed through sigtimedwait
+    unsafe { rustix::runtime::sigprocmask(How::BLOCK, Some(&set)) }
+        .context("Failed to block signals")?;
+    Ok(())
+}
+
+/// Undo [`block_signals`], e.g. before init execs the command
+///
+/// An exec'd program inherits the signal mask, so it must not stay blocked.
+pub fn unblock_signals(signals: &[Signal]) -> Result<()> {
+    let set = signal_set(signals);
+    // Safety: only restores default delivery for signals init blocked itself
+    unsafe { rustix::runtime::sigprocmask(How::UNBLOCK, Some(&set)) }
+        .context("Failed to unblock signals")?;
+    Ok(())
+}
+
+/// Wait up to `timeout` for one of the (blocked) `signals` to arrive
+///
+/// Returns None if the timeout expired or the wait was interrupted.
+pub fn wait_for_signal(signals: &[Signal], timeout: Duration) -> Result<Option<Signal>> {
+    let set = signal_set(signals);
+    let timeout = Timespec {
+        tv_sec: timeout.as_secs() as _,
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+
+    // Safety: the signals are blocked and only consumed here
+    match unsafe { rustix::runtime::sigtimedwait(&set, Some(timeout)) } {
+        Ok(info) => {
+            // Safety: si_signo is valid for every siginfo returned by the kernel
+            let signo = unsafe { info.__bindgen_anon_1.__bindgen_anon_1.si_signo };
+            Ok(Signal::from_raw(signo))
+        }
+        Err(rustix::io::Errno::INTR) | Err(rustix::io::Errno::AGAIN) => Ok(None),
+        Err(e) => Err(e).context("Failed to wait for signals"),
+    }
+}
+
+/// Reap every exited child without blocking
+///
+/// Returns the status of `child` if it was among the reaped processes.
+pub fn reap_children(child: Pid) -> Result<Option<ExitStatus>> {
+    let mut child_status = None;
+
+    loop {
+        match wait(WaitOptions::NOHANG) {
+            Ok(Some((pid, status))) if pid == child => {
+                child_status = Some(ExitStatus::from_raw(status.as_raw() as i32));
+            }
+            Ok(Some((pid, status))) => {
+                info!(
+                    "reaped orphan pid {} (status: {:#x})",
+                    pid.as_raw_nonzero(),
+                    status.as_raw()
+                );
+            }
+            // Nothing left to reap right now, or no children at all
+            Ok(None) | Err(rustix::io::Errno::CHILD) => return Ok(child_status),
+            Err(rustix::io::Errno::INTR) => continue,
+            Err(e) => return Err(e).context("Failed to reap children"),
+        }
+    }
+}
+
+/// Reap zombies until `child` exits or `deadline` passes
+///
+/// Returns None only if the deadline passed before the child exited.
+pub fn reap_until(child: Pid, deadline: Option<Instant>) -> Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = reap_children(child)? {
+            return Ok(Some(status));
+        }
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(None);
+                }
+                remaining.min(POLL_INTERVAL)
+            }
+            None => POLL_INTERVAL,
+        };
+
+        wait_for_signal(&[Signal::Child], timeout)?;
+    }
+}
+
+/// How a supervised command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildExit {
+    /// The command exited on its own
+    Exited(ExitStatus),
+    /// The command exited after init forwarded a SIGTERM/SIGINT to it
+    Shutdown(ExitStatus),
+    /// init.exec_timeout expired and init terminated the command
+    TimedOut(ExitStatus),
+}
+
+/// Reap zombies until `child` exits, returning how it ended
+///
+/// [`SUPERVISOR_SIGNALS`] must already be blocked via [`block_signals`]
+/// before the child is spawned, otherwise an early exit notification could
+/// be missed. SIGTERM/SIGINT trigger a graceful shutdown of the child. Once
+/// `exec_timeout` expires the child gets SIGTERM, then SIGKILL after
+/// `shutdown_timeout`.
+pub fn wait_for_child(
+    child: Pid,
+    shutdown_timeout: Duration,
+    exec_timeout: Option<Duration>,
+) -> Result<ChildExit> {
+    let started = Instant::now();
+    let mut timed_out = false;
+
+    loop {
+        if let Some(status) = reap_children(child)? {
+            return Ok(if timed_out {
+                ChildExit::TimedOut(status)
+            } else {
+                ChildExit::Exited(status)
+            });
+        }
+
+        let mut wait = POLL_INTERVAL;
+        if let Some(exec_timeout) = exec_timeout {
+            match shutdown::exec_timeout_action(started.elapsed(), exec_timeout, shutdown_timeout) {
+                shutdown::TimeoutAction::Wait(remaining) => wait = wait.min(remaining),
+                shutdown::TimeoutAction::Terminate(grace) => {
+                    if !timed_out {
+                        warn!(
+                            "pid {} still running after {}s, sending SIGTERM (timeout: {}s)",
+                            child.as_raw_nonzero(),
+                            exec_timeout.as_secs(),
+                            shutdown_timeout.as_secs()
+                        );
+                        shutdown::send_signal(child, Signal::Term)?;
+                        timed_out = true;
+                    }
+                    wait = wait.min(grace);
+                }
+                shutdown::TimeoutAction::Kill => {
+                    warn!(
+                        "pid {} did not exit in time, sending SIGKILL",
+                        child.as_raw_nonzero()
+                    );
+                    shutdown::send_signal(child, Signal::Kill)?;
+                    let status =
+                        reap_until(child, None)?.context("Supervised command was never reaped")?;
+                    return Ok(ChildExit::TimedOut(status));
+                }
+            }
+        }
+
+        if let Some(signal @ (Signal::Term | Signal::Int)) =
+            wait_for_signal(&SUPERVISOR_SIGNALS, wait)?
+        {
+            return shutdown::handle_shutdown_signals(child, signal, shutdown_timeout)
+                .map(ChildExit::Shutdown);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Rescue shell when the configured command can't be started
+
+use anyhow::Result;
+use rustix::io::Errno;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::cmdline::Config;
+use crate::system;
+
+/// Shell preferred over the built-in loop when the rootfs has one
+const RESCUE_SHELL: &str = "/bin/sh";
+
+/// What to do after the command failed to start
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// The command itself is broken, drop into the rescue shell
+    Rescue,
+    /// Init itself is in trouble, a rescue shell wouldn't start either
+    Abort,
+}
+
+/// exec errors that point at a bad command rather than a broken system
+const RESCUE_ERRNOS: [Errno; 8] = [
+    Errno::NOENT,
+    Errno::ACCESS,
+    Errno::PERM,
+    Errno::NOEXEC,
+    Errno::NOTDIR,
+    Errno::ISDIR,
+    Errno::LOOP,
+    Errno::NAMETOOLONG,
+];
+
+/// Decide whether a failure to start the command warrants a rescue shell
+///
+/// Failures without an OS error (e.g. the command isn't in PATH) are
+/// configuration mistakes, so they are rescued too.
+pub fn decide(err: &anyhow::Error) -> Decision {
+    let io_error = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<std::io::Error>());
+
+    match io_error.and_then(std::io::Error::raw_os_error) {
+        Some(raw) if RESCUE_ERRNOS.contains(&Errno::from_raw_os_error(raw)) => Decision::Rescue,
+        Some(_) => Decision::Abort,
+        None if io_error.is_some() => Decision::Abort,
+        None => Decision::Rescue,
+    }
+}
+
+/// Commands of the built-in rescue shell
+#[derive(Debug, PartialEq)]
+enum Builtin<'a> {
+    /// List each directory, the current one if none is given
+    Ls(Vec<&'a str>),
+    /// Print each file
+    Cat(Vec<&'a str>),
+    /// Change to the directory, / if none is given
+    Cd(Option<&'a str>),
+    /// Print /proc/mounts
+    Mount,
+    /// Leave the shell
+    Exit,
+    /// Anything else
+    Unknown(&'a str),
+}
+
+/// Split a line into a built-in command and its args, None for a blank line
+fn parse_builtin(line: &str) -> Option<Builtin<'_>> {
+    let mut words = line.split_whitespace();
+    let command = words.next()?;
+    let args: Vec<&str> = words.collect();
+
+    Some(match command {
+        "ls" => Builtin::Ls(args),
+        "cat" => Builtin::Cat(args),
+        "cd" => Builtin::Cd(args.first().copied()),
+        "mount" => Builtin::Mount,
+        "exit" => Builtin::Exit,
+        command => Builtin::Unknown(command),
+    })
+}
+
+/// Render directory entries for ls, sorted, directories with a trailing '/'
+fn format_ls(mut entries: Vec<(String, bool)>) -> String {
+    entries.sort();
+    entries
+        .into_iter()
+        .map(|(name, is_dir)| {
+            if is_dir {
+                format!("{}/\n", name)
+            } else {
+                format!("{}\n", name)
+            }
+        })
+        .collect()
+}
+
+/// Entries of a directory as (name, is_dir), in no particular order
+fn read_entries(dir: &str) -> std::io::Result<Vec<(String, bool)>> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // Symlinks aren't followed, a link to a directory lists as a file
+        let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+        entries.push((entry.file_name().to_string_lossy().into_owned(), is_dir));
+    }
+    Ok(entries)
+}
+
+/// Print each file, or an error line for one that can't be read
+fn cat_files(files: &[&str], output: &mut impl Write) -> std::io::Result<()> {
+    for file in files {
+        match std::fs::read(file) {
+            Ok(contents) => output.write_all(&contents)?,
+            Err(e) => writeln!(output, "cat: {}: {}", file, e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Minimal read-eval loop, see [`Builtin`] for the commands
+///
+/// Needs nothing from the rootfs, so init stays debuggable without /bin/sh.
+fn builtin_shell(input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
+    write!(output, "rescue> ")?;
+    output.flush()?;
+
+    for line in input.lines() {
+        let line = line?;
+
+        match parse_builtin(&line) {
+            None => {}
+            Some(Builtin::Exit) => return Ok(()),
+            Some(Builtin::Ls(dirs)) => {
+                for dir in if dirs.is_empty() { vec!["."] } else { dirs } {
+                    match read_entries(dir) {
+                        Ok(entries) => output.write_all(format_ls(entries).as_bytes())?,
+                        Err(e) => writeln!(output, "ls: {}: {}", dir, e)?,
+                    }
+                }
+            }
+            Some(Builtin::Cat(files)) => cat_files(&files, &mut output)?,
+            Some(Builtin::Cd(dir)) => {
+                let dir = dir.unwrap_or("/");
+                if let Err(e) = std::env::set_current_dir(dir) {
+                    writeln!(output, "cd: {}: {}", dir, e)?;
+                }
+            }
+            Some(Builtin::Mount) => cat_files(&["/proc/mounts"], &mut output)?,
+            Some(Builtin::Unknown(command)) => writeln!(
+                output,
+                "unknown command: {} (available: ls, cat, cd, mount, exit)",
+                command
+            )?,
+        }
+
+        write!(output, "rescue> ")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Run /bin/sh on the console, or the built-in loop if there is none
+///
+/// The built-in loop reads fd 0 and writes fd 1, which the kernel points at
+/// the console and init.console redirects once devtmpfs is up.
+fn run_rescue(console_device: &str) -> Result<()> {
+    if Path::new(RESCUE_SHELL).is_file() {
+        let status = system::execute_shell(RESCUE_SHELL, &[], console_device)?;
+        info!("rescue shell exited with status: {:?}", status.code());
+        return Ok(());
+    }
+
+    warn!("{} not found, starting built-in rescue shell", RESCUE_SHELL);
+    builtin_shell(std::io::stdin().lock(), std::io::stdout().lock())?;
+    Ok(())
+}
+
+/// Handle a failure to start the command, honouring init.rescue
+///
+/// Returns the original error when rescue is disabled or not applicable,
+/// otherwise returns once the rescue shell exits.
+pub fn handle_exec_failure(config: &Config, err: anyhow::Error) -> Result<()> {
+    if !config.rescue || decide(&err) == Decision::Abort {
+        return Err(err);
+    }
+
+    error!("failed to start command: {:?}", err);
+    error!("entering rescue shell (init.rescue)");
+    run_rescue(&config.console)
+}
+
+/// Drop into the rescue shell if init panics, then power off
+pub fn install_panic_hook(console_device: String) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        error!("init panicked, entering rescue shell (init.rescue)");
+        if let Err(e) = run_rescue(&console_device) {
+            error!("rescue shell failed: {:?}", e);
+        }
+        let _ = system::shutdown();
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn os_error(errno: Errno) -> anyhow::Error {
+        anyhow::Error::new(std::io::Error::from_raw_os_error(errno.raw_os_error()))
+    }
+
+    #[test]
+    fn test_decide_rescue() {
+        for errno in [Errno::NOENT, Errno::ACCESS, Errno::NOEXEC] {
+            let err = Err::<(), _>(os_error(errno))
+                .context("Failed to exec command")
+                .unwrap_err();
+            assert_eq!(decide(&err), Decision::Rescue, "{:?}", errno);
+        }
+
+        // Not an OS error, e.g. resolve_program failing
+        assert_eq!(
+            decide(&anyhow::anyhow!("Command not found in PATH")),
+            Decision::Rescue
+        );
+    }
+
+    #[test]
+    fn test_decide_abort() {
+        for errno in [Errno::NOMEM, Errno::FAULT, Errno::TOOBIG] {
+            assert_eq!(decide(&os_error(errno)), Decision::Abort, "{:?}", errno);
+        }
+
+        let err = anyhow::Error::new(std::io::Error::other("broken pipe"));
+        assert_eq!(decide(&err), Decision::Abort);
+    }
+
+    #[test]
+    fn test_parse_builtin() {
+        assert_eq!(parse_builtin(""), None);
+        assert_eq!(parse_builtin("   \t "), None);
+        assert_eq!(parse_builtin("ls"), Some(Builtin::Ls(vec![])));
+        assert_eq!(
+            parse_builtin("  ls /dev   /run "),
+            Some(Builtin::Ls(vec!["/dev", "/run"]))
+        );
+        assert_eq!(
+            parse_builtin("cat /proc/cmdline"),
+            Some(Builtin::Cat(vec!["/proc/cmdline"]))
+        );
+        assert_eq!(parse_builtin("cd"), Some(Builtin::Cd(None)));
+        assert_eq!(parse_builtin("cd /sys"), Some(Builtin::Cd(Some("/sys"))));
+        assert_eq!(parse_builtin("mount"), Some(Builtin::Mount));
+        assert_eq!(parse_builtin("exit 1"), Some(Builtin::Exit));
+        assert_eq!(parse_builtin("sh -c ls"), Some(Builtin::Unknown("sh")));
+    }
+
+    #[test]
+    fn test_format_ls() {
+        let entries = vec![
+            ("proc".to_string(), true),
+            ("init".to_string(), false),
+            ("dev".to_string(), true),
+            (".hidden".to_string(), false),
+        ];
+        assert_eq!(format_ls(entries), ".hidden\ndev/\ninit\nproc/\n");
+        assert_eq!(format_ls(Vec::new()), "");
+    }
+
+    #[test]
+    fn test_builtin_shell() {
+        let input = "\nbogus\ncat /nonexistent/kdf-init\nexit\nls\n";
+        let mut output = Vec::new();
+        builtin_shell(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("unknown command: bogus"));
+        assert!(output.contains("cat: /nonexistent/kdf-init:"));
+        // Nothing after exit is evaluated
+        assert_eq!(output.matches("rescue> ").count(), 4);
+    }
+}
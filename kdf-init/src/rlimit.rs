@@ -0,0 +1,151 @@
+//! Resource limits for the command (init.rlimit.<name>)
+
+use anyhow::{Context, Result};
+use rustix::process::{Resource, Rlimit};
+
+use crate::cmdline::{parse_size, ResourceLimit};
+
+/// init.rlimit names, the resources they stand for, and whether their
+/// values are byte counts that may carry a k/m/g suffix
+const RESOURCES: [(&str, Resource, bool); 16] = [
+    ("cpu", Resource::Cpu, false),
+    ("fsize", Resource::Fsize, true),
+    ("data", Resource::Data, true),
+    ("stack", Resource::Stack, true),
+    ("core", Resource::Core, true),
+    ("rss", Resource::Rss, true),
+    ("nproc", Resource::Nproc, false),
+    ("nofile", Resource::Nofile, false),
+    ("memlock", Resource::Memlock, true),
+    ("as", Resource::As, true),
+    ("locks", Resource::Locks, false),
+    ("sigpending", Resource::Sigpending, false),
+    ("msgqueue", Resource::Msgqueue, true),
+    ("nice", Resource::Nice, false),
+    ("rtprio", Resource::Rtprio, false),
+    ("rttime", Resource::Rttime, false),
+];
+
+/// Look up an init.rlimit name
+///
+/// Returns the resource and whether its values are byte counts.
+pub fn parse_resource(name: &str) -> Result<(Resource, bool)> {
+    RESOURCES
+        .iter()
+        .find(|(known, _, _)| *known == name)
+        .map(|&(_, resource, is_size)| (resource, is_size))
+        .with_context(|| {
+            let names: Vec<&str> = RESOURCES.iter().map(|(name, _, _)| *name).collect();
+            format!(
+                "Unknown resource: {:?} (expected {})",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+/// init.rlimit name of `resource`
+pub fn resource_name(resource: Resource) -> &'static str {
+    RESOURCES
+        .iter()
+        .find(|(_, known, _)| *known == resource)
+        .map(|(name, _, _)| *name)
+        .unwrap_or("unknown")
+}
+
+/// Parse a single soft or hard limit, None meaning unlimited
+///
+/// Example: "1024" -> Some(1024)
+/// Example: "2g" -> Some(2147483648), for byte count resources only
+/// Example: "unlimited" -> None
+pub fn parse_limit(value: &str, is_size: bool) -> Result<Option<u64>> {
+    if value == "unlimited" {
+        return Ok(None);
+    }
+    // parse_size rejects 0, a valid limit, e.g. to disable core dumps
+    if is_size && value != "0" {
+        return Ok(Some(parse_size(value)?));
+    }
+    let limit = value
+        .parse()
+        .with_context(|| format!("Invalid limit: {}", value))?;
+    Ok(Some(limit))
+}
+
+/// Format a limit the way init.rlimit takes it, for logging
+///
+/// Example: nofile soft 1024, hard 4096 -> "nofile=1024:4096"
+pub fn format_limit(limit: &ResourceLimit) -> String {
+    let value = |limit: Option<u64>| limit.map_or("unlimited".to_string(), |v| v.to_string());
+    format!(
+        "{}={}:{}",
+        resource_name(limit.resource),
+        value(limit.soft),
+        value(limit.hard)
+    )
+}
+
+/// Apply the init.rlimit limits to the calling process
+///
+/// Runs in the command's pre_exec hook, so it must not allocate.
+pub fn set_limits(limits: &[ResourceLimit]) -> std::io::Result<()> {
+    for limit in limits {
+        rustix::process::setrlimit(
+            limit.resource,
+            Rlimit {
+                current: limit.soft,
+                maximum: limit.hard,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resource() {
+        assert_eq!(parse_resource("nofile").unwrap(), (Resource::Nofile, false));
+        assert_eq!(parse_resource("as").unwrap(), (Resource::As, true));
+
+        let err = parse_resource("files").unwrap_err();
+        assert!(err.to_string().contains("Unknown resource: \"files\""));
+        assert!(err.to_string().contains("cpu, fsize, data"));
+        assert!(parse_resource("NOFILE").is_err());
+    }
+
+    #[test]
+    fn test_resource_name_round_trips() {
+        for (name, resource, _) in RESOURCES {
+            assert_eq!(resource_name(resource), name);
+        }
+    }
+
+    #[test]
+    fn test_format_limit() {
+        let limit = ResourceLimit {
+            resource: Resource::Core,
+            soft: Some(0),
+            hard: None,
+        };
+        assert_eq!(format_limit(&limit), "core=0:unlimited");
+    }
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("1024", false).unwrap(), Some(1024));
+        assert_eq!(parse_limit("0", false).unwrap(), Some(0));
+        assert_eq!(parse_limit("0", true).unwrap(), Some(0));
+        assert_eq!(parse_limit("64m", true).unwrap(), Some(64 << 20));
+        assert_eq!(parse_limit("unlimited", false).unwrap(), None);
+        assert_eq!(parse_limit("unlimited", true).unwrap(), None);
+
+        // Suffixes only make sense for byte counts
+        assert!(parse_limit("4k", false).is_err());
+        for value in ["", "-1", "lots", "1.5g", "infinity"] {
+            assert!(parse_limit(value, true).is_err(), "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Switching the root filesystem to a virtiofs share (init.root)
+
+use anyhow::{Context, Result};
+use rustix::io::Errno;
+use rustix::mount::{MountFlags, UnmountFlags};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::{syscall, system, virtiofs};
+
+/// Staging mount point for the new root inside the initramfs
+const NEW_ROOT: &str = "/newroot";
+
+/// Kernel filesystems carried over into the new root, in move order
+const CARRIED_MOUNTS: [&str; 4] = ["/proc", "/sys", "/dev", "/run"];
+
+/// Mount targets listed in /proc/self/mounts contents
+fn mount_targets(mounts: &str) -> Vec<&str> {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .collect()
+}
+
+/// Pick the kernel filesystems to move into the new root, in order
+///
+/// Only mounted targets are moved. A target below another carried mount
+/// moves along with its parent, so moving it separately would fail.
+fn move_order<'a>(mounted: &[&str], carried: &[&'a str]) -> Vec<&'a str> {
+    carried
+        .iter()
+        .copied()
+        .filter(|target| mounted.contains(target))
+        .filter(|target| {
+            !carried.iter().any(|parent| {
+                parent != target
+                    && mounted.contains(parent)
+                    && Path::new(target).starts_with(parent)
+            })
+        })
+        .collect()
+}
+
+/// Delete the initramfs contents without crossing into other filesystems
+///
+/// Mirrors switch_root: rootfs can't be unmounted, so its files are removed
+/// to give the memory back.
+fn remove_initramfs(dir: &Path, root_dev: u64) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("failed to read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let Ok(metadata) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.dev() != root_dev {
+            continue;
+        }
+
+        let result = if metadata.is_dir() {
+            remove_initramfs(&path, root_dev);
+            std::fs::remove_dir(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        if let Err(e) = result {
+            warn!("failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Make the current directory (the new root) the root filesystem
+fn pivot_to_cwd() -> Result<()> {
+    // Stack the old root on top of the new one, then detach it
+    match rustix::process::pivot_root(".", ".") {
+        Ok(()) => {
+            rustix::mount::unmount(".", UnmountFlags::DETACH)
+                .context("Failed to detach the old root")?;
+            info!("pivoted root, old root detached");
+        }
+        // The initramfs (rootfs) can't be pivoted away from
+        Err(Errno::INVAL) => {
+            let root_dev = std::fs::metadata("/")
+                .context("Failed to stat the initramfs root")?
+                .dev();
+            remove_initramfs(Path::new("/"), root_dev);
+
+            syscall::move_mount_logged(".", "/").context("Failed to move the new root to /")?;
+            rustix::process::chroot(".").context("Failed to chroot into the new root")?;
+            info!("switched root from the initramfs, initramfs contents removed");
+        }
+        Err(e) => return Err(e).context("Failed to pivot_root into the new root"),
+    }
+
+    rustix::process::chdir("/").context("Failed to chdir to the new root")?;
+    Ok(())
+}
+
+/// Mount virtiofs `tag` and make it the root filesystem
+///
+/// /proc, /sys, /dev and /run are moved into the new root first, so
+/// everything after this runs against the share.
+pub fn switch_root(tag: &str, dry_run: bool) -> Result<()> {
+    virtiofs::mkdir_p(NEW_ROOT, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+    system::mount_fs(
+        &format!("virtiofs {} at {} as the new root", tag, NEW_ROOT),
+        tag,
+        NEW_ROOT,
+        "virtiofs",
+        MountFlags::empty(),
+        "",
+        dry_run,
+    )
+    .with_context(|| format!("Failed to mount root virtiofs {} at {}", tag, NEW_ROOT))?;
+
+    let mounts =
+        std::fs::read_to_string("/proc/self/mounts").context("Failed to read /proc/self/mounts")?;
+    for target in move_order(&mount_targets(&mounts), &CARRIED_MOUNTS) {
+        let new_target = format!("{}{}", NEW_ROOT, target);
+        virtiofs::mkdir_p(&new_target, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+
+        if dry_run {
+            info!("dry-run: would move {} to {}", target, new_target);
+            continue;
+        }
+        syscall::move_mount_logged(target, &new_target)
+            .with_context(|| format!("Failed to move {} to {}", target, new_target))?;
+        info!("moved {} to {}", target, new_target);
+    }
+
+    if dry_run {
+        info!("dry-run: would switch root to {}", NEW_ROOT);
+        return Ok(());
+    }
+
+    rustix::process::chdir(NEW_ROOT).with_context(|| format!("Failed to chdir to {}", NEW_ROOT))?;
+    pivot_to_cwd()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_targets() {
+        let mounts = "rootfs / rootfs rw 0 0\n\
+                      proc /proc proc rw 0 0\n\
+                      share /newroot virtiofs rw 0 0\n";
+        assert_eq!(mount_targets(mounts), vec!["/", "/proc", "/newroot"]);
+    }
+
+    #[test]
+    fn test_move_order() {
+        let mounted = ["/", "/proc", "/sys", "/dev", "/run", "/newroot"];
+        assert_eq!(
+            move_order(&mounted, &CARRIED_MOUNTS),
+            vec!["/proc", "/sys", "/dev", "/run"]
+        );
+    }
+
+    #[test]
+    fn test_move_order_skips_unmounted() {
+        // e.g. init.nomount=/run
+        let mounted = ["/", "/proc", "/sys", "/dev"];
+        assert_eq!(
+            move_order(&mounted, &CARRIED_MOUNTS),
+            vec!["/proc", "/sys", "/dev"]
+        );
+    }
+
+    #[test]
+    fn test_move_order_skips_nested() {
+        let mounted = ["/", "/sys", "/sys/fs/cgroup", "/run"];
+        let carried = ["/sys", "/sys/fs/cgroup", "/run"];
+        assert_eq!(move_order(&mounted, &carried), vec!["/sys", "/run"]);
+
+        // The nested mount is moved on its own when its parent isn't mounted
+        let mounted = ["/", "/sys/fs/cgroup"];
+        assert_eq!(move_order(&mounted, &carried), vec!["/sys/fs/cgroup"]);
+    }
+}
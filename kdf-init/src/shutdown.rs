@@ -0,0 +1,237 @@
+//! Graceful shutdown of the supervised command on SIGTERM/SIGINT or when
+//! init.exec_timeout expires
+
+use anyhow::{Context, Result};
+use rustix::process::{kill_process, Pid, Signal};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use crate::reaper;
+
+/// Send `signal` to `child`, ignoring a child that already exited
+pub fn send_signal(child: Pid, signal: Signal) -> Result<()> {
+    match kill_process(child, signal) {
+        Ok(()) | Err(rustix::io::Errno::SRCH) => Ok(()),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Failed to send {:?} to pid {}",
+                signal,
+                child.as_raw_nonzero()
+            )
+        }),
+    }
+}
+
+/// Forward a shutdown signal to the child and wait for it to exit
+///
+/// If the child is still running after `timeout`, it is sent SIGKILL. The
+/// caller powers off once this returns, like after any other child exit.
+pub fn handle_shutdown_signals(
+    child: Pid,
+    signal: Signal,
+    timeout: Duration,
+) -> Result<ExitStatus> {
+    info!(
+        "received {:?}, forwarding to pid {} (timeout: {}s)",
+        signal,
+        child.as_raw_nonzero(),
+        timeout.as_secs()
+    );
+    send_signal(child, signal)?;
+
+    if let Some(status) = reaper::reap_until(child, Some(Instant::now() + timeout))? {
+        return Ok(status);
+    }
+
+    warn!(
+        "pid {} did not exit within {}s, sending SIGKILL",
+        child.as_raw_nonzero(),
+        timeout.as_secs()
+    );
+    send_signal(child, Signal::Kill)?;
+
+    reaper::reap_until(child, None)?.context("Supervised command was never reaped")
+}
+
+/// Signals init.reboot_on accepts by name, with or without the SIG prefix
+const SIGNAL_NAMES: [(&str, Signal); 11] = [
+    ("HUP", Signal::Hup),
+    ("INT", Signal::Int),
+    ("QUIT", Signal::Quit),
+    ("ABRT", Signal::Abort),
+    ("KILL", Signal::Kill),
+    ("USR1", Signal::Usr1),
+    ("SEGV", Signal::Segv),
+    ("USR2", Signal::Usr2),
+    ("PIPE", Signal::Pipe),
+    ("ALRM", Signal::Alarm),
+    ("TERM", Signal::Term),
+];
+
+/// Parse a signal by name or number
+///
+/// Example: "SIGUSR1", "USR1" or "10" -> SIGUSR1
+pub fn parse_signal(value: &str) -> Result<Signal> {
+    if let Ok(number) = value.parse() {
+        return Signal::from_raw(number).with_context(|| format!("Unknown signal: {}", value));
+    }
+    let name = value.strip_prefix("SIG").unwrap_or(value);
+    SIGNAL_NAMES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|&(_, signal)| signal)
+        .with_context(|| format!("Unknown signal: {}", value))
+}
+
+/// Name of `signal` as init.reboot_on takes it, its number if it has none
+pub fn signal_name(signal: Signal) -> String {
+    SIGNAL_NAMES
+        .iter()
+        .find(|(_, known)| *known == signal)
+        .map_or_else(
+            || (signal as i32).to_string(),
+            |(name, _)| format!("SIG{}", name),
+        )
+}
+
+/// How init goes down once the supervised command exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    /// Warm reboot, the command was killed by the init.reboot_on signal
+    Reboot,
+    /// Power off, after any other exit
+    PowerOff,
+}
+
+/// Pick the exit action for a command that exited with `status`
+pub fn exit_action(status: ExitStatus, reboot_on: Signal) -> ExitAction {
+    if status.signal() == Some(reboot_on as i32) {
+        ExitAction::Reboot
+    } else {
+        ExitAction::PowerOff
+    }
+}
+
+/// Next step for a supervised command under init.exec_timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Still within the timeout, check again after at most this long
+    Wait(Duration),
+    /// The timeout expired, SIGTERM the command and give it this long
+    Terminate(Duration),
+    /// The grace period expired as well, SIGKILL the command
+    Kill,
+}
+
+/// Decide what to do about a command that has been running for `elapsed`
+///
+/// A zero `grace` skips SIGTERM and kills the command right away.
+pub fn exec_timeout_action(elapsed: Duration, timeout: Duration, grace: Duration) -> TimeoutAction {
+    let kill_at = timeout.saturating_add(grace);
+    if elapsed < timeout {
+        TimeoutAction::Wait(timeout - elapsed)
+    } else if elapsed < kill_at {
+        TimeoutAction::Terminate(kill_at - elapsed)
+    } else {
+        TimeoutAction::Kill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    const GRACE: Duration = Duration::from_secs(10);
+
+    fn action_at(secs: u64) -> TimeoutAction {
+        exec_timeout_action(Duration::from_secs(secs), TIMEOUT, GRACE)
+    }
+
+    #[test]
+    fn test_exec_timeout_waits_before_expiry() {
+        assert_eq!(action_at(0), TimeoutAction::Wait(TIMEOUT));
+        assert_eq!(action_at(45), TimeoutAction::Wait(Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn test_exec_timeout_terminates_during_grace() {
+        assert_eq!(action_at(60), TimeoutAction::Terminate(GRACE));
+        assert_eq!(
+            action_at(67),
+            TimeoutAction::Terminate(Duration::from_secs(3))
+        );
+    }
+
+    #[test]
+    fn test_exec_timeout_kills_after_grace() {
+        assert_eq!(action_at(70), TimeoutAction::Kill);
+        assert_eq!(action_at(3600), TimeoutAction::Kill);
+    }
+
+    #[test]
+    fn test_exit_action() {
+        // Wait statuses keep a terminating signal in the low bits
+        let killed_by = |signal: Signal| ExitStatus::from_raw(signal as i32);
+        assert_eq!(
+            exit_action(killed_by(Signal::Usr1), Signal::Usr1),
+            ExitAction::Reboot
+        );
+        assert_eq!(
+            exit_action(killed_by(Signal::Usr2), Signal::Usr1),
+            ExitAction::PowerOff
+        );
+        assert_eq!(
+            exit_action(killed_by(Signal::Term), Signal::Usr1),
+            ExitAction::PowerOff
+        );
+        assert_eq!(
+            exit_action(killed_by(Signal::Hup), Signal::Hup),
+            ExitAction::Reboot
+        );
+        // Exiting with the signal number as code is not a signal death
+        assert_eq!(
+            exit_action(ExitStatus::from_raw(10 << 8), Signal::Usr1),
+            ExitAction::PowerOff
+        );
+        assert_eq!(
+            exit_action(ExitStatus::from_raw(0), Signal::Usr1),
+            ExitAction::PowerOff
+        );
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGUSR1").unwrap(), Signal::Usr1);
+        assert_eq!(parse_signal("USR2").unwrap(), Signal::Usr2);
+        assert_eq!(parse_signal("1").unwrap(), Signal::Hup);
+        assert_eq!(parse_signal("15").unwrap(), Signal::Term);
+
+        for value in ["", "usr1", "SIG", "SIGFOO", "0", "-1", "999"] {
+            assert!(parse_signal(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_signal_name() {
+        assert_eq!(signal_name(Signal::Usr1), "SIGUSR1");
+        assert_eq!(signal_name(Signal::Child), "17");
+        for (name, signal) in SIGNAL_NAMES {
+            assert_eq!(
+                parse_signal(&signal_name(signal)).unwrap(),
+                signal,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_exec_timeout_zero_grace_kills() {
+        assert_eq!(
+            exec_timeout_action(TIMEOUT, TIMEOUT, Duration::ZERO),
+            TimeoutAction::Kill
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! squashfs images loop-mounted from a share (init.squashfs)
+
+use anyhow::{Context, Result};
+use rustix::ioctl::{BadOpcode, IntegerSetter, Ioctl, IoctlOutput, Opcode};
+use rustix::mount::MountFlags;
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+
+use crate::cmdline::SquashfsMount;
+use crate::{system, virtiofs};
+
+/// Control device handing out free loop devices
+const LOOP_CONTROL: &str = "/dev/loop-control";
+
+/// LOOP_SET_FD: attach the image fd passed as the argument
+type SetFd = IntegerSetter<BadOpcode<0x4C00>>;
+
+/// LOOP_CTL_GET_FREE: returns the index of a free loop device
+///
+/// The index is the ioctl's return value, which rustix's patterns drop.
+struct GetFree;
+
+// Safety: LOOP_CTL_GET_FREE takes no argument and touches no user memory
+unsafe impl Ioctl for GetFree {
+    type Output = u32;
+
+    const OPCODE: Opcode = Opcode::old(0x4C82);
+    const IS_MUTATING: bool = false;
+
+    fn as_ptr(&mut self) -> *mut std::ffi::c_void {
+        std::ptr::null_mut()
+    }
+
+    unsafe fn output_from_ptr(
+        out: IoctlOutput,
+        _extract_output: *mut std::ffi::c_void,
+    ) -> rustix::io::Result<u32> {
+        Ok(out as u32)
+    }
+}
+
+fn check_squashfs_support() -> Result<()> {
+    if !system::filesystem_supported("squashfs")? {
+        anyhow::bail!(
+            "squashfs filesystem not supported by kernel. \
+             Make sure CONFIG_SQUASHFS is enabled (either built-in or as a module)."
+        );
+    }
+    // The loop driver is no filesystem, its control device shows it's there
+    if !std::path::Path::new(LOOP_CONTROL).exists() {
+        anyhow::bail!(
+            "{} not found. Make sure CONFIG_BLK_DEV_LOOP is enabled \
+             (either built-in or as a module).",
+            LOOP_CONTROL
+        );
+    }
+    info!("squashfs and loop support detected");
+    Ok(())
+}
+
+/// Path of the loop device with the given index
+fn loop_device_path(index: u32) -> String {
+    format!("/dev/loop{}", index)
+}
+
+/// Attach the image read-only to a free loop device, returning its path
+fn attach_loop_device(image: &str) -> Result<String> {
+    let control =
+        File::open(LOOP_CONTROL).with_context(|| format!("Failed to open {}", LOOP_CONTROL))?;
+    // Safety: GetFree matches LOOP_CTL_GET_FREE
+    let index = unsafe { rustix::ioctl::ioctl(&control, GetFree) }
+        .context("Failed to get a free loop device")?;
+    let device = loop_device_path(index);
+
+    // A read-only backing file makes the loop device read-only as well
+    let image_file =
+        File::open(image).with_context(|| format!("Failed to open squashfs image {}", image))?;
+    let loop_file = OpenOptions::new()
+        .read(true)
+        .open(&device)
+        .with_context(|| format!("Failed to open {}", device))?;
+    // Safety: LOOP_SET_FD expects an open fd, which outlives the call
+    unsafe { rustix::ioctl::ioctl(&loop_file, SetFd::new(image_file.as_raw_fd() as usize)) }
+        .with_context(|| format!("Failed to attach {} to {}", image, device))?;
+
+    Ok(device)
+}
+
+/// Loop-mount each init.squashfs image read-only, in order
+///
+/// Runs after the shares are mounted, since that is where images come from.
+pub fn mount_squashfs_images(mounts: &[SquashfsMount], dry_run: bool) -> Result<()> {
+    if mounts.is_empty() {
+        return Ok(());
+    }
+
+    if !dry_run {
+        check_squashfs_support()?;
+    }
+
+    for squashfs in mounts {
+        virtiofs::mkdir_p(&squashfs.path, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+
+        let device = if dry_run {
+            info!("dry-run: would attach {} to a loop device", squashfs.image);
+            "/dev/loopN".to_string()
+        } else {
+            attach_loop_device(&squashfs.image)?
+        };
+
+        system::mount_fs(
+            &format!(
+                "squashfs {} ({}) at {}",
+                squashfs.image, device, squashfs.path
+            ),
+            &device,
+            &squashfs.path,
+            "squashfs",
+            MountFlags::RDONLY,
+            "",
+            dry_run,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to mount squashfs {} at {}",
+                squashfs.image, squashfs.path
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loop_device_path() {
+        assert_eq!(loop_device_path(0), "/dev/loop0");
+        assert_eq!(loop_device_path(7), "/dev/loop7");
+        assert_eq!(loop_device_path(123), "/dev/loop123");
+    }
+}
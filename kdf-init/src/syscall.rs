@@ -0,0 +1,210 @@
+//! Wrappers around the syscalls worth tracing with init.verbose
+//!
+//! Each wrapper logs its exact arguments when verbose logging is on, then
+//! makes the call. Meant for a mount that mysteriously fails, where the
+//! summary lines of init.loglevel=debug don't show enough.
+
+use rustix::fd::{AsFd, AsRawFd};
+use rustix::fs::Mode;
+use rustix::mount::{MountFlags, MountPropagationFlags};
+use std::process::Command;
+
+use crate::log;
+
+/// MS_* names of the mount flag bits, in bit order
+const MOUNT_FLAG_NAMES: [(u32, &str); 21] = [
+    (0x1, "MS_RDONLY"),
+    (0x2, "MS_NOSUID"),
+    (0x4, "MS_NODEV"),
+    (0x8, "MS_NOEXEC"),
+    (0x10, "MS_SYNCHRONOUS"),
+    (0x20, "MS_REMOUNT"),
+    (0x40, "MS_MANDLOCK"),
+    (0x80, "MS_DIRSYNC"),
+    (0x100, "MS_NOSYMFOLLOW"),
+    (0x400, "MS_NOATIME"),
+    (0x800, "MS_NODIRATIME"),
+    (0x1000, "MS_BIND"),
+    (0x2000, "MS_MOVE"),
+    (0x4000, "MS_REC"),
+    (0x8000, "MS_SILENT"),
+    (0x20000, "MS_UNBINDABLE"),
+    (0x40000, "MS_PRIVATE"),
+    (0x80000, "MS_SLAVE"),
+    (0x100000, "MS_SHARED"),
+    (0x200000, "MS_RELATIME"),
+    (0x1000000, "MS_STRICTATIME"),
+];
+
+/// MS_MOVE, only to log what rustix's mount_move passes
+const MS_MOVE: MountFlags = MountFlags::from_bits_retain(0x2000);
+
+/// MS_REMOUNT, only to log what rustix's mount_remount passes
+const MS_REMOUNT: MountFlags = MountFlags::from_bits_retain(0x20);
+
+/// Render mount flags as "MS_A|MS_B (0x...)", unnamed bits in hex
+pub fn format_mount_flags(flags: MountFlags) -> String {
+    let bits = flags.bits();
+    if bits == 0 {
+        return "0".to_string();
+    }
+
+    let mut names: Vec<String> = MOUNT_FLAG_NAMES
+        .iter()
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unnamed = MOUNT_FLAG_NAMES
+        .iter()
+        .fold(bits, |rest, (bit, _)| rest & !bit);
+    if unnamed != 0 {
+        names.push(format!("{:#x}", unnamed));
+    }
+
+    format!("{} ({:#x})", names.join("|"), bits)
+}
+
+/// Log a mount(2) call, `flags` as passed to the kernel
+fn log_mount(source: &str, target: &str, fstype: &str, flags: MountFlags, data: &str) {
+    if log::verbose() {
+        info!(
+            "syscall: mount(source={:?}, target={:?}, fstype={:?}, flags={}, data={:?})",
+            source,
+            target,
+            fstype,
+            format_mount_flags(flags),
+            data
+        );
+    }
+}
+
+/// mount(2), see [`rustix::mount::mount`]
+pub fn mount_logged(
+    source: &str,
+    target: &str,
+    fstype: &str,
+    flags: MountFlags,
+    data: &str,
+) -> rustix::io::Result<()> {
+    log_mount(source, target, fstype, flags, data);
+    rustix::mount::mount(source, target, fstype, flags, data)
+}
+
+/// mount(2) with MS_BIND, see [`rustix::mount::mount_bind`]
+pub fn bind_mount_logged(source: &str, target: &str) -> rustix::io::Result<()> {
+    log_mount(source, target, "", MountFlags::BIND, "");
+    rustix::mount::mount_bind(source, target)
+}
+
+/// mount(2) with MS_REMOUNT, see [`rustix::mount::mount_remount`]
+pub fn remount_logged(target: &str, flags: MountFlags, data: &str) -> rustix::io::Result<()> {
+    log_mount("", target, "", flags | MS_REMOUNT, data);
+    rustix::mount::mount_remount(target, flags, data)
+}
+
+/// mount(2) changing propagation, see [`rustix::mount::mount_change`]
+pub fn change_mount_logged(target: &str, flags: MountPropagationFlags) -> rustix::io::Result<()> {
+    // Propagation flags share their bits with the MS_* mount flags
+    log_mount(
+        "",
+        target,
+        "",
+        MountFlags::from_bits_retain(flags.bits()),
+        "",
+    );
+    rustix::mount::mount_change(target, flags)
+}
+
+/// mount(2) with MS_MOVE, see [`rustix::mount::mount_move`]
+pub fn move_mount_logged(source: &str, target: &str) -> rustix::io::Result<()> {
+    log_mount(source, target, "", MS_MOVE, "");
+    rustix::mount::mount_move(source, target)
+}
+
+/// mkdir(2), see [`rustix::fs::mkdir`]
+pub fn mkdir_logged<P: rustix::path::Arg + std::fmt::Debug>(
+    path: P,
+    mode: Mode,
+) -> rustix::io::Result<()> {
+    if log::verbose() {
+        info!(
+            "syscall: mkdir(path={:?}, mode={:#o})",
+            path,
+            mode.as_raw_mode()
+        );
+    }
+    rustix::fs::mkdir(path, mode)
+}
+
+/// mkdirat(2), see [`rustix::fs::mkdirat`]
+pub fn mkdirat_logged<Fd: AsFd>(dirfd: Fd, path: &str, mode: Mode) -> rustix::io::Result<()> {
+    if log::verbose() {
+        info!(
+            "syscall: mkdirat(dirfd={}, path={:?}, mode={:#o})",
+            dirfd.as_fd().as_raw_fd(),
+            path,
+            mode.as_raw_mode()
+        );
+    }
+    rustix::fs::mkdirat(dirfd, path, mode)
+}
+
+/// symlink(2), creating `link` pointing at `target`
+pub fn symlink_logged(target: &str, link: &str) -> rustix::io::Result<()> {
+    if log::verbose() {
+        info!("syscall: symlink(target={:?}, linkpath={:?})", target, link);
+    }
+    rustix::fs::symlink(target, link)
+}
+
+/// Log the execve(2) a spawn or exec of `cmd` is about to make
+///
+/// std's Command makes the call itself, so this only logs. argv[0] is
+/// the program unless the caller set it with arg0.
+pub fn log_execve(cmd: &Command) {
+    if log::verbose() {
+        info!(
+            "syscall: execve(path={:?}, args={:?})",
+            cmd.get_program(),
+            cmd.get_args().collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_mount_flags() {
+        assert_eq!(format_mount_flags(MountFlags::empty()), "0");
+        assert_eq!(format_mount_flags(MountFlags::RDONLY), "MS_RDONLY (0x1)");
+        assert_eq!(
+            format_mount_flags(MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC),
+            "MS_NOSUID|MS_NODEV|MS_NOEXEC (0xe)"
+        );
+    }
+
+    #[test]
+    fn test_format_mount_flags_without_rustix_names() {
+        // MS_REMOUNT and MS_MOVE only exist as raw bits in rustix
+        let remount = MountFlags::BIND | MountFlags::RDONLY | MountFlags::from_bits_retain(0x20);
+        assert_eq!(
+            format_mount_flags(remount),
+            "MS_RDONLY|MS_REMOUNT|MS_BIND (0x1021)"
+        );
+        assert_eq!(
+            format_mount_flags(MountFlags::from_bits_retain(0x2000)),
+            "MS_MOVE (0x2000)"
+        );
+    }
+
+    #[test]
+    fn test_format_mount_flags_unnamed_bits() {
+        let flags = MountFlags::RDONLY | MountFlags::from_bits_retain(0x4000_0000);
+        assert_eq!(
+            format_mount_flags(flags),
+            "MS_RDONLY|0x40000000 (0x40000001)"
+        );
+    }
+}
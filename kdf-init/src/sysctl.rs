@@ -0,0 +1,99 @@
+//! Kernel tunables from init.sysctl, written to /proc/sys
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// Map a dotted sysctl key to its file under /proc/sys
+///
+/// Example: "net.ipv4.ip_forward" -> "/proc/sys/net/ipv4/ip_forward"
+pub fn sysctl_path(key: &str) -> Result<String> {
+    if key.starts_with('/') || key.contains("..") {
+        anyhow::bail!("Invalid sysctl key: {}", key);
+    }
+
+    let path = key.replace('.', "/");
+    if path.split('/').any(str::is_empty) {
+        anyhow::bail!("Invalid sysctl key: {}", key);
+    }
+
+    Ok(format!("/proc/sys/{}", path))
+}
+
+/// Console log level, followed by the default, minimum and boot-time levels
+const PRINTK_PATH: &str = "/proc/sys/kernel/printk";
+
+/// Set the kernel console log level from init.printk
+///
+/// Writing a single number leaves the other printk levels alone.
+pub fn set_printk_level(level: u8, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!(
+            "dry-run: would set the kernel console log level to {}",
+            level
+        );
+        return Ok(());
+    }
+
+    std::fs::write(PRINTK_PATH, level.to_string())
+        .with_context(|| format!("Failed to write {}", PRINTK_PATH))?;
+    info!("set the kernel console log level to {}", level);
+    Ok(())
+}
+
+/// Write every init.sysctl setting, in key order
+pub fn apply_sysctls(sysctls: &HashMap<String, String>, dry_run: bool) -> Result<()> {
+    let mut keys: Vec<&String> = sysctls.keys().collect();
+    keys.sort();
+
+    for key in keys {
+        let value = &sysctls[key];
+        let path = sysctl_path(key)?;
+        if dry_run {
+            info!("dry-run: would set sysctl {}={}", key, value);
+            continue;
+        }
+
+        std::fs::write(&path, value)
+            .with_context(|| format!("Failed to set sysctl {}={}", key, value))?;
+        info!("set sysctl {}={}", key, value);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sysctl_path() {
+        assert_eq!(
+            sysctl_path("net.ipv4.ip_forward").unwrap(),
+            "/proc/sys/net/ipv4/ip_forward"
+        );
+        assert_eq!(
+            sysctl_path("vm.swappiness").unwrap(),
+            "/proc/sys/vm/swappiness"
+        );
+        assert_eq!(
+            sysctl_path("kernel/panic").unwrap(),
+            "/proc/sys/kernel/panic"
+        );
+    }
+
+    #[test]
+    fn test_sysctl_path_rejects_traversal() {
+        for key in [
+            "/etc/passwd",
+            "../../etc/passwd",
+            "net..ipv4",
+            "net/../../etc",
+            "",
+            ".net",
+            "net.",
+            "net//ipv4",
+        ] {
+            assert!(sysctl_path(key).is_err(), "{:?} should be rejected", key);
+        }
+    }
+}
@@ -2,30 +2,44 @@
 
 use anyhow::{Context, Result};
 use rustix::fs::Mode;
-use rustix::mount::{mount, MountFlags};
+use rustix::io::Errno;
+use rustix::mount::{MountFlags, MountPropagationFlags, UnmountFlags};
+use std::time::{Duration, Instant};
 
+use crate::cmdline::{BindMount, ExtraMount, MoveMount, ReadyMarker, Symlink, TmpfsMount, WaitFor};
+use crate::error::InitError;
+use crate::{syscall, virtiofs};
+
+#[derive(Debug, Clone)]
 struct KernelMount {
     source: &'static str,
     target: &'static str,
     fstype: &'static str,
     flags: MountFlags,
     data: &'static str,
+    /// Whether failing to mount this aborts the boot
+    critical: bool,
 }
 
+/// Mounted before everything else since the kernel cmdline is read from it
+const PROC_MOUNT: KernelMount = KernelMount {
+    source: "proc",
+    target: "/proc",
+    fstype: "proc",
+    flags: MountFlags::empty(),
+    data: "",
+    critical: true,
+};
+
+/// Default kernel filesystems, each can be skipped with init.nomount
 const KERNEL_MOUNTS: &[KernelMount] = &[
-    KernelMount {
-        source: "proc",
-        target: "/proc",
-        fstype: "proc",
-        flags: MountFlags::empty(),
-        data: "",
-    },
     KernelMount {
         source: "sysfs",
         target: "/sys",
         fstype: "sysfs",
         flags: MountFlags::empty(),
         data: "",
+        critical: false,
     },
     KernelMount {
         source: "devtmpfs",
@@ -33,6 +47,7 @@ const KERNEL_MOUNTS: &[KernelMount] = &[
         fstype: "devtmpfs",
         flags: MountFlags::empty(),
         data: "",
+        critical: true,
     },
     KernelMount {
         source: "tmpfs",
@@ -40,48 +55,672 @@ const KERNEL_MOUNTS: &[KernelMount] = &[
         fstype: "tmpfs",
         flags: MountFlags::empty(),
         data: "mode=0755",
+        critical: false,
     },
 ];
 
-pub fn mount_kernel_filesystems() -> Result<()> {
+/// Pseudo-terminals for init.devpts, gid 5 is the conventional tty group
+const DEVPTS_MOUNT: KernelMount = KernelMount {
+    source: "devpts",
+    target: "/dev/pts",
+    fstype: "devpts",
+    flags: MountFlags::empty(),
+    data: "gid=5,mode=620,ptmxmode=666",
+    critical: false,
+};
+
+/// Where /dev/ptmx points, the devpts instance's own multiplexer
+const PTMX_TARGET: &str = "pts/ptmx";
+
+/// statfs f_type of FUSE filesystems, virtiofs included
+pub const FUSE_SUPER_MAGIC: u64 = 0x65735546;
+/// statfs f_type of overlayfs
+pub const OVERLAYFS_SUPER_MAGIC: u64 = 0x794c7630;
+
+/// Filesystem names by statfs f_type, for error messages
+const FS_MAGICS: [(u64, &str); 7] = [
+    (FUSE_SUPER_MAGIC, "fuse (virtiofs)"),
+    (OVERLAYFS_SUPER_MAGIC, "overlay"),
+    (0x01021994, "tmpfs"),
+    (0x858458f6, "ramfs"),
+    (0x01021997, "9p"),
+    (0x9fa0, "proc"),
+    (0x62656572, "sysfs"),
+];
+
+/// Name of the filesystem with statfs f_type `magic`, if known
+fn fs_name(magic: u64) -> Option<&'static str> {
+    FS_MAGICS
+        .iter()
+        .find(|(known, _)| *known == magic)
+        .map(|(_, name)| *name)
+}
+
+/// Check that the filesystem at `target` is the one that was just mounted
+///
+/// Catches a mount that reported success but left `target` on another
+/// filesystem, e.g. the initramfs.
+pub fn verify_mount(target: &str, expected_fstype_magic: u64) -> Result<()> {
+    let stat = rustix::fs::statfs(target)
+        .with_context(|| format!("Failed to statfs mount at {}", target))?;
+    // f_type is a signed long on most arches, the magics are 32-bit
+    let magic = stat.f_type as u64 & 0xffff_ffff;
+
+    if magic != expected_fstype_magic {
+        let describe = |magic| match fs_name(magic) {
+            Some(name) => name.to_string(),
+            None => format!("{:#x}", magic),
+        };
+        anyhow::bail!(
+            "Mount at {} is {}, expected {}",
+            target,
+            describe(magic),
+            describe(expected_fstype_magic)
+        );
+    }
+
+    Ok(())
+}
+
+/// Mount a filesystem, or only log the intended mount in dry-run mode
+///
+/// `what` describes the mount in the log, e.g. "virtiofs share at /mnt".
+pub fn mount_fs(
+    what: &str,
+    source: &str,
+    target: &str,
+    fstype: &str,
+    flags: MountFlags,
+    data: &str,
+    dry_run: bool,
+) -> rustix::io::Result<()> {
+    if dry_run {
+        info!(
+            "dry-run: would mount {} (flags: {:?}, data: {:?})",
+            what, flags, data
+        );
+        return Ok(());
+    }
+
+    syscall::mount_logged(source, target, fstype, flags, data)?;
+    info!("mounted {}", what);
+
+    Ok(())
+}
+
+/// Make a mount for `what` with `mount`, or only log it in dry-run
+fn mount_with(
+    what: &str,
+    dry_run: bool,
+    mount: impl FnOnce() -> rustix::io::Result<()>,
+) -> rustix::io::Result<()> {
+    if dry_run {
+        info!("dry-run: would mount {}", what);
+        return Ok(());
+    }
+
+    mount()?;
+    info!("mounted {}", what);
+    Ok(())
+}
+
+fn mount_filesystem(
+    source: &str,
+    target: &str,
+    fstype: &str,
+    flags: MountFlags,
+    data: &str,
+    dry_run: bool,
+) -> rustix::io::Result<()> {
+    // Create mount point if it doesn't exist
+    if dry_run {
+        info!("dry-run: would create {}", target);
+    } else {
+        syscall::mkdir_logged(target, Mode::from_raw_mode(0o755)).or_else(|e| {
+            if e == rustix::io::Errno::EXIST {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        })?;
+    }
+
+    // Mount filesystem
+    mount_fs(target, source, target, fstype, flags, data, dry_run)
+}
+
+/// Targets of the failed mounts that must abort the boot
+fn critical_failures(failures: &[(KernelMount, Errno)]) -> Vec<&'static str> {
+    failures
+        .iter()
+        .filter(|(m, _)| m.critical)
+        .map(|(m, _)| m.target)
+        .collect()
+}
+
+pub fn mount_proc(skip_mounted: bool) -> Result<()> {
+    let m = &PROC_MOUNT;
+    // /proc/mounts is only readable once proc is mounted
+    if skip_mounted && std::path::Path::new("/proc/mounts").exists() {
+        info!("{} already mounted, skipping", m.target);
+        return Ok(());
+    }
+    mount_filesystem(m.source, m.target, m.fstype, m.flags, m.data, false).map_err(|errno| {
+        InitError::MountFailed {
+            target: m.target.to_string(),
+            errno,
+        }
+        .into()
+    })
+}
+
+/// Make every mount below / private (init.mount_private)
+///
+/// Mounts made in a namespace of the command then stay there and vice versa.
+/// Marking an already private mount private again changes nothing.
+pub fn make_mounts_private(dry_run: bool) -> Result<()> {
+    mount_with("/ recursively private", dry_run, || {
+        syscall::change_mount_logged(
+            "/",
+            MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+        )
+    })
+    .context("Failed to make / private")?;
+    Ok(())
+}
+
+/// Mount data replacing the built-in defaults, from init.proc_opts and
+/// init.run_opts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelMountData<'a> {
+    pub proc: Option<&'a str>,
+    pub run: Option<&'a str>,
+}
+
+/// Mount data for a kernel filesystem, its override if there is one
+fn kernel_mount_data<'a>(m: &KernelMount, overrides: &KernelMountData<'a>) -> &'a str {
+    let data = match m.target {
+        "/proc" => overrides.proc,
+        "/run" => overrides.run,
+        _ => None,
+    };
+    data.unwrap_or(m.data)
+}
+
+pub fn mount_kernel_filesystems(
+    nomount: &[String],
+    extra_mounts: &[ExtraMount],
+    overrides: KernelMountData,
+    skip_mounted: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let mut failures = Vec::new();
+
+    // /proc is mounted before the cmdline is read, so its data needs a remount
+    if overrides.proc.is_some() {
+        let m = &PROC_MOUNT;
+        let data = kernel_mount_data(m, &overrides);
+        mount_with(
+            &format!("{} remounted ({})", m.target, data),
+            dry_run,
+            || syscall::remount_logged(m.target, m.flags, data),
+        )
+        .with_context(|| format!("Failed to remount {} with {}", m.target, data))?;
+    }
+
     for m in KERNEL_MOUNTS {
-        // Create mount point if it doesn't exist
-        rustix::fs::mkdir(m.target, Mode::from_raw_mode(0o755))
-            .or_else(|e| {
-                if e == rustix::io::Errno::EXIST {
-                    Ok(())
-                } else {
-                    Err(e)
-                }
-            })
-            .with_context(|| format!("Failed to create {}", m.target))?;
+        if nomount.iter().any(|target| target == m.target) {
+            info!("skipping {} (init.nomount)", m.target);
+            continue;
+        }
+        if already_mounted(m.target, skip_mounted)? {
+            continue;
+        }
+
+        // Keep going so every failing mount gets reported
+        let data = kernel_mount_data(m, &overrides);
+        if let Err(e) = mount_filesystem(m.source, m.target, m.fstype, m.flags, data, dry_run) {
+            if m.critical {
+                error!("failed to mount {}: {}", m.target, e);
+            } else {
+                warn!("failed to mount {}: {}", m.target, e);
+            }
+            failures.push((m.clone(), e));
+        }
+    }
+
+    let critical = critical_failures(&failures);
+    if !critical.is_empty() {
+        anyhow::bail!(
+            "Failed to mount critical kernel filesystems: {}",
+            critical.join(", ")
+        );
+    }
+
+    // Extra mounts come after the base set so they can live below /sys or /dev
+    for m in extra_mounts {
+        if already_mounted(&m.target, skip_mounted)? {
+            continue;
+        }
+        if let Err(e) = mount_filesystem(
+            &m.fstype,
+            &m.target,
+            &m.fstype,
+            MountFlags::empty(),
+            &m.data,
+            dry_run,
+        ) {
+            warn!("failed to mount {} ({}): {}", m.target, m.fstype, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Where securityfs goes, a directory sysfs provides for it
+const SECURITYFS_TARGET: &str = "/sys/kernel/security";
+
+/// LSM filesystems still to mount, as (fstype, target)
+///
+/// `mounts` is the contents of /proc/mounts, anything already mounted at
+/// its target is left alone.
+fn pending_lsm_mounts<'a>(
+    securityfs: bool,
+    selinuxfs: Option<&'a str>,
+    mounts: &str,
+) -> Vec<(&'static str, &'a str)> {
+    let wanted = securityfs
+        .then_some(("securityfs", SECURITYFS_TARGET))
+        .into_iter()
+        .chain(selinuxfs.map(|target| ("selinuxfs", target)));
+    wanted
+        .filter(|(fstype, target)| {
+            let mounted = mounts_contains(mounts, target);
+            if mounted {
+                info!("{} already mounted at {}, skipping", fstype, target);
+            }
+            !mounted
+        })
+        .collect()
+}
+
+/// Mount securityfs (init.securityfs) and selinuxfs (init.selinuxfs)
+///
+/// Needs /sys mounted first.
+pub fn mount_lsm_filesystems(
+    securityfs: bool,
+    selinuxfs: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    if !securityfs && selinuxfs.is_none() {
+        return Ok(());
+    }
+
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    for (fstype, target) in pending_lsm_mounts(securityfs, selinuxfs, &mounts) {
+        // Modules aren't loaded in dry-run, so the check would be meaningless
+        if !dry_run && !filesystem_supported(fstype)? {
+            anyhow::bail!("{} filesystem not supported by kernel", fstype);
+        }
+        mount_filesystem(fstype, target, fstype, MountFlags::empty(), "", dry_run)
+            .with_context(|| format!("Failed to mount {} at {}", fstype, target))?;
+    }
+    Ok(())
+}
+
+/// Mount devpts at /dev/pts and make /dev/ptmx a link into it (init.devpts)
+///
+/// Skipped if /dev/pts is already mounted. devtmpfs ships /dev/ptmx as a
+/// device node, which is replaced by the link.
+pub fn mount_devpts(dry_run: bool) -> Result<()> {
+    let m = &DEVPTS_MOUNT;
+    if is_mounted(m.target)? {
+        info!("{} already mounted, skipping", m.target);
+    } else {
+        mount_filesystem(m.source, m.target, m.fstype, m.flags, m.data, dry_run)
+            .with_context(|| format!("Failed to mount devpts at {}", m.target))?;
+    }
+
+    link_ptmx("/dev/ptmx", dry_run)
+}
+
+/// Point `ptmx` at pts/ptmx, replacing whatever else is there
+fn link_ptmx(ptmx: &str, dry_run: bool) -> Result<()> {
+    if std::fs::read_link(ptmx).is_ok_and(|target| target == std::path::Path::new(PTMX_TARGET)) {
+        return Ok(());
+    }
+    if dry_run {
+        info!("dry-run: would create symlink {} -> {}", ptmx, PTMX_TARGET);
+        return Ok(());
+    }
+
+    match std::fs::remove_file(ptmx) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to remove {}", ptmx)),
+    }
+    syscall::symlink_logged(PTMX_TARGET, ptmx)
+        .with_context(|| format!("Failed to create symlink {} -> {}", ptmx, PTMX_TARGET))?;
+    info!("created symlink {} -> {}", ptmx, PTMX_TARGET);
+
+    Ok(())
+}
+
+/// Mount the init.devshm tmpfs, unless /dev/shm is already a mount
+///
+/// Needs /dev mounted first. nosuid and nodev as on other distributions.
+pub fn mount_devshm(mount: &TmpfsMount, dry_run: bool) -> Result<()> {
+    if is_mounted(&mount.path)? {
+        info!("{} already mounted, skipping", mount.path);
+        return Ok(());
+    }
+    virtiofs::mkdir_p(&mount.path, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+
+    let data = tmpfs_options(mount, None);
+    mount_fs(
+        &format!("tmpfs ({}) at {}", data, mount.path),
+        "tmpfs",
+        &mount.path,
+        "tmpfs",
+        MountFlags::NOSUID | MountFlags::NODEV,
+        &data,
+        dry_run,
+    )
+    .with_context(|| format!("Failed to mount tmpfs at {}", mount.path))?;
+    Ok(())
+}
+
+/// Move each init.move source mount to its destination, in order
+///
+/// The destination's parents are created as needed. On a re-run, a
+/// destination that is already mounted is skipped, its source is gone.
+pub fn move_mounts(moves: &[MoveMount], skip_mounted: bool, dry_run: bool) -> Result<()> {
+    for m in moves {
+        if already_mounted(&m.target, skip_mounted)? {
+            continue;
+        }
+        // Nothing is mounted in dry-run, so the source can't be checked
+        if !dry_run && !is_mounted(&m.source)? {
+            anyhow::bail!("init.move source {} is not a mount point", m.source);
+        }
+
+        virtiofs::mkdir_p(&m.target, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+        if dry_run {
+            info!("dry-run: would move {} to {}", m.source, m.target);
+            continue;
+        }
+        syscall::move_mount_logged(&m.source, &m.target)
+            .with_context(|| format!("Failed to move {} to {}", m.source, m.target))?;
+        info!("moved {} to {}", m.source, m.target);
+    }
+
+    Ok(())
+}
+
+/// One mount call making an init.bind mount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BindStep {
+    /// Bind the source at the target
+    Bind,
+    /// Remount the bind itself read-only
+    RemountReadOnly,
+}
+
+/// Mount calls making an init.bind mount, in order
+///
+/// MS_BIND ignores MS_RDONLY, so a read-only bind needs a second call
+/// remounting the bind itself read-only.
+fn bind_steps(bind: &BindMount) -> Vec<BindStep> {
+    let mut steps = vec![BindStep::Bind];
+    if bind.read_only {
+        steps.push(BindStep::RemountReadOnly);
+    }
+    steps
+}
+
+/// Make each init.bind mount, in order
+pub fn bind_mounts(binds: &[BindMount], skip_mounted: bool, dry_run: bool) -> Result<()> {
+    for bind in binds {
+        if already_mounted(&bind.target, skip_mounted)? {
+            continue;
+        }
+
+        virtiofs::mkdir_p(&bind.target, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+        for step in bind_steps(bind) {
+            let result = match step {
+                BindStep::Bind => mount_with(
+                    &format!("{} bound at {}", bind.source, bind.target),
+                    dry_run,
+                    || syscall::bind_mount_logged(&bind.source, &bind.target),
+                ),
+                BindStep::RemountReadOnly => mount_with(
+                    &format!("bind of {} at {} read-only", bind.source, bind.target),
+                    dry_run,
+                    || {
+                        syscall::remount_logged(
+                            &bind.target,
+                            MountFlags::BIND | MountFlags::RDONLY,
+                            "",
+                        )
+                    },
+                ),
+            };
+            result.with_context(|| format!("Failed to bind {} at {}", bind.source, bind.target))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Flags to retry a failed init.umount with, if the error allows a retry
+///
+/// A busy mount point, e.g. still open or some process's cwd, is detached
+/// instead: it leaves the tree right away and goes once nothing uses it.
+fn umount_retry_flags(err: Errno) -> Option<UnmountFlags> {
+    (err == Errno::BUSY).then_some(UnmountFlags::DETACH)
+}
+
+/// Unmount each init.umount path, in order, right before handing over
+pub fn unmount_paths(paths: &[String], dry_run: bool) -> Result<()> {
+    for path in paths {
+        if dry_run {
+            info!("dry-run: would unmount {}", path);
+            continue;
+        }
+        if !is_mounted(path)? {
+            anyhow::bail!("init.umount path {} is not a mount point", path);
+        }
+
+        match rustix::mount::unmount(path.as_str(), UnmountFlags::empty()) {
+            Ok(()) => info!("unmounted {}", path),
+            Err(err) => {
+                let flags = umount_retry_flags(err)
+                    .ok_or(err)
+                    .with_context(|| format!("Failed to unmount {}", path))?;
+                rustix::mount::unmount(path.as_str(), flags)
+                    .with_context(|| format!("Failed to detach {}", path))?;
+                info!("detached busy mount {}", path);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Left behind once setup is done, so a re-exec'd init knows it ran before
+pub const DONE_MARKER: &str = "/run/kdf-init.done";
+
+/// Whether an earlier init already completed setup, see [`DONE_MARKER`]
+///
+/// On a re-run, targets that are already mounted get skipped instead of
+/// failing with EBUSY.
+pub fn setup_done() -> bool {
+    std::path::Path::new(DONE_MARKER).exists()
+}
+
+/// Create [`DONE_MARKER`] once setup is complete
+///
+/// A failure only costs the re-run protection, so it is just a warning.
+pub fn mark_setup_done(dry_run: bool) {
+    if dry_run {
+        info!("dry-run: would create {}", DONE_MARKER);
+        return;
+    }
+    if let Err(e) = std::fs::write(DONE_MARKER, "") {
+        warn!("failed to create {}: {}", DONE_MARKER, e);
+    }
+}
 
-        // Mount filesystem
-        mount(m.source, m.target, m.fstype, m.flags, m.data)
-            .with_context(|| format!("Failed to mount {}", m.target))?;
+/// Undo the octal escapes (`\040` for a space) used in /proc/mounts fields
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok())
+        {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
 
-        println!("kdf-init: mounted {}", m.target);
+/// Whether `target` is a mount point in the contents of /proc/mounts
+///
+/// Each line is "source target fstype options dump pass", paths are
+/// compared component-wise so a trailing '/' doesn't matter.
+pub fn mounts_contains(mounts: &str, target: &str) -> bool {
+    let target = std::path::Path::new(target);
+    mounts.lines().any(|line| {
+        line.split_whitespace().nth(1).is_some_and(|mount_point| {
+            std::path::Path::new(&unescape_mount_field(mount_point)) == target
+        })
+    })
+}
+
+/// Check whether something is mounted at `target`
+pub fn is_mounted(target: &str) -> Result<bool> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    Ok(mounts_contains(&mounts, target))
+}
+
+/// Whether to skip mounting `target`, only ever true on a re-run
+pub fn already_mounted(target: &str, skip_mounted: bool) -> Result<bool> {
+    if !skip_mounted || !is_mounted(target)? {
+        return Ok(false);
+    }
+    info!("{} already mounted, skipping", target);
+    Ok(true)
+}
+
+/// Whether `fstype` is listed in the contents of /proc/filesystems
+///
+/// Each line is an optional "nodev" column followed by the filesystem name.
+pub fn filesystems_contains(filesystems: &str, fstype: &str) -> bool {
+    filesystems.lines().any(|line| {
+        let mut columns = line.split_whitespace();
+        match (columns.next(), columns.next()) {
+            (Some("nodev"), Some(name)) | (Some(name), None) => name == fstype,
+            _ => false,
+        }
+    })
+}
+
+/// Read the filesystems supported by the running kernel
+pub fn read_filesystems() -> Result<String> {
+    std::fs::read_to_string("/proc/filesystems").context("Failed to read /proc/filesystems")
+}
+
+/// Check whether the running kernel supports `fstype`
+pub fn filesystem_supported(fstype: &str) -> Result<bool> {
+    Ok(filesystems_contains(&read_filesystems()?, fstype))
+}
+
+/// Whether to check /proc/filesystems before mounting a share
+///
+/// Modules aren't loaded in dry-run, so the check would be meaningless
+/// there. init.skip_fs_check leaves it to the mount, for kernels that only
+/// list a module-backed filesystem once it's first used.
+pub fn check_fs_support(skip_fs_check: bool, dry_run: bool) -> bool {
+    !skip_fs_check && !dry_run
+}
+
+/// tmpfs mount data for a size cap and root mode, both optional
+///
+/// `default_opts` (init.tmpfs_default_opts) come first, so the mount's own
+/// size and mode win over any given there.
+fn tmpfs_options(mount: &TmpfsMount, default_opts: Option<&str>) -> String {
+    let mut options: Vec<String> = default_opts
+        .filter(|opts| !opts.is_empty())
+        .map(str::to_string)
+        .into_iter()
+        .collect();
+    if let Some(size) = mount.size {
+        options.push(format!("size={}", size));
+    }
+    if let Some(mode) = mount.mode {
+        options.push(format!("mode={:o}", mode));
+    }
+    options.join(",")
+}
+
+/// Mount the init.tmpfs filesystems, creating their mount points
+pub fn mount_tmpfs_mounts(
+    mounts: &[TmpfsMount],
+    default_opts: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    for mount in mounts {
+        virtiofs::mkdir_p(&mount.path, virtiofs::DEFAULT_DIR_MODE, dry_run)?;
+
+        let data = tmpfs_options(mount, default_opts);
+        let what = if data.is_empty() {
+            format!("tmpfs at {}", mount.path)
+        } else {
+            format!("tmpfs ({}) at {}", data, mount.path)
+        };
+        mount_fs(
+            &what,
+            "tmpfs",
+            &mount.path,
+            "tmpfs",
+            MountFlags::empty(),
+            &data,
+            dry_run,
+        )
+        .with_context(|| format!("Failed to mount tmpfs at {}", mount.path))?;
     }
 
     Ok(())
 }
 
-pub fn load_kernel_modules(modules_dir: Option<&str>) -> Result<()> {
+pub fn load_kernel_modules(modules_dir: Option<&str>, dry_run: bool) -> Result<()> {
     use rustix::fd::AsFd;
     use std::fs;
 
     // If no moddir specified, skip module loading
     let Some(modules_dir) = modules_dir else {
-        println!("kdf-init: no moddir specified, skipping module loading");
+        info!("no moddir specified, skipping module loading");
         return Ok(());
     };
 
-    println!("kdf-init: loading kernel modules from '{}'", modules_dir);
+    info!("loading kernel modules from '{}'", modules_dir);
 
     // Check if modules directory exists
     if !std::path::Path::new(modules_dir).exists() {
-        println!(
-            "kdf-init: moddir '{}' does not exist, skipping module loading",
+        info!(
+            "moddir '{}' does not exist, skipping module loading",
             modules_dir
         );
         return Ok(());
@@ -108,27 +747,30 @@ pub fn load_kernel_modules(modules_dir: Option<&str>) -> Result<()> {
             {
                 total_count += 1;
                 let file_name = path.file_name().unwrap().to_string_lossy();
-                println!("kdf-init: loading module {}", file_name);
+
+                if dry_run {
+                    info!("dry-run: would load module {}", file_name);
+                    continue;
+                }
+
+                info!("loading module {}", file_name);
 
                 match fs::File::open(&path) {
                     Ok(file) => {
                         let empty_params = c"";
                         match rustix::system::finit_module(file.as_fd(), empty_params, 0) {
                             Ok(_) => {
-                                println!("kdf-init: successfully loaded {}", file_name);
+                                info!("successfully loaded {}", file_name);
                                 loaded_count += 1;
                             }
                             Err(e) => {
-                                println!(
-                                    "kdf-init: failed to load {}: {} (errno: {:?})",
-                                    file_name, e, e
-                                );
+                                warn!("failed to load {}: {} (errno: {:?})", file_name, e, e);
                                 failed_count += 1;
                             }
                         }
                     }
                     Err(e) => {
-                        println!("kdf-init: failed to open {}: {}", file_name, e);
+                        warn!("failed to open {}: {}", file_name, e);
                         failed_count += 1;
                     }
                 }
@@ -136,29 +778,56 @@ pub fn load_kernel_modules(modules_dir: Option<&str>) -> Result<()> {
         }
     }
 
-    println!(
-        "kdf-init: module loading complete: {} loaded, {} failed, {} total",
+    info!(
+        "module loading complete: {} loaded, {} failed, {} total",
         loaded_count, failed_count, total_count
     );
 
     Ok(())
 }
 
+pub fn create_symlinks(symlinks: &[Symlink], dry_run: bool) -> Result<()> {
+    for symlink in symlinks {
+        if dry_run {
+            info!(
+                "dry-run: would create symlink {} -> {}",
+                symlink.source, symlink.target
+            );
+            continue;
+        }
+
+        // The link is created at source and points to target
+        syscall::symlink_logged(&symlink.target, &symlink.source).with_context(|| {
+            format!(
+                "Failed to create symlink {} -> {}",
+                symlink.source, symlink.target
+            )
+        })?;
+
+        info!("created symlink {} -> {}", symlink.source, symlink.target);
+    }
+
+    Ok(())
+}
+
 /// Detach from parent and set up controlling terminal
 ///
 /// This should be called in pre_exec to:
 /// - Create a new session with setsid
 /// - Dup console_fd into stdin/stdout/stderr (closes old fds automatically)
 /// - Set TIOCSCTTY on stdin to make it the controlling terminal
-fn detach(console_fd: rustix::fd::BorrowedFd<'_>) -> std::io::Result<()> {
+pub fn detach(console_fd: rustix::fd::BorrowedFd<'_>) -> std::io::Result<()> {
     use rustix::process::ioctl_tiocsctty;
     use rustix::stdio::{dup2_stderr, dup2_stdin, dup2_stdout, stdin};
 
-    // Create a new session and become the session leader
-    rustix::process::setsid().map_err(|e| {
-        eprintln!("kdf-init: setsid failed: errno {}", e.raw_os_error());
-        std::io::Error::from_raw_os_error(e.raw_os_error())
-    })?;
+    // Create a new session and become the session leader, unless init
+    // already is from an exec that failed after this ran
+    if rustix::process::getsid(None).ok() != Some(rustix::process::getpid()) {
+        rustix::process::setsid().map_err(|e| {
+            eprintln!("kdf-init: setsid failed: errno {}", e.raw_os_error());
+            std::io::Error::from_raw_os_error(e.raw_os_error())
+        })?;
+    }
 
     // Dup2 console_fd into stdin/stdout/stderr (dup2 closes old fds automatically)
     dup2_stdin(console_fd).map_err(|e| {
@@ -188,12 +857,135 @@ fn detach(console_fd: rustix::fd::BorrowedFd<'_>) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Path of a console device name under /dev
+///
+/// Only a plain device name (e.g. "ttyS0", "hvc0") is accepted.
+pub fn console_path(console_device: &str) -> Result<String> {
+    if console_device.is_empty() || console_device.contains('/') || console_device == ".." {
+        anyhow::bail!("Invalid console device name: {:?}", console_device);
+    }
+    Ok(format!("/dev/{}", console_device))
+}
+
+/// Open the console device (add /dev/ prefix) with CLOEXEC, read, and write
+pub fn open_console(console_device: &str) -> Result<rustix::fd::OwnedFd> {
+    use rustix::fs::{open, Mode, OFlags};
+
+    let console_path = console_path(console_device)?;
+    open(&console_path, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())
+        .with_context(|| format!("Failed to open console device: {}", console_path))
+}
+
+/// Point init's stdin, stdout and stderr at the console device
+///
+/// Needs devtmpfs mounted. Everything printed afterwards, and the command's
+/// inherited output, goes to the console. A missing device keeps the fds
+/// init inherited from the kernel.
+pub fn redirect_console(console_device: &str) -> Result<()> {
+    use rustix::fs::{open, Mode, OFlags};
+
+    let console_path = console_path(console_device)?;
+    let console = match open(
+        &console_path,
+        OFlags::RDWR | OFlags::NOCTTY | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(console) => console,
+        Err(Errno::NOENT) | Err(Errno::NXIO) | Err(Errno::NODEV) => {
+            warn!(
+                "console device {} not available, keeping inherited stdio",
+                console_path
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to open console device: {}", console_path))
+        }
+    };
+
+    rustix::stdio::dup2_stdin(&console).context("Failed to redirect stdin to console")?;
+    rustix::stdio::dup2_stdout(&console).context("Failed to redirect stdout to console")?;
+    rustix::stdio::dup2_stderr(&console).context("Failed to redirect stderr to console")?;
+
+    info!("redirected stdio to {}", console_path);
+    Ok(())
+}
+
+/// How often [`wait_for_path`] checks for the path
+pub const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Poll until `path` exists, giving up after `timeout`
+///
+/// The path is checked at least once, so a zero timeout checks without
+/// waiting.
+pub fn wait_for_path(path: &str, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if std::path::Path::new(path).exists() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            anyhow::bail!(
+                "Timed out after {}s waiting for {} to appear",
+                timeout.as_secs(),
+                path
+            );
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Wait for each init.wait_for path in turn, each with its own timeout
+pub fn wait_for_paths(waits: &[WaitFor], dry_run: bool) -> Result<()> {
+    for wait in waits {
+        if dry_run {
+            info!(
+                "dry-run: would wait up to {}s for {}",
+                wait.timeout, wait.path
+            );
+            continue;
+        }
+
+        info!("waiting up to {}s for {}", wait.timeout, wait.path);
+        let started = Instant::now();
+        wait_for_path(&wait.path, Duration::from_secs(wait.timeout))?;
+        info!(
+            "{} appeared after {}ms",
+            wait.path,
+            started.elapsed().as_millis()
+        );
+    }
+    Ok(())
+}
+
+/// Tell a watching test harness that setup is done
+///
+/// The console form prints the marker as its own line, without the log
+/// prefix, so it can be matched exactly. Failures are only logged.
+pub fn emit_ready_marker(marker: &ReadyMarker) {
+    use std::io::Write;
+
+    match marker {
+        ReadyMarker::Console(line) => {
+            let mut stdout = std::io::stdout().lock();
+            if let Err(e) = writeln!(stdout, "{}", line).and_then(|()| stdout.flush()) {
+                warn!("failed to print ready marker: {}", e);
+            }
+        }
+        ReadyMarker::File(path) => match std::fs::write(path, "ready\n") {
+            Ok(()) => info!("wrote ready marker {}", path),
+            Err(e) => warn!("failed to write ready marker {}: {}", path, e),
+        },
+    }
+}
+
 pub fn execute_shell(
     program: &str,
     args: &[String],
     console_device: &str,
 ) -> Result<std::process::ExitStatus> {
-    use rustix::fs::{open, Mode, OFlags};
     use std::os::unix::io::AsRawFd;
     use std::os::unix::process::CommandExt;
     use std::process::Command;
@@ -203,15 +995,12 @@ pub fn execute_shell(
     } else {
         format!("{} {}", program, args.join(" "))
     };
-    println!(
-        "kdf-init: spawning shell: {} on console: {}",
+    info!(
+        "spawning shell: {} on console: {}",
         display_cmd, console_device
     );
 
-    // Open console device (add /dev/ prefix) with CLOEXEC, read, and write
-    let console_path = format!("/dev/{}", console_device);
-    let console = open(&console_path, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())
-        .with_context(|| format!("Failed to open console device: {}", console_path))?;
+    let console = open_console(console_device)?;
 
     let console_fd = console.as_raw_fd();
 
@@ -226,6 +1015,7 @@ pub fn execute_shell(
     }
 
     // Spawn and wait for completion
+    syscall::log_execve(&cmd);
     let mut child = cmd
         .spawn()
         .with_context(|| format!("Failed to spawn shell: {}", display_cmd))?;
@@ -237,14 +1027,315 @@ pub fn execute_shell(
     Ok(status)
 }
 
+/// Warm reboot, for init.reboot_on
+pub fn reboot() -> Result<()> {
+    use rustix::system::RebootCommand;
+
+    info!("rebooting system");
+
+    rustix::system::reboot(RebootCommand::Restart).context("Failed to reboot system")?;
+
+    Ok(())
+}
+
 pub fn shutdown() -> Result<()> {
     use rustix::system::reboot;
     use rustix::system::RebootCommand;
 
-    println!("kdf-init: shutting down system");
+    info!("shutting down system");
 
     // Perform system shutdown
     reboot(RebootCommand::PowerOff).context("Failed to shutdown system")?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_MOUNTS: &str = "\
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+devtmpfs /dev devtmpfs rw,nosuid,size=4096k,nr_inodes=1024,mode=755 0 0
+share /mnt/my\\040share virtiofs rw,relatime 0 0
+overlay /work overlay rw,lowerdir=/run/overlayfs/work/lower 0 0
+";
+
+    #[test]
+    fn test_pending_lsm_mounts() {
+        assert_eq!(
+            pending_lsm_mounts(true, Some("/sys/fs/selinux"), SAMPLE_MOUNTS),
+            vec![
+                ("securityfs", "/sys/kernel/security"),
+                ("selinuxfs", "/sys/fs/selinux"),
+            ]
+        );
+        assert_eq!(
+            pending_lsm_mounts(false, Some("/selinux"), SAMPLE_MOUNTS),
+            vec![("selinuxfs", "/selinux")]
+        );
+        assert!(pending_lsm_mounts(false, None, SAMPLE_MOUNTS).is_empty());
+    }
+
+    #[test]
+    fn test_pending_lsm_mounts_skips_mounted_securityfs() {
+        let mounts = format!(
+            "{}securityfs /sys/kernel/security securityfs rw,nosuid,nodev,noexec,relatime 0 0\n",
+            SAMPLE_MOUNTS
+        );
+        assert!(pending_lsm_mounts(true, None, &mounts).is_empty());
+        assert_eq!(
+            pending_lsm_mounts(true, Some("/sys/fs/selinux"), &mounts),
+            vec![("selinuxfs", "/sys/fs/selinux")]
+        );
+    }
+
+    /// Unique path under the temp dir, removed if a previous run left it
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("kdf-init-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_wait_for_path_appears() {
+        let path = temp_path("wait-appears");
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(200));
+                std::fs::write(&path, "ready").unwrap();
+            })
+        };
+
+        let result = wait_for_path(&path, Duration::from_secs(10));
+        writer.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_path_times_out() {
+        let path = temp_path("wait-missing");
+        let started = Instant::now();
+        let err = wait_for_path(&path, Duration::from_millis(150)).unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(err.to_string().contains("Timed out"));
+        assert!(err.to_string().contains(&path));
+    }
+
+    #[test]
+    fn test_wait_for_path_existing() {
+        // Already there, so even a zero timeout succeeds
+        wait_for_path("/", Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn test_mounts_contains() {
+        assert!(mounts_contains(SAMPLE_MOUNTS, "/proc"));
+        assert!(mounts_contains(SAMPLE_MOUNTS, "/dev"));
+        assert!(mounts_contains(SAMPLE_MOUNTS, "/work"));
+        assert!(mounts_contains(SAMPLE_MOUNTS, "/work/"));
+        assert!(!mounts_contains(SAMPLE_MOUNTS, "/dev/pts"));
+        assert!(!mounts_contains(SAMPLE_MOUNTS, "/wor"));
+        // Source and fstype columns are not mount points
+        assert!(!mounts_contains(SAMPLE_MOUNTS, "overlay"));
+        assert!(!mounts_contains("", "/proc"));
+    }
+
+    #[test]
+    fn test_mounts_contains_escaped_path() {
+        assert!(mounts_contains(SAMPLE_MOUNTS, "/mnt/my share"));
+        assert!(!mounts_contains(SAMPLE_MOUNTS, "/mnt/my\\040share"));
+    }
+
+    #[test]
+    fn test_unescape_mount_field() {
+        assert_eq!(unescape_mount_field("/plain"), "/plain");
+        assert_eq!(unescape_mount_field("/a\\011b\\134c"), "/a\tb\\c");
+        // Not a full escape, left as-is
+        assert_eq!(unescape_mount_field("/a\\04"), "/a\\04");
+        assert_eq!(unescape_mount_field("/a\\x40"), "/a\\x40");
+    }
+
+    #[test]
+    fn test_umount_retry_flags() {
+        assert_eq!(umount_retry_flags(Errno::BUSY), Some(UnmountFlags::DETACH));
+        assert_eq!(umount_retry_flags(Errno::INVAL), None);
+        assert_eq!(umount_retry_flags(Errno::PERM), None);
+        assert_eq!(umount_retry_flags(Errno::NOENT), None);
+    }
+
+    #[test]
+    fn test_bind_steps() {
+        let bind = |read_only| BindMount {
+            source: "/src".to_string(),
+            target: "/dst".to_string(),
+            read_only,
+        };
+
+        assert_eq!(bind_steps(&bind(false)), vec![BindStep::Bind]);
+        // The read-only flag only sticks on a remount of the bind
+        assert_eq!(
+            bind_steps(&bind(true)),
+            vec![BindStep::Bind, BindStep::RemountReadOnly]
+        );
+    }
+
+    #[test]
+    fn test_fs_name() {
+        assert_eq!(fs_name(OVERLAYFS_SUPER_MAGIC), Some("overlay"));
+        assert_eq!(fs_name(FUSE_SUPER_MAGIC), Some("fuse (virtiofs)"));
+        assert_eq!(fs_name(0x858458f6), Some("ramfs"));
+        assert_eq!(fs_name(0xdeadbeef), None);
+    }
+
+    #[test]
+    fn test_fs_magics_unique() {
+        for (i, (magic, _)) in FS_MAGICS.iter().enumerate() {
+            assert!(
+                FS_MAGICS[i + 1..].iter().all(|(other, _)| other != magic),
+                "duplicate magic {:#x}",
+                magic
+            );
+        }
+    }
+
+    #[test]
+    fn test_tmpfs_options() {
+        let mount = |size, mode| TmpfsMount {
+            path: "/tmp".to_string(),
+            size,
+            mode,
+        };
+        assert_eq!(tmpfs_options(&mount(None, None), None), "");
+        assert_eq!(tmpfs_options(&mount(Some(4096), None), None), "size=4096");
+        assert_eq!(
+            tmpfs_options(&mount(Some(4096), Some(0o1777)), None),
+            "size=4096,mode=1777"
+        );
+        assert_eq!(tmpfs_options(&mount(None, Some(0o700)), None), "mode=700");
+    }
+
+    #[test]
+    fn test_tmpfs_options_default_opts() {
+        let mount = |size, mode| TmpfsMount {
+            path: "/tmp".to_string(),
+            size,
+            mode,
+        };
+        assert_eq!(
+            tmpfs_options(&mount(None, None), Some("noswap,mode=700")),
+            "noswap,mode=700"
+        );
+        assert_eq!(
+            tmpfs_options(&mount(Some(4096), Some(0o1777)), Some("noswap")),
+            "noswap,size=4096,mode=1777"
+        );
+        assert_eq!(
+            tmpfs_options(&mount(Some(4096), None), Some("")),
+            "size=4096"
+        );
+    }
+
+    #[test]
+    fn test_kernel_mount_data_defaults() {
+        let overrides = KernelMountData::default();
+        assert_eq!(kernel_mount_data(&PROC_MOUNT, &overrides), "");
+        for m in KERNEL_MOUNTS {
+            assert_eq!(kernel_mount_data(m, &overrides), m.data);
+        }
+    }
+
+    #[test]
+    fn test_kernel_mount_data_overrides() {
+        let overrides = KernelMountData {
+            proc: Some("hidepid=2"),
+            run: Some("mode=0700,size=16m"),
+        };
+        assert_eq!(kernel_mount_data(&PROC_MOUNT, &overrides), "hidepid=2");
+        let data = |target| {
+            let m = KERNEL_MOUNTS.iter().find(|m| m.target == target).unwrap();
+            kernel_mount_data(m, &overrides)
+        };
+        assert_eq!(data("/run"), "mode=0700,size=16m");
+        // Filesystems without an override keep their built-in data
+        assert_eq!(data("/sys"), "");
+        assert_eq!(data("/dev"), "");
+    }
+
+    #[test]
+    fn test_console_path() {
+        assert_eq!(console_path("ttyS0").unwrap(), "/dev/ttyS0");
+        assert_eq!(console_path("hvc0").unwrap(), "/dev/hvc0");
+        assert_eq!(console_path("ttyAMA0").unwrap(), "/dev/ttyAMA0");
+    }
+
+    #[test]
+    fn test_console_path_rejects_paths() {
+        for device in ["", "/dev/ttyS0", "pts/0", "../mem", ".."] {
+            assert!(
+                console_path(device).is_err(),
+                "{:?} should be rejected",
+                device
+            );
+        }
+    }
+
+    #[test]
+    fn test_critical_failures() {
+        let failures: Vec<(KernelMount, Errno)> = std::iter::once(&PROC_MOUNT)
+            .chain(KERNEL_MOUNTS)
+            .map(|m| (m.clone(), Errno::NODEV))
+            .collect();
+        assert_eq!(critical_failures(&failures), vec!["/proc", "/dev"]);
+    }
+
+    #[test]
+    fn test_non_critical_failures_only() {
+        let failures: Vec<(KernelMount, Errno)> = KERNEL_MOUNTS
+            .iter()
+            .filter(|m| m.target == "/sys" || m.target == "/run")
+            .map(|m| (m.clone(), Errno::NODEV))
+            .collect();
+        assert_eq!(failures.len(), 2);
+        assert!(critical_failures(&failures).is_empty());
+        assert!(critical_failures(&[]).is_empty());
+    }
+
+    const SAMPLE_FILESYSTEMS: &str = "nodev\tsysfs\n\
+                                      nodev\tproc\n\
+                                      nodev\tvirtiofs\n\
+                                      \text4\n\
+                                      nodev\t9p\n";
+
+    #[test]
+    fn test_filesystems_contains() {
+        for fstype in ["sysfs", "virtiofs", "ext4", "9p"] {
+            assert!(
+                filesystems_contains(SAMPLE_FILESYSTEMS, fstype),
+                "{}",
+                fstype
+            );
+        }
+        assert!(!filesystems_contains(SAMPLE_FILESYSTEMS, "overlay"));
+    }
+
+    #[test]
+    fn test_check_fs_support() {
+        assert!(check_fs_support(false, false));
+        assert!(!check_fs_support(true, false));
+        assert!(!check_fs_support(false, true));
+        assert!(!check_fs_support(true, true));
+    }
+
+    #[test]
+    fn test_filesystems_contains_no_substring_match() {
+        let filesystems = "nodev\tnotvirtiofs\nnodev\tvirtiofs2\n\text4\n";
+        assert!(!filesystems_contains(filesystems, "virtiofs"));
+        assert!(!filesystems_contains(filesystems, "ext"));
+        assert!(!filesystems_contains(filesystems, "nodev"));
+        assert!(!filesystems_contains("", "virtiofs"));
+    }
+}
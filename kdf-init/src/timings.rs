@@ -0,0 +1,117 @@
+//! Per-phase boot timings (init.timings)
+//!
+//! Off by default, in which case recording a phase is a single atomic load.
+
+use rustix::time::{clock_gettime, ClockId};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::boot_timeout::Phase;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Monotonic nanoseconds at which each phase started, 0 if never entered
+static STARTS: [AtomicU64; Phase::ALL.len()] = [const { AtomicU64::new(0) }; Phase::ALL.len()];
+
+/// Start recording phase timings
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn now_nanos() -> u64 {
+    let now = clock_gettime(ClockId::Monotonic);
+    // Never 0, which marks a phase that wasn't entered
+    (now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64).max(1)
+}
+
+/// Record that `phase` starts now, see [`crate::boot_timeout::enter`]
+pub fn record(phase: Phase) {
+    if ENABLED.load(Ordering::Relaxed) {
+        STARTS[phase as usize].store(now_nanos(), Ordering::Relaxed);
+    }
+}
+
+/// Format a duration in nanoseconds for the summary
+///
+/// Example: 412_345_678 -> "412ms"
+/// Example: 2_500_000_000 -> "2.50s"
+pub fn format_duration(nanos: u64) -> String {
+    if nanos < 1_000_000 {
+        format!("{}us", nanos / 1_000)
+    } else if nanos < 10_000_000_000 {
+        format!("{}ms", nanos / 1_000_000)
+    } else {
+        format!("{:.2}s", nanos as f64 / 1e9)
+    }
+}
+
+/// Durations of the entered phases, each lasting until the next one starts
+fn phase_durations(starts: &[u64], end: u64) -> Vec<(Phase, u64)> {
+    let entered: Vec<(Phase, u64)> = Phase::ALL
+        .into_iter()
+        .zip(starts.iter().copied())
+        .filter(|&(_, start)| start != 0)
+        .collect();
+
+    entered
+        .iter()
+        .enumerate()
+        .map(|(index, &(phase, start))| {
+            let next = entered.get(index + 1).map_or(end, |&(_, next)| next);
+            (phase, next.saturating_sub(start))
+        })
+        .collect()
+}
+
+/// Log how long each phase took, if init.timings is on
+///
+/// The last phase entered runs until now.
+pub fn log_summary() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let starts: Vec<u64> = STARTS
+        .iter()
+        .map(|start| start.load(Ordering::Relaxed))
+        .collect();
+    let durations = phase_durations(&starts, now_nanos());
+    let total: u64 = durations.iter().map(|(_, nanos)| nanos).sum();
+
+    info!("boot timings:");
+    for (phase, nanos) in durations {
+        info!("  {}: {}", phase.name(), format_duration(nanos));
+    }
+    info!("  total: {}", format_duration(total));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0us");
+        assert_eq!(format_duration(850_000), "850us");
+        assert_eq!(format_duration(1_000_000), "1ms");
+        assert_eq!(format_duration(412_345_678), "412ms");
+        assert_eq!(format_duration(9_999_999_999), "9999ms");
+        assert_eq!(format_duration(12_346_000_000), "12.35s");
+    }
+
+    #[test]
+    fn test_phase_durations() {
+        // Modules never entered, its time counts towards kernel mounts
+        let starts = [100, 0, 150, 400, 450, 500];
+        assert_eq!(
+            phase_durations(&starts, 1000),
+            vec![
+                (Phase::KernelMounts, 50),
+                (Phase::Virtiofs, 250),
+                (Phase::Symlinks, 50),
+                (Phase::Env, 50),
+                (Phase::Exec, 500),
+            ]
+        );
+        assert!(phase_durations(&[0; 6], 1000).is_empty());
+    }
+}
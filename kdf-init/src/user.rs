@@ -0,0 +1,123 @@
+//! Privilege dropping for the configured command
+
+use anyhow::Result;
+use rustix::process::{Gid, Uid};
+
+use crate::cmdline::{RunAs, UserSpec};
+
+/// Resolved numeric identity for the command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Find a user in /etc/passwd contents, returning its (uid, primary gid)
+fn passwd_lookup(passwd: &str, user: &UserSpec) -> Option<(u32, u32)> {
+    passwd.lines().find_map(|line| {
+        // name:password:uid:gid:gecos:home:shell
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let uid = fields.nth(1)?.parse().ok()?;
+        let gid = fields.next()?.parse().ok()?;
+
+        let matches = match user {
+            UserSpec::Id(id) => *id == uid,
+            UserSpec::Name(user_name) => user_name == name,
+        };
+        matches.then_some((uid, gid))
+    })
+}
+
+/// Resolve init.user to numeric ids
+///
+/// Names need an /etc/passwd entry. A numeric uid without a gid takes its
+/// primary group from /etc/passwd when listed there, or a gid equal to the
+/// uid otherwise.
+pub fn resolve_credentials(run_as: &RunAs) -> Result<Credentials> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok();
+    let entry = passwd
+        .as_deref()
+        .and_then(|passwd| passwd_lookup(passwd, &run_as.user));
+
+    let (uid, primary_gid) = match (&run_as.user, entry) {
+        (_, Some(entry)) => entry,
+        (UserSpec::Id(uid), None) => (*uid, *uid),
+        (UserSpec::Name(name), None) if passwd.is_none() => {
+            anyhow::bail!("Cannot resolve user {}: /etc/passwd is missing", name)
+        }
+        (UserSpec::Name(name), None) => anyhow::bail!("User {} not found in /etc/passwd", name),
+    };
+
+    let credentials = Credentials {
+        uid,
+        gid: run_as.gid.unwrap_or(primary_gid),
+    };
+    info!(
+        "command will run as uid {} gid {}",
+        credentials.uid, credentials.gid
+    );
+    Ok(credentials)
+}
+
+/// Convert init.groups gids for setgroups
+pub fn to_gids(groups: &[u32]) -> Vec<Gid> {
+    // Safety: gids come from the cmdline parser, the kernel rejects -1
+    groups
+        .iter()
+        .map(|&gid| unsafe { Gid::from_raw(gid) })
+        .collect()
+}
+
+/// Switch to `credentials`, called in the child right before exec
+///
+/// The gid has to change first, setgid is no longer permitted once the
+/// uid is dropped.
+pub fn drop_privileges(credentials: &Credentials) -> std::io::Result<()> {
+    // Safety: neither id can be -1, the parser only accepts u32 values
+    // coming from the cmdline or /etc/passwd and the kernel rejects -1 anyway
+    let (uid, gid) = unsafe {
+        (
+            Uid::from_raw(credentials.uid),
+            Gid::from_raw(credentials.gid),
+        )
+    };
+
+    rustix::thread::set_thread_gid(gid)?;
+    rustix::thread::set_thread_uid(uid)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\n\
+                                 dev:x:1000:100:Developer:/home/dev:/bin/sh\n\
+                                 broken:x:abc:1\n";
+
+    #[test]
+    fn test_passwd_lookup_by_name() {
+        let entry = passwd_lookup(SAMPLE_PASSWD, &UserSpec::Name("dev".to_string()));
+        assert_eq!(entry, Some((1000, 100)));
+    }
+
+    #[test]
+    fn test_passwd_lookup_by_uid() {
+        assert_eq!(passwd_lookup(SAMPLE_PASSWD, &UserSpec::Id(0)), Some((0, 0)));
+        assert_eq!(
+            passwd_lookup(SAMPLE_PASSWD, &UserSpec::Id(1000)),
+            Some((1000, 100))
+        );
+    }
+
+    #[test]
+    fn test_passwd_lookup_missing() {
+        assert_eq!(passwd_lookup(SAMPLE_PASSWD, &UserSpec::Id(42)), None);
+        assert_eq!(
+            passwd_lookup(SAMPLE_PASSWD, &UserSpec::Name("broken".to_string())),
+            None
+        );
+        assert_eq!(passwd_lookup("", &UserSpec::Name("dev".to_string())), None);
+    }
+}
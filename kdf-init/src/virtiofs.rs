@@ -1,63 +1,208 @@
 //! Virtiofs mounting with optional overlayfs support
 
 use anyhow::{Context, Result};
-use rustix::fs::Mode;
-use rustix::mount::{mount, MountFlags};
+use rustix::fs::{Mode, OFlags};
+use rustix::io::Errno;
+use rustix::mount::{MountFlags, UnmountFlags};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::cmdline::VirtiofsMount;
+use crate::cmdline::{MergeMount, OverlayReset, ShareFile, VirtiofsMount};
+use crate::error::InitError;
+use crate::mount_guard::MountGuard;
+use crate::{overlay_reset, syscall, system};
+
+/// Directory holding the per-tag overlay upper/work/lower directories,
+/// unless init.overlay_base moves it
+pub const DEFAULT_OVERLAY_BASE: &str = "/run/overlayfs";
+
+/// Mode for directories created without an explicit one
+pub const DEFAULT_DIR_MODE: Mode = Mode::from_raw_mode(0o755);
+
+/// Flags for the parent fds [`mkdir_p`] creates each component under
+const DIR_WALK_FLAGS: OFlags = OFlags::PATH.union(OFlags::DIRECTORY).union(OFlags::CLOEXEC);
+
+/// Delay before the first mount retry, doubled after every attempt
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Upper bound on the delay between two mount attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// One directory per virtiofs device, each with a tag file (Linux 6.9+)
+const VIRTIOFS_SYSFS: &str = "/sys/fs/virtiofs";
+
+/// Option tokens that map to mount flags rather than virtiofs mount data
+///
+/// "rw" is the default and only there to be explicit.
+const FLAG_OPTIONS: [(&str, MountFlags); 7] = [
+    ("nosuid", MountFlags::NOSUID),
+    ("nodev", MountFlags::NODEV),
+    ("noexec", MountFlags::NOEXEC),
+    ("noatime", MountFlags::NOATIME),
+    ("nodiratime", MountFlags::NODIRATIME),
+    ("ro", MountFlags::RDONLY),
+    ("rw", MountFlags::empty()),
+];
+
+/// Whether an init.virtiofs option token is a mount flag
+pub fn is_flag_option(option: &str) -> bool {
+    FLAG_OPTIONS.iter().any(|(name, _)| *name == option)
+}
+
+/// Combine flag option tokens into MountFlags
+fn flags_from_options(options: &[String]) -> Result<MountFlags> {
+    let has = |name: &str| options.iter().any(|option| option == name);
+    if has("ro") && has("rw") {
+        anyhow::bail!("Mount flag options ro and rw are mutually exclusive");
+    }
+
+    options
+        .iter()
+        .try_fold(MountFlags::empty(), |flags, option| {
+            match FLAG_OPTIONS.iter().find(|(name, _)| name == option) {
+                Some((_, flag)) => Ok(flags | *flag),
+                None => anyhow::bail!("Unknown mount flag option: {}", option),
+            }
+        })
+}
+
+/// Flags for the read-only virtiofs lower of an overlay
+///
+/// The share's flag options apply to the lower as well as the overlay, so
+/// e.g. noatime spares the host atime updates for reads through it.
+fn lower_flags(flags: MountFlags) -> MountFlags {
+    flags | MountFlags::RDONLY
+}
 
 fn check_virtiofs_support() -> Result<()> {
-    // Check if virtiofs is available
-    let filesystems =
-        std::fs::read_to_string("/proc/filesystems").context("Failed to read /proc/filesystems")?;
+    if system::filesystem_supported("virtiofs")? {
+        info!("virtiofs support detected");
+        Ok(())
+    } else {
+        Err(InitError::VirtiofsUnsupported.into())
+    }
+}
 
-    if filesystems.contains("virtiofs") {
-        println!("kdf-init: virtiofs support detected");
+/// Check `filesystems` (the contents of /proc/filesystems) for overlayfs
+fn overlay_support(filesystems: &str) -> Result<()> {
+    if system::filesystems_contains(filesystems, "overlay") {
+        info!("overlayfs support detected");
         Ok(())
     } else {
         anyhow::bail!(
-            "virtiofs filesystem not supported by kernel. \
-             Make sure CONFIG_VIRTIO_FS is enabled (either built-in or as a module) \
-             and that the module is loaded before mounting virtiofs shares."
+            "overlay filesystem not supported by kernel. \
+             Make sure CONFIG_OVERLAY_FS is enabled (either built-in or as a module) \
+             and loaded, or mount the virtiofs shares without overlay."
         )
     }
 }
 
-fn mkdir_p(path: &str) -> Result<()> {
-    use std::path::Path;
+fn check_overlay_support() -> Result<()> {
+    overlay_support(&system::read_filesystems()?)
+}
+
+/// Create a directory and any missing parents
+///
+/// `mode` applies to `path` itself, missing parents get [`DEFAULT_DIR_MODE`].
+/// Both are subject to the umask set at startup. Each component is created
+/// with mkdirat relative to its parent's fd, so nothing is stat'ed up front
+/// and a relative `path` starts from the current directory.
+pub fn mkdir_p(path: &str, mode: Mode, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!(
+            "dry-run: would create directory {} (mode {:o})",
+            path,
+            mode.as_raw_mode()
+        );
+        return Ok(());
+    }
 
-    let path_obj = Path::new(path);
+    let start = if path.starts_with('/') { "/" } else { "." };
+    let mut parent = rustix::fs::open(start, DIR_WALK_FLAGS, Mode::empty())
+        .with_context(|| format!("Failed to open {}", start))?;
 
-    // Collect all parent directories that need to be created
-    let mut dirs_to_create = Vec::new();
-    let mut current = path_obj;
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let mut created = if path.starts_with('/') {
+        String::new()
+    } else {
+        ".".to_string()
+    };
+    for (index, component) in components.iter().enumerate() {
+        created = format!("{}/{}", created, component);
+        let last = index + 1 == components.len();
 
-    while let Some(parent) = current.parent() {
-        if parent.as_os_str().is_empty() || parent == Path::new("/") {
-            break;
+        let component_mode = if last { mode } else { DEFAULT_DIR_MODE };
+        match syscall::mkdirat_logged(&parent, component, component_mode) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create directory {}", created))
+            }
         }
-        if !parent.exists() {
-            dirs_to_create.push(parent);
+
+        // Symlinks are followed, e.g. /var/run -> /run
+        if !last {
+            parent = rustix::fs::openat(&parent, *component, DIR_WALK_FLAGS, Mode::empty())
+                .with_context(|| format!("Failed to open directory {}", created))?;
         }
-        current = parent;
     }
 
-    // Create directories from root to target
-    dirs_to_create.reverse();
-    for dir in dirs_to_create {
-        rustix::fs::mkdir(dir, Mode::from_raw_mode(0o755))
-            .or_else(|e| {
-                if e == rustix::io::Errno::EXIST {
-                    Ok(())
-                } else {
-                    Err(e)
-                }
-            })
-            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    Ok(())
+}
+
+/// Mount the share of `file` read-only at `mount_dir` and read the file
+///
+/// Used for the files init needs before any init.virtiofs mount, `what`
+/// names the parameter for logs and errors. The share stays mounted for
+/// the caller to [`unmount_share`], unless reading the file failed.
+pub fn read_share_file(file: &ShareFile, mount_dir: &str, what: &str) -> Result<Vec<u8>> {
+    mkdir_p(mount_dir, DEFAULT_DIR_MODE, false)?;
+    system::mount_fs(
+        &format!("virtiofs {} (ro) at {} for {}", file.tag, mount_dir, what),
+        &file.tag,
+        mount_dir,
+        "virtiofs",
+        MountFlags::RDONLY,
+        "",
+        false,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to mount {} virtiofs {} at {}",
+            what, file.tag, mount_dir
+        )
+    })?;
+
+    let path = format!("{}/{}", mount_dir, file.path);
+    let contents =
+        std::fs::read(&path).with_context(|| format!("Failed to read {} {}", what, path));
+    if contents.is_err() {
+        unmount_share(mount_dir);
+    }
+    contents
+}
+
+/// Unmount a share mounted by [`read_share_file`] and remove its mount point
+///
+/// Nothing else depends on the share, so a failure is only worth a warning.
+pub fn unmount_share(mount_dir: &str) {
+    if let Err(e) = rustix::mount::unmount(mount_dir, UnmountFlags::empty()) {
+        warn!("failed to unmount {}: {}", mount_dir, e);
+        return;
+    }
+    let _ = std::fs::remove_dir(mount_dir);
+    info!("unmounted share at {}", mount_dir);
+}
+
+fn create_overlay_dir(dir: &str, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("dry-run: would create overlay directory {}", dir);
+        return Ok(());
     }
 
-    // Create the target directory itself
-    rustix::fs::mkdir(path, Mode::from_raw_mode(0o755))
+    syscall::mkdir_logged(dir, DEFAULT_DIR_MODE)
         .or_else(|e| {
             if e == rustix::io::Errno::EXIST {
                 Ok(())
@@ -65,103 +210,989 @@ fn mkdir_p(path: &str) -> Result<()> {
                 Err(e)
             }
         })
-        .with_context(|| format!("Failed to create directory {}", path))?;
+        .with_context(|| format!("Failed to create overlay directory {}", dir))
+}
 
-    Ok(())
+/// Run `mount`, retrying up to `retries` times while the device isn't there yet
+///
+/// Only ENOENT/ENODEV are retried, a virtiofs tag that shows up late fails
+/// with those. The delay starts at `delay` and doubles after each attempt.
+fn mount_with_retry(
+    what: &str,
+    retries: u32,
+    mut delay: Duration,
+    mut mount: impl FnMut() -> rustix::io::Result<()>,
+) -> rustix::io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match mount() {
+            Err(e @ (Errno::NOENT | Errno::NODEV)) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "failed to mount {}: {}, retrying in {}ms ({}/{})",
+                    what,
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Assemble overlayfs mount data
+///
+/// The virtiofs lower comes first (highest precedence), followed by the
+/// extra lowers in the order given.
+/// Mounts sorted by their order= token
+///
+/// The sort is stable, so mounts with equal orders (by default all 0) keep
+/// their cmdline order.
+fn mount_order(mounts: &[VirtiofsMount]) -> Vec<&VirtiofsMount> {
+    let mut ordered: Vec<&VirtiofsMount> = mounts.iter().collect();
+    ordered.sort_by_key(|m| m.order);
+    ordered
+}
+
+/// Check a mount took effect, nothing is mounted in dry-run
+fn verify_mount(target: &str, expected_fstype_magic: u64, dry_run: bool) -> Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+    system::verify_mount(target, expected_fstype_magic)
+}
+
+/// Overlay tunables requested in the spec, always in the same order
+fn overlay_tunables(vfs_mount: &VirtiofsMount) -> Vec<&'static str> {
+    let mut tunables = Vec::new();
+    if vfs_mount.index {
+        tunables.push("index=on");
+    }
+    if vfs_mount.metacopy {
+        tunables.push("metacopy=on");
+    }
+    match vfs_mount.redirect_dir {
+        Some(true) => tunables.push("redirect_dir=on"),
+        Some(false) => tunables.push("redirect_dir=off"),
+        None => {}
+    }
+    match vfs_mount.xino {
+        Some(true) => tunables.push("xino=on"),
+        Some(false) => tunables.push("xino=off"),
+        None => {}
+    }
+    tunables
+}
+
+/// Directories backing the overlay of one share, nested under the base
+#[derive(Debug, PartialEq)]
+struct OverlayDirs {
+    /// Per-tag directory, {base}/{tag}
+    root: String,
+    /// Mount point of the share itself
+    lower: String,
+    /// Mount point of the upper_tag share, if upper/work live there
+    persist: Option<String>,
+    upper: String,
+    work: String,
+}
+
+/// Assemble the overlay directories of a share under `overlay_base`
+fn overlay_dirs(overlay_base: &str, vfs_mount: &VirtiofsMount) -> OverlayDirs {
+    let root = Path::new(overlay_base).join(&vfs_mount.tag);
+    let persist = vfs_mount.upper_tag.as_ref().map(|_| root.join("persist"));
+    let upper_parent = persist.as_ref().unwrap_or(&root);
+    let path = |path: PathBuf| path.to_string_lossy().into_owned();
+
+    OverlayDirs {
+        lower: path(root.join("lower")),
+        upper: path(upper_parent.join("upper")),
+        work: path(upper_parent.join("work")),
+        persist: persist.map(path),
+        root: path(root),
+    }
+}
+
+fn overlay_options(
+    lower_dir: &str,
+    extra_lowers: &[String],
+    upper_dir: &str,
+    work_dir: &str,
+    tunables: &[&str],
+) -> String {
+    let mut lowers = vec![lower_dir];
+    lowers.extend(extra_lowers.iter().map(String::as_str));
+
+    let mut options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lowers.join(":"),
+        upper_dir,
+        work_dir
+    );
+    for tunable in tunables {
+        options.push(',');
+        options.push_str(tunable);
+    }
+    options
+}
+
+/// FNV-1a hash of an init.merge mount point, the same on every boot
+fn path_hash(path: &str) -> u64 {
+    path.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Directories backing an init.merge overlay, nested under the base
+#[derive(Debug, PartialEq)]
+struct MergeDirs {
+    /// Per-merge directory, {base}/{hash of the mount point}
+    root: String,
+    /// Mount points of the shares, lower0 for the first tag
+    lowers: Vec<String>,
+    /// Upper and work directories, for a writable merge
+    upper: Option<(String, String)>,
+}
+
+/// Assemble the directories of an init.merge overlay under `overlay_base`
+fn merge_dirs(overlay_base: &str, merge: &MergeMount) -> MergeDirs {
+    let root = Path::new(overlay_base).join(format!("{:016x}", path_hash(&merge.path)));
+    let path = |name: String| root.join(name).to_string_lossy().into_owned();
+
+    MergeDirs {
+        lowers: (0..merge.tags.len())
+            .map(|index| path(format!("lower{}", index)))
+            .collect(),
+        upper: merge
+            .writable
+            .then(|| (path("upper".to_string()), path("work".to_string()))),
+        root: root.to_string_lossy().into_owned(),
+    }
+}
+
+/// Overlayfs mount data of an init.merge, lowers in tag order
+fn merge_options(dirs: &MergeDirs) -> String {
+    let mut options = format!("lowerdir={}", dirs.lowers.join(":"));
+    if let Some((upper, work)) = &dirs.upper {
+        options.push_str(&format!(",upperdir={},workdir={}", upper, work));
+    }
+    options
+}
+
+/// Mount a size-capped tmpfs to hold the overlay upper/work directories
+///
+/// Falls back to the filesystem the base lives on with a warning if the
+/// mount fails. Returns whether the tmpfs got mounted.
+fn mount_overlay_tmpfs(overlay_base: &str, size: u64, dry_run: bool) -> bool {
+    let what = format!("tmpfs (size={}) at {}", size, overlay_base);
+    let data = format!("size={},mode=0755", size);
+    match system::mount_fs(
+        &what,
+        "tmpfs",
+        overlay_base,
+        "tmpfs",
+        MountFlags::empty(),
+        &data,
+        dry_run,
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "failed to mount sized tmpfs at {}: {}, using its parent filesystem",
+                overlay_base, e
+            );
+            false
+        }
+    }
+}
+
+/// Where overlays keep their upper layers and what happens to them on boot
+pub struct OverlaySettings<'a> {
+    /// Directory holding the per-tag overlay directories (init.overlay_base)
+    pub base: &'a str,
+    /// Size of a dedicated tmpfs at the base (init.overlay_tmpfs_size)
+    pub tmpfs_size: Option<u64>,
+    /// Whether persistent uppers get wiped (init.overlay_reset)
+    pub reset: OverlayReset,
 }
 
-pub fn mount_virtiofs_shares(mounts: &[VirtiofsMount]) -> Result<()> {
+/// Directories holding the persistent upper/work dirs of upper= overlays
+///
+/// The clean marker goes there, see [`overlay_reset::mark_clean`].
+pub fn persistent_upper_dirs(mounts: &[VirtiofsMount], overlay_base: &str) -> Vec<String> {
+    mounts
+        .iter()
+        .filter(|m| m.with_overlay)
+        .filter_map(|m| overlay_dirs(overlay_base, m).persist)
+        .collect()
+}
+
+/// Mount the virtiofs shares in order, with their overlays
+///
+/// With `atomic` (init.atomic_mounts), a failure unmounts everything this
+/// call mounted before the error is returned.
+pub fn mount_virtiofs_shares(
+    mounts: &[VirtiofsMount],
+    overlay: &OverlaySettings,
+    mount_retries: u32,
+    atomic: bool,
+    skip_mounted: bool,
+    skip_fs_check: bool,
+    dry_run: bool,
+) -> Result<()> {
     if mounts.is_empty() {
         return Ok(());
     }
 
     // Check virtiofs support before attempting to mount
-    check_virtiofs_support()?;
+    if system::check_fs_support(skip_fs_check, dry_run) {
+        check_virtiofs_support()?;
+
+        // Checked up front so no lower gets mounted for an overlay that can't be
+        if mounts.iter().any(|m| m.with_overlay) {
+            check_overlay_support()?;
+        }
+    }
+
+    let mut mounted = MountGuard::new();
+    let result = mount_shares(
+        mounts,
+        overlay,
+        mount_retries,
+        skip_mounted,
+        dry_run,
+        &mut mounted,
+    );
+    // Nothing is mounted in dry-run, so there is nothing to roll back
+    if result.is_ok() || !atomic || dry_run {
+        mounted.commit();
+    } else {
+        warn!("virtiofs mounts failed, rolling back (init.atomic_mounts)");
+    }
+    result
+}
+
+/// Mount every share, recording each mount made in `mounted`
+fn mount_shares(
+    mounts: &[VirtiofsMount],
+    overlay: &OverlaySettings,
+    mount_retries: u32,
+    skip_mounted: bool,
+    dry_run: bool,
+    mounted: &mut MountGuard<impl FnMut(&str) -> rustix::io::Result<()>>,
+) -> Result<()> {
+    let overlay_base = overlay.base;
+    if mounts.iter().any(|m| m.with_overlay) {
+        mkdir_p(overlay_base, DEFAULT_DIR_MODE, dry_run)?;
+
+        if let Some(size) = overlay.tmpfs_size {
+            if !system::already_mounted(overlay_base, skip_mounted)?
+                && mount_overlay_tmpfs(overlay_base, size, dry_run)
+            {
+                mounted.record(overlay_base);
+            }
+        }
+    }
+
+    for vfs_mount in mount_order(mounts) {
+        if system::already_mounted(&vfs_mount.path, skip_mounted)? {
+            continue;
+        }
 
-    for vfs_mount in mounts {
         // Create mount point directory (with parents)
-        mkdir_p(&vfs_mount.path)?;
+        let mode = vfs_mount
+            .mode
+            .map(Mode::from_raw_mode)
+            .unwrap_or(DEFAULT_DIR_MODE);
+        mkdir_p(&vfs_mount.path, mode, dry_run)?;
+
+        let flags = flags_from_options(&vfs_mount.flag_options)?;
 
         if vfs_mount.with_overlay {
-            // Create overlayfs structure in /run/overlayfs/{tag}/
-            let overlay_base = format!("/run/overlayfs/{}", vfs_mount.tag);
-            let upper_dir = format!("{}/upper", overlay_base);
-            let work_dir = format!("{}/work", overlay_base);
-            let lower_dir = format!("{}/lower", overlay_base);
-
-            // Create all overlay directories
-            for dir in [&overlay_base, &upper_dir, &work_dir, &lower_dir] {
-                rustix::fs::mkdir(dir, Mode::from_raw_mode(0o755))
-                    .or_else(|e| {
-                        if e == rustix::io::Errno::EXIST {
-                            Ok(())
-                        } else {
-                            Err(e)
-                        }
-                    })
-                    .with_context(|| format!("Failed to create overlay directory {}", dir))?;
+            // Extra lowers must exist before anything gets mounted for this share
+            for lower in &vfs_mount.extra_lowers {
+                if !dry_run && !std::path::Path::new(lower).is_dir() {
+                    anyhow::bail!(
+                        "Overlay lower directory {} for virtiofs {} does not exist",
+                        lower,
+                        vfs_mount.tag
+                    );
+                }
+            }
+
+            // Create overlayfs structure in {overlay_base}/{tag}/
+            let dirs = overlay_dirs(overlay_base, vfs_mount);
+            let lower_dir = &dirs.lower;
+            for dir in [&dirs.root, lower_dir] {
+                create_overlay_dir(dir, dry_run)?;
+            }
+
+            // Persistent upper: upper/work live on a second, writable share
+            if let (Some(upper_tag), Some(persist_dir)) = (&vfs_mount.upper_tag, &dirs.persist) {
+                create_overlay_dir(persist_dir, dry_run)?;
+
+                let what = format!(
+                    "virtiofs {} (rw) at {} for overlay upper",
+                    upper_tag, persist_dir
+                );
+                mount_with_retry(&what, mount_retries, RETRY_DELAY, || {
+                    system::mount_fs(
+                        &what,
+                        upper_tag,
+                        persist_dir,
+                        "virtiofs",
+                        MountFlags::empty(),
+                        "",
+                        dry_run,
+                    )
+                })
+                .with_context(|| {
+                    format!("Failed to mount virtiofs {} at {}", upper_tag, persist_dir)
+                })?;
+                mounted.record(persist_dir);
+                verify_mount(persist_dir, system::FUSE_SUPER_MAGIC, dry_run)?;
+
+                overlay_reset::prepare_upper(
+                    overlay.reset,
+                    persist_dir,
+                    &dirs.upper,
+                    &dirs.work,
+                    dry_run,
+                )?;
+            }
+            let (upper_dir, work_dir) = (&dirs.upper, &dirs.work);
+            for dir in [upper_dir, work_dir] {
+                create_overlay_dir(dir, dry_run)?;
             }
 
             // Mount virtiofs as lower layer
-            mount(
-                &vfs_mount.tag,
-                &lower_dir,
-                "virtiofs",
-                MountFlags::RDONLY,
-                "",
-            )
+            let what = format!("virtiofs {} (ro) at {}", vfs_mount.tag, lower_dir);
+            mount_with_retry(&what, mount_retries, RETRY_DELAY, || {
+                system::mount_fs(
+                    &what,
+                    vfs_mount.mount_source(),
+                    lower_dir,
+                    "virtiofs",
+                    lower_flags(flags),
+                    "",
+                    dry_run,
+                )
+            })
             .with_context(|| {
                 format!(
                     "Failed to mount virtiofs {} at {}",
                     vfs_mount.tag, lower_dir
                 )
             })?;
-
-            println!(
-                "kdf-init: mounted virtiofs {} (ro) at {}",
-                vfs_mount.tag, lower_dir
-            );
+            mounted.record(lower_dir);
+            verify_mount(lower_dir, system::FUSE_SUPER_MAGIC, dry_run)?;
 
             // Mount overlayfs with writable upper layer
-            let overlay_opts = format!(
-                "lowerdir={},upperdir={},workdir={}",
-                lower_dir, upper_dir, work_dir
+            let overlay_opts = overlay_options(
+                lower_dir,
+                &vfs_mount.extra_lowers,
+                upper_dir,
+                work_dir,
+                &overlay_tunables(vfs_mount),
             );
-            mount(
+            system::mount_fs(
+                &format!(
+                    "overlayfs (rw) at {} over virtiofs {}",
+                    vfs_mount.path, vfs_mount.tag
+                ),
                 "overlay",
                 &vfs_mount.path,
                 "overlay",
-                MountFlags::empty(),
+                flags,
                 &overlay_opts,
+                dry_run,
             )
             .with_context(|| format!("Failed to mount overlayfs at {}", vfs_mount.path))?;
-
-            println!(
-                "kdf-init: mounted overlayfs (rw) at {} over virtiofs {}",
-                vfs_mount.path, vfs_mount.tag
-            );
+            mounted.record(&vfs_mount.path);
+            verify_mount(&vfs_mount.path, system::OVERLAYFS_SUPER_MAGIC, dry_run)?;
         } else {
             // Direct virtiofs mount without overlay
-            mount(
-                &vfs_mount.tag,
-                &vfs_mount.path,
-                "virtiofs",
-                MountFlags::empty(),
-                "",
-            )
+            let options = vfs_mount.options.join(",");
+            let mode = if flags.contains(MountFlags::RDONLY) {
+                "ro"
+            } else {
+                "rw"
+            };
+            let what = format!(
+                "virtiofs {} ({}) at {}",
+                vfs_mount.tag, mode, vfs_mount.path
+            );
+            mount_with_retry(&what, mount_retries, RETRY_DELAY, || {
+                system::mount_fs(
+                    &what,
+                    vfs_mount.mount_source(),
+                    &vfs_mount.path,
+                    "virtiofs",
+                    flags,
+                    &options,
+                    dry_run,
+                )
+            })
             .with_context(|| {
                 format!(
                     "Failed to mount virtiofs {} at {}",
                     vfs_mount.tag, vfs_mount.path
                 )
             })?;
+            mounted.record(&vfs_mount.path);
+            verify_mount(&vfs_mount.path, system::FUSE_SUPER_MAGIC, dry_run)?;
+        }
+    }
 
-            println!(
-                "kdf-init: mounted virtiofs {} at {}",
-                vfs_mount.tag, vfs_mount.path
-            );
+    Ok(())
+}
+
+/// Mount the init.merge overlays, each over its shares
+///
+/// Runs after [`mount_virtiofs_shares`], with the same `atomic` rollback.
+pub fn mount_merged_overlays(
+    merges: &[MergeMount],
+    overlay_base: &str,
+    mount_retries: u32,
+    atomic: bool,
+    skip_mounted: bool,
+    skip_fs_check: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if merges.is_empty() {
+        return Ok(());
+    }
+    if system::check_fs_support(skip_fs_check, dry_run) {
+        check_virtiofs_support()?;
+        check_overlay_support()?;
+    }
+
+    let mut mounted = MountGuard::new();
+    let result = mount_merges(
+        merges,
+        overlay_base,
+        mount_retries,
+        skip_mounted,
+        dry_run,
+        &mut mounted,
+    );
+    if result.is_ok() || !atomic || dry_run {
+        mounted.commit();
+    } else {
+        warn!("init.merge mounts failed, rolling back (init.atomic_mounts)");
+    }
+    result
+}
+
+/// Mount every merge, recording each mount made in `mounted`
+fn mount_merges(
+    merges: &[MergeMount],
+    overlay_base: &str,
+    mount_retries: u32,
+    skip_mounted: bool,
+    dry_run: bool,
+    mounted: &mut MountGuard<impl FnMut(&str) -> rustix::io::Result<()>>,
+) -> Result<()> {
+    mkdir_p(overlay_base, DEFAULT_DIR_MODE, dry_run)?;
+
+    for merge in merges {
+        if system::already_mounted(&merge.path, skip_mounted)? {
+            continue;
+        }
+        mkdir_p(&merge.path, DEFAULT_DIR_MODE, dry_run)?;
+
+        let dirs = merge_dirs(overlay_base, merge);
+        create_overlay_dir(&dirs.root, dry_run)?;
+        if let Some((upper, work)) = &dirs.upper {
+            create_overlay_dir(upper, dry_run)?;
+            create_overlay_dir(work, dry_run)?;
         }
+
+        for (tag, lower_dir) in merge.tags.iter().zip(&dirs.lowers) {
+            create_overlay_dir(lower_dir, dry_run)?;
+            let what = format!("virtiofs {} (ro) at {}", tag, lower_dir);
+            mount_with_retry(&what, mount_retries, RETRY_DELAY, || {
+                system::mount_fs(
+                    &what,
+                    tag,
+                    lower_dir,
+                    "virtiofs",
+                    MountFlags::RDONLY,
+                    "",
+                    dry_run,
+                )
+            })
+            .with_context(|| format!("Failed to mount virtiofs {} at {}", tag, lower_dir))?;
+            mounted.record(lower_dir);
+            verify_mount(lower_dir, system::FUSE_SUPER_MAGIC, dry_run)?;
+        }
+
+        let (flags, mode) = if merge.writable {
+            (MountFlags::empty(), "rw")
+        } else {
+            (MountFlags::RDONLY, "ro")
+        };
+        system::mount_fs(
+            &format!(
+                "overlayfs ({}) at {} over virtiofs {}",
+                mode,
+                merge.path,
+                merge.tags.join(", ")
+            ),
+            "overlay",
+            &merge.path,
+            "overlay",
+            flags,
+            &merge_options(&dirs),
+            dry_run,
+        )
+        .with_context(|| format!("Failed to mount overlayfs at {}", merge.path))?;
+        mounted.record(&merge.path);
+        verify_mount(&merge.path, system::OVERLAYFS_SUPER_MAGIC, dry_run)?;
     }
 
     Ok(())
 }
+
+/// Whether a virtiofs device under `sysfs_dir` advertises `tag`
+///
+/// Entries without a tag file are skipped.
+fn tag_available_in(sysfs_dir: &Path, tag: &str) -> Result<bool> {
+    let entries = std::fs::read_dir(sysfs_dir)
+        .with_context(|| format!("Failed to read {}", sysfs_dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read {}", sysfs_dir.display()))?
+            .path()
+            .join("tag");
+        match std::fs::read_to_string(&path) {
+            Ok(advertised) if advertised.trim_end_matches('\n') == tag => return Ok(true),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the host exports a virtiofs share tagged `tag`
+///
+/// Reads the tags the virtiofs driver lists in sysfs, so the module must
+/// be loaded. Kernels before 6.9 don't list them, which is an error.
+pub fn virtiofs_tag_available(tag: &str) -> Result<bool> {
+    tag_available_in(Path::new(VIRTIOFS_SYSFS), tag)
+}
+
+/// Check every tag about to be mounted is exported (init.verify_tags)
+///
+/// Fails with [`InitError::VirtiofsTagNotFound`] for the first one that
+/// isn't, before anything gets mounted. Tags that can't be checked are
+/// left to the mount.
+pub fn verify_tags<'a>(tags: impl IntoIterator<Item = &'a str>, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("dry-run: would check the virtiofs tags are exported");
+        return Ok(());
+    }
+
+    for tag in tags {
+        match virtiofs_tag_available(tag) {
+            Ok(true) => debug!("virtiofs tag {} is exported", tag),
+            Ok(false) => {
+                return Err(InitError::VirtiofsTagNotFound {
+                    tag: tag.to_string(),
+                }
+                .into())
+            }
+            Err(e) => {
+                warn!("can't verify virtiofs tags, mounting anyway: {:#}", e);
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mount closure failing with `errno` for the first `failures` calls
+    fn flaky_mount(failures: u32, errno: Errno) -> impl FnMut() -> rustix::io::Result<()> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                Err(errno)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn ordered_tags(specs: &str) -> Vec<String> {
+        let config = crate::cmdline::parse_cmdline(&format!(
+            "init.console=console init.shell=`sh` init.virtiofs={}",
+            specs
+        ))
+        .unwrap();
+        mount_order(&config.virtiofs_mounts)
+            .into_iter()
+            .map(|m| m.tag.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_mount_order() {
+        assert_eq!(
+            ordered_tags("over:/mnt:Y:lower=/base,order=2,base:/base:N:order=1"),
+            vec!["base", "over"]
+        );
+        assert_eq!(
+            ordered_tags("a:/a,b:/b:N:order=-1,c:/c:N:order=5"),
+            vec!["b", "a", "c"]
+        );
+    }
+
+    #[test]
+    fn test_mount_order_is_stable() {
+        // Without any order= the cmdline order is kept
+        assert_eq!(ordered_tags("c:/c,a:/a,b:/b"), vec!["c", "a", "b"]);
+        assert_eq!(
+            ordered_tags("d:/d:N:order=1,c:/c,b:/b:N:order=1,a:/a"),
+            vec!["c", "a", "d", "b"]
+        );
+    }
+
+    #[test]
+    fn test_mount_with_retry_succeeds() {
+        let result = mount_with_retry("test", 3, Duration::ZERO, flaky_mount(3, Errno::NODEV));
+        assert_eq!(result, Ok(()));
+
+        let result = mount_with_retry("test", 5, Duration::ZERO, flaky_mount(1, Errno::NOENT));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_mount_with_retry_gives_up() {
+        let result = mount_with_retry("test", 2, Duration::ZERO, flaky_mount(3, Errno::NODEV));
+        assert_eq!(result, Err(Errno::NODEV));
+
+        // Default of zero retries keeps the single-attempt behavior
+        let result = mount_with_retry("test", 0, Duration::ZERO, flaky_mount(1, Errno::NOENT));
+        assert_eq!(result, Err(Errno::NOENT));
+    }
+
+    #[test]
+    fn test_mount_with_retry_other_errors() {
+        let mut calls = 0;
+        let result = mount_with_retry("test", 5, Duration::ZERO, || {
+            calls += 1;
+            Err(Errno::INVAL)
+        });
+        assert_eq!(result, Err(Errno::INVAL));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_overlay_support() {
+        let with_overlay = "nodev\tsysfs\nnodev\tvirtiofs\nnodev\toverlay\n";
+        assert!(overlay_support(with_overlay).is_ok());
+
+        let without_overlay = "nodev\tsysfs\nnodev\tvirtiofs\n\text4\n";
+        let err = overlay_support(without_overlay).unwrap_err();
+        assert!(err.to_string().contains("CONFIG_OVERLAY_FS"), "{}", err);
+    }
+
+    #[test]
+    fn test_flags_from_options() {
+        let flags = |options: &[&str]| {
+            let options: Vec<String> = options.iter().map(|s| s.to_string()).collect();
+            flags_from_options(&options)
+        };
+
+        assert_eq!(flags(&[]).unwrap(), MountFlags::empty());
+        assert_eq!(flags(&["nosuid"]).unwrap(), MountFlags::NOSUID);
+        assert_eq!(flags(&["nodev"]).unwrap(), MountFlags::NODEV);
+        assert_eq!(flags(&["noexec"]).unwrap(), MountFlags::NOEXEC);
+        assert_eq!(
+            flags(&["nosuid", "nodev", "noexec"]).unwrap(),
+            MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC
+        );
+        assert_eq!(
+            flags(&["noatime", "nodiratime"]).unwrap(),
+            MountFlags::NOATIME | MountFlags::NODIRATIME
+        );
+    }
+
+    #[test]
+    fn test_flags_from_options_ro_rw() {
+        let flags = |options: &[&str]| {
+            let options: Vec<String> = options.iter().map(|s| s.to_string()).collect();
+            flags_from_options(&options)
+        };
+
+        assert_eq!(flags(&["ro"]).unwrap(), MountFlags::RDONLY);
+        assert_eq!(
+            flags(&["ro", "nodev"]).unwrap(),
+            MountFlags::RDONLY | MountFlags::NODEV
+        );
+        assert_eq!(flags(&["rw"]).unwrap(), MountFlags::empty());
+        assert!(flags(&["ro", "rw"]).is_err());
+        assert!(flags(&["rw", "nosuid", "ro"]).is_err());
+    }
+
+    #[test]
+    fn test_flags_from_options_unknown() {
+        for option in ["dax", "readonly", "NOSUID", ""] {
+            let result = flags_from_options(&[option.to_string()]);
+            assert!(result.is_err(), "{:?} should be rejected", option);
+        }
+    }
+
+    /// Fresh directory under the temp dir, removed along with its contents
+    /// if a previous run left it
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kdf-init-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_mkdir_p_nested() {
+        let base = temp_dir("mkdir-nested");
+        let deep = base.join("a/b/c/d/e");
+        mkdir_p(deep.to_str().unwrap(), Mode::from_raw_mode(0o700), false).unwrap();
+        assert!(deep.is_dir());
+
+        // Creating it again is fine
+        mkdir_p(deep.to_str().unwrap(), DEFAULT_DIR_MODE, false).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_mkdir_p_existing_prefix() {
+        let base = temp_dir("mkdir-prefix");
+        std::fs::create_dir_all(base.join("exists/already")).unwrap();
+        let target = base.join("exists/already/new/leaf");
+        // Doubled and trailing slashes and "." components are skipped
+        let path = format!("{}//exists/./already/new/leaf/", base.display());
+        mkdir_p(&path, DEFAULT_DIR_MODE, false).unwrap();
+        assert!(target.is_dir());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_mkdir_p_file_component() {
+        let base = temp_dir("mkdir-file");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(base.join("file"), "").unwrap();
+
+        let path = base.join("file/sub/dir");
+        let err = mkdir_p(path.to_str().unwrap(), DEFAULT_DIR_MODE, false).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains(&format!("{}/file", base.display())),
+            "{:#}",
+            err
+        );
+        assert!(!base.join("file/sub").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    fn first_mount(params: &str) -> VirtiofsMount {
+        let config = crate::cmdline::parse_cmdline(&format!(
+            "init.console=console init.shell=`sh` {}",
+            params
+        ))
+        .unwrap();
+        config.virtiofs_mounts[0].clone()
+    }
+
+    #[test]
+    fn test_lower_flags() {
+        let mount = first_mount("init.virtiofs=share:/mnt:Y:noatime");
+        let flags = flags_from_options(&mount.flag_options).unwrap();
+        assert_eq!(lower_flags(flags), MountFlags::RDONLY | MountFlags::NOATIME);
+        // The overlay itself stays writable
+        assert_eq!(flags, MountFlags::NOATIME);
+
+        assert_eq!(lower_flags(MountFlags::empty()), MountFlags::RDONLY);
+    }
+
+    #[test]
+    fn test_overlay_dirs_default_base() {
+        let dirs = overlay_dirs(
+            DEFAULT_OVERLAY_BASE,
+            &first_mount("init.virtiofs=src:/src:Y"),
+        );
+        assert_eq!(
+            dirs,
+            OverlayDirs {
+                root: "/run/overlayfs/src".to_string(),
+                lower: "/run/overlayfs/src/lower".to_string(),
+                persist: None,
+                upper: "/run/overlayfs/src/upper".to_string(),
+                work: "/run/overlayfs/src/work".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_overlay_dirs_custom_base() {
+        let mount = first_mount("init.overlay_base=/scratch/ovl/ init.virtiofs=src:/src:Y");
+        let dirs = overlay_dirs("/scratch/ovl/", &mount);
+        assert_eq!(dirs.root, "/scratch/ovl/src");
+        assert_eq!(dirs.lower, "/scratch/ovl/src/lower");
+        assert_eq!(dirs.upper, "/scratch/ovl/src/upper");
+        assert_eq!(dirs.work, "/scratch/ovl/src/work");
+
+        // A persistent upper share is mounted under the base as well
+        let mount = first_mount("init.virtiofs=src:/src:Y:upper=state");
+        let dirs = overlay_dirs("/scratch/ovl", &mount);
+        assert_eq!(dirs.lower, "/scratch/ovl/src/lower");
+        assert_eq!(dirs.persist.as_deref(), Some("/scratch/ovl/src/persist"));
+        assert_eq!(dirs.upper, "/scratch/ovl/src/persist/upper");
+        assert_eq!(dirs.work, "/scratch/ovl/src/persist/work");
+    }
+
+    fn merge(params: &str) -> MergeMount {
+        let config = crate::cmdline::parse_cmdline(&format!(
+            "init.console=console init.shell=`sh` {}",
+            params
+        ))
+        .unwrap();
+        config.merge_mounts[0].clone()
+    }
+
+    #[test]
+    fn test_merge_dirs() {
+        let dirs = merge_dirs(DEFAULT_OVERLAY_BASE, &merge("init.merge=/data:a,b,c:Y"));
+        let root = format!("/run/overlayfs/{:016x}", path_hash("/data"));
+        assert_eq!(
+            dirs,
+            MergeDirs {
+                lowers: vec![
+                    format!("{}/lower0", root),
+                    format!("{}/lower1", root),
+                    format!("{}/lower2", root),
+                ],
+                upper: Some((format!("{}/upper", root), format!("{}/work", root))),
+                root,
+            }
+        );
+
+        // Each mount point gets its own directory
+        let other = merge_dirs(DEFAULT_OVERLAY_BASE, &merge("init.merge=/other:a,b"));
+        assert_ne!(other.root, dirs.root);
+        assert_eq!(other.upper, None);
+    }
+
+    #[test]
+    fn test_merge_options_follow_tag_order() {
+        let dirs = merge_dirs("/base", &merge("init.merge=/data:top,middle,bottom"));
+        let root = format!("/base/{:016x}", path_hash("/data"));
+        assert_eq!(
+            merge_options(&dirs),
+            format!(
+                "lowerdir={root}/lower0:{root}/lower1:{root}/lower2",
+                root = root
+            )
+        );
+
+        let dirs = merge_dirs("/base", &merge("init.merge=/data:top,bottom:Y"));
+        assert_eq!(
+            merge_options(&dirs),
+            format!(
+                "lowerdir={root}/lower0:{root}/lower1,upperdir={root}/upper,workdir={root}/work",
+                root = root
+            )
+        );
+    }
+
+    #[test]
+    fn test_path_hash_is_stable() {
+        // FNV-1a test vectors
+        assert_eq!(path_hash(""), 0xcbf29ce484222325);
+        assert_eq!(path_hash("a"), 0xaf63dc4c8601ec8c);
+        assert_ne!(path_hash("/data"), path_hash("/data2"));
+    }
+
+    #[test]
+    fn test_overlay_options() {
+        let opts = overlay_options("/run/lower", &[], "/run/upper", "/run/work", &[]);
+        assert_eq!(
+            opts,
+            "lowerdir=/run/lower,upperdir=/run/upper,workdir=/run/work"
+        );
+    }
+
+    #[test]
+    fn test_overlay_options_extra_lowers() {
+        let extra_lowers = vec!["/base1".to_string(), "/base2".to_string()];
+        let opts = overlay_options("/run/lower", &extra_lowers, "/run/upper", "/run/work", &[]);
+        assert_eq!(
+            opts,
+            "lowerdir=/run/lower:/base1:/base2,upperdir=/run/upper,workdir=/run/work"
+        );
+    }
+
+    #[test]
+    fn test_overlay_options_tunables() {
+        let config = crate::cmdline::parse_cmdline(
+            "init.console=console init.shell=`sh` init.virtiofs=share:/mnt:Y:metacopy,index",
+        )
+        .unwrap();
+        let tunables = overlay_tunables(&config.virtiofs_mounts[0]);
+        assert_eq!(tunables, vec!["index=on", "metacopy=on"]);
+
+        let opts = overlay_options("/run/lower", &[], "/run/upper", "/run/work", &tunables);
+        assert_eq!(
+            opts,
+            "lowerdir=/run/lower,upperdir=/run/upper,workdir=/run/work,index=on,metacopy=on"
+        );
+    }
+
+    #[test]
+    fn test_overlay_options_redirect_dir_and_xino() {
+        let mount =
+            first_mount("init.virtiofs=share:/mnt:Y:xino=off,index,redirect_dir=on,metacopy");
+        let tunables = overlay_tunables(&mount);
+        assert_eq!(
+            tunables,
+            vec!["index=on", "metacopy=on", "redirect_dir=on", "xino=off"]
+        );
+
+        let opts = overlay_options("/run/lower", &[], "/run/upper", "/run/work", &tunables);
+        assert_eq!(
+            opts,
+            "lowerdir=/run/lower,upperdir=/run/upper,workdir=/run/work,\
+             index=on,metacopy=on,redirect_dir=on,xino=off"
+        );
+
+        let mount = first_mount("init.virtiofs=share:/mnt:Y:redirect_dir=off");
+        assert_eq!(overlay_tunables(&mount), vec!["redirect_dir=off"]);
+    }
+
+    #[test]
+    fn test_tag_available_in() {
+        let base = temp_dir("sysfs-tags");
+        for (device, tag) in [("0", Some("share\n")), ("1", Some("home\n")), ("2", None)] {
+            std::fs::create_dir_all(base.join(device)).unwrap();
+            if let Some(tag) = tag {
+                std::fs::write(base.join(device).join("tag"), tag).unwrap();
+            }
+        }
+
+        assert!(tag_available_in(&base, "share").unwrap());
+        assert!(tag_available_in(&base, "home").unwrap());
+        assert!(!tag_available_in(&base, "missing").unwrap());
+        assert!(!tag_available_in(&base, "shar").unwrap());
+        std::fs::remove_dir_all(&base).unwrap();
+
+        // Kernels that don't list the tags
+        assert!(tag_available_in(&base, "share").is_err());
+    }
+}
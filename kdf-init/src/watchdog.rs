@@ -0,0 +1,116 @@
+//! Hardware watchdog keepalive while init supervises the command
+
+use anyhow::{Context, Result};
+use rustix::ioctl::{Getter, ReadOpcode};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::cmdline::WatchdogConfig;
+
+/// WDIOC_GETTIMEOUT: _IOR('W', 7, int)
+type GetTimeout = Getter<ReadOpcode<b'W', 7, i32>, i32>;
+
+/// Writing this before close disarms the watchdog (when the driver allows it)
+const MAGIC_CLOSE: &[u8] = b"V";
+
+/// Keepalive thread, stopped and joined on drop
+pub struct Watchdog {
+    device: String,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Read the watchdog timeout in seconds from the driver
+fn device_timeout(file: &File) -> rustix::io::Result<u64> {
+    // Safety: WDIOC_GETTIMEOUT writes a single int
+    let timeout = unsafe { rustix::ioctl::ioctl(file, GetTimeout::new()) }?;
+    Ok(timeout.max(0) as u64)
+}
+
+/// Keepalive interval when none is configured: half the device timeout
+fn default_interval(timeout_secs: u64) -> Duration {
+    Duration::from_secs((timeout_secs / 2).max(1))
+}
+
+/// Open the watchdog device and start feeding it from a background thread
+///
+/// Must be called after the supervisor signals are blocked, so the thread
+/// inherits the mask and never consumes them.
+pub fn start(config: &WatchdogConfig) -> Result<Watchdog> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&config.device)
+        .with_context(|| format!("Failed to open watchdog {}", config.device))?;
+
+    let interval = match config.interval {
+        Some(secs) => Duration::from_secs(secs),
+        None => {
+            let timeout = device_timeout(&file).with_context(|| {
+                format!(
+                    "Failed to read the timeout of watchdog {}, \
+                     set an interval with init.watchdog={}:<secs>",
+                    config.device, config.device
+                )
+            })?;
+            default_interval(timeout)
+        }
+    };
+
+    info!(
+        "feeding watchdog {} every {}s",
+        config.device,
+        interval.as_secs()
+    );
+
+    let (stop, stopped) = mpsc::channel();
+    let device = config.device.clone();
+    let thread = std::thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || {
+            // Wake up on every interval, a message or a dropped sender ends the loop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = file.write_all(b"\0") {
+                    warn!("failed to feed watchdog {}: {}", device, e);
+                }
+            }
+
+            if let Err(e) = file.write_all(MAGIC_CLOSE) {
+                warn!("failed to disarm watchdog {}: {}", device, e);
+            }
+        })
+        .context("Failed to spawn watchdog thread")?;
+
+    Ok(Watchdog {
+        device: config.device.clone(),
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        info!("disarmed watchdog {}", self.device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_interval() {
+        assert_eq!(default_interval(60), Duration::from_secs(30));
+        assert_eq!(default_interval(7), Duration::from_secs(3));
+        assert_eq!(default_interval(1), Duration::from_secs(1));
+        assert_eq!(default_interval(0), Duration::from_secs(1));
+    }
+}